@@ -23,6 +23,7 @@ struct Tone {
 
 impl cubeb::StreamCallback for Tone {
     type Frame = cubeb::MonoFrame<i16>;
+    type Message = ();
 
     fn data_callback(&mut self, _: &[cubeb::MonoFrame<i16>], output: &mut [cubeb::MonoFrame<i16>]) -> isize {
 
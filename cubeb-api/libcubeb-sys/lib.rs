@@ -78,7 +78,7 @@ extern "C" {
     ) -> c_int;
     pub fn cubeb_stream_register_device_changed_callback(
         stream: *mut cubeb_stream,
-        device_changed_callback: cubeb_device_changed_callback,
+        device_changed_callback: Option<cubeb_device_changed_callback>,
     ) -> c_int;
     pub fn cubeb_enumerate_devices(
         context: *mut cubeb,
@@ -92,7 +92,7 @@ extern "C" {
     pub fn cubeb_register_device_collection_changed(
         context: *mut cubeb,
         devtype: cubeb_device_type,
-        callback: cubeb_device_collection_changed_callback,
+        callback: Option<cubeb_device_collection_changed_callback>,
         user_ptr: *mut c_void,
     ) -> c_int;
     pub fn cubeb_set_log_callback(
@@ -0,0 +1,842 @@
+// Copyright © 2017 Mozilla Foundation
+//
+// This program is made available under an ISC-style license.  See the
+// accompanying file LICENSE for details.
+
+//! Conversion between a stream's callback format and its device's
+//!
+//! When `StreamInitOptions` give application side params that differ from
+//! the device's, an `Adapter` sits between `data_cb_c` and the callback.
+//! Captured audio is converted to floating point, remixed to the
+//! callback's channels, resampled to its rate and converted to its sample
+//! format; rendered audio takes the same steps in reverse.
+//!
+//! All buffers are allocated up front, sized for the stream's latency.
+//! Larger device callbacks are converted a chunk at a time, calling the
+//! callback once per chunk.
+
+use {ChannelLayout, Error, ErrorCode, Result, SampleFormat, StreamInitOptions, StreamParams};
+use std::f32::consts::FRAC_1_SQRT_2;
+use std::{cmp, mem, ptr, slice};
+use stream::frame_size;
+
+// Device frames converted at a time if the stream's latency isn't known.
+const DEFAULT_CHUNK: usize = 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Channel {
+    M,
+    L,
+    R,
+    C,
+    Lfe,
+    // The single surround channel of 2F1 and 3F1.
+    S,
+    Ls,
+    Rs,
+    Rls,
+    Rrs,
+    Rc
+}
+
+// The channels of `layout`, in order, as in the `ChannelLayout` table.
+fn layout_channels(layout: ChannelLayout) -> &'static [Channel] {
+    use self::Channel::*;
+    match layout {
+        ChannelLayout::Undefined => &[],
+        ChannelLayout::DualMono | ChannelLayout::Stereo => &[L, R],
+        ChannelLayout::DualMonoLfe | ChannelLayout::StereoLfe => &[L, R, Lfe],
+        ChannelLayout::Mono => &[M],
+        ChannelLayout::MonoLfe => &[M, Lfe],
+        ChannelLayout::Layout3F => &[L, R, C],
+        ChannelLayout::Layout3FLfe => &[L, R, C, Lfe],
+        ChannelLayout::Layout2F1 => &[L, R, S],
+        ChannelLayout::Layout2F1Lfe => &[L, R, Lfe, S],
+        ChannelLayout::Layout3F1 => &[L, R, C, S],
+        ChannelLayout::Layout3F1Lfe => &[L, R, C, Lfe, S],
+        ChannelLayout::Layout2F2 => &[L, R, Ls, Rs],
+        ChannelLayout::Layout2F2Lfe => &[L, R, Lfe, Ls, Rs],
+        ChannelLayout::Layout3F2 => &[L, R, C, Ls, Rs],
+        ChannelLayout::Layout3F2Lfe => &[L, R, C, Lfe, Ls, Rs],
+        ChannelLayout::Layout3F3RLfe => &[L, R, C, Lfe, Rc, Ls, Rs],
+        ChannelLayout::Layout3F4Lfe => &[L, R, C, Lfe, Rls, Rrs, Ls, Rs],
+    }
+}
+
+// Where to send `channel` when the destination doesn't have it. The LFE
+// is dropped.
+fn fold(channel: Channel, to: &[Channel]) -> &'static [(Channel, f32)] {
+    use self::Channel::*;
+    let has = |c| to.contains(&c);
+    match channel {
+        M => &[(L, 1.0), (R, 1.0)],
+        L | R => &[(M, 0.5)],
+        C => &[(L, FRAC_1_SQRT_2), (R, FRAC_1_SQRT_2)],
+        S if has(Rc) => &[(Rc, 1.0)],
+        S => &[(Ls, FRAC_1_SQRT_2), (Rs, FRAC_1_SQRT_2)],
+        Rc if has(S) => &[(S, 1.0)],
+        Rc => &[(Rls, FRAC_1_SQRT_2), (Rrs, FRAC_1_SQRT_2)],
+        Rls => &[(Ls, 1.0)],
+        Rrs => &[(Rs, 1.0)],
+        Ls | Rs if has(S) => &[(S, FRAC_1_SQRT_2)],
+        Ls => &[(L, FRAC_1_SQRT_2)],
+        Rs => &[(R, FRAC_1_SQRT_2)],
+        Lfe => &[],
+    }
+}
+
+// Add `gain` of `channel` from input `from` to `matrix`, folding it into
+// other channels if `to` lacks it.
+fn route(matrix: &mut [f32], to: &[Channel], from: usize, inputs: usize, channel: Channel,
+         gain: f32, depth: usize) {
+    if let Some(out) = to.iter().position(|&c| c == channel) {
+        matrix[out * inputs + from] += gain;
+    } else if depth > 0 {
+        for &(c, g) in fold(channel, to) {
+            route(matrix, to, from, inputs, c, gain * g, depth - 1);
+        }
+    }
+}
+
+// Gains from each of `from`'s channels to each of `to`'s, row by output
+// channel, or `None` if they're the same.
+fn remix_matrix(from: &StreamParams, to: &StreamParams) -> Option<Vec<f32>> {
+    let (inputs, outputs) = (from.channels() as usize, to.channels() as usize);
+    if inputs == outputs && from.layout() == to.layout() {
+        return None;
+    }
+    let mut matrix = vec![0.0; inputs * outputs];
+    let from_channels = layout_channels(from.layout());
+    let to_channels = layout_channels(to.layout());
+    if from_channels.len() == inputs && to_channels.len() == outputs {
+        for (i, &channel) in from_channels.iter().enumerate() {
+            route(&mut matrix, to_channels, i, inputs, channel, 1.0, 4);
+        }
+    } else if inputs == 1 {
+        // Unknown layouts: mono goes to the first two channels, ...
+        for gain in matrix.iter_mut().take(2) {
+            *gain = 1.0;
+        }
+    } else if outputs == 1 {
+        // ... the first two make mono, ...
+        let n = cmp::min(inputs, 2);
+        for gain in matrix.iter_mut().take(n) {
+            *gain = 1.0 / n as f32;
+        }
+    } else {
+        // ... and otherwise channels map by position.
+        for c in 0..cmp::min(inputs, outputs) {
+            matrix[c * inputs + c] = 1.0;
+        }
+    }
+    Some(matrix)
+}
+
+fn remix(matrix: &Option<Vec<f32>>, inputs: usize, input: &[f32], output: &mut [f32]) {
+    let matrix = match *matrix {
+        Some(ref matrix) => matrix,
+        None => {
+            output[..input.len()].copy_from_slice(input);
+            return;
+        },
+    };
+    let outputs = matrix.len() / inputs;
+    for (i, o) in input.chunks(inputs).zip(output.chunks_mut(outputs)) {
+        for (o, row) in o.iter_mut().zip(matrix.chunks(inputs)) {
+            *o = row.iter().zip(i).map(|(g, s)| g * s).sum();
+        }
+    }
+}
+
+// Whether `format` is floating point, and whether it's little endian.
+pub(crate) fn sample_kind(format: SampleFormat) -> (bool, bool) {
+    let native = cfg!(target_endian = "little");
+    match format {
+        SampleFormat::S16LE => (false, true),
+        SampleFormat::S16BE => (false, false),
+        SampleFormat::S16NE => (false, native),
+        SampleFormat::Float32LE => (true, true),
+        SampleFormat::Float32BE => (true, false),
+        SampleFormat::Float32NE => (true, native),
+    }
+}
+
+fn to_f32(format: SampleFormat, bytes: &[u8], samples: &mut [f32]) {
+    match sample_kind(format) {
+        (false, little) => for (s, b) in samples.iter_mut().zip(bytes.chunks(2)) {
+            let b = [b[0], b[1]];
+            let x = if little { i16::from_le_bytes(b) } else { i16::from_be_bytes(b) };
+            *s = f32::from(x) / 32768.0;
+        },
+        (true, little) => for (s, b) in samples.iter_mut().zip(bytes.chunks(4)) {
+            let b = [b[0], b[1], b[2], b[3]];
+            *s = if little { f32::from_le_bytes(b) } else { f32::from_be_bytes(b) };
+        },
+    }
+}
+
+fn from_f32(format: SampleFormat, samples: &[f32], bytes: &mut [u8]) {
+    match sample_kind(format) {
+        (false, little) => for (s, b) in samples.iter().zip(bytes.chunks_mut(2)) {
+            let x = (s * 32768.0).round().clamp(-32768.0, 32767.0) as i16;
+            b.copy_from_slice(&if little { x.to_le_bytes() } else { x.to_be_bytes() });
+        },
+        (true, little) => for (s, b) in samples.iter().zip(bytes.chunks_mut(4)) {
+            b.copy_from_slice(&if little { s.to_le_bytes() } else { s.to_be_bytes() });
+        },
+    }
+}
+
+// Linear interpolating resampler of interleaved frames, carrying its
+// position from one call to the next.
+struct Resampler {
+    channels: usize,
+    // Input frames per output frame.
+    step: f64,
+    // Where the next output frame lies between `prev`, at 0, and `cur`,
+    // at 1. Past 1, more input is needed first.
+    pos: f64,
+    prev: Vec<f32>,
+    cur: Vec<f32>
+}
+
+impl Resampler {
+    fn new(from_rate: u32, to_rate: u32, channels: usize) -> Resampler {
+        Resampler {
+            channels,
+            step: f64::from(from_rate) / f64::from(to_rate),
+            // So the first output frame is the first input frame.
+            pos: 2.0,
+            prev: vec![0.0; channels],
+            cur: vec![0.0; channels]
+        }
+    }
+
+    // Input frames needed to produce `frames` more output frames.
+    fn input_for(&self, frames: usize) -> usize {
+        if frames == 0 {
+            return 0;
+        }
+        let last = self.pos + (frames - 1) as f64 * self.step;
+        (last - 1.0).ceil().max(0.0) as usize
+    }
+
+    // Resample `input` into `output` until either runs out, returning the
+    // number of frames consumed and produced.
+    fn process(&mut self, input: &[f32], output: &mut [f32]) -> (usize, usize) {
+        let ch = self.channels;
+        let (mut consumed, mut produced) = (0, 0);
+        loop {
+            if (produced + 1) * ch > output.len() {
+                return (consumed, produced);
+            }
+            while self.pos > 1.0 {
+                if (consumed + 1) * ch > input.len() {
+                    return (consumed, produced);
+                }
+                mem::swap(&mut self.prev, &mut self.cur);
+                self.cur.copy_from_slice(&input[consumed * ch..(consumed + 1) * ch]);
+                consumed += 1;
+                self.pos -= 1.0;
+            }
+            let t = self.pos as f32;
+            let frame = &mut output[produced * ch..(produced + 1) * ch];
+            for ((o, p), c) in frame.iter_mut().zip(&self.prev).zip(&self.cur) {
+                *o = p * (1.0 - t) + c * t;
+            }
+            produced += 1;
+            self.pos += self.step;
+        }
+    }
+}
+
+// Interleaved samples waiting to be resampled or handed to the callback.
+struct Fifo {
+    samples: Vec<f32>,
+    channels: usize,
+    read: usize,
+    write: usize
+}
+
+impl Fifo {
+    fn new(frames: usize, channels: usize) -> Fifo {
+        Fifo {
+            samples: vec![0.0; frames * channels],
+            channels,
+            read: 0,
+            write: 0
+        }
+    }
+
+    fn frames(&self) -> usize {
+        (self.write - self.read) / self.channels
+    }
+
+    fn data(&self) -> &[f32] {
+        &self.samples[self.read..self.write]
+    }
+
+    fn consume(&mut self, frames: usize) {
+        self.read += frames * self.channels;
+        if self.read == self.write {
+            self.read = 0;
+            self.write = 0;
+        }
+    }
+
+    // Room to append to, after moving what's waiting to the front.
+    fn space(&mut self) -> &mut [f32] {
+        if self.read > 0 {
+            self.samples.copy_within(self.read..self.write, 0);
+            self.write -= self.read;
+            self.read = 0;
+        }
+        &mut self.samples[self.write..]
+    }
+
+    fn commit(&mut self, frames: usize) {
+        self.write += frames * self.channels;
+    }
+}
+
+// An 8 byte aligned buffer of `frames` frames in callback format.
+fn frame_buffer(frames: usize, params: &StreamParams) -> Vec<u64> {
+    vec![0; (frames * frame_size(params)).div_ceil(8)]
+}
+
+fn bytes(buffer: &[u64]) -> &[u8] {
+    unsafe { slice::from_raw_parts(buffer.as_ptr() as *const u8, buffer.len() * 8) }
+}
+
+fn bytes_mut(buffer: &mut [u64]) -> &mut [u8] {
+    unsafe { slice::from_raw_parts_mut(buffer.as_mut_ptr() as *mut u8, buffer.len() * 8) }
+}
+
+// Captured audio on its way to the callback.
+struct InputSide {
+    device: StreamParams,
+    app: StreamParams,
+    matrix: Option<Vec<f32>>,
+    // A chunk of device input as samples, then remixed.
+    samples: Vec<f32>,
+    remixed: Vec<f32>,
+    resampler: Resampler,
+    // Resampled input for the callback.
+    fifo: Fifo,
+    // The callback's input, in its format.
+    frames: Vec<u64>
+}
+
+impl InputSide {
+    fn new(device: StreamParams, app: StreamParams, chunk: usize, app_chunk: usize) -> InputSide {
+        let (dch, ach) = (device.channels() as usize, app.channels() as usize);
+        InputSide {
+            matrix: remix_matrix(&device, &app),
+            samples: vec![0.0; chunk * dch],
+            remixed: vec![0.0; chunk * ach],
+            resampler: Resampler::new(device.rate(), app.rate(), ach),
+            fifo: Fifo::new(app_chunk * 2, ach),
+            frames: frame_buffer(app_chunk, &app),
+            device,
+            app
+        }
+    }
+
+    // Convert `frames` device frames at `input` and queue them for the
+    // callback, dropping the oldest queued if there's no room.
+    unsafe fn capture(&mut self, input: *const u8, frames: usize) {
+        let (dch, ach) = (self.device.channels() as usize, self.app.channels() as usize);
+        let input = slice::from_raw_parts(input, frames * frame_size(&self.device));
+        to_f32(self.device.format(), input, &mut self.samples[..frames * dch]);
+        remix(&self.matrix, dch, &self.samples[..frames * dch], &mut self.remixed[..frames * ach]);
+        let mut fed = 0;
+        while fed < frames {
+            let (consumed, produced) = {
+                let space = self.fifo.space();
+                self.resampler.process(&self.remixed[fed * ach..frames * ach], space)
+            };
+            self.fifo.commit(produced);
+            fed += consumed;
+            if fed < frames {
+                let full = self.fifo.frames();
+                self.fifo.consume(cmp::max(full / 2, 1));
+            }
+        }
+    }
+
+    // Move `frames` queued frames to the callback's buffer, padding with
+    // silence if fewer are queued.
+    fn take(&mut self, frames: usize) -> *const u8 {
+        let ach = self.app.channels() as usize;
+        let queued = cmp::min(frames, self.fifo.frames());
+        let size = frame_size(&self.app);
+        let format = self.app.format();
+        {
+            let out = bytes_mut(&mut self.frames);
+            from_f32(format, &self.fifo.data()[..queued * ach], &mut out[..queued * size]);
+            // Zero is silence in every format.
+            for b in &mut out[queued * size..frames * size] {
+                *b = 0;
+            }
+        }
+        self.fifo.consume(queued);
+        self.frames.as_ptr() as *const u8
+    }
+}
+
+// The callback's audio on its way to the device.
+struct OutputSide {
+    device: StreamParams,
+    app: StreamParams,
+    matrix: Option<Vec<f32>>,
+    // What the callback rendered, in its format, then as samples.
+    frames: Vec<u64>,
+    samples: Vec<f32>,
+    // Rendered audio remixed to the device's channels, to be resampled.
+    fifo: Fifo,
+    resampler: Resampler,
+    // A chunk of device output as samples.
+    resampled: Vec<f32>,
+    // Set once the callback returns fewer frames than asked for.
+    draining: bool
+}
+
+impl OutputSide {
+    fn new(device: StreamParams, app: StreamParams, chunk: usize, app_chunk: usize)
+        -> OutputSide {
+        let (dch, ach) = (device.channels() as usize, app.channels() as usize);
+        OutputSide {
+            matrix: remix_matrix(&app, &device),
+            frames: frame_buffer(app_chunk, &app),
+            samples: vec![0.0; app_chunk * ach],
+            fifo: Fifo::new(app_chunk, dch),
+            resampler: Resampler::new(app.rate(), device.rate(), dch),
+            resampled: vec![0.0; chunk * dch],
+            draining: false,
+            device,
+            app
+        }
+    }
+
+    // Queue the `frames` frames the callback rendered.
+    fn queue(&mut self, frames: usize) {
+        let ach = self.app.channels() as usize;
+        let size = frame_size(&self.app);
+        to_f32(self.app.format(), &bytes(&self.frames)[..frames * size],
+               &mut self.samples[..frames * ach]);
+        let dch = self.device.channels() as usize;
+        let space = self.fifo.space();
+        remix(&self.matrix, ach, &self.samples[..frames * ach], &mut space[..frames * dch]);
+        self.fifo.commit(frames);
+    }
+}
+
+/// Converts between the format a stream's callback works in and its
+/// device's.
+pub(crate) struct Adapter {
+    // Most device frames converted per step.
+    chunk: usize,
+    // Most callback frames per call.
+    app_chunk: usize,
+    input: Option<InputSide>,
+    output: Option<OutputSide>
+}
+
+// A side's device and callback params, if it has any.
+fn side(device: Option<StreamParams>, app: Option<StreamParams>)
+    -> Result<Option<(StreamParams, StreamParams)>> {
+    match (device, app) {
+        (Some(device), app) => {
+            let app = app.unwrap_or(device);
+            for params in &[device, app] {
+                if params.rate() == 0 || params.channels() == 0 {
+                    return Err(Error::from(ErrorCode::InvalidParameter));
+                }
+            }
+            Ok(Some((device, app)))
+        },
+        (None, Some(_)) => Err(Error::from(ErrorCode::InvalidParameter)),
+        (None, None) => Ok(None),
+    }
+}
+
+fn same(a: &StreamParams, b: &StreamParams) -> bool {
+    sample_kind(a.format()) == sample_kind(b.format()) && a.rate() == b.rate()
+        && a.channels() == b.channels() && a.layout() == b.layout()
+}
+
+impl Adapter {
+    /// An adapter for the application side params in `opts`, or `None`
+    /// if the callback can use the device's buffers as they are.
+    pub(crate) fn new(opts: &StreamInitOptions) -> Result<Option<Adapter>> {
+        if opts.input_app_params.is_none() && opts.output_app_params.is_none() {
+            return Ok(None);
+        }
+        let input = side(opts.input_stream_params, opts.input_app_params)?;
+        let output = side(opts.output_stream_params, opts.output_app_params)?;
+        if input.iter().chain(&output).all(|(d, a)| same(d, a)) {
+            return Ok(None);
+        }
+        // The callback sees both sides at once, at one rate.
+        let rate = match (input, output) {
+            (Some((di, ai)), Some((d, a))) => {
+                if ai.rate() != a.rate() || di.rate() != d.rate() {
+                    return Err(Error::from(ErrorCode::InvalidParameter));
+                }
+                (d.rate(), a.rate())
+            },
+            (Some((d, a)), None) | (None, Some((d, a))) => (d.rate(), a.rate()),
+            (None, None) => unreachable!(),
+        };
+        let chunk = match opts.latency_frames as usize {
+            0 => DEFAULT_CHUNK,
+            latency => latency,
+        };
+        let app_chunk = chunk * rate.1 as usize / rate.0 as usize + 2;
+        Ok(Some(Adapter {
+            chunk,
+            app_chunk,
+            input: input.map(|(d, a)| InputSide::new(d, a, chunk, app_chunk)),
+            output: output.map(|(d, a)| OutputSide::new(d, a, chunk, app_chunk))
+        }))
+    }
+
+    /// Run a device callback of `nframes` frames, calling `cb` with the
+    /// callback's input and output buffers, null for a side the stream
+    /// lacks, and their length in frames. Returns the frames handled, as
+    /// the device callback does.
+    pub(crate) unsafe fn process<F>(&mut self, input: *const u8, output: *mut u8, nframes: usize,
+                                    mut cb: F) -> isize
+    where
+        F: FnMut(*const u8, *mut u8, usize) -> isize,
+    {
+        let mut done = 0;
+        while done < nframes {
+            let n = cmp::min(self.chunk, nframes - done);
+            if let Some(ref mut side) = self.input {
+                if !input.is_null() {
+                    side.capture(input.add(done * frame_size(&side.device)), n);
+                }
+            }
+            let handled = if self.output.is_some() {
+                let at = output.add(done * frame_size(&self.output.as_ref().unwrap().device));
+                self.render(at, n, &mut cb)
+            } else {
+                self.deliver(n, &mut cb)
+            };
+            if handled < 0 {
+                return handled;
+            }
+            done += handled as usize;
+            if (handled as usize) < n {
+                break;
+            }
+        }
+        done as isize
+    }
+
+    // Hand an input only stream's queued input to the callback. Returns
+    // `frames` unless the callback stopped the stream.
+    fn deliver<F>(&mut self, frames: usize, cb: &mut F) -> isize
+    where
+        F: FnMut(*const u8, *mut u8, usize) -> isize,
+    {
+        let side = self.input.as_mut().unwrap();
+        while side.fifo.frames() > 0 {
+            let k = cmp::min(side.fifo.frames(), self.app_chunk);
+            let input = side.take(k);
+            let handled = cb(input, ptr::null_mut(), k);
+            if handled < 0 {
+                return handled;
+            }
+            if (handled as usize) < k {
+                return 0;
+            }
+        }
+        frames as isize
+    }
+
+    // Fill `frames` device frames at `output` from the callback. Returns
+    // fewer once the callback has drained.
+    unsafe fn render<F>(&mut self, output: *mut u8, frames: usize, cb: &mut F) -> isize
+    where
+        F: FnMut(*const u8, *mut u8, usize) -> isize,
+    {
+        let app_chunk = self.app_chunk;
+        let mut input = self.input.as_mut();
+        let side = self.output.as_mut().unwrap();
+        let dch = side.device.channels() as usize;
+        let mut produced = 0;
+        loop {
+            let (consumed, p) = side.resampler
+                .process(side.fifo.data(), &mut side.resampled[produced * dch..frames * dch]);
+            side.fifo.consume(consumed);
+            produced += p;
+            if produced == frames || side.draining {
+                break;
+            }
+            let k = cmp::max(cmp::min(side.resampler.input_for(frames - produced), app_chunk), 1);
+            let in_ptr = match input {
+                Some(ref mut input) => input.take(k),
+                None => ptr::null(),
+            };
+            let handled = cb(in_ptr, side.frames.as_mut_ptr() as *mut u8, k);
+            if handled < 0 {
+                return handled;
+            }
+            let handled = cmp::min(handled as usize, k);
+            if handled < k {
+                side.draining = true;
+            }
+            side.queue(handled);
+        }
+        let out = slice::from_raw_parts_mut(output, produced * frame_size(&side.device));
+        from_f32(side.device.format(), &side.resampled[..produced * dch], out);
+        produced as isize
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Adapter, Resampler, from_f32, remix, remix_matrix, to_f32};
+    use {ChannelLayout, SampleFormat, StreamInitOptionsBuilder, StreamParams, StreamParamsBuilder};
+    use std::f32::consts::FRAC_1_SQRT_2;
+    use std::{ptr, slice};
+
+    fn params(format: SampleFormat, rate: u32, layout: ChannelLayout) -> StreamParams {
+        StreamParamsBuilder::new()
+            .format(format)
+            .rate(rate)
+            .channels(layout.channel_count())
+            .layout(layout)
+            .take()
+    }
+
+    #[test]
+    fn adapt_samples() {
+        let samples = [0.0, 0.5, -1.0, 32767.0 / 32768.0];
+        for &format in &[SampleFormat::S16LE, SampleFormat::S16BE,
+                         SampleFormat::Float32LE, SampleFormat::Float32BE] {
+            let mut bytes = [0u8; 16];
+            from_f32(format, &samples, &mut bytes);
+            let mut back = [1.0f32; 4];
+            to_f32(format, &bytes, &mut back);
+            assert_eq!(back, samples);
+        }
+        let mut bytes = [0u8; 4];
+        from_f32(SampleFormat::S16BE, &[0.5, 2.0], &mut bytes);
+        assert_eq!(bytes, [0x40, 0x00, 0x7f, 0xff]);
+    }
+
+    #[test]
+    fn adapt_remix_matrices() {
+        let mono = params(SampleFormat::S16LE, 48000, ChannelLayout::Mono);
+        let stereo = params(SampleFormat::S16LE, 48000, ChannelLayout::Stereo);
+        let surround = params(SampleFormat::S16LE, 48000, ChannelLayout::Layout3F2Lfe);
+        assert_eq!(remix_matrix(&stereo, &stereo), None);
+        assert_eq!(remix_matrix(&mono, &stereo), Some(vec![1.0, 1.0]));
+        assert_eq!(remix_matrix(&stereo, &mono), Some(vec![0.5, 0.5]));
+        let h = FRAC_1_SQRT_2;
+        // L R C LFE LS RS
+        assert_eq!(
+            remix_matrix(&surround, &stereo),
+            Some(vec![1.0, 0.0, h, 0.0, h, 0.0, 0.0, 1.0, h, 0.0, 0.0, h])
+        );
+        assert_eq!(
+            remix_matrix(&stereo, &surround),
+            Some(vec![1.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0])
+        );
+
+        // Layouts that don't say where channels go.
+        let three = StreamParamsBuilder::new().channels(3).take();
+        assert_eq!(remix_matrix(&three, &mono), Some(vec![0.5, 0.5, 0.0]));
+        assert_eq!(remix_matrix(&mono, &three), Some(vec![1.0, 1.0, 0.0]));
+        assert_eq!(remix_matrix(&three, &stereo), Some(vec![1.0, 0.0, 0.0, 0.0, 1.0, 0.0]));
+
+        let mut out = [0.0; 4];
+        remix(&remix_matrix(&stereo, &mono), 2, &[1.0, 0.5, -1.0, 0.0], &mut out[..2]);
+        assert_eq!(out[..2], [0.75, -0.5]);
+        remix(&None, 2, &[1.0, 0.5, -1.0, 0.0], &mut out);
+        assert_eq!(out, [1.0, 0.5, -1.0, 0.0]);
+    }
+
+    #[test]
+    fn adapt_resample_same_rate() {
+        let mut r = Resampler::new(44100, 44100, 2);
+        let input: Vec<f32> = (0..20).map(|i| i as f32 * 0.1).collect();
+        let mut output = [0.0; 20];
+        assert_eq!(r.input_for(10), 10);
+        assert_eq!(r.process(&input[..8], &mut output), (4, 4));
+        assert_eq!(r.input_for(6), 6);
+        assert_eq!(r.process(&input[8..], &mut output[8..]), (6, 6));
+        assert_eq!(output[..], input[..]);
+    }
+
+    #[test]
+    fn adapt_resample_up_and_down() {
+        let mut up = Resampler::new(24000, 48000, 1);
+        let mut output = [0.0; 8];
+        assert_eq!(up.input_for(8), 5);
+        assert_eq!(up.process(&[0.0, 1.0, 2.0, 3.0, 4.0, 5.0], &mut output), (5, 8));
+        assert_eq!(output, [0.0, 0.5, 1.0, 1.5, 2.0, 2.5, 3.0, 3.5]);
+        // Picks up where it left off.
+        assert_eq!(up.input_for(2), 1);
+        assert_eq!(up.process(&[5.0], &mut output[..2]), (1, 2));
+        assert_eq!(output[..2], [4.0, 4.5]);
+
+        let mut down = Resampler::new(48000, 16000, 1);
+        let input: Vec<f32> = (0..12).map(|i| i as f32).collect();
+        assert_eq!(down.input_for(4), 10);
+        assert_eq!(down.process(&input, &mut output[..4]), (10, 4));
+        assert_eq!(output[..4], [0.0, 3.0, 6.0, 9.0]);
+    }
+
+    fn adapter(builder: &mut StreamInitOptionsBuilder) -> Adapter {
+        Adapter::new(&builder.take()).unwrap().unwrap()
+    }
+
+    #[test]
+    fn adapt_only_when_needed() {
+        let s16 = params(SampleFormat::S16NE, 48000, ChannelLayout::Stereo);
+        let f32s = params(SampleFormat::Float32NE, 48000, ChannelLayout::Stereo);
+        let opts = StreamInitOptionsBuilder::new().output_stream_param(&s16).take();
+        assert!(Adapter::new(&opts).unwrap().is_none());
+        let opts = StreamInitOptionsBuilder::new()
+            .output_stream_param(&s16)
+            .output_app_param(&s16)
+            .take();
+        assert!(Adapter::new(&opts).unwrap().is_none());
+        let opts = StreamInitOptionsBuilder::new()
+            .output_stream_param(&s16)
+            .output_app_param(&f32s)
+            .take();
+        assert!(Adapter::new(&opts).unwrap().is_some());
+
+        // Application params without device ones, ...
+        let opts = StreamInitOptionsBuilder::new().input_app_param(&s16).take();
+        assert!(Adapter::new(&opts).is_err());
+        // ... and a duplex callback at two rates.
+        let slow = params(SampleFormat::Float32NE, 16000, ChannelLayout::Stereo);
+        let opts = StreamInitOptionsBuilder::new()
+            .input_stream_param(&s16)
+            .input_app_param(&slow)
+            .output_stream_param(&s16)
+            .output_app_param(&f32s)
+            .take();
+        assert!(Adapter::new(&opts).is_err());
+    }
+
+    #[test]
+    fn adapt_output() {
+        // The callback renders mono s16 at 24kHz for a stereo f32 device
+        // at 48kHz, 16 frames at a time.
+        let device = params(SampleFormat::Float32NE, 48000, ChannelLayout::Stereo);
+        let app = params(SampleFormat::S16NE, 24000, ChannelLayout::Mono);
+        let mut a = adapter(StreamInitOptionsBuilder::new()
+                                .output_stream_param(&device)
+                                .output_app_param(&app)
+                                .latency(16));
+        let mut next = 0i16;
+        let mut calls = Vec::new();
+        let mut output = [0.0f32; 2 * 40];
+        let handled = unsafe {
+            a.process(ptr::null(), output.as_mut_ptr() as *mut u8, 40, |input, out, frames| {
+                assert!(input.is_null());
+                calls.push(frames);
+                let out = slice::from_raw_parts_mut(out as *mut i16, frames);
+                for s in out {
+                    *s = next * 1024;
+                    next += 1;
+                }
+                frames as isize
+            })
+        };
+        assert_eq!(handled, 40);
+        assert!(calls.iter().all(|&frames| frames <= 16 / 2 + 2));
+        for (i, frame) in output.chunks(2).enumerate() {
+            let expected = i as f32 / 2.0 * 1024.0 / 32768.0;
+            assert_eq!(frame, [expected, expected]);
+        }
+    }
+
+    #[test]
+    fn adapt_output_drains() {
+        let device = params(SampleFormat::S16NE, 48000, ChannelLayout::Mono);
+        let app = params(SampleFormat::Float32NE, 48000, ChannelLayout::Mono);
+        let mut a = adapter(StreamInitOptionsBuilder::new()
+                                .output_stream_param(&device)
+                                .output_app_param(&app)
+                                .latency(8));
+        let mut left = 20;
+        let mut output = [0i16; 32];
+        let handled = unsafe {
+            a.process(ptr::null(), output.as_mut_ptr() as *mut u8, 32, |_, out, frames| {
+                let n = frames.min(left);
+                let out = slice::from_raw_parts_mut(out as *mut f32, n);
+                for s in out {
+                    *s = 0.5;
+                }
+                left -= n;
+                n as isize
+            })
+        };
+        assert_eq!(handled, 20);
+        assert!(output[..20].iter().all(|&s| s == 16384));
+    }
+
+    #[test]
+    fn adapt_input() {
+        // A stereo s16 device at 48kHz captured as mono f32 at 16kHz.
+        let device = params(SampleFormat::S16NE, 48000, ChannelLayout::Stereo);
+        let app = params(SampleFormat::Float32NE, 16000, ChannelLayout::Mono);
+        let mut a = adapter(StreamInitOptionsBuilder::new()
+                                .input_stream_param(&device)
+                                .input_app_param(&app)
+                                .latency(30));
+        let input: Vec<i16> = (0..96).flat_map(|i| vec![i * 100, i * 100 + 50]).collect();
+        let mut captured = Vec::new();
+        let handled = unsafe {
+            a.process(input.as_ptr() as *const u8, ptr::null_mut(), 96, |input, out, frames| {
+                assert!(out.is_null());
+                captured.extend_from_slice(slice::from_raw_parts(input as *const f32, frames));
+                frames as isize
+            })
+        };
+        assert_eq!(handled, 96);
+        assert_eq!(captured.len(), 32);
+        for (i, s) in captured.iter().enumerate() {
+            assert_eq!(*s, (i as f32 * 300.0 + 25.0) / 32768.0);
+        }
+
+        // Returning fewer frames than given stops the stream.
+        let handled = unsafe {
+            a.process(input.as_ptr() as *const u8, ptr::null_mut(), 96, |_, _, _| 0)
+        };
+        assert!(handled < 96);
+    }
+
+    #[test]
+    fn adapt_duplex() {
+        // Mono s16 in and out on the device, stereo f32 for the callback,
+        // which copies its input to its output.
+        let device = params(SampleFormat::S16NE, 48000, ChannelLayout::Mono);
+        let app = params(SampleFormat::Float32NE, 48000, ChannelLayout::Stereo);
+        let mut a = adapter(StreamInitOptionsBuilder::new()
+                                .input_stream_param(&device)
+                                .input_app_param(&app)
+                                .output_stream_param(&device)
+                                .output_app_param(&app)
+                                .latency(16));
+        let input: Vec<i16> = (0..40).map(|i| i * 10).collect();
+        let mut output = [0i16; 40];
+        let handled = unsafe {
+            a.process(input.as_ptr() as *const u8, output.as_mut_ptr() as *mut u8, 40,
+                      |input, out, frames| {
+                          ptr::copy_nonoverlapping(input, out, frames * 8);
+                          frames as isize
+                      })
+        };
+        assert_eq!(handled, 40);
+        assert_eq!(output[..], input[..]);
+    }
+}
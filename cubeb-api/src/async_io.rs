@@ -0,0 +1,807 @@
+// Copyright © 2017 Mozilla Foundation
+//
+// This program is made available under an ISC-style license.  See the
+// accompanying file LICENSE for details.
+
+//! Futures for stream and device events, and async audio I/O
+//!
+//! Enabled by the `async` feature, for applications that run on an
+//! executor and can't block a thread waiting on a stream:
+//!
+//! - `Stream::state_events` and `Stream::device_changes` are
+//!   `futures_core::Stream`s of a stream's state transitions and device
+//!   changes.
+//! - `Stream::wait_for_state_async` and `Stream::drained_async` are
+//!   futures for a stream reaching a state.
+//! - `Context::device_collection_changes` is a `futures_core::Stream` of
+//!   changes to the devices of a type.
+//! - `AsyncOutputWriter` and `AsyncInputReader` are an `OutputWriter`
+//!   and an `InputReader` whose writes and reads are futures.
+//!
+//! A task registers its `Waker` before looking for what it waits on, and
+//! the thread making it happen only calls `Waker::wake_by_ref`, so the
+//! audio thread never allocates, frees or blocks for a future. Whatever
+//! the executor does in `wake_by_ref` still runs on that thread.
+//!
+//! Up to eight of a stream's event streams and futures can exist at
+//! once; creating more fails with `NotSupported`, detailed by
+//! `TOO_MANY_WAITERS`. libcubeb keeps one device changed callback per
+//! stream and one device collection changed callback per context, so
+//! creating a second `DeviceChanges` for a stream, or a second
+//! `DeviceCollectionChanges` for a context, fails with `Error`, detailed
+//! by `ALREADY_REGISTERED`.
+
+use {Binding, Context, DeviceType, Error, ErrorCode, Frame, InputReader, OutputWriter, Result, State,
+     StreamParams};
+use ffi;
+use std::future::Future;
+use std::os::raw::c_void;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::task::{self, Poll, Waker};
+use std::ptr;
+use stream::{SharedState, state_from_index};
+use sys;
+use waker::WakerCell;
+
+const WAKER_SLOTS: usize = 8;
+
+/// Detail of the error creating a ninth event stream or future for one
+/// stream fails with.
+pub const TOO_MANY_WAITERS: &str = "too many event streams and futures for one stream";
+
+/// Detail of the error registering a callback libcubeb only keeps one
+/// of fails with, while another `DeviceChanges` or
+/// `DeviceCollectionChanges` has it.
+pub const ALREADY_REGISTERED: &str = "callback already registered";
+
+fn too_many_waiters() -> Error {
+    Error::from(ErrorCode::NotSupported).with_detail(TOO_MANY_WAITERS)
+}
+
+fn already_registered() -> Error {
+    Error::from(ErrorCode::Error).with_detail(ALREADY_REGISTERED)
+}
+
+// Wakers of the futures waiting on one stream, each claiming a slot.
+struct Wakers {
+    claimed: [AtomicBool; WAKER_SLOTS],
+    cells: [WakerCell; WAKER_SLOTS]
+}
+
+impl Wakers {
+    fn claim(&self) -> Result<usize> {
+        for (slot, claimed) in self.claimed.iter().enumerate() {
+            if claimed
+                .compare_exchange(false, true, Ordering::AcqRel, Ordering::Acquire)
+                .is_ok()
+            {
+                return Ok(slot);
+            }
+        }
+        Err(too_many_waiters())
+    }
+
+    fn register(&self, slot: usize, waker: &Waker) {
+        self.cells[slot].register(waker);
+    }
+
+    fn release(&self, slot: usize) {
+        self.cells[slot].clear();
+        self.claimed[slot].store(false, Ordering::Release);
+    }
+
+    fn wake_all(&self) {
+        for (claimed, cell) in self.claimed.iter().zip(self.cells.iter()) {
+            if claimed.load(Ordering::Acquire) {
+                cell.wake();
+            }
+        }
+    }
+}
+
+// How many states a `StateEvents` can fall behind before missing some.
+const STATE_HISTORY: usize = 16;
+
+// The native stream a `DeviceChanges` registered the device changed
+// callback on.
+pub(crate) struct Registered(*mut ffi::cubeb_stream);
+
+// Only used under `StreamEvents::registered`'s lock, which the stream
+// holds while it's destroyed.
+unsafe impl Send for Registered {}
+
+// Events a stream's callbacks record for its futures.
+pub(crate) struct StreamEvents {
+    // The last `STATE_HISTORY` states signaled, each stored as its
+    // position shifted left by two, plus the state.
+    states: [AtomicUsize; STATE_HISTORY],
+    signaled: AtomicUsize,
+    device_changes: AtomicUsize,
+    // Set once the stream is destroyed.
+    closed: AtomicBool,
+    registered: Mutex<Option<Registered>>,
+    wakers: Wakers
+}
+
+impl StreamEvents {
+    pub(crate) fn new() -> StreamEvents {
+        StreamEvents {
+            states: Default::default(),
+            signaled: AtomicUsize::new(0),
+            device_changes: AtomicUsize::new(0),
+            closed: AtomicBool::new(false),
+            registered: Mutex::new(None),
+            wakers: Wakers {
+                claimed: Default::default(),
+                cells: Default::default()
+            }
+        }
+    }
+
+    // libcubeb doesn't call a stream's state callback concurrently, so
+    // there's only ever one writer.
+    pub(crate) fn state(&self, state: State) {
+        let pos = self.signaled.load(Ordering::Relaxed);
+        self.states[pos % STATE_HISTORY].store((pos << 2) | state as usize, Ordering::Release);
+        self.signaled.store(pos.wrapping_add(1), Ordering::Release);
+        self.wakers.wake_all();
+    }
+
+    pub(crate) fn device_changed(&self) {
+        self.device_changes.fetch_add(1, Ordering::AcqRel);
+        self.wakers.wake_all();
+    }
+
+    fn lock_registered(&self) -> MutexGuard<'_, Option<Registered>> {
+        self.registered.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    // Forget the device changed callback's registration before `stream`
+    // is destroyed. `DeviceChanges` can't unregister it until the guard
+    // is dropped, after destroying the stream.
+    pub(crate) fn destroying(&self) -> MutexGuard<'_, Option<Registered>> {
+        let mut registered = self.lock_registered();
+        *registered = None;
+        registered
+    }
+
+    pub(crate) fn close(&self) {
+        self.closed.store(true, Ordering::Release);
+        self.wakers.wake_all();
+    }
+
+    fn closed(&self) -> bool {
+        self.closed.load(Ordering::Acquire)
+    }
+
+    // The state signaled at `*next`, skipping ahead past states that
+    // were overwritten.
+    fn next_state(&self, next: &mut usize) -> Option<State> {
+        loop {
+            let signaled = self.signaled.load(Ordering::Acquire);
+            if signaled == *next {
+                return None;
+            }
+            if signaled.wrapping_sub(*next) > STATE_HISTORY {
+                *next = signaled.wrapping_sub(STATE_HISTORY);
+            }
+            let stored = self.states[*next % STATE_HISTORY].load(Ordering::Acquire);
+            // Otherwise a newer state overwrote it meanwhile.
+            if stored >> 2 == *next << 2 >> 2 {
+                *next = next.wrapping_add(1);
+                return Some(state_from_index(stored & 3));
+            }
+        }
+    }
+}
+
+/// The states a stream signals from now on, as a `futures_core::Stream`
+/// ending when the stream is destroyed. Created by
+/// `Stream::state_events`.
+///
+/// Up to 16 states wait for a task that falls behind; older ones are
+/// skipped.
+pub struct StateEvents {
+    shared: Arc<SharedState>,
+    slot: usize,
+    next: usize
+}
+
+impl StateEvents {
+    pub(crate) fn new(shared: Arc<SharedState>) -> Result<StateEvents> {
+        let slot = shared.events.wakers.claim()?;
+        let next = shared.events.signaled.load(Ordering::Acquire);
+        Ok(StateEvents {
+            shared,
+            slot,
+            next
+        })
+    }
+}
+
+impl ::futures_core::Stream for StateEvents {
+    type Item = State;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<Option<State>> {
+        let this = self.get_mut();
+        let events = &this.shared.events;
+        events.wakers.register(this.slot, cx.waker());
+        if let Some(state) = events.next_state(&mut this.next) {
+            Poll::Ready(Some(state))
+        } else if events.closed() {
+            Poll::Ready(None)
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+impl Drop for StateEvents {
+    fn drop(&mut self) {
+        self.shared.events.wakers.release(self.slot);
+    }
+}
+
+/// A stream's device changes from now on, as a `futures_core::Stream`
+/// ending when the stream is destroyed. Created by
+/// `Stream::device_changes`.
+///
+/// Changes made before the task gets to poll are yielded as one. Only one
+/// of these can exist per stream at a time.
+pub struct DeviceChanges {
+    shared: Arc<SharedState>,
+    slot: usize,
+    seen: usize
+}
+
+impl DeviceChanges {
+    pub(crate) fn new(
+        shared: Arc<SharedState>,
+        context: *mut ffi::cubeb,
+        stream: *mut ffi::cubeb_stream,
+        callback: ffi::cubeb_device_changed_callback,
+    ) -> Result<DeviceChanges> {
+        let events = &shared.events;
+        let mut registered = events.lock_registered();
+        if registered.is_some() {
+            return Err(already_registered());
+        }
+        let slot = events.wakers.claim()?;
+        let result = unsafe {
+            call!(sys::cubeb_stream_register_device_changed_callback(
+                stream,
+                Some(callback)
+            ))
+        };
+        let operation = "cubeb_stream_register_device_changed_callback";
+        if let Err(e) = ::call::try(result, operation, context, || None) {
+            events.wakers.release(slot);
+            return Err(e);
+        }
+        *registered = Some(Registered(stream));
+        let seen = events.device_changes.load(Ordering::Acquire);
+        drop(registered);
+        Ok(DeviceChanges {
+            shared,
+            slot,
+            seen
+        })
+    }
+}
+
+impl ::futures_core::Stream for DeviceChanges {
+    type Item = ();
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<Option<()>> {
+        let this = self.get_mut();
+        let events = &this.shared.events;
+        events.wakers.register(this.slot, cx.waker());
+        let changes = events.device_changes.load(Ordering::Acquire);
+        if changes != this.seen {
+            this.seen = changes;
+            Poll::Ready(Some(()))
+        } else if events.closed() {
+            Poll::Ready(None)
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+impl Drop for DeviceChanges {
+    fn drop(&mut self) {
+        let events = &self.shared.events;
+        // Unless the stream was destroyed meanwhile.
+        if let Some(Registered(stream)) = events.lock_registered().take() {
+            unsafe {
+                call!(sys::cubeb_stream_register_device_changed_callback(stream, None));
+            }
+        }
+        events.wakers.release(self.slot);
+    }
+}
+
+/// A future for a stream signaling a state. Created by
+/// `Stream::wait_for_state_async` and `Stream::drained_async`.
+///
+/// Fails if the stream signals `Error` instead, or is destroyed first.
+pub struct WaitForState {
+    shared: Arc<SharedState>,
+    slot: usize,
+    state: State
+}
+
+impl WaitForState {
+    pub(crate) fn new(shared: Arc<SharedState>, state: State) -> Result<WaitForState> {
+        let slot = shared.events.wakers.claim()?;
+        Ok(WaitForState {
+            shared,
+            slot,
+            state
+        })
+    }
+}
+
+impl Future for WaitForState {
+    type Output = Result<()>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<Result<()>> {
+        let this = self.get_mut();
+        this.shared.events.wakers.register(this.slot, cx.waker());
+        match this.shared.reached(this.state) {
+            Ok(true) => Poll::Ready(Ok(())),
+            Err(e) => Poll::Ready(Err(e)),
+            _ if this.shared.events.closed() => Poll::Ready(Err(Error::new())),
+            _ => Poll::Pending,
+        }
+    }
+}
+
+impl Drop for WaitForState {
+    fn drop(&mut self) {
+        self.shared.events.wakers.release(self.slot);
+    }
+}
+
+// Counts a context's device collection changes.
+struct CollectionEvents {
+    changes: AtomicUsize,
+    waker: WakerCell
+}
+
+extern "C" fn collection_changed_cb_c(_: *mut ffi::cubeb, user_ptr: *mut c_void) {
+    let events = unsafe { &*(user_ptr as *const CollectionEvents) };
+    events.changes.fetch_add(1, Ordering::AcqRel);
+    events.waker.wake();
+}
+
+/// Changes to a context's devices of a type, as a `futures_core::Stream`
+/// that never ends. Created by `Context::device_collection_changes`.
+///
+/// Changes made before the task gets to poll are yielded as one.
+/// libcubeb keeps one such callback per context, so only one of these
+/// can exist per context at a time.
+pub struct DeviceCollectionChanges<'a> {
+    context: &'a Context,
+    devtype: DeviceType,
+    events: Box<CollectionEvents>,
+    seen: usize
+}
+
+impl<'a> DeviceCollectionChanges<'a> {
+    pub(crate) fn new(context: &'a Context, devtype: DeviceType) -> Result<Self> {
+        if context.collection_changes.get() {
+            return Err(already_registered());
+        }
+        let events = Box::new(CollectionEvents {
+            changes: AtomicUsize::new(0),
+            waker: WakerCell::default()
+        });
+        let callback: ffi::cubeb_device_collection_changed_callback = collection_changed_cb_c;
+        unsafe {
+            try_call!(in context.raw(), sys::cubeb_register_device_collection_changed(
+                context.raw(),
+                devtype.bits(),
+                Some(callback),
+                &*events as *const _ as *mut c_void
+            ), format!("{:?} devices", devtype));
+        }
+        context.collection_changes.set(true);
+        Ok(DeviceCollectionChanges {
+            context,
+            devtype,
+            events,
+            seen: 0
+        })
+    }
+}
+
+impl<'a> ::futures_core::Stream for DeviceCollectionChanges<'a> {
+    type Item = ();
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<Option<()>> {
+        let this = self.get_mut();
+        this.events.waker.register(cx.waker());
+        let changes = this.events.changes.load(Ordering::Acquire);
+        if changes != this.seen {
+            this.seen = changes;
+            Poll::Ready(Some(()))
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+impl<'a> Drop for DeviceCollectionChanges<'a> {
+    fn drop(&mut self) {
+        unsafe {
+            call!(sys::cubeb_register_device_collection_changed(
+                self.context.raw(),
+                self.devtype.bits(),
+                None,
+                ptr::null_mut()
+            ));
+        }
+        self.context.collection_changes.set(false);
+    }
+}
+
+/// An `OutputWriter` whose writes are futures.
+pub struct AsyncOutputWriter<F>
+where
+    F: Frame + Copy + Send + 'static,
+{
+    writer: OutputWriter<F>
+}
+
+impl<F> AsyncOutputWriter<F>
+where
+    F: Frame + Copy + Send + 'static,
+{
+    /// Create a writer playing `params` at the context's minimum latency.
+    pub fn new(context: &Context, params: &StreamParams) -> Result<Self> {
+        OutputWriter::new(context, params).map(Self::from)
+    }
+
+    /// Create a writer playing `params` with `latency_frames` of latency.
+    pub fn with_latency(context: &Context, params: &StreamParams, latency_frames: u32)
+        -> Result<Self> {
+        OutputWriter::with_latency(context, params, latency_frames).map(Self::from)
+    }
+
+    /// Queue as many of `frames` as there is room for and return how many
+    /// that was, or wake the task once there is room.
+    pub fn poll_write_frames(&mut self, cx: &mut task::Context<'_>, frames: &[F])
+        -> Poll<Result<usize>> {
+        self.writer.poll_write(cx.waker(), frames)
+    }
+
+    /// A future queueing all of `frames`.
+    pub fn write_frames<'a>(&'a mut self, frames: &'a [F]) -> WriteFrames<'a, F> {
+        WriteFrames {
+            writer: &mut self.writer,
+            frames
+        }
+    }
+
+    /// A future for everything queued being handed to the stream.
+    pub fn drain(&mut self) -> Drain<'_, F> {
+        Drain {
+            writer: &mut self.writer
+        }
+    }
+
+    /// Frames of silence played because the writer fell behind.
+    pub fn underruns(&self) -> u64 {
+        self.writer.underruns()
+    }
+
+    /// The stream's playback position.
+    pub fn position(&self) -> Result<u64> {
+        self.writer.position()
+    }
+
+    pub fn into_inner(self) -> OutputWriter<F> {
+        self.writer
+    }
+}
+
+impl<F> From<OutputWriter<F>> for AsyncOutputWriter<F>
+where
+    F: Frame + Copy + Send + 'static,
+{
+    fn from(writer: OutputWriter<F>) -> Self {
+        AsyncOutputWriter {
+            writer
+        }
+    }
+}
+
+/// Future returned by `AsyncOutputWriter::write_frames`.
+pub struct WriteFrames<'a, F>
+where
+    F: Frame + Copy + Send + 'static,
+{
+    writer: &'a mut OutputWriter<F>,
+    frames: &'a [F]
+}
+
+impl<'a, F> Future for WriteFrames<'a, F>
+where
+    F: Frame + Copy + Send + 'static,
+{
+    type Output = Result<()>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<Result<()>> {
+        let this = self.get_mut();
+        while !this.frames.is_empty() {
+            match this.writer.poll_write(cx.waker(), this.frames) {
+                Poll::Ready(Ok(written)) => this.frames = &this.frames[written..],
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Future returned by `AsyncOutputWriter::drain`.
+pub struct Drain<'a, F>
+where
+    F: Frame + Copy + Send + 'static,
+{
+    writer: &'a mut OutputWriter<F>
+}
+
+impl<'a, F> Future for Drain<'a, F>
+where
+    F: Frame + Copy + Send + 'static,
+{
+    type Output = Result<()>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<Result<()>> {
+        self.get_mut().writer.poll_drain(cx.waker())
+    }
+}
+
+/// An `InputReader` whose reads are futures.
+pub struct AsyncInputReader<F>
+where
+    F: Frame + Copy + Send + 'static,
+{
+    reader: InputReader<F>
+}
+
+impl<F> AsyncInputReader<F>
+where
+    F: Frame + Copy + Send + 'static,
+{
+    /// Create a reader capturing `params` at the context's minimum
+    /// latency.
+    pub fn new(context: &Context, params: &StreamParams) -> Result<Self> {
+        InputReader::new(context, params).map(Self::from)
+    }
+
+    /// Create a reader capturing `params` with `latency_frames` of
+    /// latency.
+    pub fn with_latency(context: &Context, params: &StreamParams, latency_frames: u32)
+        -> Result<Self> {
+        InputReader::with_latency(context, params, latency_frames).map(Self::from)
+    }
+
+    /// Fill as many of `frames` as have been captured and return how many
+    /// that was, or wake the task once some have been.
+    pub fn poll_read_frames(&mut self, cx: &mut task::Context<'_>, frames: &mut [F])
+        -> Poll<Result<usize>> {
+        self.reader.poll_read(cx.waker(), frames)
+    }
+
+    /// A future filling all of `frames`.
+    pub fn read_frames<'a>(&'a mut self, frames: &'a mut [F]) -> ReadFrames<'a, F> {
+        ReadFrames {
+            reader: &mut self.reader,
+            frames,
+            read: 0
+        }
+    }
+
+    /// Frames of input dropped because the reader fell behind.
+    pub fn overruns(&self) -> u64 {
+        self.reader.overruns()
+    }
+
+    /// The stream's capture position.
+    pub fn position(&self) -> Result<u64> {
+        self.reader.position()
+    }
+
+    pub fn into_inner(self) -> InputReader<F> {
+        self.reader
+    }
+}
+
+impl<F> From<InputReader<F>> for AsyncInputReader<F>
+where
+    F: Frame + Copy + Send + 'static,
+{
+    fn from(reader: InputReader<F>) -> Self {
+        AsyncInputReader {
+            reader
+        }
+    }
+}
+
+/// Future returned by `AsyncInputReader::read_frames`.
+pub struct ReadFrames<'a, F>
+where
+    F: Frame + Copy + Send + 'static,
+{
+    reader: &'a mut InputReader<F>,
+    frames: &'a mut [F],
+    read: usize
+}
+
+impl<'a, F> Future for ReadFrames<'a, F>
+where
+    F: Frame + Copy + Send + 'static,
+{
+    type Output = Result<()>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<Result<()>> {
+        let this = self.get_mut();
+        while this.read < this.frames.len() {
+            match this.reader.poll_read(cx.waker(), &mut this.frames[this.read..]) {
+                Poll::Ready(Ok(read)) => this.read += read,
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::{ALREADY_REGISTERED, CollectionEvents, DeviceChanges, Registered, StateEvents,
+                TOO_MANY_WAITERS, WaitForState, collection_changed_cb_c};
+    use {ErrorCode, State};
+    use futures_core::Stream;
+    use std::future::Future;
+    use std::os::raw::c_void;
+    use std::pin::Pin;
+    use std::ptr;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::task::{Context, Poll, Waker};
+    use stream::SharedState;
+    use waker::WakerCell;
+    use waker::tests::counting_waker;
+
+    fn next<S: Stream + Unpin>(stream: &mut S, waker: &Waker) -> Poll<Option<S::Item>> {
+        Pin::new(stream).poll_next(&mut Context::from_waker(waker))
+    }
+
+    fn poll<F: Future + Unpin>(future: &mut F, waker: &Waker) -> Poll<F::Output> {
+        Pin::new(future).poll(&mut Context::from_waker(waker))
+    }
+
+    #[test]
+    fn state_events() {
+        let shared = SharedState::new();
+        shared.signal(State::Started);
+        let mut events = StateEvents::new(shared.clone()).unwrap();
+        let (counter, waker) = counting_waker();
+        assert_eq!(next(&mut events, &waker), Poll::Pending);
+        shared.signal(State::Drained);
+        shared.signal(State::Stopped);
+        assert_eq!(counter.count(), 2);
+        assert_eq!(next(&mut events, &waker), Poll::Ready(Some(State::Drained)));
+        assert_eq!(next(&mut events, &waker), Poll::Ready(Some(State::Stopped)));
+        assert_eq!(next(&mut events, &waker), Poll::Pending);
+
+        // Only the last 16 wait.
+        for i in 0..20 {
+            shared.signal(if i % 2 == 0 { State::Started } else { State::Stopped });
+        }
+        let mut seen = Vec::new();
+        while let Poll::Ready(Some(state)) = next(&mut events, &waker) {
+            seen.push(state);
+        }
+        assert_eq!(seen.len(), 16);
+        assert_eq!(seen[0], State::Started);
+        assert_eq!(seen[15], State::Stopped);
+
+        shared.events.close();
+        assert_eq!(next(&mut events, &waker), Poll::Ready(None));
+    }
+
+    // `DeviceChanges` for a stream without a native one.
+    fn detached_changes(shared: Arc<SharedState>) -> DeviceChanges {
+        let slot = shared.events.wakers.claim().unwrap();
+        DeviceChanges {
+            shared,
+            slot,
+            seen: 0
+        }
+    }
+
+    #[test]
+    fn device_changes() {
+        let shared = SharedState::new();
+        let mut changes = detached_changes(shared.clone());
+        let (counter, waker) = counting_waker();
+        assert_eq!(next(&mut changes, &waker), Poll::Pending);
+        for _ in 0..3 {
+            shared.events.device_changed();
+        }
+        assert_eq!(counter.count(), 3);
+        assert_eq!(next(&mut changes, &waker), Poll::Ready(Some(())));
+        assert_eq!(next(&mut changes, &waker), Poll::Pending);
+        shared.events.close();
+        assert_eq!(next(&mut changes, &waker), Poll::Ready(None));
+    }
+
+    #[test]
+    fn wait_for_state() {
+        let shared = SharedState::new();
+        let mut started = WaitForState::new(shared.clone(), State::Started).unwrap();
+        let mut drained = WaitForState::new(shared.clone(), State::Drained).unwrap();
+        let (counter, waker) = counting_waker();
+        assert!(poll(&mut started, &waker).is_pending());
+        assert!(poll(&mut drained, &waker).is_pending());
+        shared.signal(State::Started);
+        assert_eq!(counter.count(), 2);
+        assert_eq!(poll(&mut started, &waker), Poll::Ready(Ok(())));
+        assert!(poll(&mut drained, &waker).is_pending());
+        shared.signal(State::Error);
+        assert!(matches!(poll(&mut drained, &waker), Poll::Ready(Err(_))));
+
+        let shared = SharedState::new();
+        let mut drained = WaitForState::new(shared.clone(), State::Drained).unwrap();
+        assert!(poll(&mut drained, &waker).is_pending());
+        shared.events.close();
+        assert!(matches!(poll(&mut drained, &waker), Poll::Ready(Err(_))));
+    }
+
+    #[test]
+    fn waker_slots_run_out() {
+        let shared = SharedState::new();
+        let mut events: Vec<_> = (0..8).map(|_| StateEvents::new(shared.clone()).unwrap()).collect();
+        let e = WaitForState::new(shared.clone(), State::Drained).err().unwrap();
+        assert_eq!(e.code(), ErrorCode::NotSupported);
+        assert_eq!(e.detail(), Some(TOO_MANY_WAITERS));
+        events.pop();
+        assert!(WaitForState::new(shared.clone(), State::Drained).is_ok());
+    }
+
+    #[test]
+    fn device_changes_registered_once() {
+        extern "C" fn callback(_: *mut c_void) {}
+        let shared = SharedState::new();
+        *shared.events.lock_registered() = Some(Registered(ptr::null_mut()));
+        let e = DeviceChanges::new(shared.clone(), ptr::null_mut(), ptr::null_mut(), callback).err().unwrap();
+        assert_eq!(e.detail(), Some(ALREADY_REGISTERED));
+        // Nor is a slot kept for it.
+        let events: Vec<_> = (0..8).map(|_| StateEvents::new(shared.clone()).unwrap()).collect();
+        drop(events);
+        // Once the stream is destroyed, dropping its `DeviceChanges`
+        // leaves the callback alone.
+        assert!(shared.events.destroying().is_none());
+        drop(detached_changes(shared.clone()));
+    }
+
+    #[test]
+    fn collection_changed_callback() {
+        let events = CollectionEvents {
+            changes: AtomicUsize::new(0),
+            waker: WakerCell::default()
+        };
+        let (counter, waker) = counting_waker();
+        events.waker.register(&waker);
+        collection_changed_cb_c(ptr::null_mut(), &events as *const _ as *mut _);
+        assert_eq!(events.changes.load(Ordering::SeqCst), 1);
+        assert_eq!(counter.count(), 1);
+    }
+}
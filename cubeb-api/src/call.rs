@@ -1,5 +1,8 @@
-use Error;
+use {Error, StreamParams};
+use ffi;
+use std::ffi::CStr;
 use std::os::raw::c_int;
+use sys;
 
 macro_rules! call {
     (sys::$p:ident ($($e:expr),*)) => (
@@ -7,20 +10,80 @@ macro_rules! call {
     )
 }
 
+// `try_call!(in context, sys::f(...), detail)` also names the backend of
+// `context` in the error, and `detail`, which is only evaluated if the
+// call fails.
 macro_rules! try_call {
-    (sys::$p:ident ($($e:expr),*)) => ({
-        match ::call::try(sys::$p($(::call::convert(&$e)),*)) {
+    (sys::$p:ident ($($e:expr),*)) => (
+        try_call!(@ ::std::ptr::null_mut(), sys::$p($($e),*), None)
+    );
+    (in $context:expr, sys::$p:ident ($($e:expr),*)) => (
+        try_call!(@ $context, sys::$p($($e),*), None)
+    );
+    (in $context:expr, sys::$p:ident ($($e:expr),*), $detail:expr) => (
+        try_call!(@ $context, sys::$p($($e),*), Some($detail))
+    );
+    (@ $context:expr, sys::$p:ident ($($e:expr),*), $detail:expr) => ({
+        let ret = sys::$p($(::call::convert(&$e)),*);
+        match ::call::try(ret, stringify!($p), $context, || $detail) {
             Ok(o) => o,
             Err(e) => { return Err(e) }
         }
     })
 }
 
-pub fn try(ret: c_int) -> Result<c_int, Error> {
-    match ret {
-        n if n < 0 => Err(unsafe { Error::from_raw(n) }),
-        n => Ok(n),
+/// Turn the result of the libcubeb call `operation` into an error if it
+/// failed, naming the backend of `context`, if not null, and `detail`.
+pub fn try<D>(
+    ret: c_int,
+    operation: &'static str,
+    context: *mut ffi::cubeb,
+    detail: D,
+) -> Result<c_int, Error>
+where
+    D: FnOnce() -> Option<String>,
+{
+    if ret >= 0 {
+        return Ok(ret);
+    }
+    Err(failed(ret, operation, backend_id(context), detail()))
+}
+
+fn failed(
+    ret: c_int,
+    operation: &'static str,
+    backend: Option<String>,
+    detail: Option<String>,
+) -> Error {
+    let mut e = unsafe { Error::from_raw(ret) }.with_operation(operation);
+    if let Some(backend) = backend {
+        e = e.with_backend(backend);
+    }
+    if let Some(detail) = detail {
+        e = e.with_detail(detail);
+    }
+    e
+}
+
+fn backend_id(context: *mut ffi::cubeb) -> Option<String> {
+    if context.is_null() {
+        return None;
+    }
+    let id = unsafe { sys::cubeb_get_backend_id(context) };
+    if id.is_null() {
+        return None;
     }
+    Some(unsafe { CStr::from_ptr(id) }.to_string_lossy().into_owned())
+}
+
+/// `params`, as error details describe them.
+pub fn describe_params(params: &StreamParams) -> String {
+    format!(
+        "{} channels of {} at {} Hz",
+        params.channels(),
+        params.format(),
+        params.rate()
+    )
 }
 
 #[doc(hidden)]
@@ -143,3 +206,44 @@ mod impls {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{describe_params, failed, try};
+    use {ErrorCode, SampleFormat, StreamParamsBuilder};
+    use ffi;
+    use std::ptr;
+
+    #[test]
+    fn try_passes_results_through() {
+        assert_eq!(try(3, "cubeb_stream_start", ptr::null_mut(), || None), Ok(3));
+        let e = try(ffi::CUBEB_ERROR, "cubeb_stream_start", ptr::null_mut(), || None)
+            .unwrap_err();
+        assert_eq!(e.code(), ErrorCode::Error);
+        assert_eq!(e.backend(), None);
+        assert_eq!(e.to_string(), "cubeb_stream_start: Error");
+    }
+
+    #[test]
+    fn failed_names_backend_and_detail() {
+        let params = StreamParamsBuilder::new()
+            .format(SampleFormat::Float32LE)
+            .rate(48000)
+            .channels(2)
+            .take();
+        let detail = describe_params(&params);
+        let e = failed(
+            ffi::CUBEB_ERROR_INVALID_FORMAT,
+            "cubeb_stream_init",
+            Some("pulse".to_string()),
+            Some(format!("output {}", detail))
+        );
+        assert_eq!(e.code(), ErrorCode::InvalidFormat);
+        assert_eq!(e.backend(), Some("pulse"));
+        assert_eq!(
+            e.to_string(),
+            "cubeb_stream_init on pulse: Invalid format \
+             (output 2 channels of f32le at 48000 Hz)"
+        );
+    }
+}
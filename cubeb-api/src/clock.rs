@@ -0,0 +1,288 @@
+// Copyright © 2017 Mozilla Foundation
+//
+// This program is made available under an ISC-style license.  See the
+// accompanying file LICENSE for details.
+
+//! Presentation clock for a stream
+//!
+//! A stream's `position` only advances once per callback, which is too
+//! coarse to synchronise video or other events to. `StreamClock` turns it
+//! into a `Duration` that advances smoothly between callbacks, never goes
+//! backwards, and follows the audio device's clock rather than the
+//! system's.
+//!
+//! # Example
+//! ```no_run
+//! extern crate cubeb;
+//!
+//! use std::thread;
+//! use std::time::Duration;
+//!
+//! // Wait until the audio at `time` into the stream is being heard.
+//! fn wait_until_heard<CB>(stream: &cubeb::Stream<CB>, rate: u32, time: Duration)
+//!     -> cubeb::Result<()>
+//! where
+//!     CB: cubeb::DuplexCallback,
+//! {
+//!     let mut clock = cubeb::StreamClock::new(stream, rate)?;
+//!     while clock.audible_time()? < time {
+//!         thread::sleep(Duration::from_millis(5));
+//!     }
+//!     Ok(())
+//! }
+//! # fn main() {}
+//! ```
+
+use {Error, ErrorCode, Result, StreamControl};
+use cubeb_core::frames::frames_to_duration;
+use std::cmp;
+use std::time::{Duration, Instant};
+
+// Position updates further apart than this many times what they advance
+// by are taken as the stream having been stopped, and left out of the
+// drift estimate.
+const MAX_GAP: u32 = 4;
+
+// Interpolates a position that advances in steps, given when each step
+// was seen.
+struct Interpolator {
+    rate: u32,
+    // The latest position, and when it was first seen.
+    anchor: Option<(Instant, u64)>,
+    // Frames the position last advanced by. Interpolation doesn't run
+    // further ahead of the anchor than this.
+    step: u64,
+    // Media and system time elapsed between updates while running.
+    media: Duration,
+    system: Duration,
+    time: Duration,
+    audible: Duration
+}
+
+impl Interpolator {
+    fn new(rate: u32) -> Interpolator {
+        Interpolator {
+            rate,
+            anchor: None,
+            step: 0,
+            media: Duration::ZERO,
+            system: Duration::ZERO,
+            time: Duration::ZERO,
+            audible: Duration::ZERO
+        }
+    }
+
+    fn reset(&mut self) {
+        *self = Interpolator {
+            time: self.time,
+            audible: self.audible,
+            ..Interpolator::new(self.rate)
+        };
+    }
+
+    fn drift(&self) -> Option<f64> {
+        if self.media.is_zero() || self.system.is_zero() {
+            return None;
+        }
+        Some(self.media.as_secs_f64() / self.system.as_secs_f64())
+    }
+
+    // Record that the stream was at `position` at `now`, and return the
+    // interpolated time.
+    fn update(&mut self, now: Instant, position: u64) -> Duration {
+        match self.anchor {
+            Some((_, last)) if last == position => {},
+            Some((seen, last)) => {
+                if position > last {
+                    self.step = position - last;
+                    let media = frames_to_duration(self.step, self.rate);
+                    let system = now.saturating_duration_since(seen);
+                    if system <= media * MAX_GAP {
+                        self.media += media;
+                        self.system += system;
+                    }
+                }
+                self.anchor = Some((now, position));
+            },
+            None => self.anchor = Some((now, position)),
+        }
+
+        let (seen, position) = self.anchor.unwrap();
+        let ahead = now.saturating_duration_since(seen)
+            .mul_f64(self.drift().unwrap_or(1.0));
+        let time = frames_to_duration(position, self.rate)
+            + cmp::min(ahead, frames_to_duration(self.step, self.rate));
+        self.time = cmp::max(self.time, time);
+        self.time
+    }
+
+    // The time `latency` frames behind the latest interpolated time.
+    fn audible(&mut self, latency: u32) -> Duration {
+        let latency = frames_to_duration(u64::from(latency), self.rate);
+        self.audible = cmp::max(self.audible, self.time.saturating_sub(latency));
+        self.audible
+    }
+}
+
+/// Interpolated presentation time of a stream playing `rate` frames a
+/// second.
+///
+/// Between the stream's position updates the clock runs at the rate the
+/// position has been advancing at, but never past where the next update
+/// is due, so it holds still soon after the stream stops.
+pub struct StreamClock<'a> {
+    stream: &'a dyn StreamControl,
+    clock: Interpolator
+}
+
+impl<'a> StreamClock<'a> {
+    pub fn new(stream: &'a dyn StreamControl, rate: u32) -> Result<Self> {
+        if rate == 0 {
+            return Err(Error::from(ErrorCode::InvalidParameter));
+        }
+        Ok(StreamClock {
+            stream,
+            clock: Interpolator::new(rate)
+        })
+    }
+
+    /// Time of the audio the stream has played up to. It never goes
+    /// backwards, even if the position does.
+    pub fn time(&mut self) -> Result<Duration> {
+        let position = self.stream.position()?;
+        Ok(self.clock.update(Instant::now(), position))
+    }
+
+    /// Time of the audio currently audible at the speaker: `time` less the
+    /// stream's latency. It never goes backwards, even if the latency
+    /// grows.
+    pub fn audible_time(&mut self) -> Result<Duration> {
+        self.time()?;
+        let latency = self.stream.latency()?;
+        Ok(self.clock.audible(latency))
+    }
+
+    /// How fast the stream plays relative to `Instant`, as a ratio: above
+    /// 1.0 if the audio clock runs fast. `None` until the position has
+    /// advanced twice.
+    pub fn drift(&self) -> Option<f64> {
+        self.clock.drift()
+    }
+
+    /// Forget the position history, for example after the stream changed
+    /// device. Times still don't go backwards.
+    pub fn reset(&mut self) {
+        self.clock.reset();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Interpolator;
+    use std::time::{Duration, Instant};
+
+    fn ms(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    // Drives an interpolator from a virtual clock.
+    struct Virtual {
+        start: Instant,
+        clock: Interpolator
+    }
+
+    impl Virtual {
+        fn new(rate: u32) -> Virtual {
+            Virtual {
+                start: Instant::now(),
+                clock: Interpolator::new(rate)
+            }
+        }
+
+        fn at(&mut self, now: Duration, position: u64) -> Duration {
+            self.clock.update(self.start + now, position)
+        }
+    }
+
+    #[test]
+    fn clock_interpolates_between_updates() {
+        let mut v = Virtual::new(1000);
+        assert_eq!(v.at(ms(0), 0), ms(0));
+        // Nothing to interpolate by before the position first advances.
+        assert_eq!(v.at(ms(5), 0), ms(0));
+        assert_eq!(v.at(ms(10), 10), ms(10));
+        assert_eq!(v.at(ms(13), 10), ms(13));
+        assert_eq!(v.at(ms(18), 10), ms(18));
+        // Held one step past the last update while the stream is late.
+        assert_eq!(v.at(ms(25), 10), ms(20));
+        assert_eq!(v.at(ms(40), 10), ms(20));
+    }
+
+    #[test]
+    fn clock_is_monotonic() {
+        let mut v = Virtual::new(1000);
+        v.at(ms(0), 0);
+        v.at(ms(10), 10);
+        assert_eq!(v.at(ms(19), 10), ms(19));
+        // An update arriving late, or a position going backwards, doesn't
+        // move the clock back.
+        assert_eq!(v.at(ms(20), 15), ms(19));
+        assert_eq!(v.at(ms(21), 5), ms(19));
+        assert_eq!(v.at(ms(31), 20), ms(20));
+    }
+
+    #[test]
+    fn clock_estimates_drift() {
+        let mut v = Virtual::new(1000);
+        assert_eq!(v.clock.drift(), None);
+        // A device running 1% fast, updating every 10ms of its own time.
+        let mut now = Duration::ZERO;
+        for i in 0..100 {
+            v.at(now, i * 10);
+            now += Duration::from_micros(9901);
+        }
+        let drift = v.clock.drift().unwrap();
+        assert!((drift - 1.01).abs() < 0.001, "{}", drift);
+
+        // A pause doesn't count towards the estimate.
+        now += ms(1000);
+        for i in 100..200 {
+            v.at(now, i * 10);
+            now += Duration::from_micros(9901);
+        }
+        let drift = v.clock.drift().unwrap();
+        assert!((drift - 1.01).abs() < 0.001, "{}", drift);
+
+        // And interpolation follows the device's rate.
+        let last = now - Duration::from_micros(9901);
+        let time = v.at(last + Duration::from_micros(4950), 1990);
+        assert!(time > ms(1994) && time < ms(1996), "{:?}", time);
+    }
+
+    #[test]
+    fn clock_audible_time() {
+        let mut v = Virtual::new(1000);
+        v.at(ms(0), 0);
+        assert_eq!(v.clock.audible(30), ms(0));
+        v.at(ms(50), 50);
+        assert_eq!(v.clock.audible(30), ms(20));
+        // A growing latency doesn't move the audible time back.
+        assert_eq!(v.clock.audible(40), ms(20));
+        v.at(ms(60), 60);
+        assert_eq!(v.clock.audible(40), ms(20));
+        assert_eq!(v.clock.audible(20), ms(40));
+    }
+
+    #[test]
+    fn clock_reset_keeps_time() {
+        let mut v = Virtual::new(1000);
+        v.at(ms(0), 0);
+        v.at(ms(10), 10);
+        v.at(ms(20), 20);
+        assert!(v.clock.drift().is_some());
+        v.clock.reset();
+        assert_eq!(v.clock.drift(), None);
+        assert_eq!(v.at(ms(21), 0), ms(20));
+        assert_eq!(v.at(ms(31), 30), ms(30));
+    }
+}
@@ -1,15 +1,84 @@
-use {ChannelLayout, DeviceCollection, DeviceType, Result, Stream, StreamInitOptions, StreamParams};
+use {CallbackFn, ChannelLayout, DeviceCollection, DeviceFormat, DeviceInfo, DeviceType, DynStream,
+     Error, ErrorCode, Frame, Frames, Result, Stream, StreamInitOptions, StreamParams};
 use {ffi, sys};
-use Binding;
+use {Binding, DEVICE_TYPE_OUTPUT};
 
 use dev_coll;
+use negotiate::{DeviceCaps, Negotiated, NegotiationPolicy, negotiate};
+use retry::{RetryPolicy, retry};
 use std::{ptr, str};
+use std::cell::Cell;
 use std::ffi::CString;
-use stream::{StreamCallback, stream_init};
+use std::marker::PhantomData;
+use std::sync::Arc;
+use std::time::Duration;
+use stream::{DuplexCallback, StreamCallback, stream_init};
+use call::describe_params;
 use util::{opt_bytes, opt_cstr};
+#[cfg(feature = "async")]
+use async_io::DeviceCollectionChanges;
 
 pub struct Context {
-    raw: *mut ffi::cubeb
+    raw: Arc<NativeContext>,
+    // Keeps `Context` from being `Sync`, which sharing `raw` would make
+    // it.
+    _not_sync: PhantomData<Cell<()>>,
+    // Whether a `DeviceCollectionChanges` registered the context's device
+    // collection changed callback.
+    #[cfg(feature = "async")]
+    pub(crate) collection_changes: Cell<bool>
+}
+
+// `Context` is `Send` because no `cubeb_*` call it makes depends on the
+// calling thread:
+//
+// - `cubeb_init` keeps no thread local state, and hands back a context
+//   any thread can use.
+// - `cubeb_get_backend_id`, `cubeb_get_max_channel_count`,
+//   `cubeb_get_min_latency`, `cubeb_get_preferred_sample_rate`,
+//   `cubeb_get_preferred_channel_layout`, `cubeb_enumerate_devices` and
+//   `cubeb_device_collection_destroy` may be called from any thread, one
+//   at a time per context.
+// - `cubeb_register_device_collection_changed` may be called from any
+//   thread; backends call the callback on a thread of their own.
+// - `cubeb_stream_init` may be called from any thread, and the stream
+//   it makes isn't tied to that thread either; see `Stream`.
+// - `cubeb_destroy` may be called from any thread once the context's
+//   streams are destroyed, which `NativeContext` sees to.
+//
+// None of these calls is safe concurrently with another on the same
+// context, so `Context` isn't `Sync`; see `SharedContext`.
+unsafe impl Send for Context {}
+
+// The native context, destroyed once its `Context` and every `Stream`
+// made from it are gone: libcubeb requires streams to be destroyed
+// before their context.
+pub(crate) struct NativeContext(*mut ffi::cubeb);
+
+// A `NativeContext` only hands out its pointer, and only calls
+// `cubeb_destroy`, from whichever thread drops the last reference.
+// Streams share it across threads only to keep it alive.
+unsafe impl Send for NativeContext {}
+unsafe impl Sync for NativeContext {}
+
+impl NativeContext {
+    pub(crate) fn raw(&self) -> *mut ffi::cubeb {
+        self.0
+    }
+
+    // One that owns no context, for streams made in tests.
+    #[cfg(test)]
+    pub(crate) fn detached() -> Arc<NativeContext> {
+        Arc::new(NativeContext(ptr::null_mut()))
+    }
+}
+
+impl Drop for NativeContext {
+    fn drop(&mut self) {
+        if !self.0.is_null() {
+            unsafe { sys::cubeb_destroy(self.0) }
+        }
+    }
 }
 
 impl Context {
@@ -27,14 +96,14 @@ impl Context {
         str::from_utf8(self.backend_id_bytes()).unwrap()
     }
     pub fn backend_id_bytes(&self) -> &[u8] {
-        unsafe { opt_bytes(self, call!(sys::cubeb_get_backend_id(self.raw))).unwrap() }
+        unsafe { opt_bytes(self, call!(sys::cubeb_get_backend_id(self.raw()))).unwrap() }
     }
 
     pub fn max_channel_count(&self) -> Result<u32> {
         let mut channel_count = 0u32;
         unsafe {
-            try_call!(sys::cubeb_get_max_channel_count(
-                self.raw,
+            try_call!(in self.raw(), sys::cubeb_get_max_channel_count(
+                self.raw(),
                 &mut channel_count
             ));
         }
@@ -44,19 +113,33 @@ impl Context {
     pub fn min_latency(&self, params: &StreamParams) -> Result<u32> {
         let mut latency = 0u32;
         unsafe {
-            try_call!(sys::cubeb_get_min_latency(
-                self.raw,
+            try_call!(in self.raw(), sys::cubeb_get_min_latency(
+                self.raw(),
                 params.raw(),
                 &mut latency
-            ));
+            ), describe_params(params));
         }
         Ok(latency)
     }
 
+    /// `min_latency` at the rate of `params`.
+    pub fn min_latency_frames(&self, params: &StreamParams) -> Result<Frames> {
+        if params.rate() == 0 {
+            return Err(Error::from(ErrorCode::InvalidParameter));
+        }
+        let latency = self.min_latency(params)?;
+        Ok(Frames::new(u64::from(latency), params.rate()))
+    }
+
+    /// `min_latency` as a duration at the rate of `params`.
+    pub fn min_latency_duration(&self, params: &StreamParams) -> Result<Duration> {
+        self.min_latency_frames(params).map(Frames::to_duration)
+    }
+
     pub fn preferred_sample_rate(&self) -> Result<u32> {
         let mut rate = 0u32;
         unsafe {
-            try_call!(sys::cubeb_get_preferred_sample_rate(self.raw, &mut rate));
+            try_call!(in self.raw(), sys::cubeb_get_preferred_sample_rate(self.raw(), &mut rate));
         }
         Ok(rate)
     }
@@ -64,8 +147,8 @@ impl Context {
     pub fn preferred_channel_layout(&self) -> Result<ChannelLayout> {
         let mut layout: ffi::cubeb_channel_layout = ffi::CUBEB_LAYOUT_UNDEFINED;
         unsafe {
-            try_call!(sys::cubeb_get_preferred_channel_layout(
-                self.raw,
+            try_call!(in self.raw(), sys::cubeb_get_preferred_channel_layout(
+                self.raw(),
                 &mut layout
             ));
         }
@@ -101,18 +184,111 @@ impl Context {
         )
     }
 
+    /// What the default `devtype` device supports: what its `DeviceInfo`
+    /// says if the backend lists it, otherwise the preferred rate, and
+    /// for output the channel count, the backend reports. Output also
+    /// takes the preferred layout. Anything the backend doesn't report is
+    /// left unknown.
+    pub fn default_caps(&self, devtype: DeviceType) -> DeviceCaps {
+        let output = devtype.contains(DEVICE_TYPE_OUTPUT);
+        let layout = if output {
+            self.preferred_channel_layout().unwrap_or(ChannelLayout::Undefined)
+        } else {
+            ChannelLayout::Undefined
+        };
+        if let Ok(devices) = self.enumerate_devices(devtype) {
+            if let Some(info) = devices.iter().find(|info| !info.preferred().is_empty()) {
+                return DeviceCaps {
+                    layout,
+                    ..DeviceCaps::from(info)
+                };
+            }
+        }
+        let rate = self.preferred_sample_rate().unwrap_or(0);
+        DeviceCaps {
+            formats: DeviceFormat::empty(),
+            default_format: DeviceFormat::empty(),
+            min_rate: rate,
+            max_rate: rate,
+            default_rate: rate,
+            max_channels: if output { self.max_channel_count().unwrap_or(0) } else { 0 },
+            layout
+        }
+    }
+
+    /// Negotiate `desired` against `device`, or against the default
+    /// `devtype` device if there's none.
+    pub fn negotiate(&self, desired: &StreamParams, devtype: DeviceType,
+                     device: Option<&DeviceInfo>, policy: &NegotiationPolicy) -> Negotiated {
+        let caps = match device {
+            Some(info) => DeviceCaps::from(info),
+            None => self.default_caps(devtype),
+        };
+        negotiate(desired, &caps, policy)
+    }
+
     /// Initialize a stream associated with the supplied application context.
     pub fn stream_init<CB>(&self, opts: &StreamInitOptions, cb: CB) -> Result<Stream<CB>>
     where
-        CB: StreamCallback,
+        CB: DuplexCallback,
     {
         stream_init(self, opts, cb)
     }
 
+    /// Like `stream_init`, but retries transient failures as `policy`
+    /// says, such as a device being unavailable while it's hotplugged.
+    /// `cb` makes the callback for each attempt.
+    pub fn stream_init_retry<CB, F>(
+        &self,
+        opts: &StreamInitOptions,
+        policy: &RetryPolicy,
+        mut cb: F
+    ) -> Result<Stream<CB>>
+    where
+        CB: DuplexCallback,
+        F: FnMut() -> CB,
+    {
+        retry(policy, || stream_init(self, opts, cb()))
+    }
+
+    /// Initialize a stream whose callback is only known at run time.
+    pub fn stream_init_boxed<F, M>(
+        &self,
+        opts: &StreamInitOptions,
+        cb: Box<dyn StreamCallback<Frame = F, Message = M>>,
+    ) -> Result<DynStream>
+    where
+        F: Frame + 'static,
+        M: Send + 'static,
+    {
+        Ok(Box::new(stream_init(self, opts, cb)?))
+    }
+
+    /// Initialize a stream whose data callback is the closure `data`.
+    ///
+    /// The frame type comes from `data`'s signature and, as with
+    /// `stream_init`, must match the size of `opts`' frames.
+    pub fn stream_init_fn<F, D>(&self, opts: &StreamInitOptions, data: D)
+        -> Result<Stream<CallbackFn<F, D>>>
+    where
+        F: Frame + 'static,
+        D: FnMut(&[F], &mut [F]) -> isize + Send + 'static,
+    {
+        stream_init(self, opts, CallbackFn::new(data))
+    }
+
     pub fn enumerate_devices(&self, devtype: DeviceType) -> Result<DeviceCollection> {
         dev_coll::enumerate(self, devtype)
     }
 
+    /// Changes to the context's `devtype` devices from now on, as a
+    /// `futures_core::Stream`.
+    #[cfg(feature = "async")]
+    pub fn device_collection_changes(&self, devtype: DeviceType)
+        -> Result<DeviceCollectionChanges<'_>> {
+        DeviceCollectionChanges::new(self, devtype)
+    }
+
     /*
     pub fn register_device_collection_changed(
         &self,
@@ -129,20 +305,24 @@ impl Context {
 */
 }
 
+impl Context {
+    // Keeps the native context alive for as long as a stream needs it.
+    pub(crate) fn native(&self) -> Arc<NativeContext> {
+        self.raw.clone()
+    }
+}
+
 impl Binding for Context {
     type Raw = *mut ffi::cubeb;
     unsafe fn from_raw(raw: *mut ffi::cubeb) -> Self {
         Self {
-            raw: raw
+            raw: Arc::new(NativeContext(raw)),
+            _not_sync: PhantomData,
+            #[cfg(feature = "async")]
+            collection_changes: Cell::new(false)
         }
     }
     fn raw(&self) -> Self::Raw {
-        self.raw
-    }
-}
-
-impl Drop for Context {
-    fn drop(&mut self) {
-        unsafe { sys::cubeb_destroy(self.raw) }
+        self.raw.0
     }
 }
@@ -20,11 +20,11 @@ impl<'coll, 'ctx> DeviceCollection<'coll, 'ctx> {
             count: 0
         };
         let devices = unsafe {
-            try_call!(sys::cubeb_enumerate_devices(
+            try_call!(in ctx.raw(), sys::cubeb_enumerate_devices(
                 ctx.raw(),
                 devtype.bits(),
                 &mut coll
-            ));
+            ), format!("{:?} devices", devtype));
             slice::from_raw_parts(coll.device as *const _, coll.count)
         };
         Ok(DeviceCollection {
@@ -1,27 +1,50 @@
 //! Frame utilities
 
-use ChannelLayout;
+use {ChannelLayout, SampleFormat, SampleType};
 
 /// A `Frame` is a collection of samples which have a a specific
 /// layout represented by `ChannelLayout`
 pub trait Frame {
     fn layout() -> ChannelLayout;
+    /// Samples in a frame.
+    fn channels() -> u32;
+    /// Format of each sample, `None` for `()`, the frame of a stream's
+    /// missing side.
+    fn format() -> Option<SampleFormat>;
 }
 
-#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+impl Frame for () {
+    fn layout() -> ChannelLayout {
+        ChannelLayout::Undefined
+    }
+    fn channels() -> u32 {
+        0
+    }
+    fn format() -> Option<SampleFormat> {
+        None
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
 /// A monaural frame.
 pub struct MonoFrame<T> {
     /// Mono channel
     pub m: T
 }
 
-impl<T> Frame for MonoFrame<T> {
+impl<T: SampleType> Frame for MonoFrame<T> {
     fn layout() -> ChannelLayout {
         ChannelLayout::Mono
     }
+    fn channels() -> u32 {
+        1
+    }
+    fn format() -> Option<SampleFormat> {
+        Some(T::format())
+    }
 }
 
-#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
 /// A stereo frame.
 pub struct StereoFrame<T> {
     /// Left channel
@@ -30,8 +53,14 @@ pub struct StereoFrame<T> {
     pub r: T
 }
 
-impl<T> Frame for StereoFrame<T> {
+impl<T: SampleType> Frame for StereoFrame<T> {
     fn layout() -> ChannelLayout {
         ChannelLayout::Stereo
     }
+    fn channels() -> u32 {
+        2
+    }
+    fn format() -> Option<SampleFormat> {
+        Some(T::format())
+    }
 }
@@ -0,0 +1,816 @@
+// Copyright © 2017 Mozilla Foundation
+//
+// This program is made available under an ISC-style license.  See the
+// accompanying file LICENSE for details.
+
+//! Blocking, loop style audio I/O
+//!
+//! `OutputWriter` and `InputReader` own a `Stream` whose callback drains
+//! or fills a ring, so audio can be produced or consumed in a loop
+//! instead of from a callback.
+//!
+//! # Example
+//! ```no_run
+//! extern crate cubeb;
+//!
+//! fn main() {
+//!     let ctx = cubeb::Context::init("Cubeb writer example", None).unwrap();
+//!
+//!     let params = cubeb::StreamParamsBuilder::new()
+//!         .format(cubeb::SampleFormat::Float32NE)
+//!         .rate(44100)
+//!         .channels(1)
+//!         .layout(cubeb::ChannelLayout::Mono)
+//!         .take();
+//!
+//!     let mut writer = cubeb::OutputWriter::new(&ctx, &params).unwrap();
+//!
+//!     // Play a second of a 440Hz sine wave, a tenth of a second at a time.
+//!     let mut t = 0;
+//!     let mut buffer = [cubeb::MonoFrame { m: 0.0f32 }; 4410];
+//!     for _ in 0..10 {
+//!         for f in buffer.iter_mut() {
+//!             let x = t as f32 * 440.0 * 2.0 * ::std::f32::consts::PI / 44100.0;
+//!             f.m = 0.25 * x.sin();
+//!             t += 1;
+//!         }
+//!         writer.write_frames(&buffer).unwrap();
+//!     }
+//!     writer.drain().unwrap();
+//! }
+//! ```
+
+use {Context, Error, ErrorCode, Frame, Input, InputCallback, Output, OutputCallback, Result, State,
+     Stream, StreamInitOptionsBuilder, StreamParams};
+use ring::{self, Consumer, Producer};
+use std::{cmp, io, mem, slice, thread};
+use std::marker::PhantomData;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+#[cfg(feature = "async")]
+use std::task::{Poll, Waker};
+use waker::{WakerCell, thread_waker};
+
+/// Periods of audio the ring between a writer or reader and its stream
+/// holds.
+const BUFFER_PERIODS: usize = 4;
+
+fn as_bytes<F: Copy>(frames: &[F]) -> &[u8] {
+    unsafe { slice::from_raw_parts(frames.as_ptr() as *const u8, mem::size_of_val(frames)) }
+}
+
+fn as_bytes_mut<F: Copy>(frames: &mut [F]) -> &mut [u8] {
+    unsafe {
+        slice::from_raw_parts_mut(frames.as_mut_ptr() as *mut u8, mem::size_of_val(frames))
+    }
+}
+
+// State shared between a writer or reader and its stream's callback.
+struct Shared {
+    // Frames of silence played, or of input dropped.
+    xruns: AtomicU64,
+    // Whether the writer has nothing more to play, so silence isn't an
+    // underrun: before the first write, and after `drain` until the next.
+    idle: AtomicBool,
+    failed: AtomicBool,
+    // Whether the stream signaled `Stopped` since it last signaled
+    // `Started`, so nothing queued will play.
+    stopped: AtomicBool,
+    // Woken by the callbacks, for the thread or task waiting on them.
+    waker: WakerCell
+}
+
+impl Shared {
+    fn new() -> Shared {
+        Shared {
+            xruns: AtomicU64::new(0),
+            idle: AtomicBool::new(true),
+            failed: AtomicBool::new(false),
+            stopped: AtomicBool::new(false),
+            waker: WakerCell::default()
+        }
+    }
+
+    // Call `step` until it returns true, parked until the callbacks make
+    // progress in between. Returns false if `deadline` passes first.
+    fn poll<G>(&self, deadline: Option<Instant>, mut step: G) -> Result<bool>
+    where
+        G: FnMut() -> bool,
+    {
+        self.waker.register(&thread_waker());
+        loop {
+            if step() {
+                return Ok(true);
+            }
+            if self.failed.load(Ordering::Acquire) {
+                return Err(Error::new());
+            }
+            match deadline {
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return Ok(false);
+                    }
+                    thread::park_timeout(deadline - now);
+                },
+                None => thread::park(),
+            }
+        }
+    }
+
+    fn state_callback(&self, state: State) {
+        match state {
+            State::Error => self.failed.store(true, Ordering::Release),
+            State::Started => self.stopped.store(false, Ordering::Release),
+            State::Stopped => self.stopped.store(true, Ordering::Release),
+            State::Drained => {},
+        }
+        self.wake();
+    }
+
+    // Tell the writer or reader the callbacks made progress.
+    fn wake(&self) {
+        self.waker.wake();
+    }
+
+    // Finish a poll that made no progress after registering its waker.
+    #[cfg(feature = "async")]
+    fn pending<T>(&self) -> Poll<Result<T>> {
+        if self.failed.load(Ordering::Acquire) {
+            Poll::Ready(Err(Error::new()))
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+// Plays what the writer queued.
+struct Render<F> {
+    consumer: Consumer<u8>,
+    shared: Arc<Shared>,
+    frame: PhantomData<F>
+}
+
+impl<F> OutputCallback for Render<F>
+where
+    F: Frame + Copy + Send + 'static,
+{
+    type Frame = F;
+    type Message = ();
+
+    fn data_callback(&mut self, output: &mut [F]) -> isize {
+        let frame_size = mem::size_of::<F>();
+        let output_len = output.len();
+        let bytes = as_bytes_mut(output);
+        let len = cmp::min(self.consumer.available() / frame_size * frame_size, bytes.len());
+        let got = self.consumer.read(&mut bytes[..len]);
+        if got < bytes.len() {
+            for b in &mut bytes[got..] {
+                *b = 0;
+            }
+            if !self.shared.idle.load(Ordering::Acquire) {
+                let missing = (bytes.len() - got) / frame_size;
+                self.shared.xruns.fetch_add(missing as u64, Ordering::Relaxed);
+            }
+        }
+        self.shared.wake();
+        output_len as isize
+    }
+
+    fn state_callback(&mut self, state: State) {
+        self.shared.state_callback(state);
+    }
+}
+
+// Queues frames for a `Render`.
+struct Writer<F> {
+    producer: Producer<u8>,
+    shared: Arc<Shared>,
+    frame: PhantomData<F>
+}
+
+fn output_pipe<F>(frames: usize) -> (Writer<F>, Render<F>) {
+    let (producer, consumer) = ring::ring::<u8>(frames * mem::size_of::<F>());
+    let shared = Arc::new(Shared::new());
+    (
+        Writer {
+            producer,
+            shared: shared.clone(),
+            frame: PhantomData
+        },
+        Render {
+            consumer,
+            shared,
+            frame: PhantomData
+        }
+    )
+}
+
+impl<F: Copy> Writer<F> {
+    fn try_write(&mut self, frames: &[F]) -> usize {
+        let frame_size = mem::size_of::<F>();
+        let len = cmp::min(self.producer.available() / frame_size, frames.len());
+        self.write_raw(as_bytes(&frames[..len])) / frame_size
+    }
+
+    fn write_raw(&mut self, buf: &[u8]) -> usize {
+        if !buf.is_empty() {
+            self.shared.idle.store(false, Ordering::Release);
+        }
+        self.producer.write(buf)
+    }
+
+    fn write_until(&mut self, frames: &[F], deadline: Option<Instant>) -> Result<usize> {
+        let mut written = 0;
+        let shared = self.shared.clone();
+        shared.poll(deadline, || {
+            written += self.try_write(&frames[written..]);
+            written == frames.len()
+        })?;
+        Ok(written)
+    }
+
+    // Whether everything queued was played, or won't be as the stream
+    // stopped.
+    fn drained(&self) -> bool {
+        self.producer.capacity() - self.producer.available() < mem::size_of::<F>() ||
+            self.shared.stopped.load(Ordering::Acquire)
+    }
+
+    fn drain(&mut self, deadline: Option<Instant>) -> Result<bool> {
+        // Everything is queued, so running out now is the end of it.
+        self.shared.idle.store(true, Ordering::Release);
+        let shared = self.shared.clone();
+        shared.poll(deadline, || self.drained())
+    }
+
+    fn write_bytes(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut written = 0;
+        let shared = self.shared.clone();
+        shared.poll(None, || {
+            written = self.write_raw(buf);
+            written > 0 || buf.is_empty()
+        }).map_err(io::Error::from)?;
+        Ok(written)
+    }
+}
+
+#[cfg(feature = "async")]
+impl<F: Copy> Writer<F> {
+    fn poll_write(&mut self, waker: &Waker, frames: &[F]) -> Poll<Result<usize>> {
+        self.shared.waker.register(waker);
+        let written = self.try_write(frames);
+        if written > 0 || frames.is_empty() {
+            return Poll::Ready(Ok(written));
+        }
+        self.shared.pending()
+    }
+
+    fn poll_drain(&mut self, waker: &Waker) -> Poll<Result<()>> {
+        self.shared.idle.store(true, Ordering::Release);
+        self.shared.waker.register(waker);
+        if self.drained() {
+            return Poll::Ready(Ok(()));
+        }
+        self.shared.pending()
+    }
+}
+
+// Queues what the stream captured for a `Reader`.
+struct Capture<F> {
+    producer: Producer<u8>,
+    shared: Arc<Shared>,
+    frame: PhantomData<F>
+}
+
+impl<F> InputCallback for Capture<F>
+where
+    F: Frame + Copy + Send + 'static,
+{
+    type Frame = F;
+    type Message = ();
+
+    fn data_callback(&mut self, input: &[F]) -> isize {
+        let frame_size = mem::size_of::<F>();
+        let room = self.producer.available() / frame_size;
+        let len = cmp::min(room, input.len());
+        self.producer.write(as_bytes(&input[..len]));
+        if len < input.len() {
+            let dropped = input.len() - len;
+            self.shared.xruns.fetch_add(dropped as u64, Ordering::Relaxed);
+        }
+        self.shared.wake();
+        input.len() as isize
+    }
+
+    fn state_callback(&mut self, state: State) {
+        self.shared.state_callback(state);
+    }
+}
+
+// Takes frames from a `Capture`.
+struct Reader<F> {
+    consumer: Consumer<u8>,
+    shared: Arc<Shared>,
+    frame: PhantomData<F>
+}
+
+fn input_pipe<F>(frames: usize) -> (Reader<F>, Capture<F>) {
+    let (producer, consumer) = ring::ring::<u8>(frames * mem::size_of::<F>());
+    let shared = Arc::new(Shared::new());
+    (
+        Reader {
+            consumer,
+            shared: shared.clone(),
+            frame: PhantomData
+        },
+        Capture {
+            producer,
+            shared,
+            frame: PhantomData
+        }
+    )
+}
+
+impl<F: Copy> Reader<F> {
+    fn try_read(&mut self, frames: &mut [F]) -> usize {
+        let frame_size = mem::size_of::<F>();
+        let len = cmp::min(self.consumer.available() / frame_size, frames.len());
+        self.consumer.read(as_bytes_mut(&mut frames[..len])) / frame_size
+    }
+
+    fn read_until(&mut self, frames: &mut [F], deadline: Option<Instant>) -> Result<usize> {
+        let mut read = 0;
+        let shared = self.shared.clone();
+        shared.poll(deadline, || {
+            read += self.try_read(&mut frames[read..]);
+            read == frames.len()
+        })?;
+        Ok(read)
+    }
+
+    fn read_bytes(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut read = 0;
+        let shared = self.shared.clone();
+        let consumer = &mut self.consumer;
+        shared.poll(None, || {
+            read = consumer.read(buf);
+            read > 0 || buf.is_empty()
+        }).map_err(io::Error::from)?;
+        Ok(read)
+    }
+}
+
+#[cfg(feature = "async")]
+impl<F: Copy> Reader<F> {
+    fn poll_read(&mut self, waker: &Waker, frames: &mut [F]) -> Poll<Result<usize>> {
+        self.shared.waker.register(waker);
+        let read = self.try_read(frames);
+        if read > 0 || frames.is_empty() {
+            return Poll::Ready(Ok(read));
+        }
+        self.shared.pending()
+    }
+}
+
+// Latency to ask for.
+fn latency(context: &Context, params: &StreamParams, latency_frames: Option<u32>)
+    -> Result<u32> {
+    if params.rate() == 0 || params.channels() == 0 {
+        return Err(Error::from(ErrorCode::InvalidParameter));
+    }
+    match latency_frames {
+        Some(frames) => Ok(frames),
+        None => context.min_latency(params),
+    }
+}
+
+/// Plays frames of type `F` written from a loop.
+///
+/// The stream starts when the writer is created and plays silence
+/// whenever it runs out of frames. Running out because the writer didn't
+/// keep up is counted by `underruns`; running out before the first write
+/// or after `drain` isn't.
+pub struct OutputWriter<F>
+where
+    F: Frame + Copy + Send + 'static,
+{
+    writer: Writer<F>,
+    stream: Stream<Output<Render<F>>>
+}
+
+impl<F> OutputWriter<F>
+where
+    F: Frame + Copy + Send + 'static,
+{
+    /// Create a writer playing `params` at the context's minimum latency.
+    pub fn new(context: &Context, params: &StreamParams) -> Result<Self> {
+        Self::init(context, params, None)
+    }
+
+    /// Create a writer playing `params` with `latency_frames` of latency.
+    pub fn with_latency(context: &Context, params: &StreamParams, latency_frames: u32)
+        -> Result<Self> {
+        Self::init(context, params, Some(latency_frames))
+    }
+
+    fn init(context: &Context, params: &StreamParams, latency_frames: Option<u32>)
+        -> Result<Self> {
+        let latency_frames = latency(context, params, latency_frames)?;
+        let (writer, render) = output_pipe(latency_frames as usize * BUFFER_PERIODS);
+        let opts = StreamInitOptionsBuilder::new()
+            .stream_name("OutputWriter")
+            .output_stream_param(params)
+            .latency(latency_frames)
+            .take();
+        let stream = context.stream_init(&opts, Output(render))?;
+        stream.start()?;
+        Ok(OutputWriter {
+            writer,
+            stream
+        })
+    }
+
+    /// Queue all of `frames`, blocking until there is room for them.
+    pub fn write_frames(&mut self, frames: &[F]) -> Result<()> {
+        self.writer.write_until(frames, None).map(|_| ())
+    }
+
+    /// Queue as many of `frames` as there is room for without blocking,
+    /// and return how many that was.
+    pub fn try_write_frames(&mut self, frames: &[F]) -> usize {
+        self.writer.try_write(frames)
+    }
+
+    /// Queue `frames`, blocking for at most `timeout`, and return how
+    /// many were queued.
+    pub fn write_frames_timeout(&mut self, frames: &[F], timeout: Duration) -> Result<usize> {
+        self.writer.write_until(frames, Some(Instant::now() + timeout))
+    }
+
+    /// Block until everything queued has been handed to the stream, or
+    /// the stream stopped.
+    pub fn drain(&mut self) -> Result<()> {
+        self.writer.drain(None).map(|_| ())
+    }
+
+    /// `drain`, blocking for at most `timeout`. Returns false if there
+    /// was still something queued by then.
+    pub fn drain_timeout(&mut self, timeout: Duration) -> Result<bool> {
+        self.writer.drain(Some(Instant::now() + timeout))
+    }
+
+    /// Frames of silence played because the writer fell behind.
+    pub fn underruns(&self) -> u64 {
+        self.writer.shared.xruns.load(Ordering::Relaxed)
+    }
+
+    /// The stream's playback position.
+    pub fn position(&self) -> Result<u64> {
+        self.stream.position()
+    }
+
+    #[cfg(feature = "async")]
+    pub(crate) fn poll_write(&mut self, waker: &Waker, frames: &[F]) -> Poll<Result<usize>> {
+        self.writer.poll_write(waker, frames)
+    }
+
+    #[cfg(feature = "async")]
+    pub(crate) fn poll_drain(&mut self, waker: &Waker) -> Poll<Result<()>> {
+        self.writer.poll_drain(waker)
+    }
+}
+
+impl<F> io::Write for OutputWriter<F>
+where
+    F: Frame + Copy + Send + 'static,
+{
+    /// Queue raw samples, blocking until there is room for some.
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.writer.write_bytes(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.drain().map_err(io::Error::from)
+    }
+}
+
+/// Captures frames of type `F` to be read from a loop.
+///
+/// The stream starts when the reader is created. Input dropped because
+/// the reader didn't keep up is counted by `overruns`.
+pub struct InputReader<F>
+where
+    F: Frame + Copy + Send + 'static,
+{
+    reader: Reader<F>,
+    stream: Stream<Input<Capture<F>>>
+}
+
+impl<F> InputReader<F>
+where
+    F: Frame + Copy + Send + 'static,
+{
+    /// Create a reader capturing `params` at the context's minimum
+    /// latency.
+    pub fn new(context: &Context, params: &StreamParams) -> Result<Self> {
+        Self::init(context, params, None)
+    }
+
+    /// Create a reader capturing `params` with `latency_frames` of
+    /// latency.
+    pub fn with_latency(context: &Context, params: &StreamParams, latency_frames: u32)
+        -> Result<Self> {
+        Self::init(context, params, Some(latency_frames))
+    }
+
+    fn init(context: &Context, params: &StreamParams, latency_frames: Option<u32>)
+        -> Result<Self> {
+        let latency_frames = latency(context, params, latency_frames)?;
+        let (reader, capture) = input_pipe(latency_frames as usize * BUFFER_PERIODS);
+        let opts = StreamInitOptionsBuilder::new()
+            .stream_name("InputReader")
+            .input_stream_param(params)
+            .latency(latency_frames)
+            .take();
+        let stream = context.stream_init(&opts, Input(capture))?;
+        stream.start()?;
+        Ok(InputReader {
+            reader,
+            stream
+        })
+    }
+
+    /// Fill all of `frames`, blocking until they have been captured.
+    pub fn read_frames(&mut self, frames: &mut [F]) -> Result<()> {
+        self.reader.read_until(frames, None).map(|_| ())
+    }
+
+    /// Fill as many of `frames` as have been captured without blocking,
+    /// and return how many that was.
+    pub fn try_read_frames(&mut self, frames: &mut [F]) -> usize {
+        self.reader.try_read(frames)
+    }
+
+    /// Fill `frames`, blocking for at most `timeout`, and return how many
+    /// were filled.
+    pub fn read_frames_timeout(&mut self, frames: &mut [F], timeout: Duration)
+        -> Result<usize> {
+        self.reader.read_until(frames, Some(Instant::now() + timeout))
+    }
+
+    /// Frames of input dropped because the reader fell behind.
+    pub fn overruns(&self) -> u64 {
+        self.reader.shared.xruns.load(Ordering::Relaxed)
+    }
+
+    /// The stream's capture position.
+    pub fn position(&self) -> Result<u64> {
+        self.stream.position()
+    }
+
+    #[cfg(feature = "async")]
+    pub(crate) fn poll_read(&mut self, waker: &Waker, frames: &mut [F]) -> Poll<Result<usize>> {
+        self.reader.poll_read(waker, frames)
+    }
+}
+
+impl<F> io::Read for InputReader<F>
+where
+    F: Frame + Copy + Send + 'static,
+{
+    /// Read raw samples, blocking until some have been captured.
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.reader.read_bytes(buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{input_pipe, output_pipe};
+    use {Error, InputCallback, MonoFrame, OutputCallback, State, StereoFrame};
+    use std::{io, thread};
+    use std::time::{Duration, Instant};
+
+    fn stereo(n: usize) -> Vec<StereoFrame<i16>> {
+        (0..n)
+            .map(|i| StereoFrame {
+                l: i as i16,
+                r: -(i as i16)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn writer_plays_in_order() {
+        let (mut writer, mut render) = output_pipe::<StereoFrame<i16>>(64);
+        let frames = stereo(1000);
+        let player = thread::spawn(move || {
+            let mut played: Vec<StereoFrame<i16>> = Vec::new();
+            let mut output = [StereoFrame { l: 0, r: 0 }; 48];
+            while played.len() < 999 {
+                render.data_callback(&mut output);
+                played.extend(output.iter().filter(|f| **f != StereoFrame { l: 0, r: 0 }));
+                thread::sleep(Duration::from_millis(1));
+            }
+            played
+        });
+        writer.write_until(&frames[1..], None).unwrap();
+        writer.drain(None).unwrap();
+        let played = player.join().unwrap();
+        assert!(played[..999] == frames[1..]);
+    }
+
+    #[test]
+    fn writer_try_and_timeout() {
+        let (mut writer, _render) = output_pipe::<MonoFrame<f32>>(16);
+        let frames = [MonoFrame { m: 0.5f32 }; 20];
+        assert_eq!(writer.try_write(&frames), 16);
+        assert_eq!(writer.try_write(&frames), 0);
+        let start = Instant::now();
+        let timeout = Duration::from_millis(20);
+        assert_eq!(writer.write_until(&frames, Some(start + timeout)).unwrap(), 0);
+        assert!(start.elapsed() >= timeout);
+    }
+
+    #[test]
+    fn writer_counts_underruns() {
+        let (mut writer, mut render) = output_pipe::<MonoFrame<f32>>(16);
+        let mut output = [MonoFrame { m: 1.0f32 }; 10];
+        // Silence before anything was written isn't an underrun.
+        render.data_callback(&mut output);
+        assert!(output.iter().all(|f| f.m == 0.0));
+        assert_eq!(writer.shared.xruns.load(super::Ordering::Relaxed), 0);
+        writer.try_write(&[MonoFrame { m: 0.5 }; 4]);
+        render.data_callback(&mut output);
+        assert!(output[..4].iter().all(|f| f.m == 0.5));
+        assert!(output[4..].iter().all(|f| f.m == 0.0));
+        assert_eq!(writer.shared.xruns.load(super::Ordering::Relaxed), 6);
+        // Nor is silence after draining.
+        writer.try_write(&[MonoFrame { m: 0.5 }; 4]);
+        let player = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(10));
+            render.data_callback(&mut output);
+        });
+        writer.drain(None).unwrap();
+        player.join().unwrap();
+        assert_eq!(writer.shared.xruns.load(super::Ordering::Relaxed), 6);
+    }
+
+    #[test]
+    fn writer_drain_ends_with_stream() {
+        let (mut writer, mut render) = output_pipe::<MonoFrame<f32>>(16);
+        writer.try_write(&[MonoFrame { m: 0.5 }; 4]);
+        let start = Instant::now();
+        let timeout = Duration::from_millis(20);
+        assert!(!writer.drain(Some(start + timeout)).unwrap());
+        assert!(start.elapsed() >= timeout);
+        let stopper = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(10));
+            render.state_callback(State::Stopped);
+            render
+        });
+        assert!(writer.drain(None).unwrap());
+        let mut render = stopper.join().unwrap();
+        // Restarted, it plays what's left.
+        render.state_callback(State::Started);
+        assert!(!writer.drain(Some(Instant::now())).unwrap());
+        render.data_callback(&mut [MonoFrame { m: 0.0 }; 4]);
+        assert!(writer.drain(Some(Instant::now())).unwrap());
+    }
+
+    #[test]
+    fn writer_partial_frames() {
+        // Bytes of a frame that isn't complete yet wait in the ring.
+        let (mut writer, mut render) = output_pipe::<MonoFrame<i16>>(16);
+        let mut output = [MonoFrame { m: 1i16 }; 2];
+        assert_eq!(writer.write_bytes(&[0x11, 0x22, 0x33]).unwrap(), 3);
+        render.data_callback(&mut output);
+        assert_eq!(output[0].m, i16::from_ne_bytes([0x11, 0x22]));
+        assert_eq!(output[1].m, 0);
+        assert_eq!(writer.write_bytes(&[0x44]).unwrap(), 1);
+        render.data_callback(&mut output);
+        assert_eq!(output[0].m, i16::from_ne_bytes([0x33, 0x44]));
+    }
+
+    #[test]
+    fn writer_fails_with_stream() {
+        let (mut writer, mut render) = output_pipe::<MonoFrame<f32>>(4);
+        writer.try_write(&[MonoFrame { m: 0.5 }; 4]);
+        render.state_callback(State::Error);
+        assert!(writer.write_until(&[MonoFrame { m: 0.5 }], None).is_err());
+        assert!(writer.drain(None).is_err());
+    }
+
+    #[test]
+    fn bytes_fail_with_stream() {
+        let (mut writer, mut render) = output_pipe::<MonoFrame<f32>>(4);
+        writer.try_write(&[MonoFrame { m: 0.5 }; 4]);
+        render.state_callback(State::Error);
+        let e = writer.write_bytes(&[0; 4]).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::Other);
+        assert!(e.get_ref().unwrap().downcast_ref::<Error>().is_some());
+
+        let (mut reader, mut capture) = input_pipe::<MonoFrame<i16>>(4);
+        capture.state_callback(State::Error);
+        let e = reader.read_bytes(&mut [0; 2]).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::Other);
+    }
+
+    #[test]
+    fn reader_reads_in_order() {
+        let (mut reader, mut capture) = input_pipe::<StereoFrame<i16>>(64);
+        let frames = stereo(1000);
+        let input = frames.clone();
+        let recorder = thread::spawn(move || {
+            for chunk in input.chunks(10) {
+                assert_eq!(capture.data_callback(chunk), 10);
+                thread::sleep(Duration::from_millis(1));
+            }
+            capture
+        });
+        let mut read = vec![StereoFrame { l: 0, r: 0 }; 1000];
+        reader.read_until(&mut read, None).unwrap();
+        recorder.join().unwrap();
+        assert!(read == frames);
+        assert_eq!(reader.shared.xruns.load(super::Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn reader_counts_overruns() {
+        let (mut reader, mut capture) = input_pipe::<MonoFrame<i16>>(8);
+        let input: Vec<_> = (0..12).map(|m| MonoFrame { m }).collect();
+        assert_eq!(capture.data_callback(&input), 12);
+        assert_eq!(reader.shared.xruns.load(super::Ordering::Relaxed), 4);
+        let mut read = [MonoFrame { m: 0 }; 12];
+        assert_eq!(reader.try_read(&mut read), 8);
+        assert!(read[..8] == input[..8]);
+        let start = Instant::now();
+        let timeout = Duration::from_millis(20);
+        assert_eq!(reader.read_until(&mut read, Some(start + timeout)).unwrap(), 0);
+        assert!(start.elapsed() >= timeout);
+    }
+
+    #[test]
+    fn reader_bytes() {
+        let (mut reader, mut capture) = input_pipe::<MonoFrame<i16>>(8);
+        capture.data_callback(&[MonoFrame { m: 0x1122 }, MonoFrame { m: 0x3344 }]);
+        let mut buf = [0u8; 3];
+        assert_eq!(reader.read_bytes(&mut buf).unwrap(), 3);
+        let mut rest = [0u8; 3];
+        assert_eq!(reader.read_bytes(&mut rest).unwrap(), 1);
+        let mut expected = 0x1122i16.to_ne_bytes().to_vec();
+        expected.extend_from_slice(&0x3344i16.to_ne_bytes());
+        assert_eq!(buf[..], expected[..3]);
+        assert_eq!(rest[0], expected[3]);
+    }
+
+    #[cfg(feature = "async")]
+    #[test]
+    fn writer_polls() {
+        use waker::tests::counting_waker;
+        use std::task::Poll;
+
+        let (mut writer, mut render) = output_pipe::<MonoFrame<i16>>(4);
+        let (counter, waker) = counting_waker();
+        let frames: Vec<_> = (0..6).map(|m| MonoFrame { m }).collect();
+        assert_eq!(writer.poll_write(&waker, &frames), Poll::Ready(Ok(4)));
+        assert_eq!(writer.poll_write(&waker, &frames[4..]), Poll::Pending);
+        let mut output = [MonoFrame { m: 0 }; 2];
+        assert_eq!(render.data_callback(&mut output), 2);
+        assert_eq!(counter.count(), 1);
+        assert_eq!(writer.poll_write(&waker, &frames[4..]), Poll::Ready(Ok(2)));
+
+        assert_eq!(writer.poll_drain(&waker), Poll::Pending);
+        let mut output = [MonoFrame { m: 0 }; 4];
+        render.data_callback(&mut output);
+        assert_eq!(counter.count(), 2);
+        assert_eq!(writer.poll_drain(&waker), Poll::Ready(Ok(())));
+
+        assert_eq!(writer.poll_write(&waker, &frames), Poll::Ready(Ok(4)));
+        assert_eq!(writer.poll_write(&waker, &frames), Poll::Pending);
+        render.state_callback(State::Error);
+        assert_eq!(counter.count(), 3);
+        assert!(writer.poll_write(&waker, &frames).is_ready());
+    }
+
+    #[cfg(feature = "async")]
+    #[test]
+    fn reader_polls() {
+        use waker::tests::counting_waker;
+        use std::task::Poll;
+
+        let (mut reader, mut capture) = input_pipe::<MonoFrame<i16>>(4);
+        let (counter, waker) = counting_waker();
+        let mut read = [MonoFrame { m: 0 }; 4];
+        assert_eq!(reader.poll_read(&waker, &mut read), Poll::Pending);
+        let input: Vec<_> = (0..3).map(|m| MonoFrame { m }).collect();
+        capture.data_callback(&input);
+        assert_eq!(counter.count(), 1);
+        assert_eq!(reader.poll_read(&waker, &mut read), Poll::Ready(Ok(3)));
+        assert!(read[..3] == input[..]);
+        capture.state_callback(State::Error);
+        assert!(matches!(reader.poll_read(&waker, &mut read), Poll::Ready(Err(_))));
+    }
+}
@@ -12,34 +12,55 @@
 //! libcubeb via implementing a cubeb backend in rust.
 
 extern crate cubeb_core;
+#[cfg(feature = "async")]
+extern crate futures_core;
 extern crate libcubeb_sys as sys;
 
 #[macro_use]
 mod call;
+mod adapt;
+#[cfg(feature = "async")]
+pub mod async_io;
+mod clock;
 mod context;
 mod dev_coll;
 mod frame;
+mod io;
 mod log;
+mod negotiate;
+mod queue;
+mod retry;
+mod shared;
 mod stream;
 mod util;
+mod waker;
 
+pub use clock::StreamClock;
 pub use context::Context;
 // Re-export cubeb_core types
 pub use cubeb_core::{ChannelLayout, Device, DeviceFormat, DeviceId, DeviceInfo,
-                     DeviceState, DeviceType, Error, ErrorCode, LogLevel, Result,
-                     SampleFormat, State, StreamParams};
+                     DeviceState, DeviceType, Error, ErrorCode, Frames, LogLevel,
+                     ParseError, Result, SampleFormat, State, StreamParams};
 pub use cubeb_core::{DEVICE_FMT_F32BE, DEVICE_FMT_F32LE, DEVICE_FMT_S16BE,
                      DEVICE_FMT_S16LE};
 pub use cubeb_core::{DEVICE_PREF_ALL, DEVICE_PREF_MULTIMEDIA, DEVICE_PREF_NONE,
                      DEVICE_PREF_NOTIFICATION, DEVICE_PREF_VOICE};
 pub use cubeb_core::{DEVICE_TYPE_INPUT, DEVICE_TYPE_OUTPUT, DEVICE_TYPE_UNKNOWN};
+pub use cubeb_core::ring;
 
 use cubeb_core::binding::Binding;
 use cubeb_core::ffi;
 pub use dev_coll::DeviceCollection;
 pub use frame::{Frame, MonoFrame, StereoFrame};
+pub use io::{InputReader, OutputWriter};
 pub use log::*;
-pub use stream::{SampleType, Stream, StreamCallback, StreamInitOptions,
+pub use negotiate::{Conversion, DeviceCaps, Negotiated, NegotiationPolicy, Prefer,
+                    negotiate};
+pub use retry::{RetryPolicy, retry};
+pub use shared::{SharedContext, SharedStream};
+pub use stream::{CallbackFn, DuplexCallback, DynStream, Input, InputCallback,
+                 MESSAGE_QUEUE_LEN, Output, OutputCallback, SampleType, Stream,
+                 StreamCallback, StreamControl, StreamInitOptions,
                  StreamInitOptionsBuilder, StreamParamsBuilder};
 
 pub type DeviceChangedCb<'a> = FnMut() + 'a;
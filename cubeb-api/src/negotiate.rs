@@ -0,0 +1,426 @@
+// Copyright © 2017 Mozilla Foundation
+//
+// This program is made available under an ISC-style license.  See the
+// accompanying file LICENSE for details.
+
+//! Choosing stream parameters a device supports
+//!
+//! `negotiate` compares the parameters an application would like with
+//! what a device reports it can do, and picks the closest parameters the
+//! device supports, along with the conversions needed to get from one to
+//! the other.
+//!
+//! # Example
+//! ```no_run
+//! extern crate cubeb;
+//!
+//! fn main() {
+//!     let ctx = cubeb::Context::init("Cubeb negotiation example", None).unwrap();
+//!     let desired = cubeb::StreamParamsBuilder::new()
+//!         .format(cubeb::SampleFormat::Float32NE)
+//!         .rate(44100)
+//!         .channels(2)
+//!         .layout(cubeb::ChannelLayout::Stereo)
+//!         .take();
+//!
+//!     let policy = cubeb::NegotiationPolicy::default();
+//!     let negotiated = ctx.negotiate(&desired, cubeb::DEVICE_TYPE_OUTPUT, None, &policy);
+//!     for conversion in &negotiated.conversions {
+//!         println!("{:?}", conversion);
+//!     }
+//! }
+//! ```
+
+use {ChannelLayout, DeviceFormat, DeviceInfo, SampleFormat, StreamParams, StreamParamsBuilder};
+use {DEVICE_FMT_F32BE, DEVICE_FMT_F32LE, DEVICE_FMT_S16BE, DEVICE_FMT_S16LE};
+use stream::layout_for;
+
+/// What a device can play or capture.
+///
+/// Zero or empty fields mean the device didn't say, and anything is taken
+/// to be supported.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DeviceCaps {
+    pub formats: DeviceFormat,
+    pub default_format: DeviceFormat,
+    pub min_rate: u32,
+    pub max_rate: u32,
+    pub default_rate: u32,
+    pub max_channels: u32,
+    /// Layout the device prefers.
+    pub layout: ChannelLayout
+}
+
+impl From<&DeviceInfo> for DeviceCaps {
+    fn from(info: &DeviceInfo) -> DeviceCaps {
+        DeviceCaps {
+            formats: info.format(),
+            default_format: info.default_format(),
+            min_rate: info.min_rate(),
+            max_rate: info.max_rate(),
+            default_rate: info.default_rate(),
+            max_channels: info.max_channels(),
+            layout: ChannelLayout::Undefined
+        }
+    }
+}
+
+/// Which supported value to settle for when a device doesn't support the
+/// one asked for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Prefer {
+    /// The device's default, so it doesn't convert in turn.
+    DeviceDefault,
+    /// The one nearest to what was asked for.
+    Closest,
+    /// The one with the most resolution: the highest rate, floating point
+    /// samples or the most channels.
+    Highest
+}
+
+/// How `negotiate` ranks supported values for each parameter.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct NegotiationPolicy {
+    pub rate: Prefer,
+    pub format: Prefer,
+    pub channels: Prefer
+}
+
+impl Default for NegotiationPolicy {
+    /// Resample to the device's rate, and otherwise stay close.
+    fn default() -> Self {
+        NegotiationPolicy {
+            rate: Prefer::DeviceDefault,
+            format: Prefer::Closest,
+            channels: Prefer::Closest
+        }
+    }
+}
+
+/// A conversion from the parameters asked for to negotiated ones.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Conversion {
+    Rate { from: u32, to: u32 },
+    Format { from: SampleFormat, to: SampleFormat },
+    Layout {
+        from: ChannelLayout,
+        from_channels: u32,
+        to: ChannelLayout,
+        to_channels: u32
+    }
+}
+
+/// The outcome of `negotiate`.
+#[derive(Debug)]
+pub struct Negotiated {
+    /// Parameters to open the stream with.
+    pub params: StreamParams,
+    /// What to convert, in the order parameters are checked: rate, format,
+    /// then layout.
+    pub conversions: Vec<Conversion>
+}
+
+impl Negotiated {
+    /// Whether the device supports what was asked for as it is.
+    pub fn is_exact(&self) -> bool {
+        self.conversions.is_empty()
+    }
+}
+
+fn device_format(format: SampleFormat) -> DeviceFormat {
+    let little = cfg!(target_endian = "little");
+    match format {
+        SampleFormat::S16LE => DEVICE_FMT_S16LE,
+        SampleFormat::S16BE => DEVICE_FMT_S16BE,
+        SampleFormat::S16NE if little => DEVICE_FMT_S16LE,
+        SampleFormat::S16NE => DEVICE_FMT_S16BE,
+        SampleFormat::Float32LE => DEVICE_FMT_F32LE,
+        SampleFormat::Float32BE => DEVICE_FMT_F32BE,
+        SampleFormat::Float32NE if little => DEVICE_FMT_F32LE,
+        SampleFormat::Float32NE => DEVICE_FMT_F32BE,
+    }
+}
+
+fn sample_format(format: DeviceFormat) -> Option<SampleFormat> {
+    match format {
+        DEVICE_FMT_S16LE => Some(SampleFormat::S16LE),
+        DEVICE_FMT_S16BE => Some(SampleFormat::S16BE),
+        DEVICE_FMT_F32LE => Some(SampleFormat::Float32LE),
+        DEVICE_FMT_F32BE => Some(SampleFormat::Float32BE),
+        _ => None,
+    }
+}
+
+fn negotiate_rate(rate: u32, caps: &DeviceCaps, prefer: Prefer) -> u32 {
+    let (min, max) = if caps.max_rate == 0 {
+        (caps.default_rate, caps.default_rate)
+    } else {
+        (caps.min_rate, caps.max_rate)
+    };
+    if max == 0 || (min <= rate && rate <= max) {
+        return rate;
+    }
+    match prefer {
+        Prefer::DeviceDefault if min <= caps.default_rate && caps.default_rate <= max => {
+            caps.default_rate
+        },
+        Prefer::Highest => max,
+        _ => rate.max(min).min(max),
+    }
+}
+
+fn negotiate_format(format: SampleFormat, caps: &DeviceCaps, prefer: Prefer) -> SampleFormat {
+    let wanted = device_format(format);
+    if caps.formats.is_empty() || caps.formats.contains(wanted) {
+        return format;
+    }
+    if prefer == Prefer::DeviceDefault && caps.formats.contains(caps.default_format) {
+        if let Some(format) = sample_format(caps.default_format) {
+            return format;
+        }
+    }
+    let f32s = DEVICE_FMT_F32LE | DEVICE_FMT_F32BE;
+    let s16s = DEVICE_FMT_S16LE | DEVICE_FMT_S16BE;
+    let f32ne = device_format(SampleFormat::Float32NE);
+    let s16ne = device_format(SampleFormat::S16NE);
+    // Keeping the sample type matters more than keeping its endianness.
+    let order = if prefer == Prefer::Highest {
+        [f32ne, f32s - f32ne, s16ne, s16s - s16ne]
+    } else if f32s.contains(wanted) {
+        [wanted, f32s - wanted, s16ne, s16s - s16ne]
+    } else {
+        [wanted, s16s - wanted, f32ne, f32s - f32ne]
+    };
+    order
+        .iter()
+        .find(|f| caps.formats.contains(**f))
+        .and_then(|f| sample_format(*f))
+        .unwrap_or(format)
+}
+
+fn negotiate_channels(channels: u32, caps: &DeviceCaps, prefer: Prefer) -> u32 {
+    let max = caps.max_channels;
+    if max == 0 || channels <= max {
+        return channels;
+    }
+    let preferred = caps.layout.channel_count();
+    if prefer == Prefer::DeviceDefault && preferred != 0 && preferred <= max {
+        return preferred;
+    }
+    max
+}
+
+/// The parameters closest to `desired` that a device with `caps` supports,
+/// ranked by `policy`.
+pub fn negotiate(desired: &StreamParams, caps: &DeviceCaps, policy: &NegotiationPolicy)
+    -> Negotiated {
+    let mut conversions = Vec::new();
+
+    let rate = negotiate_rate(desired.rate(), caps, policy.rate);
+    if rate != desired.rate() {
+        conversions.push(Conversion::Rate {
+            from: desired.rate(),
+            to: rate
+        });
+    }
+
+    let format = negotiate_format(desired.format(), caps, policy.format);
+    if format != desired.format() {
+        conversions.push(Conversion::Format {
+            from: desired.format(),
+            to: format
+        });
+    }
+
+    let channels = negotiate_channels(desired.channels(), caps, policy.channels);
+    let layout = if channels == desired.channels() {
+        desired.layout()
+    } else if caps.layout.channel_count() == channels {
+        caps.layout
+    } else {
+        layout_for(channels)
+    };
+    if channels != desired.channels() {
+        conversions.push(Conversion::Layout {
+            from: desired.layout(),
+            from_channels: desired.channels(),
+            to: layout,
+            to_channels: channels
+        });
+    }
+
+    Negotiated {
+        params: StreamParamsBuilder::new()
+            .format(format)
+            .rate(rate)
+            .channels(channels)
+            .layout(layout)
+            .take(),
+        conversions
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Conversion, DeviceCaps, NegotiationPolicy, Prefer, negotiate};
+    use {ChannelLayout, DeviceFormat, SampleFormat, StreamParams, StreamParamsBuilder};
+    use {DEVICE_FMT_F32BE, DEVICE_FMT_F32LE, DEVICE_FMT_S16BE, DEVICE_FMT_S16LE};
+
+    fn params(format: SampleFormat, rate: u32, layout: ChannelLayout) -> StreamParams {
+        StreamParamsBuilder::new()
+            .format(format)
+            .rate(rate)
+            .channels(layout.channel_count())
+            .layout(layout)
+            .take()
+    }
+
+    // A stereo, little endian device running at 48kHz.
+    fn caps() -> DeviceCaps {
+        DeviceCaps {
+            formats: DEVICE_FMT_S16LE | DEVICE_FMT_F32LE,
+            default_format: DEVICE_FMT_F32LE,
+            min_rate: 44100,
+            max_rate: 96000,
+            default_rate: 48000,
+            max_channels: 2,
+            layout: ChannelLayout::Stereo
+        }
+    }
+
+    #[test]
+    fn negotiate_exact() {
+        let desired = params(SampleFormat::S16LE, 44100, ChannelLayout::Mono);
+        let n = negotiate(&desired, &caps(), &NegotiationPolicy::default());
+        assert!(n.is_exact());
+        assert_eq!(n.params.format(), SampleFormat::S16LE);
+        assert_eq!(n.params.rate(), 44100);
+        assert_eq!(n.params.channels(), 1);
+        assert_eq!(n.params.layout(), ChannelLayout::Mono);
+    }
+
+    #[test]
+    fn negotiate_unknown_caps_accept_anything() {
+        let unknown = DeviceCaps {
+            formats: DeviceFormat::empty(),
+            default_format: DeviceFormat::empty(),
+            min_rate: 0,
+            max_rate: 0,
+            default_rate: 0,
+            max_channels: 0,
+            layout: ChannelLayout::Undefined
+        };
+        let desired = params(SampleFormat::Float32BE, 8000, ChannelLayout::Layout3F4Lfe);
+        assert!(negotiate(&desired, &unknown, &NegotiationPolicy::default()).is_exact());
+    }
+
+    #[test]
+    fn negotiate_rate_policies() {
+        let desired = params(SampleFormat::S16LE, 192000, ChannelLayout::Stereo);
+        let mut policy = NegotiationPolicy::default();
+        let n = negotiate(&desired, &caps(), &policy);
+        assert_eq!(n.conversions, [Conversion::Rate { from: 192000, to: 48000 }]);
+        policy.rate = Prefer::Closest;
+        assert_eq!(negotiate(&desired, &caps(), &policy).params.rate(), 96000);
+        let desired = params(SampleFormat::S16LE, 22050, ChannelLayout::Stereo);
+        assert_eq!(negotiate(&desired, &caps(), &policy).params.rate(), 44100);
+        policy.rate = Prefer::Highest;
+        assert_eq!(negotiate(&desired, &caps(), &policy).params.rate(), 96000);
+
+        // A device reporting only its default rate.
+        let fixed = DeviceCaps {
+            min_rate: 0,
+            max_rate: 0,
+            ..caps()
+        };
+        policy.rate = Prefer::Closest;
+        assert_eq!(negotiate(&desired, &fixed, &policy).params.rate(), 48000);
+    }
+
+    #[test]
+    fn negotiate_format_policies() {
+        let mut policy = NegotiationPolicy::default();
+        let desired = params(SampleFormat::S16BE, 48000, ChannelLayout::Stereo);
+        let n = negotiate(&desired, &caps(), &policy);
+        assert_eq!(
+            n.conversions,
+            [
+                Conversion::Format {
+                    from: SampleFormat::S16BE,
+                    to: SampleFormat::S16LE
+                }
+            ]
+        );
+        policy.format = Prefer::DeviceDefault;
+        let n = negotiate(&desired, &caps(), &policy);
+        assert_eq!(n.params.format(), SampleFormat::Float32LE);
+        policy.format = Prefer::Highest;
+        let n = negotiate(&desired, &caps(), &policy);
+        assert_eq!(n.params.format(), SampleFormat::Float32LE);
+
+        // Keeping the sample type beats keeping native endianness.
+        let big = DeviceCaps {
+            formats: DEVICE_FMT_S16LE | DEVICE_FMT_S16BE | DEVICE_FMT_F32BE,
+            ..caps()
+        };
+        policy.format = Prefer::Closest;
+        let desired = params(SampleFormat::Float32LE, 48000, ChannelLayout::Stereo);
+        assert_eq!(negotiate(&desired, &big, &policy).params.format(), SampleFormat::Float32BE);
+    }
+
+    #[test]
+    fn negotiate_layout_policies() {
+        let mut policy = NegotiationPolicy::default();
+        let surround = DeviceCaps {
+            max_channels: 6,
+            layout: ChannelLayout::Layout3F2Lfe,
+            ..caps()
+        };
+        let desired = params(SampleFormat::Float32LE, 48000, ChannelLayout::Layout3F4Lfe);
+        let n = negotiate(&desired, &caps(), &policy);
+        assert_eq!(
+            n.conversions,
+            [
+                Conversion::Layout {
+                    from: ChannelLayout::Layout3F4Lfe,
+                    from_channels: 8,
+                    to: ChannelLayout::Stereo,
+                    to_channels: 2
+                }
+            ]
+        );
+        let n = negotiate(&desired, &surround, &policy);
+        assert_eq!(n.params.layout(), ChannelLayout::Layout3F2Lfe);
+        assert_eq!(n.params.channels(), 6);
+
+        // Counts several layouts share are left without one.
+        let three = DeviceCaps {
+            max_channels: 3,
+            ..caps()
+        };
+        let n = negotiate(&desired, &three, &policy);
+        assert_eq!(n.params.channels(), 3);
+        assert_eq!(n.params.layout(), ChannelLayout::Undefined);
+
+        // Up to 6 channels, but preferring stereo.
+        let stereo = DeviceCaps {
+            max_channels: 6,
+            ..caps()
+        };
+        assert_eq!(negotiate(&desired, &stereo, &policy).params.channels(), 6);
+        policy.channels = Prefer::DeviceDefault;
+        let n = negotiate(&desired, &stereo, &policy);
+        assert_eq!(n.params.channels(), 2);
+        assert_eq!(n.params.layout(), ChannelLayout::Stereo);
+    }
+
+    #[test]
+    fn negotiate_everything() {
+        let desired = params(SampleFormat::S16BE, 8000, ChannelLayout::Layout2F2);
+        let n = negotiate(&desired, &caps(), &NegotiationPolicy::default());
+        assert_eq!(n.conversions.len(), 3);
+        assert_eq!(n.params.format(), SampleFormat::S16LE);
+        assert_eq!(n.params.rate(), 48000);
+        assert_eq!(n.params.layout(), ChannelLayout::Stereo);
+    }
+}
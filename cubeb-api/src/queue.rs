@@ -0,0 +1,156 @@
+// Copyright © 2017 Mozilla Foundation
+//
+// This program is made available under an ISC-style license.  See the
+// accompanying file LICENSE for details.
+
+//! Wait-free single producer, single consumer queue of owned values.
+//!
+//! Unlike a ring, values are moved rather than copied, so a queue can
+//! carry values that own allocations. Pushing and popping never allocate
+//! or free; values still queued are dropped with the last end.
+
+use std::cell::UnsafeCell;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+struct Slots<T> {
+    // Free running positions, as in a ring.
+    read: AtomicUsize,
+    write: AtomicUsize,
+    slots: Box<[UnsafeCell<Option<T>>]>
+}
+
+// The sender only touches free slots, the receiver only full ones.
+unsafe impl<T: Send> Sync for Slots<T> {}
+
+impl<T> Slots<T> {
+    fn slot(&self, pos: usize) -> *mut Option<T> {
+        self.slots[pos & (self.slots.len() - 1)].get()
+    }
+}
+
+/// Create a queue holding at least `capacity` values.
+pub fn queue<T: Send>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    let capacity = capacity.max(1).next_power_of_two();
+    let slots = Arc::new(Slots {
+        read: AtomicUsize::new(0),
+        write: AtomicUsize::new(0),
+        slots: (0..capacity).map(|_| UnsafeCell::new(None)).collect()
+    });
+    (
+        Sender {
+            slots: slots.clone()
+        },
+        Receiver {
+            slots
+        }
+    )
+}
+
+/// Sending end of a queue.
+pub struct Sender<T> {
+    slots: Arc<Slots<T>>
+}
+
+impl<T> Sender<T> {
+    /// Whether there's room to push a value.
+    pub fn has_room(&self) -> bool {
+        let read = self.slots.read.load(Ordering::Acquire);
+        let write = self.slots.write.load(Ordering::Relaxed);
+        write.wrapping_sub(read) < self.slots.slots.len()
+    }
+
+    /// Append `value`, or hand it back if the queue is full.
+    pub fn push(&mut self, value: T) -> Result<(), T> {
+        if !self.has_room() {
+            return Err(value);
+        }
+        let write = self.slots.write.load(Ordering::Relaxed);
+        // The slot was emptied by `pop`, so nothing is dropped here.
+        unsafe { *self.slots.slot(write) = Some(value) };
+        self.slots
+            .write
+            .store(write.wrapping_add(1), Ordering::Release);
+        Ok(())
+    }
+}
+
+/// Receiving end of a queue.
+pub struct Receiver<T> {
+    slots: Arc<Slots<T>>
+}
+
+impl<T> Receiver<T> {
+    /// Take the oldest value, if any.
+    pub fn pop(&mut self) -> Option<T> {
+        let write = self.slots.write.load(Ordering::Acquire);
+        let read = self.slots.read.load(Ordering::Relaxed);
+        if read == write {
+            return None;
+        }
+        let value = unsafe { (*self.slots.slot(read)).take() };
+        self.slots
+            .read
+            .store(read.wrapping_add(1), Ordering::Release);
+        value
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::queue;
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn queue_full_and_empty() {
+        let (mut tx, mut rx) = queue(2);
+        assert_eq!(rx.pop(), None);
+        assert_eq!(tx.push(String::from("a")), Ok(()));
+        assert_eq!(tx.push(String::from("b")), Ok(()));
+        assert!(!tx.has_room());
+        assert_eq!(tx.push(String::from("c")), Err(String::from("c")));
+        assert_eq!(rx.pop(), Some(String::from("a")));
+        assert_eq!(tx.push(String::from("c")), Ok(()));
+        assert_eq!(rx.pop(), Some(String::from("b")));
+        assert_eq!(rx.pop(), Some(String::from("c")));
+        assert_eq!(rx.pop(), None);
+    }
+
+    #[test]
+    fn queue_drops_leftovers() {
+        let value = Arc::new(());
+        let (mut tx, rx) = queue(4);
+        tx.push(value.clone()).unwrap();
+        tx.push(value.clone()).unwrap();
+        assert_eq!(Arc::strong_count(&value), 3);
+        drop(tx);
+        drop(rx);
+        assert_eq!(Arc::strong_count(&value), 1);
+    }
+
+    #[test]
+    fn queue_threads() {
+        let (mut tx, mut rx) = queue(8);
+        let sender = thread::spawn(move || {
+            let mut i = 0;
+            while i < 10_000 {
+                match tx.push(Box::new(i)) {
+                    Ok(()) => i += 1,
+                    Err(_) => thread::yield_now(),
+                }
+            }
+        });
+        let mut next = 0;
+        while next < 10_000 {
+            match rx.pop() {
+                Some(i) => {
+                    assert_eq!(*i, next);
+                    next += 1;
+                },
+                None => thread::yield_now(),
+            }
+        }
+        sender.join().unwrap();
+    }
+}
@@ -0,0 +1,161 @@
+// Copyright © 2017 Mozilla Foundation
+//
+// This program is made available under an ISC-style license.  See the
+// accompanying file LICENSE for details.
+
+//! Retrying operations that fail while devices come and go.
+
+use Result;
+use std::thread;
+use std::time::Duration;
+
+/// How many times `retry` tries, and how long it waits in between.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RetryPolicy {
+    /// Attempts in all, including the first.
+    pub attempts: u32,
+    /// Wait before the first retry.
+    pub initial_delay: Duration,
+    /// Each wait is this many times longer than the one before, ...
+    pub backoff: f64,
+    /// ... but no longer than this.
+    pub max_delay: Duration
+}
+
+impl Default for RetryPolicy {
+    /// Five attempts over about three quarters of a second.
+    fn default() -> Self {
+        RetryPolicy {
+            attempts: 5,
+            initial_delay: Duration::from_millis(50),
+            backoff: 2.0,
+            max_delay: Duration::from_secs(1)
+        }
+    }
+}
+
+impl RetryPolicy {
+    // The wait before retry `n`, counting from zero.
+    fn delay(&self, n: u32) -> Duration {
+        let backoff = self.backoff.max(1.0).powi(n as i32);
+        let secs = self.initial_delay.as_secs_f64() * backoff;
+        if secs < self.max_delay.as_secs_f64() {
+            Duration::from_secs_f64(secs)
+        } else {
+            self.max_delay
+        }
+    }
+}
+
+/// Call `op` until it succeeds, fails with an error that isn't
+/// transient, or `policy` runs out of attempts, sleeping in between.
+/// Returns the last result.
+pub fn retry<T, F>(policy: &RetryPolicy, op: F) -> Result<T>
+where
+    F: FnMut() -> Result<T>,
+{
+    retry_with(policy, op, thread::sleep)
+}
+
+fn retry_with<T, F, S>(policy: &RetryPolicy, mut op: F, mut sleep: S) -> Result<T>
+where
+    F: FnMut() -> Result<T>,
+    S: FnMut(Duration),
+{
+    let mut n = 0;
+    loop {
+        match op() {
+            Err(ref e) if e.is_transient() && n + 1 < policy.attempts => {
+                sleep(policy.delay(n));
+                n += 1;
+            },
+            result => return result,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{RetryPolicy, retry_with};
+    use {Error, ErrorCode};
+    use std::time::Duration;
+
+    fn ms(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    // Runs `op` under `policy`, returning the result, the number of calls
+    // and the waits in between.
+    fn run<F>(policy: &RetryPolicy, mut op: F)
+        -> (Result<u32, ErrorCode>, u32, Vec<Duration>)
+    where
+        F: FnMut(u32) -> ::Result<u32>,
+    {
+        let mut calls = 0;
+        let mut waits = Vec::new();
+        let result = retry_with(
+            policy,
+            || {
+                calls += 1;
+                op(calls)
+            },
+            |wait| waits.push(wait)
+        );
+        (result.map_err(|e| e.code()), calls, waits)
+    }
+
+    fn unavailable() -> Error {
+        Error::from(ErrorCode::DeviceUnavailable)
+    }
+
+    #[test]
+    fn retry_backs_off() {
+        let policy = RetryPolicy::default();
+        let (result, calls, waits) = run(&policy, |n| {
+            if n < 4 { Err(unavailable()) } else { Ok(n) }
+        });
+        assert_eq!(result, Ok(4));
+        assert_eq!(calls, 4);
+        assert_eq!(waits, [ms(50), ms(100), ms(200)]);
+
+        let policy = RetryPolicy {
+            attempts: 6,
+            initial_delay: ms(300),
+            backoff: 3.0,
+            max_delay: ms(1000)
+        };
+        let (result, calls, waits) = run(&policy, |_| Err(unavailable()));
+        assert_eq!(result, Err(ErrorCode::DeviceUnavailable));
+        assert_eq!(calls, 6);
+        assert_eq!(waits, [ms(300), ms(900), ms(1000), ms(1000), ms(1000)]);
+    }
+
+    #[test]
+    fn retry_only_transient_errors() {
+        let policy = RetryPolicy::default();
+        let invalid = || Err(Error::from(ErrorCode::InvalidFormat));
+        let (result, calls, waits) = run(&policy, |_| invalid());
+        assert_eq!(result, Err(ErrorCode::InvalidFormat));
+        assert_eq!(calls, 1);
+        assert!(waits.is_empty());
+
+        let (result, calls, _) = run(&policy, |n| {
+            let code = if n < 2 { ErrorCode::DeviceUnavailable } else { ErrorCode::NotSupported };
+            Err(Error::from(code))
+        });
+        assert_eq!(result, Err(ErrorCode::NotSupported));
+        assert_eq!(calls, 2);
+    }
+
+    #[test]
+    fn retry_without_attempts() {
+        let policy = RetryPolicy {
+            attempts: 0,
+            ..RetryPolicy::default()
+        };
+        let (result, calls, waits) = run(&policy, |_| Err(unavailable()));
+        assert_eq!(result, Err(ErrorCode::DeviceUnavailable));
+        assert_eq!(calls, 1);
+        assert!(waits.is_empty());
+    }
+}
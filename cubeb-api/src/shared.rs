@@ -0,0 +1,173 @@
+// Copyright © 2017 Mozilla Foundation
+//
+// This program is made available under an ISC-style license.  See the
+// accompanying file LICENSE for details.
+
+//! Contexts and streams shared between threads
+//!
+//! libcubeb contexts and streams aren't tied to the thread that made
+//! them, so `Context` and `Stream` are `Send` and can be handed to a
+//! worker thread. libcubeb doesn't promise that calls on one context, or
+//! on one stream, are safe to make concurrently, though: some backends
+//! lock around them and some don't. So neither is `Sync`:
+//!
+//! ```compile_fail
+//! fn share<T: Sync>(_: &T) {}
+//!
+//! fn context(context: &cubeb::Context) {
+//!     share(context);
+//! }
+//! ```
+//!
+//! ```compile_fail
+//! fn share<T: Sync>(_: &T) {}
+//!
+//! fn stream<CB: cubeb::DuplexCallback>(stream: &cubeb::Stream<CB>) {
+//!     share(stream);
+//! }
+//! ```
+//!
+//! To call them from several threads at once, `SharedContext` and
+//! `SharedStream` take a lock around each call instead:
+//!
+//! ```no_run
+//! extern crate cubeb;
+//!
+//! use std::sync::Arc;
+//! use std::thread;
+//!
+//! fn share<T: Send + Sync>(_: &T) {}
+//!
+//! fn play<CB: cubeb::DuplexCallback>(stream: cubeb::Stream<CB>) {
+//!     let stream = Arc::new(cubeb::SharedStream::new(stream));
+//!     share(&stream);
+//!     let control = stream.clone();
+//!     thread::spawn(move || control.start());
+//!     let timeout = std::time::Duration::from_secs(1);
+//!     stream.wait_for_state(cubeb::State::Started, timeout).unwrap();
+//! }
+//! # fn main() {}
+//! ```
+
+use {Context, DuplexCallback, Result, State, Stream, StreamInitOptions};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant};
+use stream::SharedState;
+
+/// A `Context` that can be used from several threads at once, one call at
+/// a time.
+pub struct SharedContext {
+    context: Mutex<Context>
+}
+
+impl SharedContext {
+    pub fn new(context: Context) -> SharedContext {
+        SharedContext {
+            context: Mutex::new(context)
+        }
+    }
+
+    /// The context, locked until the guard is dropped.
+    ///
+    /// A panic while it was locked doesn't leave libcubeb in a bad state,
+    /// so the lock isn't poisoned.
+    pub fn lock(&self) -> MutexGuard<'_, Context> {
+        self.context.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub fn into_inner(self) -> Context {
+        self.context.into_inner().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// `Context::stream_init`, under the lock.
+    pub fn stream_init<CB>(
+        &self,
+        opts: &StreamInitOptions,
+        cb: CB
+    ) -> Result<Stream<CB>>
+    where
+        CB: DuplexCallback,
+    {
+        self.lock().stream_init(opts, cb)
+    }
+}
+
+/// A `Stream` that can be used from several threads at once.
+///
+/// Calls into libcubeb take turns, but the stream's state is tracked
+/// without the lock, so waiting for one doesn't hold up other threads.
+pub struct SharedStream<CB>
+where
+    CB: DuplexCallback,
+{
+    stream: Mutex<Stream<CB>>,
+    state: Arc<SharedState>
+}
+
+impl<CB> SharedStream<CB>
+where
+    CB: DuplexCallback,
+{
+    pub fn new(stream: Stream<CB>) -> SharedStream<CB> {
+        SharedStream {
+            state: stream.shared_state().clone(),
+            stream: Mutex::new(stream)
+        }
+    }
+
+    /// The stream, locked until the guard is dropped.
+    ///
+    /// As with `SharedContext::lock`, the lock isn't poisoned.
+    pub fn lock(&self) -> MutexGuard<'_, Stream<CB>> {
+        self.stream.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub fn into_inner(self) -> Stream<CB> {
+        self.stream.into_inner().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub fn start(&self) -> Result<()> {
+        self.lock().start()
+    }
+
+    pub fn stop(&self) -> Result<()> {
+        self.lock().stop()
+    }
+
+    pub fn reset_default_device(&self) -> Result<()> {
+        self.lock().reset_default_device()
+    }
+
+    pub fn position(&self) -> Result<u64> {
+        self.lock().position()
+    }
+
+    pub fn latency(&self) -> Result<u32> {
+        self.lock().latency()
+    }
+
+    pub fn set_volume(&self, volume: f32) -> Result<()> {
+        self.lock().set_volume(volume)
+    }
+
+    pub fn set_panning(&self, panning: f32) -> Result<()> {
+        self.lock().set_panning(panning)
+    }
+
+    /// As `Stream::state`, without taking the lock.
+    pub fn state(&self) -> State {
+        self.state.get()
+    }
+
+    /// As `Stream::wait_for_state`, without holding the lock.
+    pub fn wait_for_state(&self, state: State, timeout: Duration) -> Result<bool> {
+        self.state.wait(state, Some(Instant::now() + timeout))
+    }
+
+    /// As `Stream::drained`, holding the lock only to check the stream
+    /// was started.
+    pub fn drained(&self) -> Result<()> {
+        self.lock().check_drains()?;
+        self.state.wait(State::Drained, None).map(|_| ())
+    }
+}
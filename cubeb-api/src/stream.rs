@@ -14,6 +14,7 @@
 //!
 //! impl cubeb::StreamCallback for SquareWave {
 //!    type Frame = cubeb::MonoFrame<f32>;
+//!    type Message = f32;
 //!
 //!    fn data_callback(&mut self, _: &[cubeb::MonoFrame<f32>], output: &mut [cubeb::MonoFrame<f32>]) -> isize {
 //!        // Generate a square wave
@@ -30,6 +31,8 @@
 //!    }
 //!
 //!    fn state_callback(&mut self, state: cubeb::State) { println!("stream {:?}", state); }
+//!
+//!    fn handle_message(&mut self, volume: &mut f32) { self.volume = *volume; }
 //! }
 //!
 //! fn main() {
@@ -59,22 +62,34 @@
 //!     // Start playback
 //!     stream.start().unwrap();
 //!
-//!     // Play for 1/2 second
-//!     thread::sleep(Duration::from_millis(500));
+//!     // Play for 1/2 second, getting quieter halfway through
+//!     thread::sleep(Duration::from_millis(250));
+//!     stream.send(0.1).unwrap();
+//!     thread::sleep(Duration::from_millis(250));
 //!
 //!     // Shutdown
 //!     stream.stop().unwrap();
 //! }
 //! ```
 
-use {Binding, ChannelLayout, Context, Device, DeviceId, Error, Result,
-     SampleFormat, State, StreamParams};
+use {Binding, ChannelLayout, Context, Device, DeviceId, Error, ErrorCode, Frame, Frames,
+     Result, SampleFormat, State, StreamParams};
+use context::NativeContext;
 use ffi;
-use std::ptr;
+use queue::{self, Receiver, Sender};
+use std::{cmp, mem, ptr, result};
+use std::marker::PhantomData;
+use std::cell::{Cell, RefCell};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant};
 use std::ffi::CString;
 use std::os::raw::{c_long, c_void};
 use sys;
 use util::IntoCString;
+use adapt::{Adapter, sample_kind};
+use call::describe_params;
+#[cfg(feature = "async")]
+use async_io::{DeviceChanges, StateEvents, StreamEvents, WaitForState};
 
 /// An extension trait which allows the implementation of converting
 /// void* buffers from libcubeb-sys into rust slices of the appropriate
@@ -104,13 +119,460 @@ impl SampleType for f32 {
     }
 }
 
+/// Number of messages sent with `Stream::send` that can wait for the
+/// callback at once.
+pub const MESSAGE_QUEUE_LEN: usize = 64;
+
 pub trait StreamCallback: Send + 'static
 {
-    type Frame;
-
+    type Frame: Frame;
+    /// Messages `Stream::send` delivers to `handle_message`. Callbacks
+    /// that take none use `()`.
+    type Message: Send;
+
+    /// Fill `output` from `input` and return the number of frames
+    /// handled. Returning fewer than asked for drains the stream: the
+    /// backend plays what was returned, then signals `State::Drained`
+    /// and stops calling back.
     // This should return a Result<usize,Error>
     fn data_callback(&mut self, &[Self::Frame], &mut [Self::Frame]) -> isize;
     fn state_callback(&mut self, state: State);
+
+    /// Handle a message sent with `Stream::send`. Called on the audio
+    /// thread before `data_callback`.
+    ///
+    /// `msg` goes back to the sending thread afterwards, so anything
+    /// swapped into it is freed there rather than on the audio thread.
+    fn handle_message(&mut self, _msg: &mut Self::Message) {}
+}
+
+/// Callback for a duplex stream whose input and output frames differ,
+/// such as a mono 16 bit microphone feeding stereo float output.
+///
+/// Every `StreamCallback` is a `DuplexCallback` with the same frame type
+/// on both sides.
+pub trait DuplexCallback: Send + 'static {
+    type InputFrame: Frame;
+    type OutputFrame: Frame;
+    type Message: Send;
+
+    fn data_callback(
+        &mut self,
+        input: &[Self::InputFrame],
+        output: &mut [Self::OutputFrame],
+    ) -> isize;
+    fn state_callback(&mut self, state: State);
+    fn handle_message(&mut self, _msg: &mut Self::Message) {}
+}
+
+impl<CB> DuplexCallback for CB
+where
+    CB: StreamCallback,
+{
+    type InputFrame = CB::Frame;
+    type OutputFrame = CB::Frame;
+    type Message = CB::Message;
+
+    fn data_callback(&mut self, input: &[CB::Frame], output: &mut [CB::Frame]) -> isize {
+        StreamCallback::data_callback(self, input, output)
+    }
+
+    fn state_callback(&mut self, state: State) {
+        StreamCallback::state_callback(self, state)
+    }
+
+    fn handle_message(&mut self, msg: &mut CB::Message) {
+        StreamCallback::handle_message(self, msg)
+    }
+}
+
+/// Callback for an input only stream, run with `Input`.
+pub trait InputCallback: Send + 'static {
+    type Frame: Frame;
+    type Message: Send;
+
+    /// Consume `input`. Returning less than `input.len()` stops the
+    /// stream.
+    fn data_callback(&mut self, input: &[Self::Frame]) -> isize;
+    fn state_callback(&mut self, state: State);
+    fn handle_message(&mut self, _msg: &mut Self::Message) {}
+}
+
+/// Callback for an output only stream, run with `Output`.
+pub trait OutputCallback: Send + 'static {
+    type Frame: Frame;
+    type Message: Send;
+
+    /// Fill `output`. Returning less than `output.len()` drains the
+    /// stream.
+    fn data_callback(&mut self, output: &mut [Self::Frame]) -> isize;
+    fn state_callback(&mut self, state: State);
+    fn handle_message(&mut self, _msg: &mut Self::Message) {}
+}
+
+/// Runs an `InputCallback` as a stream's callback, as in
+/// `context.stream_init(&opts, Input(cb))`.
+pub struct Input<CB>(pub CB);
+
+impl<CB> DuplexCallback for Input<CB>
+where
+    CB: InputCallback,
+{
+    type InputFrame = CB::Frame;
+    type OutputFrame = ();
+    type Message = CB::Message;
+
+    fn data_callback(&mut self, input: &[CB::Frame], _: &mut [()]) -> isize {
+        self.0.data_callback(input)
+    }
+
+    fn state_callback(&mut self, state: State) {
+        self.0.state_callback(state)
+    }
+
+    fn handle_message(&mut self, msg: &mut CB::Message) {
+        self.0.handle_message(msg)
+    }
+}
+
+/// Runs an `OutputCallback` as a stream's callback, as in
+/// `context.stream_init(&opts, Output(cb))`.
+pub struct Output<CB>(pub CB);
+
+impl<CB> DuplexCallback for Output<CB>
+where
+    CB: OutputCallback,
+{
+    type InputFrame = ();
+    type OutputFrame = CB::Frame;
+    type Message = CB::Message;
+
+    fn data_callback(&mut self, _: &[()], output: &mut [CB::Frame]) -> isize {
+        self.0.data_callback(output)
+    }
+
+    fn state_callback(&mut self, state: State) {
+        self.0.state_callback(state)
+    }
+
+    fn handle_message(&mut self, msg: &mut CB::Message) {
+        self.0.handle_message(msg)
+    }
+}
+
+/// A `StreamCallback` made of a data closure and, optionally, a state
+/// closure. It takes no messages.
+///
+/// The frame type is inferred from the data closure's signature:
+///
+/// ```
+/// use cubeb::{CallbackFn, MonoFrame, StreamCallback};
+///
+/// let mut cb = CallbackFn::new(|_: &[MonoFrame<f32>], output: &mut [MonoFrame<f32>]| {
+///     for f in output.iter_mut() {
+///         f.m = 0.0;
+///     }
+///     output.len() as isize
+/// }).state(|state| println!("stream {:?}", state));
+/// # let mut output = [MonoFrame { m: 1.0 }];
+/// # assert_eq!(cb.data_callback(&[], &mut output), 1);
+/// ```
+pub struct CallbackFn<F, D, S = fn(State)> {
+    data: D,
+    state: S,
+    frame: PhantomData<fn(&[F])>
+}
+
+impl<F, D> CallbackFn<F, D>
+where
+    D: FnMut(&[F], &mut [F]) -> isize,
+{
+    pub fn new(data: D) -> Self {
+        fn ignore(_: State) {}
+        CallbackFn {
+            data,
+            state: ignore,
+            frame: PhantomData
+        }
+    }
+}
+
+impl<F, D, S> CallbackFn<F, D, S> {
+    /// Call `state` from `state_callback`.
+    pub fn state<T>(self, state: T) -> CallbackFn<F, D, T>
+    where
+        T: FnMut(State),
+    {
+        CallbackFn {
+            data: self.data,
+            state,
+            frame: PhantomData
+        }
+    }
+}
+
+impl<F, D, S> StreamCallback for CallbackFn<F, D, S>
+where
+    F: Frame + 'static,
+    D: FnMut(&[F], &mut [F]) -> isize + Send + 'static,
+    S: FnMut(State) + Send + 'static,
+{
+    type Frame = F;
+    type Message = ();
+
+    fn data_callback(&mut self, input: &[F], output: &mut [F]) -> isize {
+        (self.data)(input, output)
+    }
+
+    fn state_callback(&mut self, state: State) {
+        (self.state)(state)
+    }
+}
+
+pub(crate) fn frame_size(params: &StreamParams) -> usize {
+    let sample_size = match params.format() {
+        SampleFormat::S16LE | SampleFormat::S16BE | SampleFormat::S16NE => 2,
+        SampleFormat::Float32LE | SampleFormat::Float32BE | SampleFormat::Float32NE => 4,
+    };
+    params.channels() as usize * sample_size
+}
+
+// What position and latency count in.
+fn stream_rate(opts: &StreamInitOptions) -> u32 {
+    opts.output_stream_params
+        .or(opts.input_stream_params)
+        .map_or(0, |params| params.rate())
+}
+
+// `opts`, as a failed `cubeb_stream_init` describes them.
+fn describe_options(opts: &StreamInitOptions) -> String {
+    let sides = [("input", &opts.input_stream_params), ("output", &opts.output_stream_params)];
+    let mut parts: Vec<String> = sides
+        .iter()
+        .filter_map(|&(side, params)| {
+            params.as_ref().map(|p| format!("{} {}", side, describe_params(p)))
+        })
+        .collect();
+    parts.push(format!("latency {} frames", opts.latency_frames));
+    parts.join(", ")
+}
+
+// The callback's buffers are handed to it as slices of `I` and `O`, so
+// its frames must be in the format, and have the channels, `opts`
+// describe for the application side. A side without frames, `()`, can't
+// have params.
+fn check_frames<I: Frame, O: Frame>(opts: &StreamInitOptions) -> Result<()> {
+    let sides = [
+        (opts.input_app_params.or(opts.input_stream_params), frame_info::<I>()),
+        (opts.output_app_params.or(opts.output_stream_params), frame_info::<O>()),
+    ];
+    for &(params, (format, channels, size)) in &sides {
+        if let Some(params) = params {
+            let same_format = format.map(sample_kind) == Some(sample_kind(params.format()));
+            if !same_format || channels != params.channels() || frame_size(&params) != size {
+                return Err(Error::from(ErrorCode::InvalidFormat));
+            }
+        }
+    }
+    Ok(())
+}
+
+fn frame_info<F: Frame>() -> (Option<SampleFormat>, u32, usize) {
+    (F::format(), F::channels(), mem::size_of::<F>())
+}
+
+// States are stored as `State as usize`.
+pub(crate) fn state_from_index(index: usize) -> State {
+    match index {
+        0 => State::Started,
+        1 => State::Stopped,
+        2 => State::Drained,
+        _ => State::Error,
+    }
+}
+
+// What a stream has signaled.
+struct Signaled {
+    // The state last delivered to the state callback.
+    last: State,
+    // `Drained` or `Error` if the stream ended so since it was last
+    // started. Cleared by `start` rather than by a signal, as backends
+    // may signal `Started` after `start` returns.
+    ended: Option<State>
+}
+
+impl Signaled {
+    // Whether the stream is in `state`, or ended in it since it was last
+    // started. Fails if it ended in `Error` instead.
+    fn reached(&self, state: State) -> Result<bool> {
+        let reached = match state {
+            State::Drained | State::Error => self.ended == Some(state),
+            _ => self.last == state,
+        };
+        if !reached && self.ended == Some(State::Error) {
+            return Err(Error::new());
+        }
+        Ok(reached)
+    }
+}
+
+// What a stream has signaled, shared between the `Stream` and its state
+// callback.
+pub(crate) struct SharedState {
+    signaled: Mutex<Signaled>,
+    // Notified whenever `signaled` changes.
+    changed: Condvar,
+    // Held while the state callback runs, and while
+    // `with_callback_stopped` lends the callback out.
+    delivery: Mutex<()>,
+    #[cfg(feature = "async")]
+    pub(crate) events: StreamEvents
+}
+
+impl SharedState {
+    pub(crate) fn new() -> Arc<SharedState> {
+        Arc::new(SharedState {
+            signaled: Mutex::new(Signaled {
+                last: State::Stopped,
+                ended: None
+            }),
+            changed: Condvar::new(),
+            delivery: Mutex::new(()),
+            #[cfg(feature = "async")]
+            events: StreamEvents::new()
+        })
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Signaled> {
+        self.signaled.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub(crate) fn get(&self) -> State {
+        self.lock().last
+    }
+
+    pub(crate) fn reached(&self, state: State) -> Result<bool> {
+        self.lock().reached(state)
+    }
+
+    // Forget how the stream last ended, returning it.
+    fn clear_ended(&self) -> Option<State> {
+        let ended = self.lock().ended.take();
+        self.changed.notify_all();
+        ended
+    }
+
+    // Put back what `clear_ended` took, unless the stream ended again
+    // meanwhile.
+    fn restore_ended(&self, ended: Option<State>) {
+        let mut signaled = self.lock();
+        if signaled.ended.is_none() {
+            signaled.ended = ended;
+        }
+        drop(signaled);
+        self.changed.notify_all();
+    }
+
+    // Wait until `deadline`, if any, for the stream to reach `state`.
+    pub(crate) fn wait(&self, state: State, deadline: Option<Instant>) -> Result<bool> {
+        let mut signaled = self.lock();
+        loop {
+            if signaled.reached(state)? {
+                return Ok(true);
+            }
+            signaled = match deadline {
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return Ok(false);
+                    }
+                    self.changed
+                        .wait_timeout(signaled, deadline - now)
+                        .unwrap_or_else(PoisonError::into_inner)
+                        .0
+                },
+                None => self.changed.wait(signaled).unwrap_or_else(PoisonError::into_inner),
+            };
+        }
+    }
+
+    // Record a state the stream signaled.
+    pub(crate) fn signal(&self, state: State) {
+        {
+            let mut signaled = self.lock();
+            signaled.last = state;
+            match state {
+                State::Drained | State::Error => signaled.ended = Some(state),
+                _ => {},
+            }
+        }
+        self.changed.notify_all();
+        #[cfg(feature = "async")]
+        self.events.state(state);
+    }
+}
+
+// What the native stream's callbacks reach through their user pointer.
+struct Callback<CB>
+where
+    CB: DuplexCallback,
+{
+    cb: CB,
+    messages: Receiver<CB::Message>,
+    handled: Sender<CB::Message>,
+    state: Arc<SharedState>,
+    // Set if the callback's format differs from the device's.
+    adapter: Option<Adapter>
+}
+
+impl<CB> Callback<CB>
+where
+    CB: DuplexCallback,
+{
+    fn new(cb: CB) -> (Self, Sender<CB::Message>, Receiver<CB::Message>) {
+        let (send, messages) = queue::queue(MESSAGE_QUEUE_LEN);
+        let (handled, reclaim) = queue::queue(MESSAGE_QUEUE_LEN);
+        (
+            Callback {
+                cb,
+                messages,
+                handled,
+                state: SharedState::new(),
+                adapter: None
+            },
+            send,
+            reclaim
+        )
+    }
+
+    fn handle_messages(&mut self) {
+        // Only take messages there's room to hand back, so none are
+        // dropped on the audio thread.
+        while self.handled.has_room() {
+            match self.messages.pop() {
+                Some(mut msg) => {
+                    self.cb.handle_message(&mut msg);
+                    let _ = self.handled.push(msg);
+                },
+                None => break,
+            }
+        }
+    }
+}
+
+// The layout of `channels` channels, where only one layout has that many
+// or, for two, stereo. Counts several layouts share, such as three for
+// `Layout3F` and `Layout2F1`, are left `Undefined`, and their channels
+// are then taken in order.
+pub(crate) fn layout_for(channels: u32) -> ChannelLayout {
+    match channels {
+        1 => ChannelLayout::Mono,
+        2 => ChannelLayout::Stereo,
+        6 => ChannelLayout::Layout3F2Lfe,
+        7 => ChannelLayout::Layout3F3RLfe,
+        8 => ChannelLayout::Layout3F4Lfe,
+        _ => ChannelLayout::Undefined,
+    }
 }
 
 ///
@@ -157,6 +619,34 @@ impl StreamParamsBuilder {
         self
     }
 
+    /// Like `take`, but checks the parameters first.
+    ///
+    /// Without `channels`, the count is taken from `layout`. Without
+    /// `layout`, it's taken from `channels` where only one layout has that
+    /// many, with two channels taken to be stereo. Fails with
+    /// `InvalidFormat`, as libcubeb would later, if the rate is zero, the
+    /// channel count is zero and can't be worked out, or it contradicts the
+    /// layout.
+    pub fn build(&self) -> Result<StreamParams> {
+        let mut channels = self.channels;
+        let mut layout = self.layout;
+        if channels == 0 {
+            channels = layout.channel_count();
+        } else if layout == ChannelLayout::Undefined {
+            layout = layout_for(channels);
+        }
+        if self.rate == 0 || channels == 0 ||
+            (layout != ChannelLayout::Undefined && layout.channel_count() != channels)
+        {
+            return Err(Error::from(ErrorCode::InvalidFormat));
+        }
+        Ok(StreamParamsBuilder {
+            channels,
+            layout,
+            ..*self
+        }.take())
+    }
+
     #[cfg_attr(feature = "cargo-clippy", allow(match_same_arms))]
     pub fn take(&self) -> StreamParams {
         // Convert native endian types to matching format
@@ -182,20 +672,36 @@ impl StreamParamsBuilder {
 ///
 pub struct Stream<CB>
 where
-    CB: StreamCallback,
+    CB: DuplexCallback,
 {
     raw: *mut ffi::cubeb_stream,
-    cbs: Box<CB>
+    // The context `raw` was made from, kept alive until `raw` is
+    // destroyed.
+    context: Arc<NativeContext>,
+    // Taken by `into_callback`, after destroying `raw`.
+    cbs: Option<Box<Callback<CB>>>,
+    messages: RefCell<Sender<CB::Message>>,
+    handled: RefCell<Receiver<CB::Message>>,
+    // Whether `start` was called more recently than `stop`.
+    started: Cell<bool>,
+    state: Arc<SharedState>,
+    // Of the output, or the input if there's no output: what position
+    // and latency count in.
+    rate: u32
 }
 
 impl<CB> Stream<CB>
 where
-    CB: StreamCallback,
+    CB: DuplexCallback,
 {
     fn init(context: &Context, opts: &StreamInitOptions, cb: CB) -> Result<Stream<CB>> {
+        check_frames::<CB::InputFrame, CB::OutputFrame>(opts)?;
+
         let mut stream: *mut ffi::cubeb_stream = ptr::null_mut();
 
-        let cbs = Box::new(cb);
+        let (mut cbs, messages, handled) = Callback::new(cb);
+        cbs.adapter = Adapter::new(opts)?;
+        let cbs = Box::new(cbs);
 
         unsafe {
             let input_stream_params = opts.input_stream_params
@@ -210,7 +716,7 @@ where
 
             let user_ptr: *mut c_void = &*cbs as *const _ as *mut _;
 
-            try_call!(sys::cubeb_stream_init(
+            try_call!(in context.raw(), sys::cubeb_stream_init(
                 context.raw(),
                 &mut stream,
                 opts.stream_name,
@@ -222,34 +728,142 @@ where
                 Stream::<CB>::data_cb_c,
                 Stream::<CB>::state_cb_c,
                 user_ptr
-            ));
+            ), describe_options(opts));
         }
 
         Ok(Stream {
             raw: stream,
-            cbs: cbs
+            context: context.native(),
+            state: cbs.state.clone(),
+            cbs: Some(cbs),
+            messages: RefCell::new(messages),
+            handled: RefCell::new(handled),
+            started: Cell::new(false),
+            rate: stream_rate(opts)
         })
     }
 
+    /// Stop and destroy the stream and hand back its callback.
+    pub fn into_callback(mut self) -> CB {
+        let _ = self.stop();
+        self.destroy();
+        self.cbs.take().unwrap().cb
+    }
+
+    // Destroy the native stream, if not yet destroyed.
+    fn destroy(&mut self) {
+        if self.raw.is_null() {
+            return;
+        }
+        #[cfg(feature = "async")]
+        let _registered = self.state.events.destroying();
+        unsafe {
+            sys::cubeb_stream_destroy(self.raw);
+        }
+        self.raw = ptr::null_mut();
+    }
+
+    /// Stop the stream, call `f` with its callback, then restart it if
+    /// it had been started.
+    ///
+    /// Relies on libcubeb not calling `data_callback` once
+    /// `cubeb_stream_stop` returns. `state_callback` can still be called
+    /// after that, from another thread: it waits for `f` to return.
+    /// `f` mustn't wait for a state the stream signals, as it would never
+    /// see it.
+    pub fn with_callback_stopped<F, R>(&mut self, f: F) -> Result<R>
+    where
+        F: FnOnce(&mut CB) -> R,
+    {
+        let started = self.started.get();
+        self.stop()?;
+        let r = {
+            let _delivery = self.state.delivery.lock().unwrap_or_else(PoisonError::into_inner);
+            f(&mut self.cbs.as_mut().unwrap().cb)
+        };
+        if started {
+            self.start()?;
+        }
+        Ok(r)
+    }
+
+    /// Queue `msg` for the callback's `handle_message` without blocking.
+    /// `msg` is handed back if `MESSAGE_QUEUE_LEN` messages are already
+    /// waiting.
+    ///
+    /// Messages the callback has handled are dropped here, unless taken
+    /// with `reclaim` first.
+    pub fn send(&self, msg: CB::Message) -> result::Result<(), CB::Message> {
+        let mut handled = self.handled.borrow_mut();
+        while handled.pop().is_some() {}
+        self.messages.borrow_mut().push(msg)
+    }
+
+    /// Take back a message the callback has handled, to reuse it.
+    pub fn reclaim(&self) -> Option<CB::Message> {
+        self.handled.borrow_mut().pop()
+    }
+
+    /// The state the stream last signaled, `Stopped` until it signals
+    /// one.
+    pub fn state(&self) -> State {
+        self.state.get()
+    }
+
+    /// Wait up to `timeout` for the stream to signal `state`. Returns
+    /// false if it didn't in time, and fails if the stream signals
+    /// `Error` instead. `Drained` and `Error` count if signaled since
+    /// the stream was last started.
+    pub fn wait_for_state(&self, state: State, timeout: Duration) -> Result<bool> {
+        self.state.wait(state, Some(Instant::now() + timeout))
+    }
+
+    /// Wait for the stream to drain, which it does once `data_callback`
+    /// returns fewer frames than asked for and the backend has played
+    /// them. Fails if the stream isn't started, as it then never drains.
+    pub fn drained(&self) -> Result<()> {
+        self.check_drains()?;
+        self.state.wait(State::Drained, None).map(|_| ())
+    }
+
+    // Fails if the stream will never drain.
+    pub(crate) fn check_drains(&self) -> Result<()> {
+        if !self.started.get() && !self.state.reached(State::Drained)? {
+            return Err(Error::new());
+        }
+        Ok(())
+    }
+
+    pub(crate) fn shared_state(&self) -> &Arc<SharedState> {
+        &self.state
+    }
+
     // start playback.
     pub fn start(&self) -> Result<()> {
-        unsafe {
-            try_call!(sys::cubeb_stream_start(self.raw));
+        // Forget how an earlier run ended before the backend can end this
+        // one.
+        let ended = self.state.clear_ended();
+        let started = unsafe { call!(sys::cubeb_stream_start(self.raw)) };
+        if let Err(e) = ::call::try(started, "cubeb_stream_start", self.context.raw(), || None) {
+            self.state.restore_ended(ended);
+            return Err(e);
         }
+        self.started.set(true);
         Ok(())
     }
 
     // Stop playback.
     pub fn stop(&self) -> Result<()> {
         unsafe {
-            try_call!(sys::cubeb_stream_stop(self.raw));
+            try_call!(in self.context.raw(), sys::cubeb_stream_stop(self.raw));
         }
+        self.started.set(false);
         Ok(())
     }
 
     pub fn reset_default_device(&self) -> Result<()> {
         unsafe {
-            try_call!(sys::cubeb_stream_reset_default_device(self.raw));
+            try_call!(in self.context.raw(), sys::cubeb_stream_reset_default_device(self.raw));
         }
         Ok(())
     }
@@ -258,7 +872,7 @@ where
     pub fn position(&self) -> Result<u64> {
         let mut position: u64 = 0;
         unsafe {
-            try_call!(sys::cubeb_stream_get_position(self.raw, &mut position));
+            try_call!(in self.context.raw(), sys::cubeb_stream_get_position(self.raw, &mut position));
         }
         Ok(position)
     }
@@ -266,21 +880,34 @@ where
     pub fn latency(&self) -> Result<u32> {
         let mut latency: u32 = 0;
         unsafe {
-            try_call!(sys::cubeb_stream_get_latency(self.raw, &mut latency));
+            try_call!(in self.context.raw(), sys::cubeb_stream_get_latency(self.raw, &mut latency));
         }
         Ok(latency)
     }
 
+    /// `position` at the stream's rate.
+    pub fn position_frames(&self) -> Result<Frames> {
+        self.position().map(|position| Frames::new(position, self.rate))
+    }
+
+    /// `latency` at the stream's rate.
+    pub fn latency_frames(&self) -> Result<Frames> {
+        self.latency()
+            .map(|latency| Frames::new(u64::from(latency), self.rate))
+    }
+
     pub fn set_volume(&self, volume: f32) -> Result<()> {
         unsafe {
-            try_call!(sys::cubeb_stream_set_volume(self.raw, volume));
+            try_call!(in self.context.raw(), sys::cubeb_stream_set_volume(self.raw, volume),
+                      format!("volume {}", volume));
         }
         Ok(())
     }
 
     pub fn set_panning(&self, panning: f32) -> Result<()> {
         unsafe {
-            try_call!(sys::cubeb_stream_set_panning(self.raw, panning));
+            try_call!(in self.context.raw(), sys::cubeb_stream_set_panning(self.raw, panning),
+                      format!("panning {}", panning));
         }
         Ok(())
     }
@@ -288,7 +915,7 @@ where
     pub fn current_device(&self) -> Result<Device> {
         let mut device_ptr: *const ffi::cubeb_device = ptr::null();
         unsafe {
-            try_call!(sys::cubeb_stream_get_current_device(
+            try_call!(in self.context.raw(), sys::cubeb_stream_get_current_device(
                 self.raw,
                 &mut device_ptr
             ));
@@ -298,7 +925,7 @@ where
 
     pub fn destroy_device(&self, device: Device) -> Result<()> {
         unsafe {
-            try_call!(sys::cubeb_stream_device_destroy(self.raw, device.raw()));
+            try_call!(in self.context.raw(), sys::cubeb_stream_device_destroy(self.raw, device.raw()));
         }
         Ok(())
     }
@@ -318,24 +945,46 @@ where
         output_buffer: *mut c_void,
         nframes: c_long,
     ) -> c_long {
-        use std::slice::{from_raw_parts, from_raw_parts_mut};
-
         unsafe {
-            let cbs = &mut *(user_ptr as *mut CB);
-            let input: &[CB::Frame] = if input_buffer.is_null() {
-                &[]
-            } else {
-                from_raw_parts(input_buffer as *const _, nframes as usize)
-            };
-            let output: &mut [CB::Frame] = if output_buffer.is_null() {
-                &mut []
-            } else {
-                from_raw_parts_mut(output_buffer as *mut _, nframes as usize)
-            };
-            cbs.data_callback(input, output) as c_long
+            let cbs = &mut *(user_ptr as *mut Callback<CB>);
+            cbs.handle_messages();
+            let Callback {
+                ref mut cb,
+                ref mut adapter,
+                ..
+            } = *cbs;
+            let nframes = nframes as usize;
+            match *adapter {
+                Some(ref mut adapter) => adapter.process(
+                    input_buffer as *const u8,
+                    output_buffer as *mut u8,
+                    nframes,
+                    |input, output, frames| Stream::<CB>::run(cb, input, output, frames)
+                ) as c_long,
+                None => Stream::<CB>::run(cb, input_buffer as *const u8, output_buffer as *mut u8,
+                                          nframes) as c_long,
+            }
         }
     }
 
+    // Call the callback with `frames` frames at `input` and `output`, or
+    // none for a null side.
+    unsafe fn run(cb: &mut CB, input: *const u8, output: *mut u8, frames: usize) -> isize {
+        use std::slice::{from_raw_parts, from_raw_parts_mut};
+
+        let input: &[CB::InputFrame] = if input.is_null() {
+            &[]
+        } else {
+            from_raw_parts(input as *const _, frames)
+        };
+        let output: &mut [CB::OutputFrame] = if output.is_null() {
+            &mut []
+        } else {
+            from_raw_parts_mut(output as *mut _, frames)
+        };
+        cb.data_callback(input, output)
+    }
+
     extern "C" fn state_cb_c(_: *mut ffi::cubeb_stream, user_ptr: *mut c_void, state: ffi::cubeb_state) {
         let state = match state {
             ffi::CUBEB_STATE_STARTED => State::Started,
@@ -345,27 +994,188 @@ where
             n => panic!("unknown state: {}", n),
         };
         unsafe {
-            let cbs = &mut *(user_ptr as *mut CB);
-            cbs.state_callback(state);
+            // Only the fields are borrowed, as `with_callback_stopped` may
+            // be lending out `cb` until `delivery` is ours.
+            let cbs = user_ptr as *mut Callback<CB>;
+            let shared = &(*cbs).state;
+            let _delivery = shared.delivery.lock().unwrap_or_else(PoisonError::into_inner);
+            let cb = &mut (*cbs).cb;
+            cb.state_callback(state);
+            shared.signal(state);
         };
     }
 }
 
+// `Stream` is `Send` because no `cubeb_stream_*` call it makes depends
+// on the calling thread, and the callback and message queues are `Send`
+// already:
+//
+// - `cubeb_stream_start`, `cubeb_stream_stop`,
+//   `cubeb_stream_reset_default_device`, `cubeb_stream_get_position`,
+//   `cubeb_stream_get_latency`, `cubeb_stream_set_volume`,
+//   `cubeb_stream_set_panning`, `cubeb_stream_get_current_device`,
+//   `cubeb_stream_device_destroy` and
+//   `cubeb_stream_register_device_changed_callback` may be called from
+//   any thread, one at a time per stream. libcubeb allows them alongside
+//   calls on the stream's context from other threads.
+// - `cubeb_stream_destroy` may be called from any thread but a callback
+//   thread, and before `cubeb_destroy`; `context` sees to the latter.
+//
+// None of these calls is safe concurrently with another on the same
+// stream, so `Stream` isn't `Sync`; see `SharedStream`.
+unsafe impl<CB> Send for Stream<CB>
+where
+    CB: DuplexCallback,
+{
+}
+
 impl<CB> Drop for Stream<CB>
 where
-    CB: StreamCallback,
+    CB: DuplexCallback,
 {
     fn drop(&mut self) {
+        self.destroy();
+        #[cfg(feature = "async")]
+        self.state.events.close();
+    }
+}
+
+#[cfg(feature = "async")]
+impl<CB> Stream<CB>
+where
+    CB: DuplexCallback,
+{
+    /// The states the stream signals from now on, as a
+    /// `futures_core::Stream`.
+    pub fn state_events(&self) -> Result<StateEvents> {
+        StateEvents::new(self.state.clone())
+    }
+
+    /// The stream's device changes from now on, as a
+    /// `futures_core::Stream`. Fails while another exists.
+    pub fn device_changes(&self) -> Result<DeviceChanges> {
+        let callback: ffi::cubeb_device_changed_callback = Stream::<CB>::device_changed_cb_c;
+        DeviceChanges::new(self.state.clone(), self.context.raw(), self.raw, callback)
+    }
+
+    /// A future for the stream signaling `state`, as `wait_for_state`
+    /// without a timeout.
+    pub fn wait_for_state_async(&self, state: State) -> Result<WaitForState> {
+        WaitForState::new(self.state.clone(), state)
+    }
+
+    /// A future for the stream draining, as `drained`.
+    pub fn drained_async(&self) -> Result<WaitForState> {
+        self.check_drains()?;
+        WaitForState::new(self.state.clone(), State::Drained)
+    }
+
+    extern "C" fn device_changed_cb_c(user_ptr: *mut c_void) {
         unsafe {
-            sys::cubeb_stream_destroy(self.raw);
+            let cbs = &*(user_ptr as *const Callback<CB>);
+            cbs.state.events.device_changed();
         }
     }
 }
 
+/// Control of a stream that doesn't depend on its callback's type, so
+/// streams with different callbacks can be kept together as `DynStream`s.
+pub trait StreamControl {
+    fn start(&self) -> Result<()>;
+    fn stop(&self) -> Result<()>;
+    fn reset_default_device(&self) -> Result<()>;
+    fn position(&self) -> Result<u64>;
+    fn latency(&self) -> Result<u32>;
+    fn set_volume(&self, volume: f32) -> Result<()>;
+    fn set_panning(&self, panning: f32) -> Result<()>;
+    fn current_device(&self) -> Result<Device<'_>>;
+    fn destroy_device(&self, device: Device) -> Result<()>;
+    fn state(&self) -> State;
+    fn wait_for_state(&self, state: State, timeout: Duration) -> Result<bool>;
+    fn drained(&self) -> Result<()>;
+}
+
+/// A stream of any callback type.
+pub type DynStream = Box<dyn StreamControl + Send>;
+
+impl<CB> StreamControl for Stream<CB>
+where
+    CB: DuplexCallback,
+{
+    fn start(&self) -> Result<()> {
+        Stream::start(self)
+    }
+
+    fn stop(&self) -> Result<()> {
+        Stream::stop(self)
+    }
+
+    fn reset_default_device(&self) -> Result<()> {
+        Stream::reset_default_device(self)
+    }
+
+    fn position(&self) -> Result<u64> {
+        Stream::position(self)
+    }
+
+    fn latency(&self) -> Result<u32> {
+        Stream::latency(self)
+    }
+
+    fn set_volume(&self, volume: f32) -> Result<()> {
+        Stream::set_volume(self, volume)
+    }
+
+    fn set_panning(&self, panning: f32) -> Result<()> {
+        Stream::set_panning(self, panning)
+    }
+
+    fn current_device(&self) -> Result<Device<'_>> {
+        Stream::current_device(self)
+    }
+
+    fn destroy_device(&self, device: Device) -> Result<()> {
+        Stream::destroy_device(self, device)
+    }
+
+    fn state(&self) -> State {
+        Stream::state(self)
+    }
+
+    fn wait_for_state(&self, state: State, timeout: Duration) -> Result<bool> {
+        Stream::wait_for_state(self, state, timeout)
+    }
+
+    fn drained(&self) -> Result<()> {
+        Stream::drained(self)
+    }
+}
+
+/// Boxed callbacks, for choosing a callback at run time.
+impl<CB> StreamCallback for Box<CB>
+where
+    CB: StreamCallback + ?Sized,
+{
+    type Frame = CB::Frame;
+    type Message = CB::Message;
+
+    fn data_callback(&mut self, input: &[Self::Frame], output: &mut [Self::Frame]) -> isize {
+        (**self).data_callback(input, output)
+    }
+
+    fn state_callback(&mut self, state: State) {
+        (**self).state_callback(state)
+    }
+
+    fn handle_message(&mut self, msg: &mut Self::Message) {
+        (**self).handle_message(msg)
+    }
+}
+
 #[doc(hidden)]
 pub fn stream_init<CB>(context: &Context, opts: &StreamInitOptions, cb: CB) -> Result<Stream<CB>>
 where
-    CB: StreamCallback,
+    CB: DuplexCallback,
 {
     Stream::init(context, opts, cb)
 }
@@ -376,7 +1186,11 @@ pub struct StreamInitOptions {
     pub input_stream_params: Option<StreamParams>,
     pub output_device: DeviceId,
     pub output_stream_params: Option<StreamParams>,
-    pub latency_frames: u32
+    pub latency_frames: u32,
+    /// What the callback works in, if not the device's params. Audio is
+    /// converted, remixed and resampled between the two.
+    pub input_app_params: Option<StreamParams>,
+    pub output_app_params: Option<StreamParams>
 }
 
 impl StreamInitOptions {
@@ -387,7 +1201,9 @@ impl StreamInitOptions {
             input_stream_params: None,
             output_device: DeviceId::default(),
             output_stream_params: None,
-            latency_frames: 0
+            latency_frames: 0,
+            input_app_params: None,
+            output_app_params: None
         }
     }
 }
@@ -400,7 +1216,9 @@ impl Default for StreamInitOptions {
 
 /// Structure describing options about how stream should be initialized.
 pub struct StreamInitOptionsBuilder {
-    opts: StreamInitOptions
+    opts: StreamInitOptions,
+    // Set by `latency_duration`, converted once the rate is known.
+    latency: Option<Duration>
 }
 
 impl Default for StreamInitOptionsBuilder {
@@ -412,7 +1230,8 @@ impl Default for StreamInitOptionsBuilder {
 impl StreamInitOptionsBuilder {
     pub fn new() -> Self {
         StreamInitOptionsBuilder {
-            opts: Default::default()
+            opts: Default::default(),
+            latency: None
         }
     }
 
@@ -444,21 +1263,641 @@ impl StreamInitOptionsBuilder {
         self
     }
 
+    /// Have the callback take input in `param` rather than the input
+    /// device's format.
+    pub fn input_app_param(&mut self, param: &StreamParams) -> &mut Self {
+        self.opts.input_app_params = Some(*param);
+        self
+    }
+
+    /// Have the callback render output in `param` rather than the output
+    /// device's format.
+    pub fn output_app_param(&mut self, param: &StreamParams) -> &mut Self {
+        self.opts.output_app_params = Some(*param);
+        self
+    }
+
     pub fn latency(&mut self, latency: u32) -> &mut Self {
         self.opts.latency_frames = latency;
+        self.latency = None;
         self
     }
 
+    /// Set the latency as a duration, converted to frames at the output
+    /// stream's rate, or the input's if there's no output.
+    pub fn latency_duration(&mut self, latency: Duration) -> &mut Self {
+        self.latency = Some(latency);
+        self
+    }
+
+    /// Set the latency, converted like `latency_duration` if it's at
+    /// another rate than the stream's.
+    pub fn latency_frames(&mut self, latency: Frames) -> &mut Self {
+        self.latency_duration(latency.to_duration())
+    }
+
     pub fn take(&mut self) -> StreamInitOptions {
         use std::mem::replace;
+        if let Some(latency) = self.latency.take() {
+            let params = self.opts.output_stream_params.or(self.opts.input_stream_params);
+            if let Some(params) = params {
+                let frames = params.duration_to_frames(latency);
+                self.opts.latency_frames = cmp::min(frames, u64::from(u32::MAX)) as u32;
+            }
+        }
         replace(&mut self.opts, Default::default())
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use {StreamParamsBuilder, ffi};
+    use {MonoFrame, State, StreamCallback, StreamParamsBuilder, ffi};
     use cubeb_core::binding::Binding;
+    use std::ptr;
+    use std::os::raw::{c_long, c_void};
+    use super::{Callback, CallbackFn, DynStream, Input, InputCallback, MESSAGE_QUEUE_LEN,
+                Output, OutputCallback, SharedState, Stream, check_frames};
+    use {ChannelLayout, ErrorCode, Frames, SampleFormat, StereoFrame, StreamInitOptionsBuilder,
+         StreamParams};
+    use adapt::Adapter;
+    use context::NativeContext;
+    use {Context, SharedContext, SharedStream};
+    use std::cell::{Cell, RefCell};
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    struct Adder {
+        sum: i32,
+        // The sum each data callback saw.
+        seen: Vec<i32>
+    }
+
+    impl StreamCallback for Adder {
+        type Frame = MonoFrame<f32>;
+        type Message = Box<i32>;
+
+        fn data_callback(&mut self, _: &[MonoFrame<f32>], output: &mut [MonoFrame<f32>]) -> isize {
+            self.seen.push(self.sum);
+            output.len() as isize
+        }
+
+        fn state_callback(&mut self, _: State) {}
+
+        fn handle_message(&mut self, msg: &mut Box<i32>) {
+            self.sum += **msg;
+            **msg = -**msg;
+        }
+    }
+
+    fn run(cbs: &mut Callback<Adder>) {
+        let mut output = [MonoFrame { m: 0.0f32 }; 8];
+        let got = Stream::<Adder>::data_cb_c(
+            ptr::null_mut(),
+            cbs as *mut _ as *mut c_void,
+            ptr::null(),
+            output.as_mut_ptr() as *mut c_void,
+            8
+        );
+        assert_eq!(got, 8);
+    }
+
+    #[test]
+    fn stream_messages_handled_before_data() {
+        let (mut cbs, mut send, mut reclaim) = Callback::new(Adder {
+            sum: 0,
+            seen: Vec::new()
+        });
+        run(&mut cbs);
+        for i in 1..4 {
+            send.push(Box::new(i)).unwrap();
+        }
+        run(&mut cbs);
+        assert_eq!(cbs.cb.seen, [0, 6]);
+        for i in 1..4 {
+            assert_eq!(reclaim.pop(), Some(Box::new(-i)));
+        }
+        assert_eq!(reclaim.pop(), None);
+    }
+
+    #[test]
+    fn stream_messages_wait_for_reclaim() {
+        let (mut cbs, mut send, mut reclaim) = Callback::new(Adder {
+            sum: 0,
+            seen: Vec::new()
+        });
+        for _ in 0..MESSAGE_QUEUE_LEN {
+            send.push(Box::new(1)).unwrap();
+        }
+        run(&mut cbs);
+        // Nowhere to hand the next message back to, so it waits.
+        send.push(Box::new(1)).unwrap();
+        run(&mut cbs);
+        assert!(reclaim.pop().is_some());
+        run(&mut cbs);
+        let n = MESSAGE_QUEUE_LEN as i32;
+        assert_eq!(cbs.cb.seen, [n, n, n + 1]);
+    }
+
+    #[test]
+    fn stream_frames_match_params() {
+        let mono_f32 = StreamParamsBuilder::new()
+            .format(SampleFormat::Float32NE)
+            .channels(1)
+            .take();
+        let stereo_s16 = StreamParamsBuilder::new()
+            .format(SampleFormat::S16NE)
+            .channels(2)
+            .take();
+        let stereo_f32 = StreamParamsBuilder::new()
+            .format(SampleFormat::Float32LE)
+            .channels(2)
+            .take();
+        let opts = StreamInitOptionsBuilder::new()
+            .output_stream_param(&mono_f32)
+            .take();
+        assert!(check_frames::<(), MonoFrame<f32>>(&opts).is_ok());
+        assert!(check_frames::<(), StereoFrame<f32>>(&opts).is_err());
+        assert!(check_frames::<MonoFrame<f32>, ()>(&opts).is_err());
+        let opts = StreamInitOptionsBuilder::new()
+            .input_stream_param(&stereo_s16)
+            .take();
+        assert!(check_frames::<StereoFrame<i16>, ()>(&opts).is_ok());
+        assert!(check_frames::<MonoFrame<i16>, ()>(&opts).is_err());
+        assert!(check_frames::<(), StereoFrame<i16>>(&opts).is_err());
+        let opts = StreamInitOptionsBuilder::new()
+            .input_stream_param(&stereo_s16)
+            .output_stream_param(&stereo_f32)
+            .take();
+        assert!(check_frames::<StereoFrame<i16>, StereoFrame<f32>>(&opts).is_ok());
+        assert!(check_frames::<StereoFrame<i16>, StereoFrame<i16>>(&opts).is_err());
+        assert!(check_frames::<StereoFrame<f32>, StereoFrame<f32>>(&opts).is_err());
+    }
+
+    #[test]
+    fn stream_frames_of_same_size_checked() {
+        let stereo_s16 = StreamParamsBuilder::new()
+            .format(SampleFormat::S16NE)
+            .channels(2)
+            .take();
+        let mono_f32 = StreamParamsBuilder::new()
+            .format(SampleFormat::Float32NE)
+            .channels(1)
+            .take();
+        let opts = StreamInitOptionsBuilder::new()
+            .output_stream_param(&stereo_s16)
+            .take();
+        assert!(check_frames::<(), MonoFrame<f32>>(&opts).is_err());
+        let opts = StreamInitOptionsBuilder::new()
+            .output_stream_param(&mono_f32)
+            .take();
+        assert!(check_frames::<(), StereoFrame<i16>>(&opts).is_err());
+        assert!(check_frames::<(), MonoFrame<f32>>(&opts).is_ok());
+    }
+
+    #[test]
+    fn stream_app_params_adapted() {
+        // A mono f32 callback on a stereo s16 device.
+        let device = StreamParamsBuilder::new()
+            .format(SampleFormat::S16NE)
+            .rate(48000)
+            .channels(2)
+            .take();
+        let app = StreamParamsBuilder::new()
+            .format(SampleFormat::Float32NE)
+            .rate(48000)
+            .channels(1)
+            .take();
+        let opts = StreamInitOptionsBuilder::new()
+            .output_stream_param(&device)
+            .output_app_param(&app)
+            .take();
+        // Frames are checked against what the callback sees.
+        assert!(check_frames::<(), MonoFrame<f32>>(&opts).is_ok());
+        assert!(check_frames::<(), StereoFrame<f32>>(&opts).is_err());
+
+        let cb = CallbackFn::new(|_: &[MonoFrame<f32>], output: &mut [MonoFrame<f32>]| {
+            for o in output.iter_mut() {
+                o.m = 0.25;
+            }
+            output.len() as isize
+        });
+        let mut cbs = Callback::new(cb).0;
+        cbs.adapter = Adapter::new(&opts).unwrap();
+
+        // The backend's buffer is in the device's format.
+        fn render<CB>(cbs: &mut Callback<CB>, output: &mut [StereoFrame<i16>]) -> c_long
+        where
+            CB: super::DuplexCallback,
+        {
+            Stream::<CB>::data_cb_c(
+                ptr::null_mut(),
+                cbs as *mut _ as *mut c_void,
+                ptr::null(),
+                output.as_mut_ptr() as *mut c_void,
+                output.len() as c_long
+            )
+        }
+        let mut output = [StereoFrame { l: 0i16, r: 0 }; 4];
+        assert_eq!(render(&mut cbs, &mut output), 4);
+        assert_eq!(output, [StereoFrame { l: 8192, r: 8192 }; 4]);
+    }
+
+    #[test]
+    fn stream_init_options_latency_duration() {
+        let input = StreamParamsBuilder::new().rate(16000).take();
+        let output = StreamParamsBuilder::new().rate(48000).take();
+        let latency = Duration::from_millis(10);
+        let opts = StreamInitOptionsBuilder::new()
+            .latency_duration(latency)
+            .input_stream_param(&input)
+            .output_stream_param(&output)
+            .take();
+        assert_eq!(opts.latency_frames, 480);
+        let opts = StreamInitOptionsBuilder::new()
+            .input_stream_param(&input)
+            .latency_duration(latency)
+            .take();
+        assert_eq!(opts.latency_frames, 160);
+        let opts = StreamInitOptionsBuilder::new()
+            .output_stream_param(&output)
+            .latency_duration(latency)
+            .latency(256)
+            .take();
+        assert_eq!(opts.latency_frames, 256);
+        let opts = StreamInitOptionsBuilder::new()
+            .output_stream_param(&output)
+            .latency_frames(Frames::new(441, 44100))
+            .take();
+        assert_eq!(opts.latency_frames, 480);
+    }
+
+    // Upmixes a mono 16 bit microphone to stereo float output.
+    struct Upmix {
+        states: Vec<State>
+    }
+
+    // Not imported, as `StreamCallback` types would then have two
+    // `data_callback` methods in scope.
+    impl super::DuplexCallback for Upmix {
+        type InputFrame = MonoFrame<i16>;
+        type OutputFrame = StereoFrame<f32>;
+        type Message = ();
+
+        fn data_callback(&mut self, input: &[MonoFrame<i16>], output: &mut [StereoFrame<f32>]) -> isize {
+            for (i, o) in input.iter().zip(output.iter_mut()) {
+                let m = f32::from(i.m) / 32768.0;
+                *o = StereoFrame { l: m, r: m };
+            }
+            output.len() as isize
+        }
+
+        fn state_callback(&mut self, state: State) {
+            self.states.push(state);
+        }
+    }
+
+    #[test]
+    fn stream_duplex_callback() {
+        let mut cbs = Callback::new(Upmix {
+            states: Vec::new()
+        }).0;
+        let input = [MonoFrame { m: 16384i16 }, MonoFrame { m: -8192 }];
+        let mut output = [StereoFrame { l: 0.0f32, r: 0.0 }; 2];
+        let got = Stream::<Upmix>::data_cb_c(
+            ptr::null_mut(),
+            &mut cbs as *mut _ as *mut c_void,
+            input.as_ptr() as *const c_void,
+            output.as_mut_ptr() as *mut c_void,
+            2
+        );
+        assert_eq!(got, 2);
+        assert_eq!(output, [StereoFrame { l: 0.5, r: 0.5 }, StereoFrame { l: -0.25, r: -0.25 }]);
+        Stream::<Upmix>::state_cb_c(
+            ptr::null_mut(),
+            &mut cbs as *mut _ as *mut c_void,
+            ffi::CUBEB_STATE_STARTED
+        );
+        assert_eq!(cbs.cb.states, [State::Started]);
+    }
+
+    struct Meter {
+        peak: i16
+    }
+
+    impl InputCallback for Meter {
+        type Frame = MonoFrame<i16>;
+        type Message = ();
+
+        fn data_callback(&mut self, input: &[MonoFrame<i16>]) -> isize {
+            for f in input {
+                self.peak = self.peak.max(f.m.saturating_abs());
+            }
+            input.len() as isize
+        }
+
+        fn state_callback(&mut self, _: State) {}
+    }
+
+    struct Ramp {
+        next: f32
+    }
+
+    impl OutputCallback for Ramp {
+        type Frame = MonoFrame<f32>;
+        type Message = ();
+
+        fn data_callback(&mut self, output: &mut [MonoFrame<f32>]) -> isize {
+            for f in output.iter_mut() {
+                f.m = self.next;
+                self.next += 1.0;
+            }
+            output.len() as isize
+        }
+
+        fn state_callback(&mut self, _: State) {}
+    }
+
+    #[test]
+    fn stream_input_and_output_callbacks() {
+        let mut cbs = Callback::new(Input(Meter { peak: 0 })).0;
+        let input = [MonoFrame { m: 3i16 }, MonoFrame { m: -7 }, MonoFrame { m: 5 }];
+        let got = Stream::<Input<Meter>>::data_cb_c(
+            ptr::null_mut(),
+            &mut cbs as *mut _ as *mut c_void,
+            input.as_ptr() as *const c_void,
+            ptr::null_mut(),
+            3
+        );
+        assert_eq!(got, 3);
+        assert_eq!((cbs.cb.0).peak, 7);
+
+        let mut cbs = Callback::new(Output(Ramp { next: 0.0 })).0;
+        let mut output = [MonoFrame { m: -1.0f32 }; 3];
+        let got = Stream::<Output<Ramp>>::data_cb_c(
+            ptr::null_mut(),
+            &mut cbs as *mut _ as *mut c_void,
+            ptr::null(),
+            output.as_mut_ptr() as *mut c_void,
+            3
+        );
+        assert_eq!(got, 3);
+        assert_eq!(output, [MonoFrame { m: 0.0 }, MonoFrame { m: 1.0 }, MonoFrame { m: 2.0 }]);
+
+        let mono_i16 = StreamParamsBuilder::new()
+            .format(SampleFormat::S16NE)
+            .channels(1)
+            .take();
+        let opts = StreamInitOptionsBuilder::new()
+            .input_stream_param(&mono_i16)
+            .take();
+        assert!(check_frames::<MonoFrame<i16>, ()>(&opts).is_ok());
+        assert!(check_frames::<(), MonoFrame<f32>>(&opts).is_err());
+    }
+
+    #[test]
+    fn stream_callback_fn() {
+        let states = Arc::new(Mutex::new(Vec::new()));
+        let seen = states.clone();
+        let mut cb = CallbackFn::new(|input: &[StereoFrame<i16>], output: &mut [StereoFrame<i16>]| {
+            output.copy_from_slice(input);
+            output.len() as isize
+        }).state(move |state| seen.lock().unwrap().push(state));
+        let input = [StereoFrame { l: 1, r: 2 }; 3];
+        let mut output = [StereoFrame::default(); 3];
+        assert_eq!(cb.data_callback(&input, &mut output), 3);
+        assert_eq!(output, input);
+        cb.state_callback(State::Started);
+        cb.state_callback(State::Drained);
+        assert_eq!(*states.lock().unwrap(), [State::Started, State::Drained]);
+    }
+
+    #[test]
+    fn stream_boxed_callback() {
+        let mut cbs: Vec<Box<dyn StreamCallback<Frame = MonoFrame<f32>, Message = Box<i32>>>> =
+            vec![
+                Box::new(Adder {
+                    sum: 0,
+                    seen: Vec::new()
+                }),
+                Box::new(Adder {
+                    sum: 10,
+                    seen: Vec::new()
+                }),
+            ];
+        let mut output = [MonoFrame { m: 0.0 }; 4];
+        for cb in &mut cbs {
+            let mut msg = Box::new(5);
+            cb.handle_message(&mut msg);
+            assert_eq!(*msg, -5);
+            assert_eq!(cb.data_callback(&[], &mut output), 4);
+        }
+    }
+
+    #[test]
+    fn stream_control_is_dyn() {
+        fn dyn_stream<CB: StreamCallback>(stream: Stream<CB>) -> DynStream {
+            Box::new(stream)
+        }
+        let _ = dyn_stream::<Adder>;
+        let _ = dyn_stream::<Box<dyn StreamCallback<Frame = MonoFrame<f32>, Message = ()>>>;
+    }
+
+    #[test]
+    fn stream_callback_fn_without_state() {
+        let mut cb = CallbackFn::new(|_: &[MonoFrame<f32>], _: &mut [MonoFrame<f32>]| 0);
+        cb.state_callback(State::Error);
+        assert_eq!(cb.data_callback(&[], &mut []), 0);
+    }
+
+    // A stream without a native one, so only the methods that don't call
+    // into libcubeb work. The callback is driven by hand.
+    fn detached<CB>(cb: CB) -> (Stream<CB>, Box<Callback<CB>>)
+    where
+        CB: super::DuplexCallback,
+    {
+        let (cbs, messages, handled) = Callback::new(cb);
+        let cbs = Box::new(cbs);
+        let stream = Stream {
+            raw: ptr::null_mut(),
+            context: NativeContext::detached(),
+            state: cbs.state.clone(),
+            cbs: None,
+            messages: RefCell::new(messages),
+            handled: RefCell::new(handled),
+            started: Cell::new(false),
+            rate: 48000
+        };
+        (stream, cbs)
+    }
+
+    fn signal<CB>(cbs: &mut Callback<CB>, state: ffi::cubeb_state)
+    where
+        CB: super::DuplexCallback,
+    {
+        Stream::<CB>::state_cb_c(ptr::null_mut(), cbs as *mut _ as *mut c_void, state);
+    }
+
+    fn play<CB>(cbs: &mut Callback<CB>, output: &mut [CB::OutputFrame]) -> c_long
+    where
+        CB: super::DuplexCallback,
+    {
+        Stream::<CB>::data_cb_c(
+            ptr::null_mut(),
+            cbs as *mut _ as *mut c_void,
+            ptr::null(),
+            output.as_mut_ptr() as *mut c_void,
+            output.len() as c_long
+        )
+    }
+
+    #[test]
+    fn stream_state_follows_callback() {
+        let states = Arc::new(Mutex::new(Vec::new()));
+        let seen = states.clone();
+        let cb = CallbackFn::new(|_: &[MonoFrame<f32>], _: &mut [MonoFrame<f32>]| 0)
+            .state(move |state| seen.lock().unwrap().push(state));
+        let (stream, mut cbs) = detached(cb);
+        assert_eq!(stream.state(), State::Stopped);
+        let signals = [
+            (ffi::CUBEB_STATE_STARTED, State::Started),
+            (ffi::CUBEB_STATE_DRAINED, State::Drained),
+            (ffi::CUBEB_STATE_STOPPED, State::Stopped),
+            (ffi::CUBEB_STATE_ERROR, State::Error),
+        ];
+        for &(raw, state) in &signals {
+            signal(&mut cbs, raw);
+            assert_eq!(stream.state(), state);
+        }
+        assert_eq!(
+            *states.lock().unwrap(),
+            [State::Started, State::Drained, State::Stopped, State::Error]
+        );
+    }
+
+    #[test]
+    fn stream_state_callback_waits_for_lent_callback() {
+        let (stream, mut cbs) = detached(Adder {
+            sum: 0,
+            seen: Vec::new()
+        });
+        // What `with_callback_stopped` holds while `f` runs.
+        let delivery = stream.state.delivery.lock().unwrap();
+        let signaler = thread::spawn(move || {
+            signal(&mut cbs, ffi::CUBEB_STATE_STARTED);
+            cbs
+        });
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(stream.state(), State::Stopped);
+        drop(delivery);
+        let _cbs = signaler.join().unwrap();
+        assert_eq!(stream.state(), State::Started);
+    }
+
+    #[test]
+    fn stream_wait_for_state() {
+        let (stream, mut cbs) = detached(Adder {
+            sum: 0,
+            seen: Vec::new()
+        });
+        assert!(!stream.wait_for_state(State::Started, Duration::from_millis(10)).unwrap());
+        let signaler = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            signal(&mut cbs, ffi::CUBEB_STATE_STARTED);
+            cbs
+        });
+        assert!(stream.wait_for_state(State::Started, Duration::from_secs(10)).unwrap());
+        let mut cbs = signaler.join().unwrap();
+        signal(&mut cbs, ffi::CUBEB_STATE_ERROR);
+        assert!(stream.wait_for_state(State::Drained, Duration::from_secs(10)).is_err());
+    }
+
+    #[test]
+    fn stream_start_forgets_how_it_ended() {
+        let state = SharedState::new();
+        state.signal(State::Drained);
+        assert!(state.reached(State::Drained).unwrap());
+        let ended = state.clear_ended();
+        // Only what the backend signals is recorded.
+        assert_eq!(state.get(), State::Drained);
+        assert!(!state.reached(State::Drained).unwrap());
+        assert!(!state.wait(State::Drained, Some(Instant::now())).unwrap());
+        state.restore_ended(ended);
+        assert!(state.reached(State::Drained).unwrap());
+        state.clear_ended();
+        let waiter = {
+            let state = state.clone();
+            thread::spawn(move || state.wait(State::Drained, None))
+        };
+        state.signal(State::Started);
+        state.signal(State::Drained);
+        assert!(waiter.join().unwrap().unwrap());
+    }
+
+    #[test]
+    fn stream_send_and_shared_sync() {
+        fn send<T: Send>() {}
+        fn sync<T: Send + Sync>() {}
+        send::<Context>();
+        send::<Stream<Adder>>();
+        send::<DynStream>();
+        sync::<SharedContext>();
+        sync::<SharedStream<Adder>>();
+    }
+
+    #[test]
+    fn stream_shared_state_without_lock() {
+        let (stream, mut cbs) = detached(Adder {
+            sum: 0,
+            seen: Vec::new()
+        });
+        let shared = Arc::new(SharedStream::new(stream));
+        assert!(shared.drained().is_err());
+        // Another thread holds the lock while the stream starts.
+        let guard = shared.lock();
+        let waiter = {
+            let shared = shared.clone();
+            let timeout = Duration::from_secs(10);
+            thread::spawn(move || shared.wait_for_state(State::Started, timeout))
+        };
+        signal(&mut cbs, ffi::CUBEB_STATE_STARTED);
+        assert!(waiter.join().unwrap().unwrap());
+        assert_eq!(shared.state(), State::Started);
+        drop(guard);
+        let stream = Arc::try_unwrap(shared).ok().unwrap().into_inner();
+        assert_eq!(stream.state(), State::Started);
+    }
+
+    #[test]
+    fn stream_drained_by_short_callback() {
+        // Plays 20 frames, then returns fewer frames than asked for.
+        let mut left = 20;
+        let cb = CallbackFn::new(move |_: &[MonoFrame<f32>], output: &mut [MonoFrame<f32>]| {
+            let len = output.len().min(left);
+            left -= len;
+            len as isize
+        });
+        let (stream, mut cbs) = detached(cb);
+        assert!(stream.drained().is_err());
+        stream.started.set(true);
+        // Call back the way a backend does, until the callback comes up
+        // short.
+        let backend = thread::spawn(move || {
+            signal(&mut cbs, ffi::CUBEB_STATE_STARTED);
+            let mut output = [MonoFrame { m: 0.0f32 }; 8];
+            loop {
+                thread::sleep(Duration::from_millis(1));
+                let got = play(&mut cbs, &mut output);
+                if got < 8 {
+                    signal(&mut cbs, ffi::CUBEB_STATE_DRAINED);
+                    return got;
+                }
+            }
+        });
+        stream.drained().unwrap();
+        assert_eq!(stream.state(), State::Drained);
+        assert_eq!(backend.join().unwrap(), 4);
+    }
 
     #[test]
     fn stream_params_builder_channels() {
@@ -633,6 +2072,42 @@ mod tests {
                Layout3F4Lfe => CUBEB_LAYOUT_3F4_LFE);
     }
 
+    #[test]
+    fn stream_params_builder_build() {
+        let params = StreamParamsBuilder::new()
+            .format(SampleFormat::Float32NE)
+            .rate(48000)
+            .channels(2)
+            .build()
+            .unwrap();
+        assert_eq!(params, StreamParams::stereo_f32(48000));
+        let params = StreamParamsBuilder::new()
+            .rate(16000)
+            .layout(ChannelLayout::Mono)
+            .build()
+            .unwrap();
+        assert_eq!(params, StreamParams::mono_s16(16000));
+        let params = StreamParamsBuilder::new().rate(44100).channels(6).build().unwrap();
+        assert_eq!(params.layout(), ChannelLayout::Layout3F2Lfe);
+        // Several layouts have four channels, so none is picked.
+        let params = StreamParamsBuilder::new().rate(44100).channels(4).build().unwrap();
+        assert_eq!(params.channels(), 4);
+        assert_eq!(params.layout(), ChannelLayout::Undefined);
+
+        let invalid = [
+            StreamParamsBuilder::new().channels(2).build(),
+            StreamParamsBuilder::new().rate(48000).build(),
+            StreamParamsBuilder::new()
+                .rate(48000)
+                .channels(2)
+                .layout(ChannelLayout::Layout3F)
+                .build(),
+        ];
+        for result in &invalid {
+            assert_eq!(result.as_ref().unwrap_err().code(), ErrorCode::InvalidFormat);
+        }
+    }
+
     #[test]
     fn stream_params_builder_to_raw_rate() {
         let params = StreamParamsBuilder::new().rate(44100).take();
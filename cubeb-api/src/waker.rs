@@ -0,0 +1,230 @@
+// Copyright © 2017 Mozilla Foundation
+//
+// This program is made available under an ISC-style license.  See the
+// accompanying file LICENSE for details.
+
+// Wake-ups from the audio thread, for futures and for blocked threads.
+
+use std::cell::UnsafeCell;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::task::{Wake, Waker};
+use std::thread::{self, Thread};
+
+// `WakerCell` state bits. `LOCKED` is held by whoever touches the
+// waker, `PENDING` marks a wake-up still to be delivered.
+const LOCKED: usize = 1;
+const PENDING: usize = 2;
+
+// A waker registered by one task and woken from any thread. Waking only
+// borrows the waker, so the waking thread never drops one, and never
+// waits: if the cell is locked, whoever holds it delivers the wake-up.
+pub(crate) struct WakerCell {
+    state: AtomicUsize,
+    waker: UnsafeCell<Option<Waker>>
+}
+
+// The waker is only touched with `LOCKED` held.
+unsafe impl Sync for WakerCell {}
+
+impl Default for WakerCell {
+    fn default() -> WakerCell {
+        WakerCell {
+            state: AtomicUsize::new(0),
+            waker: UnsafeCell::new(None)
+        }
+    }
+}
+
+impl WakerCell {
+    pub(crate) fn register(&self, waker: &Waker) {
+        self.lock();
+        let slot = unsafe { &mut *self.waker.get() };
+        if !slot.as_ref().is_some_and(|w| w.will_wake(waker)) {
+            *slot = Some(waker.clone());
+        }
+        self.unlock();
+    }
+
+    pub(crate) fn clear(&self) {
+        self.lock();
+        let waker = unsafe { (*self.waker.get()).take() };
+        self.unlock();
+        drop(waker);
+    }
+
+    // Wake the registered waker, now or as soon as the cell is unlocked.
+    pub(crate) fn wake(&self) {
+        self.state.fetch_or(PENDING, Ordering::AcqRel);
+        if self.state
+            .compare_exchange(PENDING, LOCKED, Ordering::AcqRel, Ordering::Acquire)
+            .is_ok()
+        {
+            self.wake_and_unlock();
+        }
+    }
+
+    // Only registering spins, and wakers hold the lock briefly.
+    fn lock(&self) {
+        loop {
+            let state = self.state.load(Ordering::Relaxed);
+            if state & LOCKED == 0 &&
+                self.state
+                    .compare_exchange_weak(state, state | LOCKED, Ordering::Acquire, Ordering::Relaxed)
+                    .is_ok()
+            {
+                return;
+            }
+            thread::yield_now();
+        }
+    }
+
+    fn unlock(&self) {
+        if self.state
+            .compare_exchange(LOCKED, 0, Ordering::AcqRel, Ordering::Acquire)
+            .is_err()
+        {
+            // Woken while locked.
+            self.state.fetch_and(!PENDING, Ordering::AcqRel);
+            self.wake_and_unlock();
+        }
+    }
+
+    // Wake the waker, again for every wake-up arriving meanwhile, and
+    // unlock.
+    fn wake_and_unlock(&self) {
+        loop {
+            unsafe {
+                if let Some(ref waker) = *self.waker.get() {
+                    waker.wake_by_ref();
+                }
+            }
+            if self.state
+                .compare_exchange(LOCKED, 0, Ordering::AcqRel, Ordering::Acquire)
+                .is_ok()
+            {
+                return;
+            }
+            self.state.fetch_and(!PENDING, Ordering::AcqRel);
+        }
+    }
+}
+
+// Unparks a thread blocked in `thread::park`.
+struct Unpark(Thread);
+
+impl Wake for Unpark {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+// A waker unparking the current thread, so a thread can wait on a
+// `WakerCell` with `thread::park`.
+pub(crate) fn thread_waker() -> Waker {
+    Waker::from(Arc::new(Unpark(thread::current())))
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::{WakerCell, thread_waker};
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::task::{Wake, Waker};
+    use std::time::{Duration, Instant};
+    use std::thread;
+
+    pub(crate) struct Counter(AtomicUsize);
+
+    impl Wake for Counter {
+        fn wake(self: Arc<Self>) {
+            self.wake_by_ref();
+        }
+
+        fn wake_by_ref(self: &Arc<Self>) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    // A waker counting how often it's woken.
+    pub(crate) fn counting_waker() -> (Arc<Counter>, Waker) {
+        let counter = Arc::new(Counter(AtomicUsize::new(0)));
+        (counter.clone(), Waker::from(counter))
+    }
+
+    impl Counter {
+        pub(crate) fn count(&self) -> usize {
+            self.0.load(Ordering::SeqCst)
+        }
+    }
+
+    #[test]
+    fn waker_cell_wakes_registered() {
+        let cell = WakerCell::default();
+        cell.wake();
+        let (counter, waker) = counting_waker();
+        cell.register(&waker);
+        cell.register(&waker);
+        assert_eq!(Arc::strong_count(&counter), 3);
+        cell.wake();
+        cell.wake();
+        assert_eq!(counter.count(), 2);
+        cell.clear();
+        cell.wake();
+        assert_eq!(counter.count(), 2);
+        assert_eq!(Arc::strong_count(&counter), 2);
+    }
+
+    // A task registering while another thread wakes must not miss the
+    // last wake-up.
+    #[test]
+    fn waker_cell_threads() {
+        const WAKES: usize = 10_000;
+        let cell = Arc::new(WakerCell::default());
+        let done = Arc::new(AtomicUsize::new(0));
+        let waking = {
+            let cell = cell.clone();
+            let done = done.clone();
+            thread::spawn(move || for i in 1..WAKES + 1 {
+                done.store(i, Ordering::SeqCst);
+                cell.wake();
+                thread::yield_now();
+            })
+        };
+        let (counter, waker) = counting_waker();
+        loop {
+            let woken = counter.count();
+            cell.register(&waker);
+            if done.load(Ordering::SeqCst) == WAKES {
+                break;
+            }
+            let deadline = Instant::now() + Duration::from_secs(10);
+            while counter.count() == woken {
+                assert!(Instant::now() < deadline, "missed a wake-up");
+                thread::yield_now();
+            }
+        }
+        waking.join().unwrap();
+    }
+
+    #[test]
+    fn thread_waker_unparks() {
+        let cell = Arc::new(WakerCell::default());
+        cell.register(&thread_waker());
+        let waking = {
+            let cell = cell.clone();
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(20));
+                cell.wake();
+            })
+        };
+        let start = Instant::now();
+        thread::park_timeout(Duration::from_secs(10));
+        assert!(start.elapsed() < Duration::from_secs(10));
+        waking.join().unwrap();
+    }
+}
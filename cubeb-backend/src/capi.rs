@@ -43,7 +43,7 @@ macro_rules! capi_new(
             stream_set_panning: Some($crate::capi::capi_stream_set_panning::<$stm>),
             stream_get_current_device: Some($crate::capi::capi_stream_get_current_device::<$stm>),
             stream_device_destroy: Some($crate::capi::capi_stream_device_destroy::<$stm>),
-            stream_register_device_changed_callback: None,
+            stream_register_device_changed_callback: Some($crate::capi::capi_stream_register_device_changed_callback::<$stm>),
             register_device_collection_changed: Some($crate::capi::capi_register_device_collection_changed::<$ctx>)
         }));
 
@@ -255,10 +255,20 @@ pub unsafe extern "C" fn capi_stream_device_destroy<STM: Stream>(
     ffi::CUBEB_OK
 }
 
+pub unsafe extern "C" fn capi_stream_register_device_changed_callback<STM: Stream>(
+    s: *mut ffi::cubeb_stream,
+    device_changed_callback: Option<ffi::cubeb_device_changed_callback>,
+) -> c_int {
+    let stm = &mut *(s as *mut STM);
+
+    _try!(stm.register_device_changed_callback(device_changed_callback));
+    ffi::CUBEB_OK
+}
+
 pub unsafe extern "C" fn capi_register_device_collection_changed<CTX: Context>(
     c: *mut ffi::cubeb,
     devtype: ffi::cubeb_device_type,
-    collection_changed_callback: ffi::cubeb_device_collection_changed_callback,
+    collection_changed_callback: Option<ffi::cubeb_device_collection_changed_callback>,
     user_ptr: *mut c_void,
 ) -> i32 {
     let ctx = &*(c as *const CTX);
@@ -0,0 +1,163 @@
+// Copyright © 2017 Mozilla Foundation
+//
+// This program is made available under an ISC-style license.  See the
+// accompanying file LICENSE for details.
+
+//! An owned copy of `cubeb_device_info`, as traces record it and the
+//! remote protocol carries it.
+
+use cubeb_core::ffi;
+use std::io;
+use std::slice;
+use util;
+
+/// A device, as reported by `enumerate_devices`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DeviceInfo {
+    /// The backend's devid in traces. The remote protocol carries an id
+    /// the server gave the device instead.
+    pub devid: u64,
+    pub device_id: Option<String>,
+    pub friendly_name: Option<String>,
+    pub group_id: Option<String>,
+    pub vendor_name: Option<String>,
+    pub device_type: ffi::cubeb_device_type,
+    pub state: ffi::cubeb_device_state,
+    pub preferred: ffi::cubeb_device_pref,
+    pub format: ffi::cubeb_device_fmt,
+    pub default_format: ffi::cubeb_device_fmt,
+    pub max_channels: u32,
+    pub default_rate: u32,
+    pub max_rate: u32,
+    pub min_rate: u32,
+    pub latency_lo: u32,
+    pub latency_hi: u32
+}
+
+/// Writes the fields of a record in some encoding.
+pub trait WriteFields {
+    fn write_u32(&mut self, x: u32);
+    fn write_i32(&mut self, x: i32);
+    fn write_u64(&mut self, x: u64);
+    fn write_string(&mut self, s: &Option<String>);
+}
+
+/// Reads back the fields `WriteFields` wrote.
+pub trait ReadFields {
+    fn read_u32(&mut self) -> io::Result<u32>;
+    fn read_i32(&mut self) -> io::Result<i32>;
+    fn read_u64(&mut self) -> io::Result<u64>;
+    fn read_string(&mut self) -> io::Result<Option<String>>;
+}
+
+impl DeviceInfo {
+    /// Copy `info`.
+    ///
+    /// # Safety
+    ///
+    /// `info` must have been filled in by `enumerate_devices`.
+    pub unsafe fn from_ffi(info: &ffi::cubeb_device_info) -> DeviceInfo {
+        DeviceInfo {
+            devid: info.devid as usize as u64,
+            device_id: util::from_c_string(info.device_id),
+            friendly_name: util::from_c_string(info.friendly_name),
+            group_id: util::from_c_string(info.group_id),
+            vendor_name: util::from_c_string(info.vendor_name),
+            device_type: info.device_type,
+            state: info.state,
+            preferred: info.preferred,
+            format: info.format,
+            default_format: info.default_format,
+            max_channels: info.max_channels,
+            default_rate: info.default_rate,
+            max_rate: info.max_rate,
+            min_rate: info.min_rate,
+            latency_lo: info.latency_lo,
+            latency_hi: info.latency_hi
+        }
+    }
+
+    /// Hand the device over as a `cubeb_device_info`, whose strings
+    /// `util::destroy_owned_device_collection` releases.
+    pub fn into_ffi(self) -> ffi::cubeb_device_info {
+        ffi::cubeb_device_info {
+            devid: self.devid as usize as ffi::cubeb_devid,
+            device_id: util::into_c_string(self.device_id),
+            friendly_name: util::into_c_string(self.friendly_name),
+            group_id: util::into_c_string(self.group_id),
+            vendor_name: util::into_c_string(self.vendor_name),
+            device_type: self.device_type,
+            state: self.state,
+            preferred: self.preferred,
+            format: self.format,
+            default_format: self.default_format,
+            max_channels: self.max_channels,
+            default_rate: self.default_rate,
+            max_rate: self.max_rate,
+            min_rate: self.min_rate,
+            latency_lo: self.latency_lo,
+            latency_hi: self.latency_hi
+        }
+    }
+
+    /// Copy the devices of `collection`.
+    ///
+    /// # Safety
+    ///
+    /// `collection` must have been filled in by `enumerate_devices`.
+    pub unsafe fn from_collection(collection: &ffi::cubeb_device_collection) -> Vec<DeviceInfo> {
+        if collection.device.is_null() {
+            return Vec::new();
+        }
+        slice::from_raw_parts(collection.device, collection.count)
+            .iter()
+            .map(|d| DeviceInfo::from_ffi(d))
+            .collect()
+    }
+
+    /// Build a collection of `devices`. Release it with
+    /// `util::destroy_owned_device_collection`.
+    pub fn into_collection(devices: Vec<DeviceInfo>) -> ffi::cubeb_device_collection {
+        util::device_collection(devices.into_iter().map(DeviceInfo::into_ffi).collect())
+    }
+
+    pub fn write<W: WriteFields>(&self, w: &mut W) {
+        w.write_u64(self.devid);
+        w.write_string(&self.device_id);
+        w.write_string(&self.friendly_name);
+        w.write_string(&self.group_id);
+        w.write_string(&self.vendor_name);
+        w.write_i32(self.device_type as i32);
+        w.write_i32(self.state as i32);
+        w.write_i32(self.preferred as i32);
+        w.write_i32(self.format as i32);
+        w.write_i32(self.default_format as i32);
+        w.write_u32(self.max_channels);
+        w.write_u32(self.default_rate);
+        w.write_u32(self.max_rate);
+        w.write_u32(self.min_rate);
+        w.write_u32(self.latency_lo);
+        w.write_u32(self.latency_hi);
+    }
+
+    pub fn read<R: ReadFields>(r: &mut R) -> io::Result<DeviceInfo> {
+        Ok(DeviceInfo {
+            devid: r.read_u64()?,
+            device_id: r.read_string()?,
+            friendly_name: r.read_string()?,
+            group_id: r.read_string()?,
+            vendor_name: r.read_string()?,
+            device_type: r.read_i32()? as _,
+            state: r.read_i32()? as _,
+            preferred: r.read_i32()? as _,
+            format: r.read_i32()? as _,
+            default_format: r.read_i32()? as _,
+            max_channels: r.read_u32()?,
+            default_rate: r.read_u32()?,
+            max_rate: r.read_u32()?,
+            min_rate: r.read_u32()?,
+            latency_lo: r.read_u32()?,
+            latency_hi: r.read_u32()?
+        })
+    }
+}
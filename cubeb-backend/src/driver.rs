@@ -0,0 +1,889 @@
+// Copyright © 2017 Mozilla Foundation
+//
+// This program is made available under an ISC-style license.  See the
+// accompanying file LICENSE for details.
+
+//! Audio thread for backends that push to, or pull from, their device.
+//!
+//! A `CallbackDriver` owns the thread that calls the user's
+//! `cubeb_data_callback` once per period, moves the audio to a `Sink`
+//! and/or from a `Source`, and emits the stream state changes in the
+//! order libcubeb expects. Backends only have to implement the device
+//! I/O.
+
+use Stream;
+use cubeb_core::{Error, ErrorCode, Result, SampleFormat, StreamParams};
+use cubeb_core::ffi;
+use cubeb_core::frames::frames_to_duration;
+use std::ffi::CString;
+use std::os::raw::{c_long, c_void};
+use std::ptr;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicPtr, AtomicU32, AtomicU64, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::Instant;
+
+/// Destination for the audio rendered by an output stream.
+pub trait Sink: Send {
+    /// Sample format the sink consumes. `None` accepts the stream format.
+    fn format(&self) -> Option<SampleFormat> {
+        None
+    }
+    /// Consume `frames` frames of interleaved audio.
+    fn write(&mut self, buffer: &[u8], frames: usize) -> Result<()>;
+    /// Called once after the final `write` of a drained stream.
+    fn drain(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Origin of the audio captured by an input stream.
+pub trait Source: Send {
+    /// Sample format the source produces. `None` produces the stream
+    /// format.
+    fn format(&self) -> Option<SampleFormat> {
+        None
+    }
+    /// Fill `buffer` with up to `frames` frames of interleaved audio and
+    /// return the number of frames read. Returning fewer than `frames`
+    /// signals the end of the input and drains the stream.
+    fn read(&mut self, buffer: &mut [u8], frames: usize) -> Result<usize>;
+}
+
+/// How the driver schedules periods.
+#[derive(PartialEq, Eq, Clone, Debug, Copy)]
+pub enum Pacing {
+    /// Run one period per period of wall-clock time.
+    RealTime,
+    /// Run periods back to back, as fast as the sink allows.
+    Unpaced
+}
+
+/// Size in bytes of one sample in `format`.
+pub fn sample_size(format: SampleFormat) -> usize {
+    match format {
+        SampleFormat::S16LE | SampleFormat::S16BE | SampleFormat::S16NE => 2,
+        SampleFormat::Float32LE | SampleFormat::Float32BE | SampleFormat::Float32NE => 4,
+    }
+}
+
+/// Size in bytes of one frame of a stream using `params`.
+pub fn frame_size(params: &StreamParams) -> usize {
+    sample_size(params.format()) * params.channels() as usize
+}
+
+fn read_sample(format: SampleFormat, b: &[u8]) -> f32 {
+    match native(format) {
+        SampleFormat::S16LE => f32::from(i16::from_le_bytes([b[0], b[1]])) / 32768.0,
+        SampleFormat::S16BE => f32::from(i16::from_be_bytes([b[0], b[1]])) / 32768.0,
+        SampleFormat::Float32LE => f32::from_bits(u32::from_le_bytes([b[0], b[1], b[2], b[3]])),
+        _ => f32::from_bits(u32::from_be_bytes([b[0], b[1], b[2], b[3]])),
+    }
+}
+
+fn write_sample(format: SampleFormat, x: f32, b: &mut [u8]) {
+    let s16 = || (x * 32768.0).round().clamp(-32768.0, 32767.0) as i16;
+    match native(format) {
+        SampleFormat::S16LE => b[..2].copy_from_slice(&s16().to_le_bytes()),
+        SampleFormat::S16BE => b[..2].copy_from_slice(&s16().to_be_bytes()),
+        SampleFormat::Float32LE => b[..4].copy_from_slice(&x.to_bits().to_le_bytes()),
+        _ => b[..4].copy_from_slice(&x.to_bits().to_be_bytes()),
+    }
+}
+
+// Resolve the native endian aliases to the concrete format.
+fn native(format: SampleFormat) -> SampleFormat {
+    match format {
+        SampleFormat::S16NE if cfg!(target_endian = "little") => SampleFormat::S16LE,
+        SampleFormat::S16NE => SampleFormat::S16BE,
+        SampleFormat::Float32NE if cfg!(target_endian = "little") => SampleFormat::Float32LE,
+        SampleFormat::Float32NE => SampleFormat::Float32BE,
+        f => f,
+    }
+}
+
+/// Convert interleaved samples in `input` from format `from` into
+/// `output` in format `to`. `output` must hold as many samples as
+/// `input`.
+pub fn convert(from: SampleFormat, input: &[u8], to: SampleFormat, output: &mut [u8]) {
+    let (from, to) = (native(from), native(to));
+    let samples = input.len() / sample_size(from);
+    if from == to {
+        output[..input.len()].copy_from_slice(input);
+        return;
+    }
+    let (is, os) = (sample_size(from), sample_size(to));
+    for i in 0..samples {
+        let x = read_sample(from, &input[i * is..]);
+        write_sample(to, x, &mut output[i * os..]);
+    }
+}
+
+/// Scale the interleaved samples in `buffer` by `volume`.
+pub fn apply_volume(format: SampleFormat, buffer: &mut [u8], volume: f32) {
+    if volume == 1.0 {
+        return;
+    }
+    let size = sample_size(format);
+    for sample in buffer.chunks_mut(size) {
+        let x = read_sample(format, sample);
+        write_sample(format, x * volume, sample);
+    }
+}
+
+/// Pan interleaved stereo `buffer` by `panning`, from -1.0 (left) to
+/// 1.0 (right), moving the other channel into the side panned towards
+/// as libcubeb's panner does.
+pub fn apply_panning(format: SampleFormat, buffer: &mut [u8], panning: f32) {
+    if panning == 0.0 {
+        return;
+    }
+    let pan = (panning + 1.0) / 2.0;
+    let angle = pan * ::std::f32::consts::FRAC_PI_2;
+    let (left_gain, right_gain) = (angle.cos(), angle.sin());
+    let size = sample_size(format);
+    for frame in buffer.chunks_mut(2 * size) {
+        let (l, r) = frame.split_at_mut(size);
+        let (x, y) = (read_sample(format, l), read_sample(format, r));
+        let (x, y) = if pan < 0.5 {
+            (x + y * left_gain, y * right_gain)
+        } else {
+            (x * left_gain, y + x * right_gain)
+        };
+        write_sample(format, x, l);
+        write_sample(format, y, r);
+    }
+}
+
+// Stream buffer plus, when the device format differs, a device buffer.
+struct Buffers {
+    params: StreamParams,
+    device_format: SampleFormat,
+    stream: Vec<u8>,
+    device: Vec<u8>
+}
+
+impl Buffers {
+    fn new(params: &StreamParams, device_format: Option<SampleFormat>, period: usize) -> Buffers {
+        let device_format = device_format.unwrap_or_else(|| params.format());
+        let converts = native(device_format) != native(params.format());
+        let samples = period * params.channels() as usize;
+        Buffers {
+            params: *params,
+            device_format,
+            stream: vec![0; samples * sample_size(params.format())],
+            device: if converts {
+                vec![0; samples * sample_size(device_format)]
+            } else {
+                Vec::new()
+            }
+        }
+    }
+
+    fn frame_size(&self) -> usize {
+        frame_size(&self.params)
+    }
+}
+
+struct Input {
+    source: Box<dyn Source>,
+    buffers: Buffers
+}
+
+impl Input {
+    fn fill(&mut self, frames: usize) -> Result<usize> {
+        let b = &mut self.buffers;
+        let len = frames * b.frame_size();
+        let read = if b.device.is_empty() {
+            self.source.read(&mut b.stream[..len], frames)?
+        } else {
+            let dev_len = frames * b.params.channels() as usize * sample_size(b.device_format);
+            let read = self.source.read(&mut b.device[..dev_len], frames)?;
+            convert(b.device_format, &b.device[..dev_len], b.params.format(), &mut b.stream[..len]);
+            read
+        };
+        let read = read.min(frames);
+        let filled = read * b.frame_size();
+        for x in &mut b.stream[filled..len] {
+            *x = 0;
+        }
+        Ok(read)
+    }
+}
+
+struct Output {
+    sink: Box<dyn Sink>,
+    buffers: Buffers
+}
+
+impl Output {
+    fn flush(&mut self, frames: usize, volume: f32, panning: f32) -> Result<()> {
+        let b = &mut self.buffers;
+        let len = frames * b.frame_size();
+        apply_volume(b.params.format(), &mut b.stream[..len], volume);
+        if b.params.channels() == 2 {
+            apply_panning(b.params.format(), &mut b.stream[..len], panning);
+        }
+        if b.device.is_empty() {
+            self.sink.write(&b.stream[..len], frames)
+        } else {
+            let dev_len = frames * b.params.channels() as usize * sample_size(b.device_format);
+            convert(b.params.format(), &b.stream[..len], b.device_format, &mut b.device[..dev_len]);
+            self.sink.write(&b.device[..dev_len], frames)
+        }
+    }
+}
+
+struct Callbacks {
+    data: ffi::cubeb_data_callback,
+    state: ffi::cubeb_state_callback,
+    user_ptr: *mut c_void
+}
+
+// Everything the audio thread owns while the stream is running.
+struct Io {
+    input: Option<Input>,
+    output: Option<Output>,
+    callbacks: Callbacks,
+    period: usize,
+    rate: u32,
+    pacing: Pacing
+}
+
+// The user pointer is only ever dereferenced by the user's callbacks,
+// which libcubeb already requires to be callable from the audio thread.
+unsafe impl Send for Io {}
+
+struct Shared {
+    running: AtomicBool,
+    position: AtomicU64,
+    volume: AtomicU32,
+    panning: AtomicU32,
+    stream: AtomicPtr<ffi::cubeb_stream>
+}
+
+/// Builder for `CallbackDriver`.
+pub struct CallbackDriverBuilder {
+    input: Option<(StreamParams, Box<dyn Source>)>,
+    output: Option<(StreamParams, Box<dyn Sink>)>,
+    period: u32,
+    pacing: Pacing
+}
+
+impl Default for CallbackDriverBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CallbackDriverBuilder {
+    pub fn new() -> Self {
+        CallbackDriverBuilder {
+            input: None,
+            output: None,
+            period: 0,
+            pacing: Pacing::RealTime
+        }
+    }
+
+    pub fn input<S>(&mut self, params: &StreamParams, source: S) -> &mut Self
+    where
+        S: Source + 'static,
+    {
+        self.input = Some((*params, Box::new(source)));
+        self
+    }
+
+    pub fn output<S>(&mut self, params: &StreamParams, sink: S) -> &mut Self
+    where
+        S: Sink + 'static,
+    {
+        self.output = Some((*params, Box::new(sink)));
+        self
+    }
+
+    /// Number of frames passed to each data callback. Defaults to 10ms
+    /// worth of frames.
+    pub fn period(&mut self, frames: u32) -> &mut Self {
+        self.period = frames;
+        self
+    }
+
+    pub fn pacing(&mut self, pacing: Pacing) -> &mut Self {
+        self.pacing = pacing;
+        self
+    }
+
+    /// Create the driver. The callbacks and `user_ptr` are those passed
+    /// to the backend's `stream_init`.
+    pub fn build(
+        &mut self,
+        data_callback: ffi::cubeb_data_callback,
+        state_callback: ffi::cubeb_state_callback,
+        user_ptr: *mut c_void,
+    ) -> Result<CallbackDriver> {
+        let (input, output) = (self.input.take(), self.output.take());
+        let rate = match (&input, &output) {
+            (&Some((ref i, _)), &Some((ref o, _))) if i.rate() != o.rate() => {
+                return Err(Error::from(ErrorCode::InvalidFormat))
+            },
+            (_, &Some((ref p, _))) | (&Some((ref p, _)), _) => p.rate(),
+            (&None, &None) => return Err(Error::from(ErrorCode::InvalidParameter)),
+        };
+        let params = input.iter().map(|i| &i.0).chain(output.iter().map(|o| &o.0));
+        for p in params {
+            if p.rate() == 0 || p.channels() == 0 {
+                return Err(Error::from(ErrorCode::InvalidFormat));
+            }
+        }
+
+        let period = if self.period == 0 {
+            (rate as usize / 100).max(1)
+        } else {
+            self.period as usize
+        };
+        let input = input.map(|(params, source)| {
+            let format = source.format();
+            Input {
+                source,
+                buffers: Buffers::new(&params, format, period)
+            }
+        });
+        let output = output.map(|(params, sink)| {
+            let format = sink.format();
+            Output {
+                sink,
+                buffers: Buffers::new(&params, format, period)
+            }
+        });
+
+        Ok(CallbackDriver {
+            shared: Arc::new(Shared {
+                running: AtomicBool::new(false),
+                position: AtomicU64::new(0),
+                volume: AtomicU32::new(1.0f32.to_bits()),
+                panning: AtomicU32::new(0.0f32.to_bits()),
+                stream: AtomicPtr::new(ptr::null_mut())
+            }),
+            stereo: output.as_ref().is_some_and(|o| o.buffers.params.channels() == 2),
+            io: Mutex::new(Some(Io {
+                input,
+                output,
+                callbacks: Callbacks {
+                    data: data_callback,
+                    state: state_callback,
+                    user_ptr
+                },
+                period,
+                rate,
+                pacing: self.pacing
+            })),
+            thread: Mutex::new(None)
+        })
+    }
+}
+
+/// Runs the data callback loop of a stream on a dedicated thread.
+///
+/// `start` emits `CUBEB_STATE_STARTED` and `stop` emits
+/// `CUBEB_STATE_STOPPED`. The audio thread emits
+/// `CUBEB_STATE_DRAINED` when the data callback returns fewer frames than
+/// requested or the source runs dry, and `CUBEB_STATE_ERROR` when the
+/// callback returns an error or the device I/O fails.
+pub struct CallbackDriver {
+    shared: Arc<Shared>,
+    io: Mutex<Option<Io>>,
+    thread: Mutex<Option<JoinHandle<Io>>>,
+    stereo: bool
+}
+
+impl CallbackDriver {
+    /// Start the audio thread. `stream` is the backend stream handed to
+    /// the user's callbacks.
+    pub fn start(&self, stream: *mut ffi::cubeb_stream) -> Result<()> {
+        let mut thread = self.thread.lock().unwrap();
+        if self.shared.running.load(Ordering::Acquire) {
+            return Ok(());
+        }
+        // Recover the I/O state from a thread that drained or failed.
+        if let Some(handle) = thread.take() {
+            let io = handle.join().map_err(|_| Error::new())?;
+            *self.io.lock().unwrap() = Some(io);
+        }
+        let io = match self.io.lock().unwrap().take() {
+            Some(io) => io,
+            None => return Err(Error::new()),
+        };
+
+        self.shared.stream.store(stream, Ordering::Release);
+        self.shared.running.store(true, Ordering::Release);
+        (io.callbacks.state)(stream, io.callbacks.user_ptr, ffi::CUBEB_STATE_STARTED);
+
+        let shared = self.shared.clone();
+        let spawned = thread::Builder::new()
+            .name("cubeb audio".into())
+            .spawn(move || run(io, &shared));
+        match spawned {
+            Ok(handle) => {
+                *thread = Some(handle);
+                Ok(())
+            },
+            Err(_) => {
+                self.shared.running.store(false, Ordering::Release);
+                Err(Error::new())
+            },
+        }
+    }
+
+    /// Stop the audio thread and wait for it to exit.
+    pub fn stop(&self) -> Result<()> {
+        self.halt()?;
+        if let Some(ref io) = *self.io.lock().unwrap() {
+            let stream = self.shared.stream.load(Ordering::Acquire);
+            (io.callbacks.state)(stream, io.callbacks.user_ptr, ffi::CUBEB_STATE_STOPPED);
+        }
+        Ok(())
+    }
+
+    fn halt(&self) -> Result<()> {
+        let mut thread = self.thread.lock().unwrap();
+        self.shared.running.store(false, Ordering::Release);
+        if let Some(handle) = thread.take() {
+            handle.thread().unpark();
+            let io = handle.join().map_err(|_| Error::new())?;
+            *self.io.lock().unwrap() = Some(io);
+        }
+        Ok(())
+    }
+
+    /// Whether the audio thread is running.
+    pub fn is_running(&self) -> bool {
+        self.shared.running.load(Ordering::Acquire)
+    }
+
+    /// Number of frames passed through the data callback so far.
+    pub fn position(&self) -> u64 {
+        self.shared.position.load(Ordering::Acquire)
+    }
+
+    /// Number of frames in each period.
+    pub fn period(&self) -> u32 {
+        match *self.io.lock().unwrap() {
+            Some(ref io) => io.period as u32,
+            None => 0,
+        }
+    }
+
+    /// Gain applied to the output before it reaches the sink.
+    pub fn volume(&self) -> f32 {
+        f32::from_bits(self.shared.volume.load(Ordering::Relaxed))
+    }
+
+    pub fn set_volume(&self, volume: f32) {
+        self.shared.volume.store(volume.to_bits(), Ordering::Relaxed);
+    }
+
+    /// Pan applied to the output before it reaches the sink. Only
+    /// stereo output can be panned.
+    pub fn set_panning(&self, panning: f32) -> Result<()> {
+        if !self.stereo {
+            return Err(Error::from(ErrorCode::NotSupported));
+        }
+        self.shared.panning.store(panning.to_bits(), Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+impl Drop for CallbackDriver {
+    fn drop(&mut self) {
+        // Don't signal STOPPED here, the user state may already be gone.
+        let _ = self.halt();
+    }
+}
+
+/// `Stream` implementation for backends whose streams are driven by a
+/// `CallbackDriver`.
+///
+/// The first field points at the owning context, as libcubeb requires.
+#[repr(C)]
+pub struct DriverStream {
+    context: *const c_void,
+    driver: CallbackDriver,
+    latency: u32,
+    input_name: Option<CString>,
+    output_name: Option<CString>
+}
+
+impl DriverStream {
+    /// Create a stream of `context`. `input_name` and `output_name` are
+    /// reported by `current_device`.
+    pub fn new<C>(
+        context: *const C,
+        driver: CallbackDriver,
+        latency: u32,
+        input_name: Option<CString>,
+        output_name: Option<CString>,
+    ) -> DriverStream {
+        DriverStream {
+            context: context as *const c_void,
+            driver,
+            latency,
+            input_name,
+            output_name
+        }
+    }
+
+    /// Box the stream for returning from `stream_init`.
+    pub fn into_raw(self) -> *mut ffi::cubeb_stream {
+        Box::into_raw(Box::new(self)) as *mut _
+    }
+
+    pub fn driver(&self) -> &CallbackDriver {
+        &self.driver
+    }
+}
+
+impl Stream for DriverStream {
+    fn start(&self) -> Result<()> {
+        self.driver.start(self as *const _ as *mut _)
+    }
+
+    fn stop(&self) -> Result<()> {
+        self.driver.stop()
+    }
+
+    fn reset_default_device(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn position(&self) -> Result<u64> {
+        Ok(self.driver.position())
+    }
+
+    fn latency(&self) -> Result<u32> {
+        Ok(self.latency)
+    }
+
+    fn set_volume(&self, volume: f32) -> Result<()> {
+        if !(0.0..=1.0).contains(&volume) {
+            return Err(Error::from(ErrorCode::InvalidParameter));
+        }
+        self.driver.set_volume(volume);
+        Ok(())
+    }
+
+    fn set_panning(&self, panning: f32) -> Result<()> {
+        if !(-1.0..=1.0).contains(&panning) {
+            return Err(Error::from(ErrorCode::InvalidParameter));
+        }
+        self.driver.set_panning(panning)
+    }
+
+    fn current_device(&self) -> Result<*const ffi::cubeb_device> {
+        let name = |n: &Option<CString>| n.as_ref().map_or(ptr::null(), |n| n.as_ptr());
+        let device = Box::new(ffi::cubeb_device {
+            output_name: name(&self.output_name),
+            input_name: name(&self.input_name)
+        });
+        Ok(Box::into_raw(device))
+    }
+
+    fn device_destroy(&self, device: *const ffi::cubeb_device) -> Result<()> {
+        if device.is_null() {
+            return Err(Error::from(ErrorCode::InvalidParameter));
+        }
+        let _ = unsafe { Box::from_raw(device as *mut ffi::cubeb_device) };
+        Ok(())
+    }
+
+    fn register_device_changed_callback(
+        &self,
+        _: Option<ffi::cubeb_device_changed_callback>,
+    ) -> Result<()> {
+        // The devices of driven streams never change, so the callback
+        // would never fire.
+        Ok(())
+    }
+}
+
+fn run(mut io: Io, shared: &Shared) -> Io {
+    let stream = shared.stream.load(Ordering::Acquire);
+    let (data_cb, state_cb, user_ptr) =
+        (io.callbacks.data, io.callbacks.state, io.callbacks.user_ptr);
+    let started = Instant::now();
+    let mut elapsed = 0u64;
+
+    while shared.running.load(Ordering::Acquire) {
+        let period = io.period;
+
+        let mut frames = period;
+        if let Some(ref mut input) = io.input {
+            match input.fill(period) {
+                Ok(n) => frames = n,
+                Err(_) => {
+                    shared.running.store(false, Ordering::Release);
+                    state_cb(stream, user_ptr, ffi::CUBEB_STATE_ERROR);
+                    break;
+                },
+            }
+        }
+
+        let got = if frames > 0 {
+            let input_ptr = io.input
+                .as_ref()
+                .map_or(ptr::null(), |i| i.buffers.stream.as_ptr() as *const c_void);
+            let output_ptr = io.output
+                .as_mut()
+                .map_or(ptr::null_mut(), |o| o.buffers.stream.as_mut_ptr() as *mut c_void);
+            data_cb(stream, user_ptr, input_ptr, output_ptr, frames as c_long)
+        } else {
+            0
+        };
+        if got < 0 || got as usize > frames {
+            shared.running.store(false, Ordering::Release);
+            state_cb(stream, user_ptr, ffi::CUBEB_STATE_ERROR);
+            break;
+        }
+        let got = got as usize;
+
+        if let Some(ref mut output) = io.output {
+            let volume = f32::from_bits(shared.volume.load(Ordering::Relaxed));
+            let panning = f32::from_bits(shared.panning.load(Ordering::Relaxed));
+            if got > 0 && output.flush(got, volume, panning).is_err() {
+                shared.running.store(false, Ordering::Release);
+                state_cb(stream, user_ptr, ffi::CUBEB_STATE_ERROR);
+                break;
+            }
+        }
+        shared.position.fetch_add(got as u64, Ordering::AcqRel);
+
+        if got < period {
+            let drained = io.output.as_mut().map_or(Ok(()), |o| o.sink.drain());
+            shared.running.store(false, Ordering::Release);
+            let state = if drained.is_ok() {
+                ffi::CUBEB_STATE_DRAINED
+            } else {
+                ffi::CUBEB_STATE_ERROR
+            };
+            state_cb(stream, user_ptr, state);
+            break;
+        }
+
+        if io.pacing == Pacing::RealTime {
+            elapsed += period as u64;
+            let deadline = started + frames_to_duration(elapsed, io.rate);
+            loop {
+                let now = Instant::now();
+                if now >= deadline || !shared.running.load(Ordering::Acquire) {
+                    break;
+                }
+                thread::park_timeout(deadline - now);
+            }
+        }
+    }
+
+    io
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cubeb_core::binding::Binding;
+    use std::time::Duration;
+
+    fn params(format: ffi::cubeb_sample_format, channels: u32) -> StreamParams {
+        let raw = ffi::cubeb_stream_params {
+            format,
+            rate: 48000,
+            channels,
+            layout: ffi::CUBEB_LAYOUT_UNDEFINED
+        };
+        unsafe { StreamParams::from_raw(&raw as *const _) }
+    }
+
+    #[derive(Default)]
+    struct User {
+        // Frames to produce before returning short.
+        remaining: Mutex<usize>,
+        states: Mutex<Vec<ffi::cubeb_state>>
+    }
+
+    extern "C" fn data_cb(
+        _: *mut ffi::cubeb_stream,
+        user_ptr: *mut c_void,
+        _: *const c_void,
+        output: *mut c_void,
+        nframes: c_long,
+    ) -> c_long {
+        let user = unsafe { &*(user_ptr as *const User) };
+        let mut remaining = user.remaining.lock().unwrap();
+        let n = (*remaining).min(nframes as usize);
+        *remaining -= n;
+        let out = unsafe { ::std::slice::from_raw_parts_mut(output as *mut i16, n) };
+        for x in out.iter_mut() {
+            *x = 0x100;
+        }
+        n as c_long
+    }
+
+    extern "C" fn state_cb(_: *mut ffi::cubeb_stream, user_ptr: *mut c_void, state: ffi::cubeb_state) {
+        let user = unsafe { &*(user_ptr as *const User) };
+        user.states.lock().unwrap().push(state);
+    }
+
+    struct VecSink(Arc<Mutex<Vec<u8>>>, Option<SampleFormat>);
+
+    impl Sink for VecSink {
+        fn format(&self) -> Option<SampleFormat> {
+            self.1
+        }
+        fn write(&mut self, buffer: &[u8], _: usize) -> Result<()> {
+            self.0.lock().unwrap().extend_from_slice(buffer);
+            Ok(())
+        }
+    }
+
+    fn wait_for_drain(driver: &CallbackDriver) {
+        while driver.is_running() {
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn test_convert_round_trip() {
+        let input: Vec<u8> = [0i16, 1, -1, 16384, i16::MAX, i16::MIN]
+            .iter()
+            .flat_map(|x| x.to_le_bytes().to_vec())
+            .collect();
+        let mut float = vec![0u8; input.len() * 2];
+        let mut back = vec![0u8; input.len()];
+        convert(SampleFormat::S16LE, &input, SampleFormat::Float32BE, &mut float);
+        assert_eq!(read_sample(SampleFormat::Float32BE, &float[12..]), 0.5);
+        convert(SampleFormat::Float32BE, &float, SampleFormat::S16LE, &mut back);
+        assert_eq!(input, back);
+    }
+
+    #[test]
+    fn test_drain_and_position() {
+        let user = User::default();
+        *user.remaining.lock().unwrap() = 250;
+        let data = Arc::new(Mutex::new(Vec::new()));
+        let driver = CallbackDriverBuilder::new()
+            .output(&params(ffi::CUBEB_SAMPLE_S16NE, 2), VecSink(data.clone(), None))
+            .period(100)
+            .pacing(Pacing::Unpaced)
+            .build(data_cb, state_cb, &user as *const _ as *mut _)
+            .unwrap();
+
+        driver.start(ptr::null_mut()).unwrap();
+        wait_for_drain(&driver);
+        driver.stop().unwrap();
+
+        assert_eq!(driver.position(), 250);
+        assert_eq!(data.lock().unwrap().len(), 250 * 4);
+        assert_eq!(
+            *user.states.lock().unwrap(),
+            vec![
+                ffi::CUBEB_STATE_STARTED,
+                ffi::CUBEB_STATE_DRAINED,
+                ffi::CUBEB_STATE_STOPPED,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_stop_and_sink_format() {
+        let user = User::default();
+        *user.remaining.lock().unwrap() = usize::MAX;
+        let data = Arc::new(Mutex::new(Vec::new()));
+        let driver = CallbackDriverBuilder::new()
+            .output(
+                &params(ffi::CUBEB_SAMPLE_S16LE, 1),
+                VecSink(data.clone(), Some(SampleFormat::Float32LE))
+            )
+            .period(64)
+            .build(data_cb, state_cb, &user as *const _ as *mut _)
+            .unwrap();
+        driver.set_volume(0.5);
+
+        driver.start(ptr::null_mut()).unwrap();
+        thread::sleep(Duration::from_millis(20));
+        driver.stop().unwrap();
+
+        let position = driver.position();
+        assert!(position > 0 && position.is_multiple_of(64));
+        let data = data.lock().unwrap();
+        assert_eq!(data.len() as u64, position * 4);
+        assert_eq!(read_sample(SampleFormat::Float32LE, &data[..4]), 0x80 as f32 / 32768.0);
+        assert_eq!(
+            *user.states.lock().unwrap(),
+            vec![ffi::CUBEB_STATE_STARTED, ffi::CUBEB_STATE_STOPPED]
+        );
+    }
+
+    #[test]
+    fn test_panning() {
+        let stereo: Vec<u8> = [0.5f32, 0.25]
+            .iter()
+            .flat_map(|x| x.to_bits().to_le_bytes().to_vec())
+            .collect();
+        let mut left = stereo.clone();
+        apply_panning(SampleFormat::Float32LE, &mut left, -1.0);
+        assert_eq!(read_sample(SampleFormat::Float32LE, &left[..4]), 0.75);
+        assert!(read_sample(SampleFormat::Float32LE, &left[4..]).abs() < 1e-6);
+        let mut centre = stereo.clone();
+        apply_panning(SampleFormat::Float32LE, &mut centre, 0.0);
+        assert_eq!(centre, stereo);
+
+        let user = User::default();
+        *user.remaining.lock().unwrap() = 100;
+        let data = Arc::new(Mutex::new(Vec::new()));
+        let driver = CallbackDriverBuilder::new()
+            .output(&params(ffi::CUBEB_SAMPLE_S16NE, 2), VecSink(data.clone(), None))
+            .period(100)
+            .pacing(Pacing::Unpaced)
+            .build(data_cb, state_cb, &user as *const _ as *mut _)
+            .unwrap();
+        driver.set_panning(1.0).unwrap();
+        driver.start(ptr::null_mut()).unwrap();
+        wait_for_drain(&driver);
+        driver.stop().unwrap();
+        let data = data.lock().unwrap();
+        assert_eq!(data.len(), 100 * 4);
+        assert_eq!(read_sample(SampleFormat::S16NE, &data[..2]), 0.0);
+        assert_eq!(read_sample(SampleFormat::S16NE, &data[2..]), 0x200 as f32 / 32768.0);
+
+        let mono = CallbackDriverBuilder::new()
+            .output(&params(ffi::CUBEB_SAMPLE_S16NE, 1), VecSink(Arc::default(), None))
+            .build(data_cb, state_cb, ptr::null_mut())
+            .unwrap();
+        assert_eq!(mono.set_panning(0.5).unwrap_err().code(), ErrorCode::NotSupported);
+    }
+
+    #[test]
+    fn test_mismatched_rates() {
+        struct Silence;
+        impl Source for Silence {
+            fn read(&mut self, _: &mut [u8], frames: usize) -> Result<usize> {
+                Ok(frames)
+            }
+        }
+        let mut raw = ffi::cubeb_stream_params {
+            format: ffi::CUBEB_SAMPLE_S16NE,
+            rate: 44100,
+            channels: 1,
+            layout: ffi::CUBEB_LAYOUT_MONO
+        };
+        let input = unsafe { StreamParams::from_raw(&raw as *const _) };
+        raw.rate = 48000;
+        let output = unsafe { StreamParams::from_raw(&raw as *const _) };
+        let data = Arc::new(Mutex::new(Vec::new()));
+        let err = CallbackDriverBuilder::new()
+            .input(&input, Silence)
+            .output(&output, VecSink(data, None))
+            .build(data_cb, state_cb, ptr::null_mut())
+            .err()
+            .unwrap();
+        assert_eq!(err.code(), ErrorCode::InvalidFormat);
+    }
+}
@@ -0,0 +1,481 @@
+// Copyright © 2017 Mozilla Foundation
+//
+// This program is made available under an ISC-style license.  See the
+// accompanying file LICENSE for details.
+
+//! A backend that wraps another and injects faults on demand.
+//!
+//! Contexts created by `FaultContext::wrap` forward every operation to
+//! the wrapped backend, except where a `FaultHandle` has scripted a
+//! failure. Tests keep a clone of the handle to provoke the errors,
+//! stalls and notifications real devices produce only occasionally.
+
+use {Context, Ops, Stream};
+use cubeb_core::{DeviceId, DeviceType, Error, ErrorCode, Result, StreamParams};
+use cubeb_core::binding::Binding;
+use cubeb_core::ffi;
+use driver;
+use null;
+use raw::{RawContext, RawStream};
+use std::ffi::CStr;
+use std::os::raw::{c_long, c_void};
+use std::ptr;
+use std::slice;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicPtr, AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+pub const OPS: Ops = capi_new!(FaultContext, FaultStream);
+
+/// How `position()` misreports the stream position.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PositionFault {
+    /// Add a signed offset to the real position.
+    Offset(i64),
+    /// Always report this position.
+    Fixed(u64),
+    /// Fail with this error.
+    Fail(ErrorCode)
+}
+
+#[derive(Default)]
+struct Script {
+    stream_init_calls: usize,
+    // Call numbers of `stream_init` that fail, and how.
+    stream_init_failures: Vec<(usize, ErrorCode)>,
+    start_failure: Option<ErrorCode>,
+    stop_failure: Option<ErrorCode>,
+    position: Option<PositionFault>,
+    drop_until: Option<Instant>,
+    stall: Option<Duration>
+}
+
+#[derive(Default)]
+struct Faults {
+    script: Mutex<Script>,
+    stream_error: AtomicBool,
+    dropped_callbacks: AtomicUsize,
+    // Live contexts and streams, for firing notifications.
+    contexts: Mutex<Vec<usize>>,
+    streams: Mutex<Vec<usize>>
+}
+
+/// Scripts the faults of every context wrapped with it. Clones share
+/// the same script.
+#[derive(Clone, Default)]
+pub struct FaultHandle {
+    faults: Arc<Faults>
+}
+
+impl FaultHandle {
+    pub fn new() -> FaultHandle {
+        FaultHandle::default()
+    }
+
+    fn script(&self) -> ::std::sync::MutexGuard<'_, Script> {
+        self.faults.script.lock().unwrap()
+    }
+
+    /// Make the `nth` call to `stream_init` from now on fail with
+    /// `error`, counting from 1.
+    pub fn fail_stream_init(&self, nth: usize, error: ErrorCode) {
+        let mut script = self.script();
+        let call = script.stream_init_calls + nth;
+        script.stream_init_failures.push((call, error));
+    }
+
+    /// Number of `stream_init` calls so far.
+    pub fn stream_init_calls(&self) -> usize {
+        self.script().stream_init_calls
+    }
+
+    /// Make the next `start` fail with `error`, leaving the stream
+    /// stopped.
+    pub fn fail_next_start(&self, error: ErrorCode) {
+        self.script().start_failure = Some(error);
+    }
+
+    /// Make the next `stop` fail with `error`, leaving the stream
+    /// running.
+    pub fn fail_next_stop(&self, error: ErrorCode) {
+        self.script().stop_failure = Some(error);
+    }
+
+    /// Fail the next data callback of any stream, so the wrapped backend
+    /// reports `CUBEB_STATE_ERROR`.
+    pub fn inject_stream_error(&self) {
+        self.faults.stream_error.store(true, Ordering::Release);
+    }
+
+    /// Skip the application's data callbacks for `duration`, playing
+    /// silence and discarding input instead.
+    pub fn drop_callbacks(&self, duration: Duration) {
+        self.script().drop_until = Some(Instant::now() + duration);
+    }
+
+    /// Number of data callbacks skipped by `drop_callbacks`.
+    pub fn dropped_callbacks(&self) -> usize {
+        self.faults.dropped_callbacks.load(Ordering::Acquire)
+    }
+
+    /// Block the next data callback of any stream for `duration` before
+    /// running the application's callback.
+    pub fn stall_next_callback(&self, duration: Duration) {
+        self.script().stall = Some(duration);
+    }
+
+    /// Misreport the position of every stream, or stop doing so with
+    /// `None`.
+    pub fn corrupt_position(&self, fault: Option<PositionFault>) {
+        self.script().position = fault;
+    }
+
+    /// Call the device changed callback of every stream that registered
+    /// one. The callbacks must not use the handle.
+    pub fn fire_device_changed(&self) {
+        let streams = self.faults.streams.lock().unwrap();
+        for &stream in streams.iter() {
+            let state = unsafe { &*(stream as *const StreamState) };
+            state.device_changed();
+        }
+    }
+
+    /// Call the device collection changed callback of every context that
+    /// registered one. The callbacks must not use the handle.
+    pub fn fire_collection_changed(&self) {
+        let contexts = self.faults.contexts.lock().unwrap();
+        for &context in contexts.iter() {
+            let context = unsafe { &*(context as *const FaultContext) };
+            context.collection_changed();
+        }
+    }
+}
+
+fn register(list: &Mutex<Vec<usize>>, item: usize) {
+    list.lock().unwrap().push(item);
+}
+
+fn unregister(list: &Mutex<Vec<usize>>, item: usize) {
+    list.lock().unwrap().retain(|&i| i != item);
+}
+
+type CollectionChanged = (ffi::cubeb_device_collection_changed_callback, *mut c_void);
+
+#[repr(C)]
+pub struct FaultContext {
+    ops: *const Ops,
+    inner: RawContext,
+    handle: FaultHandle,
+    collection_changed: Mutex<Option<CollectionChanged>>
+}
+
+impl FaultContext {
+    /// Wrap `inner`, injecting the faults scripted through `handle`.
+    pub fn wrap(inner: RawContext, handle: &FaultHandle) -> *mut ffi::cubeb {
+        let ctx = Box::into_raw(Box::new(FaultContext {
+            ops: &OPS as *const _,
+            inner,
+            handle: handle.clone(),
+            collection_changed: Mutex::new(None)
+        }));
+        register(&handle.faults.contexts, ctx as usize);
+        ctx as *mut _
+    }
+
+    fn collection_changed(&self) {
+        if let Some((callback, user_ptr)) = *self.collection_changed.lock().unwrap() {
+            callback(self as *const _ as *mut _, user_ptr);
+        }
+    }
+}
+
+impl Drop for FaultContext {
+    fn drop(&mut self) {
+        unregister(&self.handle.faults.contexts, self as *const _ as usize);
+    }
+}
+
+impl Context for FaultContext {
+    /// Wrap the null backend. Its faults can't be scripted.
+    fn init(context_name: Option<&CStr>) -> Result<*mut ffi::cubeb> {
+        let inner = RawContext::init(&null::OPS, context_name)?;
+        Ok(FaultContext::wrap(inner, &FaultHandle::new()))
+    }
+
+    fn backend_id(&self) -> &'static CStr {
+        unsafe { CStr::from_ptr(b"fault\0".as_ptr() as *const _) }
+    }
+
+    fn max_channel_count(&self) -> Result<u32> {
+        self.inner.max_channel_count()
+    }
+
+    fn min_latency(&self, params: &StreamParams) -> Result<u32> {
+        self.inner.min_latency(params)
+    }
+
+    fn preferred_sample_rate(&self) -> Result<u32> {
+        self.inner.preferred_sample_rate()
+    }
+
+    fn preferred_channel_layout(&self) -> Result<ffi::cubeb_channel_layout> {
+        self.inner.preferred_channel_layout()
+    }
+
+    fn enumerate_devices(
+        &self,
+        devtype: DeviceType,
+    ) -> Result<ffi::cubeb_device_collection> {
+        self.inner.enumerate_devices(devtype)
+    }
+
+    #[allow(clippy::not_unsafe_ptr_arg_deref)]
+    fn device_collection_destroy(
+        &self,
+        collection: *mut ffi::cubeb_device_collection,
+    ) {
+        let _ = self.inner
+            .device_collection_destroy(unsafe { &mut *collection });
+    }
+
+    fn stream_init(
+        &self,
+        stream_name: Option<&CStr>,
+        input_device: DeviceId,
+        input_stream_params: Option<&ffi::cubeb_stream_params>,
+        output_device: DeviceId,
+        output_stream_params: Option<&ffi::cubeb_stream_params>,
+        latency_frames: u32,
+        data_callback: ffi::cubeb_data_callback,
+        state_callback: ffi::cubeb_state_callback,
+        user_ptr: *mut c_void,
+    ) -> Result<*mut ffi::cubeb_stream> {
+        {
+            let mut script = self.handle.script();
+            script.stream_init_calls += 1;
+            let call = script.stream_init_calls;
+            if let Some(i) = script
+                .stream_init_failures
+                .iter()
+                .position(|&(n, _)| n == call)
+            {
+                let (_, error) = script.stream_init_failures.remove(i);
+                return Err(Error::from(error));
+            }
+        }
+
+        let output_frame = output_stream_params
+            .map_or(0, |p| driver::frame_size(&unsafe { StreamParams::from_raw(p) }));
+        let state = Box::new(StreamState {
+            handle: self.handle.clone(),
+            stream: AtomicPtr::new(ptr::null_mut()),
+            output_frame,
+            data_callback,
+            state_callback,
+            user_ptr,
+            device_changed: Mutex::new(None)
+        });
+        let inner = unsafe {
+            self.inner.stream_init(
+                stream_name,
+                input_device,
+                input_stream_params,
+                output_device,
+                output_stream_params,
+                latency_frames,
+                data_cb,
+                state_cb,
+                &*state as *const StreamState as *mut c_void
+            )?
+        };
+
+        let stm = Box::into_raw(Box::new(FaultStream {
+            context: self,
+            inner,
+            state
+        }));
+        unsafe {
+            (*stm).state.stream.store(stm as *mut _, Ordering::Release);
+            register(&self.handle.faults.streams, &*(*stm).state as *const _ as usize);
+        }
+        Ok(stm as *mut _)
+    }
+
+    fn register_device_collection_changed(
+        &self,
+        devtype: DeviceType,
+        cb: Option<ffi::cubeb_device_collection_changed_callback>,
+        user_ptr: *mut c_void,
+    ) -> Result<()> {
+        *self.collection_changed.lock().unwrap() = cb.map(|cb| (cb, user_ptr));
+        let this = self as *const FaultContext as *mut c_void;
+        // Unregister from the wrapped backend too when `cb` is `None`.
+        let forward = cb.map(|_| collection_changed_cb as _);
+        // Faults can still be fired if the wrapped backend never notifies.
+        match unsafe {
+            self.inner
+                .register_device_collection_changed(devtype, forward, this)
+        } {
+            Err(e) if e.code() == ErrorCode::NotSupported => Ok(()),
+            r => r,
+        }
+    }
+}
+
+// Shared with the wrapped stream's callbacks.
+struct StreamState {
+    handle: FaultHandle,
+    stream: AtomicPtr<ffi::cubeb_stream>,
+    output_frame: usize,
+    data_callback: ffi::cubeb_data_callback,
+    state_callback: ffi::cubeb_state_callback,
+    user_ptr: *mut c_void,
+    device_changed: Mutex<Option<ffi::cubeb_device_changed_callback>>
+}
+
+impl StreamState {
+    fn device_changed(&self) {
+        if let Some(callback) = *self.device_changed.lock().unwrap() {
+            callback(self.user_ptr);
+        }
+    }
+}
+
+/// Fault injecting stream.
+///
+/// The first field points at the owning context, as libcubeb requires.
+#[repr(C)]
+pub struct FaultStream {
+    context: *const FaultContext,
+    // Destroyed before `state`, which its callbacks use.
+    inner: RawStream,
+    state: Box<StreamState>
+}
+
+impl Drop for FaultStream {
+    fn drop(&mut self) {
+        let context = unsafe { &*self.context };
+        unregister(&context.handle.faults.streams, &*self.state as *const _ as usize);
+    }
+}
+
+impl Stream for FaultStream {
+    fn start(&self) -> Result<()> {
+        if let Some(error) = self.state.handle.script().start_failure.take() {
+            return Err(Error::from(error));
+        }
+        self.inner.start()
+    }
+
+    fn stop(&self) -> Result<()> {
+        if let Some(error) = self.state.handle.script().stop_failure.take() {
+            return Err(Error::from(error));
+        }
+        self.inner.stop()
+    }
+
+    fn reset_default_device(&self) -> Result<()> {
+        self.inner.reset_default_device()
+    }
+
+    fn position(&self) -> Result<u64> {
+        let fault = self.state.handle.script().position;
+        let position = self.inner.position()?;
+        match fault {
+            None => Ok(position),
+            Some(PositionFault::Offset(offset)) => Ok(position.saturating_add_signed(offset)),
+            Some(PositionFault::Fixed(position)) => Ok(position),
+            Some(PositionFault::Fail(error)) => Err(Error::from(error)),
+        }
+    }
+
+    fn latency(&self) -> Result<u32> {
+        self.inner.latency()
+    }
+
+    fn set_volume(&self, volume: f32) -> Result<()> {
+        self.inner.set_volume(volume)
+    }
+
+    fn set_panning(&self, panning: f32) -> Result<()> {
+        self.inner.set_panning(panning)
+    }
+
+    fn current_device(&self) -> Result<*const ffi::cubeb_device> {
+        self.inner.current_device()
+    }
+
+    #[allow(clippy::not_unsafe_ptr_arg_deref)]
+    fn device_destroy(&self, device: *const ffi::cubeb_device) -> Result<()> {
+        unsafe { self.inner.device_destroy(device) }
+    }
+
+    fn register_device_changed_callback(
+        &self,
+        device_changed_callback: Option<ffi::cubeb_device_changed_callback>,
+    ) -> Result<()> {
+        *self.state.device_changed.lock().unwrap() = device_changed_callback;
+        let forward = device_changed_callback.map(|_| device_changed_cb as _);
+        match self.inner.register_device_changed_callback(forward) {
+            Err(e) if e.code() == ErrorCode::NotSupported => Ok(()),
+            r => r,
+        }
+    }
+}
+
+extern "C" fn data_cb(
+    _: *mut ffi::cubeb_stream,
+    user_ptr: *mut c_void,
+    input_buffer: *const c_void,
+    output_buffer: *mut c_void,
+    nframes: c_long,
+) -> c_long {
+    let state = unsafe { &*(user_ptr as *const StreamState) };
+    let faults = &state.handle.faults;
+    if faults.stream_error.swap(false, Ordering::AcqRel) {
+        return ffi::CUBEB_ERROR as c_long;
+    }
+
+    let (dropping, stall) = {
+        let mut script = state.handle.script();
+        let dropping = script.drop_until.is_some_and(|until| Instant::now() < until);
+        if !dropping {
+            script.drop_until = None;
+        }
+        (dropping, script.stall.take())
+    };
+    if let Some(stall) = stall {
+        thread::sleep(stall);
+    }
+    if dropping {
+        faults.dropped_callbacks.fetch_add(1, Ordering::AcqRel);
+        if !output_buffer.is_null() {
+            let len = nframes.max(0) as usize * state.output_frame;
+            let output = unsafe { slice::from_raw_parts_mut(output_buffer as *mut u8, len) };
+            for x in output.iter_mut() {
+                *x = 0;
+            }
+        }
+        return nframes;
+    }
+
+    let stream = state.stream.load(Ordering::Acquire);
+    (state.data_callback)(stream, state.user_ptr, input_buffer, output_buffer, nframes)
+}
+
+extern "C" fn state_cb(_: *mut ffi::cubeb_stream, user_ptr: *mut c_void, s: ffi::cubeb_state) {
+    let state = unsafe { &*(user_ptr as *const StreamState) };
+    let stream = state.stream.load(Ordering::Acquire);
+    (state.state_callback)(stream, state.user_ptr, s);
+}
+
+extern "C" fn device_changed_cb(user_ptr: *mut c_void) {
+    let state = unsafe { &*(user_ptr as *const StreamState) };
+    state.device_changed();
+}
+
+extern "C" fn collection_changed_cb(_: *mut ffi::cubeb, user_ptr: *mut c_void) {
+    let context = unsafe { &*(user_ptr as *const FaultContext) };
+    context.collection_changed();
+}
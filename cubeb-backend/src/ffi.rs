@@ -95,11 +95,12 @@ pub type StreamDeviceDestroyFn =
                      device: *const ffi::cubeb_device) -> c_int;
 pub type StreamRegisterDeviceChangedCallbackFn =
     unsafe extern fn(stream: *mut ffi::cubeb_stream,
-                     device_changed_callback: ffi::cubeb_device_changed_callback) -> c_int;
+                     device_changed_callback: Option<ffi::cubeb_device_changed_callback>)
+                     -> c_int;
 pub type RegisterDeviceCollectionChangedFn =
     unsafe extern fn(context: *mut ffi::cubeb,
                      devtype: ffi::cubeb_device_type,
-                     callback: ffi::cubeb_device_collection_changed_callback,
+                     callback: Option<ffi::cubeb_device_collection_changed_callback>,
                      user_ptr: *mut c_void) -> c_int;
 
 #[repr(C)]
@@ -157,7 +158,7 @@ pub struct Ops {
     pub stream_register_device_changed_callback: Option<
         unsafe extern fn(stream: *mut ffi::cubeb_stream,
                          device_changed_callback:
-                         ffi::cubeb_device_changed_callback)
+                         Option<ffi::cubeb_device_changed_callback>)
                          -> c_int>,
     pub register_device_collection_changed: Option<
             RegisterDeviceCollectionChangedFn>
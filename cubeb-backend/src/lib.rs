@@ -4,10 +4,27 @@
 // accompanying file LICENSE for details.
 
 extern crate cubeb_core;
+#[cfg(unix)]
+extern crate libc;
 
 pub mod ffi;
+#[macro_use]
 pub mod capi;
+mod device;
+pub mod driver;
+pub mod fault;
+pub mod null;
+pub mod raw;
+#[cfg(unix)]
+pub mod remote;
+pub mod tee;
+pub mod trace;
 mod traits;
+mod util;
+pub mod wav;
 
+pub use cubeb_core::ring;
+pub use device::DeviceInfo;
+pub use driver::{CallbackDriver, CallbackDriverBuilder, DriverStream, Pacing, Sink, Source};
 pub use ffi::Ops;
 pub use traits::{Context, Stream};
@@ -0,0 +1,297 @@
+// Copyright © 2017 Mozilla Foundation
+//
+// This program is made available under an ISC-style license.  See the
+// accompanying file LICENSE for details.
+
+//! A backend that behaves like a real device but makes no sound.
+//!
+//! Output is discarded and input is silence, both paced by wall-clock
+//! time at the requested rate, so the full stream life cycle can be
+//! exercised on machines without audio hardware.
+
+use {Context, Ops};
+use cubeb_core::{ChannelLayout, DeviceId, DeviceType, Error, ErrorCode, Result,
+                 StreamParams, DEVICE_TYPE_INPUT, DEVICE_TYPE_OUTPUT};
+use cubeb_core::binding::Binding;
+use cubeb_core::ffi;
+use driver::{CallbackDriverBuilder, DriverStream, Pacing, Sink, Source};
+use util;
+use std::ffi::{CStr, CString};
+use std::os::raw::c_void;
+use std::ptr;
+
+pub const OPS: Ops = capi_new!(NullContext, NullStream);
+
+/// A fake device exposed through `enumerate_devices`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct NullDevice {
+    pub name: String,
+    pub channels: u32,
+    pub default_rate: u32,
+    pub min_rate: u32,
+    pub max_rate: u32,
+    pub latency_lo: u32,
+    pub latency_hi: u32
+}
+
+impl NullDevice {
+    pub fn new<S: Into<String>>(name: S, channels: u32) -> NullDevice {
+        NullDevice {
+            name: name.into(),
+            channels,
+            default_rate: 48_000,
+            min_rate: 8_000,
+            max_rate: 192_000,
+            latency_lo: 64,
+            latency_hi: 8192
+        }
+    }
+}
+
+/// Null backend configuration.
+#[derive(Clone, Debug)]
+pub struct Config {
+    pub max_channels: u32,
+    pub preferred_rate: u32,
+    pub preferred_layout: ChannelLayout,
+    pub min_latency: u32,
+    /// The first device of each list is the default device.
+    pub input_devices: Vec<NullDevice>,
+    pub output_devices: Vec<NullDevice>,
+    pub pacing: Pacing
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            max_channels: 2,
+            preferred_rate: 48_000,
+            preferred_layout: ChannelLayout::Stereo,
+            min_latency: 256,
+            input_devices: vec![NullDevice::new("Null Input", 2)],
+            output_devices: vec![NullDevice::new("Null Output", 2)],
+            pacing: Pacing::RealTime
+        }
+    }
+}
+
+struct DeviceEntry {
+    device: NullDevice,
+    devtype: DeviceType,
+    devid: ffi::cubeb_devid,
+    device_id: CString,
+    friendly_name: CString
+}
+
+#[repr(C)]
+pub struct NullContext {
+    ops: *const Ops,
+    config: Config,
+    devices: Vec<DeviceEntry>
+}
+
+impl NullContext {
+    /// Create a null backend context using `config`.
+    pub fn init_with_config(config: Config) -> Result<*mut ffi::cubeb> {
+        let entries = |devices: &[NullDevice], devtype: DeviceType| -> Result<Vec<DeviceEntry>> {
+            let kind = if devtype == DEVICE_TYPE_INPUT {
+                "input"
+            } else {
+                "output"
+            };
+            devices
+                .iter()
+                .enumerate()
+                .map(|(i, d)| {
+                    Ok(DeviceEntry {
+                        device: d.clone(),
+                        devtype,
+                        devid: util::devid(i, devtype),
+                        device_id: CString::new(format!("null:{}:{}", kind, i))?,
+                        friendly_name: CString::new(d.name.clone())?
+                    })
+                })
+                .collect()
+        };
+        let mut devices = entries(&config.input_devices, DEVICE_TYPE_INPUT)?;
+        devices.extend(entries(&config.output_devices, DEVICE_TYPE_OUTPUT)?);
+
+        let ctx = Box::new(NullContext {
+            ops: &OPS as *const _,
+            config,
+            devices
+        });
+        Ok(Box::into_raw(ctx) as *mut _)
+    }
+
+    // Resolve a requested device, where null selects the default.
+    fn find_device(&self, devid: DeviceId, devtype: DeviceType) -> Result<&DeviceEntry> {
+        let mut devices = self.devices.iter().filter(|d| d.devtype == devtype);
+        let found = if devid.raw().is_null() {
+            devices.next()
+        } else {
+            devices.find(|d| d.devid == devid.raw())
+        };
+        found.ok_or_else(|| Error::from(ErrorCode::DeviceUnavailable))
+    }
+
+    fn check_params(&self, device: &NullDevice, params: &ffi::cubeb_stream_params) -> Result<StreamParams> {
+        let valid_format = matches!(
+            params.format,
+            ffi::CUBEB_SAMPLE_S16LE |
+                ffi::CUBEB_SAMPLE_S16BE |
+                ffi::CUBEB_SAMPLE_FLOAT32LE |
+                ffi::CUBEB_SAMPLE_FLOAT32BE
+        );
+        let max_channels = device.channels.min(self.config.max_channels);
+        if !valid_format || params.channels == 0 || params.channels > max_channels ||
+            params.rate < device.min_rate || params.rate > device.max_rate
+        {
+            return Err(Error::from(ErrorCode::InvalidFormat));
+        }
+        Ok(unsafe { StreamParams::from_raw(params as *const _) })
+    }
+}
+
+impl Context for NullContext {
+    fn init(_context_name: Option<&CStr>) -> Result<*mut ffi::cubeb> {
+        NullContext::init_with_config(Config::default())
+    }
+
+    fn backend_id(&self) -> &'static CStr {
+        unsafe { CStr::from_ptr(b"null\0".as_ptr() as *const _) }
+    }
+
+    fn max_channel_count(&self) -> Result<u32> {
+        Ok(self.config.max_channels)
+    }
+
+    fn min_latency(&self, _params: &StreamParams) -> Result<u32> {
+        Ok(self.config.min_latency)
+    }
+
+    fn preferred_sample_rate(&self) -> Result<u32> {
+        Ok(self.config.preferred_rate)
+    }
+
+    fn preferred_channel_layout(&self) -> Result<ffi::cubeb_channel_layout> {
+        Ok(self.config.preferred_layout as _)
+    }
+
+    fn enumerate_devices(
+        &self,
+        devtype: DeviceType,
+    ) -> Result<ffi::cubeb_device_collection> {
+        let infos: Vec<ffi::cubeb_device_info> = self.devices
+            .iter()
+            .filter(|d| devtype.contains(d.devtype))
+            .map(|d| {
+                let first = self.devices
+                    .iter()
+                    .find(|e| e.devtype == d.devtype)
+                    .is_some_and(|e| e.devid == d.devid);
+                ffi::cubeb_device_info {
+                    devid: d.devid,
+                    device_id: d.device_id.as_ptr(),
+                    friendly_name: d.friendly_name.as_ptr(),
+                    group_id: d.device_id.as_ptr(),
+                    vendor_name: ptr::null(),
+                    device_type: d.devtype.bits(),
+                    state: ffi::CUBEB_DEVICE_STATE_ENABLED,
+                    preferred: if first {
+                        ffi::CUBEB_DEVICE_PREF_ALL
+                    } else {
+                        ffi::CUBEB_DEVICE_PREF_NONE
+                    },
+                    format: ffi::CUBEB_DEVICE_FMT_ALL,
+                    default_format: ffi::CUBEB_DEVICE_FMT_F32NE,
+                    max_channels: d.device.channels,
+                    default_rate: d.device.default_rate,
+                    max_rate: d.device.max_rate,
+                    min_rate: d.device.min_rate,
+                    latency_lo: d.device.latency_lo,
+                    latency_hi: d.device.latency_hi
+                }
+            })
+            .collect();
+        Ok(util::device_collection(infos))
+    }
+
+    #[allow(clippy::not_unsafe_ptr_arg_deref)]
+    fn device_collection_destroy(
+        &self,
+        collection: *mut ffi::cubeb_device_collection,
+    ) {
+        unsafe { util::destroy_device_collection(collection) }
+    }
+
+    fn stream_init(
+        &self,
+        _stream_name: Option<&CStr>,
+        input_device: DeviceId,
+        input_stream_params: Option<&ffi::cubeb_stream_params>,
+        output_device: DeviceId,
+        output_stream_params: Option<&ffi::cubeb_stream_params>,
+        latency_frames: u32,
+        data_callback: ffi::cubeb_data_callback,
+        state_callback: ffi::cubeb_state_callback,
+        user_ptr: *mut c_void,
+    ) -> Result<*mut ffi::cubeb_stream> {
+        let mut builder = CallbackDriverBuilder::new();
+        let mut latency = latency_frames.max(self.config.min_latency);
+        let mut input_name = None;
+        let mut output_name = None;
+
+        if let Some(params) = input_stream_params {
+            let device = self.find_device(input_device, DEVICE_TYPE_INPUT)?;
+            builder.input(&self.check_params(&device.device, params)?, NullSource);
+            latency = latency.max(device.device.latency_lo).min(device.device.latency_hi);
+            input_name = Some(device.friendly_name.clone());
+        }
+        if let Some(params) = output_stream_params {
+            let device = self.find_device(output_device, DEVICE_TYPE_OUTPUT)?;
+            builder.output(&self.check_params(&device.device, params)?, NullSink);
+            latency = latency.max(device.device.latency_lo).min(device.device.latency_hi);
+            output_name = Some(device.friendly_name.clone());
+        }
+
+        let driver = builder
+            .period(latency)
+            .pacing(self.config.pacing)
+            .build(data_callback, state_callback, user_ptr)?;
+        Ok(DriverStream::new(self, driver, latency, input_name, output_name).into_raw())
+    }
+
+    fn register_device_collection_changed(
+        &self,
+        _devtype: DeviceType,
+        _cb: Option<ffi::cubeb_device_collection_changed_callback>,
+        _user_ptr: *mut c_void,
+    ) -> Result<()> {
+        // The fake devices are fixed by the `Config`, so there is nothing
+        // to notify about.
+        Err(Error::from(ErrorCode::NotSupported))
+    }
+}
+
+struct NullSink;
+
+impl Sink for NullSink {
+    fn write(&mut self, _: &[u8], _: usize) -> Result<()> {
+        Ok(())
+    }
+}
+
+struct NullSource;
+
+impl Source for NullSource {
+    fn read(&mut self, buffer: &mut [u8], frames: usize) -> Result<usize> {
+        for x in buffer.iter_mut() {
+            *x = 0;
+        }
+        Ok(frames)
+    }
+}
+
+/// Null backend stream.
+pub type NullStream = DriverStream;
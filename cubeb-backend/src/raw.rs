@@ -0,0 +1,316 @@
+// Copyright © 2017 Mozilla Foundation
+//
+// This program is made available under an ISC-style license.  See the
+// accompanying file LICENSE for details.
+
+//! Owning handles to the contexts and streams of any backend.
+//!
+//! Every call goes through the `Ops` table the context points at, so the
+//! backend may be written in C or Rust. Missing operations return
+//! `ErrorCode::NotSupported`.
+
+use Ops;
+use cubeb_core::{DeviceId, DeviceType, Error, ErrorCode, Result, StreamParams};
+use cubeb_core::binding::Binding;
+use cubeb_core::ffi;
+use std::ffi::CStr;
+use std::mem;
+use std::os::raw::{c_int, c_void};
+use std::ptr;
+
+fn check(r: c_int, operation: &'static str) -> Result<()> {
+    if r == ffi::CUBEB_OK {
+        Ok(())
+    } else {
+        Err(unsafe { Error::from_raw(r) }.with_operation(operation))
+    }
+}
+
+macro_rules! call {
+    ($ops:expr, $f:ident($($arg:expr),*)) => {
+        match $ops.$f {
+            Some(f) => check(unsafe { f($($arg),*) }, stringify!($f)),
+            None => {
+                Err(Error::from(ErrorCode::NotSupported).with_operation(stringify!($f)))
+            },
+        }
+    };
+}
+
+// libcubeb requires the first field of every context to be its ops
+// table, and the first field of every stream to be its context.
+unsafe fn context_ops<'a>(context: *mut ffi::cubeb) -> &'a Ops {
+    &**(context as *const *const Ops)
+}
+
+/// An initialized context, destroyed on drop.
+pub struct RawContext {
+    ptr: *mut ffi::cubeb
+}
+
+impl RawContext {
+    /// Initialize a context of the backend implementing `ops`.
+    pub fn init(ops: &Ops, context_name: Option<&CStr>) -> Result<RawContext> {
+        let mut ptr = ptr::null_mut();
+        let name = context_name.map_or(ptr::null(), |n| n.as_ptr());
+        call!(ops, init(&mut ptr, name))?;
+        Ok(RawContext {
+            ptr
+        })
+    }
+
+    /// Take ownership of an initialized context.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must be a context created by a backend's `init`, not owned
+    /// by anything else.
+    pub unsafe fn from_ptr(ptr: *mut ffi::cubeb) -> RawContext {
+        RawContext {
+            ptr
+        }
+    }
+
+    pub fn as_ptr(&self) -> *mut ffi::cubeb {
+        self.ptr
+    }
+
+    /// Give up ownership without destroying the context.
+    pub fn into_ptr(self) -> *mut ffi::cubeb {
+        let ptr = self.ptr;
+        mem::forget(self);
+        ptr
+    }
+
+    pub fn ops(&self) -> &Ops {
+        unsafe { context_ops(self.ptr) }
+    }
+
+    pub fn backend_id(&self) -> &CStr {
+        match self.ops().get_backend_id {
+            Some(f) => unsafe { CStr::from_ptr(f(self.ptr)) },
+            None => unsafe { CStr::from_ptr(b"\0".as_ptr() as *const _) },
+        }
+    }
+
+    pub fn max_channel_count(&self) -> Result<u32> {
+        let mut value = 0;
+        call!(self.ops(), get_max_channel_count(self.ptr, &mut value))?;
+        Ok(value)
+    }
+
+    pub fn min_latency(&self, params: &StreamParams) -> Result<u32> {
+        let mut value = 0;
+        let params = unsafe { *params.raw() };
+        call!(self.ops(), get_min_latency(self.ptr, params, &mut value))?;
+        Ok(value)
+    }
+
+    pub fn preferred_sample_rate(&self) -> Result<u32> {
+        let mut value = 0;
+        call!(self.ops(), get_preferred_sample_rate(self.ptr, &mut value))?;
+        Ok(value)
+    }
+
+    pub fn preferred_channel_layout(&self) -> Result<ffi::cubeb_channel_layout> {
+        let mut value = ffi::CUBEB_LAYOUT_UNDEFINED;
+        call!(self.ops(), get_preferred_channel_layout(self.ptr, &mut value))?;
+        Ok(value)
+    }
+
+    /// Release the result with `device_collection_destroy`.
+    pub fn enumerate_devices(
+        &self,
+        devtype: DeviceType,
+    ) -> Result<ffi::cubeb_device_collection> {
+        let mut collection = ffi::cubeb_device_collection {
+            device: ptr::null(),
+            count: 0
+        };
+        call!(
+            self.ops(),
+            enumerate_devices(self.ptr, devtype.bits(), &mut collection)
+        )?;
+        Ok(collection)
+    }
+
+    pub fn device_collection_destroy(
+        &self,
+        collection: &mut ffi::cubeb_device_collection,
+    ) -> Result<()> {
+        call!(self.ops(), device_collection_destroy(self.ptr, collection))
+    }
+
+    /// Create a stream.
+    ///
+    /// # Safety
+    ///
+    /// The callbacks receive `user_ptr`, which must stay valid until the
+    /// stream is dropped.
+    #[allow(clippy::too_many_arguments)]
+    pub unsafe fn stream_init(
+        &self,
+        stream_name: Option<&CStr>,
+        input_device: DeviceId,
+        input_stream_params: Option<&ffi::cubeb_stream_params>,
+        output_device: DeviceId,
+        output_stream_params: Option<&ffi::cubeb_stream_params>,
+        latency_frames: u32,
+        data_callback: ffi::cubeb_data_callback,
+        state_callback: ffi::cubeb_state_callback,
+        user_ptr: *mut c_void,
+    ) -> Result<RawStream> {
+        let mut stm = ptr::null_mut();
+        let opt = |p: Option<&ffi::cubeb_stream_params>| p.map_or(ptr::null(), |p| p as *const _);
+        call!(
+            self.ops(),
+            stream_init(
+                self.ptr,
+                &mut stm,
+                stream_name.map_or(ptr::null(), |n| n.as_ptr()),
+                input_device.raw(),
+                opt(input_stream_params),
+                output_device.raw(),
+                opt(output_stream_params),
+                latency_frames,
+                data_callback,
+                state_callback,
+                user_ptr
+            )
+        )?;
+        Ok(RawStream {
+            ptr: stm
+        })
+    }
+
+    /// Register `callback`, which receives `user_ptr`, for device
+    /// collection changes.
+    ///
+    /// # Safety
+    ///
+    /// `user_ptr` must stay valid until the context is dropped.
+    pub unsafe fn register_device_collection_changed(
+        &self,
+        devtype: DeviceType,
+        callback: Option<ffi::cubeb_device_collection_changed_callback>,
+        user_ptr: *mut c_void,
+    ) -> Result<()> {
+        call!(
+            self.ops(),
+            register_device_collection_changed(self.ptr, devtype.bits(), callback, user_ptr)
+        )
+    }
+}
+
+impl Drop for RawContext {
+    fn drop(&mut self) {
+        if let Some(destroy) = self.ops().destroy {
+            unsafe { destroy(self.ptr) }
+        }
+    }
+}
+
+/// A stream created by `RawContext::stream_init`, destroyed on drop.
+///
+/// The stream must be dropped before its context.
+pub struct RawStream {
+    ptr: *mut ffi::cubeb_stream
+}
+
+impl RawStream {
+    /// Take ownership of an initialized stream.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must be a stream created by a backend's `stream_init`, not
+    /// owned by anything else, whose context outlives the result.
+    pub unsafe fn from_ptr(ptr: *mut ffi::cubeb_stream) -> RawStream {
+        RawStream {
+            ptr
+        }
+    }
+
+    pub fn as_ptr(&self) -> *mut ffi::cubeb_stream {
+        self.ptr
+    }
+
+    /// Give up ownership without destroying the stream.
+    pub fn into_ptr(self) -> *mut ffi::cubeb_stream {
+        let ptr = self.ptr;
+        mem::forget(self);
+        ptr
+    }
+
+    pub fn ops(&self) -> &Ops {
+        unsafe { context_ops(*(self.ptr as *const *mut ffi::cubeb)) }
+    }
+
+    pub fn start(&self) -> Result<()> {
+        call!(self.ops(), stream_start(self.ptr))
+    }
+
+    pub fn stop(&self) -> Result<()> {
+        call!(self.ops(), stream_stop(self.ptr))
+    }
+
+    pub fn reset_default_device(&self) -> Result<()> {
+        call!(self.ops(), stream_reset_default_device(self.ptr))
+    }
+
+    pub fn position(&self) -> Result<u64> {
+        let mut value = 0;
+        call!(self.ops(), stream_get_position(self.ptr, &mut value))?;
+        Ok(value)
+    }
+
+    pub fn latency(&self) -> Result<u32> {
+        let mut value = 0;
+        call!(self.ops(), stream_get_latency(self.ptr, &mut value))?;
+        Ok(value)
+    }
+
+    pub fn set_volume(&self, volume: f32) -> Result<()> {
+        call!(self.ops(), stream_set_volume(self.ptr, volume))
+    }
+
+    pub fn set_panning(&self, panning: f32) -> Result<()> {
+        call!(self.ops(), stream_set_panning(self.ptr, panning))
+    }
+
+    /// Release the result with `device_destroy`.
+    pub fn current_device(&self) -> Result<*const ffi::cubeb_device> {
+        let mut device = ptr::null();
+        call!(self.ops(), stream_get_current_device(self.ptr, &mut device))?;
+        Ok(device)
+    }
+
+    /// Release a device returned by `current_device`.
+    ///
+    /// # Safety
+    ///
+    /// `device` must come from this stream's `current_device` and not have
+    /// been released yet.
+    pub unsafe fn device_destroy(&self, device: *const ffi::cubeb_device) -> Result<()> {
+        call!(self.ops(), stream_device_destroy(self.ptr, device))
+    }
+
+    /// Register `callback`, which receives the stream's user pointer, for
+    /// changes of the stream's device.
+    pub fn register_device_changed_callback(
+        &self,
+        callback: Option<ffi::cubeb_device_changed_callback>,
+    ) -> Result<()> {
+        call!(
+            self.ops(),
+            stream_register_device_changed_callback(self.ptr, callback)
+        )
+    }
+}
+
+impl Drop for RawStream {
+    fn drop(&mut self) {
+        if let Some(destroy) = self.ops().stream_destroy {
+            unsafe { destroy(self.ptr) }
+        }
+    }
+}
@@ -0,0 +1,587 @@
+// Copyright © 2017 Mozilla Foundation
+//
+// This program is made available under an ISC-style license.  See the
+// accompanying file LICENSE for details.
+
+//! Client end of the remote backend.
+//!
+//! Every operation is a request to the server. Each stream has a thread
+//! that waits for the server's events and runs the application's data
+//! callback whenever the shared rings need servicing.
+
+use {Context, DeviceInfo, Ops, Stream};
+use cubeb_core::{DeviceId, DeviceType, Error, ErrorCode, Result, StreamParams};
+use cubeb_core::binding::Binding;
+use cubeb_core::ffi;
+use remote::protocol::{self, frame_size, Event, Request, Response, StreamDirection,
+                       PROTOCOL_VERSION};
+use remote::shm::{StreamShm, FLAG_DRAINING, FLAG_ERROR};
+use remote::sys;
+use ring::{Consumer, Producer};
+use util;
+use std::cmp;
+use std::env;
+use std::ffi::CStr;
+use std::fs::File;
+use std::io;
+use std::net::Shutdown;
+use std::os::fd::{FromRawFd, OwnedFd};
+use std::os::raw::{c_long, c_void};
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::ptr;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicPtr, Ordering};
+use std::thread::{self, JoinHandle};
+
+pub const OPS: Ops = capi_new!(ClientContext, ClientStream);
+
+/// Environment variable holding the server's socket path, used by
+/// `Context::init`.
+pub const SOCKET_ENV: &str = "CUBEB_REMOTE_SOCKET";
+
+fn io_error(e: io::Error) -> Error {
+    let error = match e.kind() {
+        io::ErrorKind::NotFound | io::ErrorKind::ConnectionRefused => {
+            Error::from(ErrorCode::DeviceUnavailable)
+        },
+        _ => Error::new(),
+    };
+    error.with_source(e)
+}
+
+fn exchange(conn: &mut UnixStream, request: &Request) -> Result<Response> {
+    protocol::write_message(conn, &request.encode()).map_err(io_error)?;
+    let message = protocol::read_message(conn).map_err(io_error)?;
+    match Response::decode(&message).map_err(io_error)? {
+        Response::Error(code) => Err(unsafe { Error::from_raw(code) }),
+        response => Ok(response),
+    }
+}
+
+fn owned_fds(conn: &UnixStream, count: usize) -> Result<Vec<OwnedFd>> {
+    let fds = sys::recv_fds(conn, count).map_err(io_error)?;
+    Ok(fds
+        .into_iter()
+        .map(|fd| unsafe { OwnedFd::from_raw_fd(fd) })
+        .collect())
+}
+
+fn unexpected<T>() -> Result<T> {
+    Err(Error::new().with_detail("unexpected response from server"))
+}
+
+struct CollectionChanged {
+    callback: Option<(ffi::cubeb_device_collection_changed_callback, usize)>,
+    context: usize
+}
+
+#[repr(C)]
+pub struct ClientContext {
+    ops: *const Ops,
+    rpc: Mutex<UnixStream>,
+    events: UnixStream,
+    collection_changed: Arc<Mutex<CollectionChanged>>,
+    thread: Option<JoinHandle<()>>
+}
+
+impl ClientContext {
+    /// Connect to the server listening at `path`.
+    pub fn connect<P: AsRef<Path>>(path: P, context_name: Option<&CStr>) -> Result<*mut ffi::cubeb> {
+        let mut conn = UnixStream::connect(path).map_err(io_error)?;
+        let hello = Request::Hello {
+            version: PROTOCOL_VERSION
+        };
+        match exchange(&mut conn, &hello)? {
+            Response::Hello {
+                version
+            } if version == PROTOCOL_VERSION => {},
+            _ => return Err(Error::from(ErrorCode::NotSupported)),
+        }
+
+        let init = Request::ContextInit {
+            name: context_name.map(|n| n.to_string_lossy().into_owned())
+        };
+        match exchange(&mut conn, &init)? {
+            Response::ContextCreated => {},
+            _ => return unexpected(),
+        }
+        let events = UnixStream::from(owned_fds(&conn, 1)?.remove(0));
+
+        let collection_changed = Arc::new(Mutex::new(CollectionChanged {
+            callback: None,
+            context: 0
+        }));
+        let mut ctx = Box::new(ClientContext {
+            ops: &OPS as *const _,
+            rpc: Mutex::new(conn),
+            events: events.try_clone().map_err(io_error)?,
+            collection_changed: collection_changed.clone(),
+            thread: None
+        });
+        collection_changed.lock().unwrap().context = &*ctx as *const _ as usize;
+        ctx.thread = Some(thread::spawn(move || {
+            while let Ok(event) = sys::recv_event(&events) {
+                if event != Event::CollectionChanged {
+                    continue;
+                }
+                let changed = collection_changed.lock().unwrap();
+                if let Some((callback, user_ptr)) = changed.callback {
+                    callback(changed.context as *mut ffi::cubeb, user_ptr as *mut c_void);
+                }
+            }
+        }));
+        Ok(Box::into_raw(ctx) as *mut _)
+    }
+
+    fn call(&self, request: &Request) -> Result<Response> {
+        exchange(&mut self.rpc.lock().unwrap(), request)
+    }
+
+    fn call_u32(&self, request: &Request) -> Result<u32> {
+        match self.call(request)? {
+            Response::U32(value) => Ok(value),
+            _ => unexpected(),
+        }
+    }
+
+    fn call_ok(&self, request: &Request) -> Result<()> {
+        match self.call(request)? {
+            Response::Ok => Ok(()),
+            _ => unexpected(),
+        }
+    }
+
+    fn create_stream(
+        &self,
+        request: &Request,
+        data_callback: ffi::cubeb_data_callback,
+        state_callback: ffi::cubeb_state_callback,
+        user_ptr: *mut c_void,
+    ) -> Result<*mut ffi::cubeb_stream> {
+        let (input_frame, output_frame) = match *request {
+            Request::StreamInit {
+                ref input,
+                ref output,
+                ..
+            } => {
+                let size = |d: &Option<StreamDirection>| match *d {
+                    Some(ref d) => frame_size(&d.params),
+                    None => Some(0),
+                };
+                match (size(input), size(output)) {
+                    (Some(i), Some(o)) => (i, o),
+                    _ => return Err(Error::from(ErrorCode::InvalidFormat)),
+                }
+            },
+            _ => return unexpected(),
+        };
+
+        let (id, latency_frames, mut fds) = {
+            let mut conn = self.rpc.lock().unwrap();
+            match exchange(&mut conn, request)? {
+                Response::StreamCreated {
+                    id,
+                    latency_frames
+                } => (id, latency_frames, owned_fds(&conn, 2)?),
+                _ => return unexpected(),
+            }
+        };
+        let events = UnixStream::from(fds.pop().unwrap());
+        let shm = File::from(fds.pop().unwrap());
+
+        // A period of the latency the server's backend granted.
+        let period = latency_frames as usize;
+        let shared = StreamShm::open(shm).map_err(io_error).and_then(|shm| {
+            // The rings must hold what `service` queues.
+            let fits = |capacity: Option<usize>, frame: usize| match capacity {
+                Some(capacity) => frame > 0 && capacity >= 4 * period * frame,
+                None => frame == 0,
+            };
+            let input = shm.input().map(|(_, consumer)| consumer);
+            let output = shm.output().map(|(producer, _)| producer);
+            if period == 0 || !fits(input.as_ref().map(|c| c.capacity()), input_frame) ||
+                !fits(output.as_ref().map(|p| p.capacity()), output_frame)
+            {
+                return unexpected();
+            }
+            Ok(Arc::new(Shared {
+                audio: Mutex::new(Audio {
+                    input,
+                    output,
+                    input_buffer: vec![0; period * input_frame],
+                    output_buffer: vec![0; period * output_frame]
+                }),
+                shm,
+                events,
+                period,
+                input_frame,
+                output_frame,
+                running: AtomicBool::new(false),
+                stream: AtomicPtr::new(ptr::null_mut()),
+                data_callback,
+                state_callback,
+                user_ptr: user_ptr as usize,
+                device_changed: Mutex::new(None)
+            }))
+        });
+        let shared = match shared {
+            Ok(shared) => shared,
+            Err(e) => {
+                let _ = self.call(&Request::StreamDestroy(id));
+                return Err(e);
+            },
+        };
+
+        let mut stm = Box::new(ClientStream {
+            context: self,
+            id,
+            shared: shared.clone(),
+            thread: None
+        });
+        let ptr = &mut *stm as *mut ClientStream as *mut ffi::cubeb_stream;
+        shared.stream.store(ptr, Ordering::Release);
+        stm.thread = Some(thread::spawn(move || shared.run()));
+        Ok(Box::into_raw(stm) as *mut _)
+    }
+}
+
+impl Drop for ClientContext {
+    fn drop(&mut self) {
+        let _ = self.events.shutdown(Shutdown::Both);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Context for ClientContext {
+    fn init(context_name: Option<&CStr>) -> Result<*mut ffi::cubeb> {
+        match env::var_os(SOCKET_ENV) {
+            Some(path) => ClientContext::connect(path, context_name),
+            None => Err(Error::from(ErrorCode::NotSupported)),
+        }
+    }
+
+    fn backend_id(&self) -> &'static CStr {
+        unsafe { CStr::from_ptr(b"remote\0".as_ptr() as *const _) }
+    }
+
+    fn max_channel_count(&self) -> Result<u32> {
+        self.call_u32(&Request::MaxChannelCount)
+    }
+
+    fn min_latency(&self, params: &StreamParams) -> Result<u32> {
+        self.call_u32(&Request::MinLatency(unsafe { *params.raw() }))
+    }
+
+    fn preferred_sample_rate(&self) -> Result<u32> {
+        self.call_u32(&Request::PreferredSampleRate)
+    }
+
+    fn preferred_channel_layout(&self) -> Result<ffi::cubeb_channel_layout> {
+        Ok(self.call_u32(&Request::PreferredChannelLayout)? as ffi::cubeb_channel_layout)
+    }
+
+    fn enumerate_devices(
+        &self,
+        devtype: DeviceType,
+    ) -> Result<ffi::cubeb_device_collection> {
+        let devices = match self.call(&Request::EnumerateDevices(devtype.bits()))? {
+            Response::Devices(devices) => devices,
+            _ => return unexpected(),
+        };
+        Ok(DeviceInfo::into_collection(devices))
+    }
+
+    #[allow(clippy::not_unsafe_ptr_arg_deref)]
+    fn device_collection_destroy(
+        &self,
+        collection: *mut ffi::cubeb_device_collection,
+    ) {
+        unsafe { util::destroy_owned_device_collection(collection) }
+    }
+
+    fn stream_init(
+        &self,
+        stream_name: Option<&CStr>,
+        input_device: DeviceId,
+        input_stream_params: Option<&ffi::cubeb_stream_params>,
+        output_device: DeviceId,
+        output_stream_params: Option<&ffi::cubeb_stream_params>,
+        latency_frames: u32,
+        data_callback: ffi::cubeb_data_callback,
+        state_callback: ffi::cubeb_state_callback,
+        user_ptr: *mut c_void,
+    ) -> Result<*mut ffi::cubeb_stream> {
+        let direction = |devid: DeviceId, params: Option<&ffi::cubeb_stream_params>| {
+            params.map(|params| StreamDirection {
+                devid: devid.raw() as usize as u64,
+                params: *params
+            })
+        };
+        let request = Request::StreamInit {
+            name: stream_name.map(|n| n.to_string_lossy().into_owned()),
+            input: direction(input_device, input_stream_params),
+            output: direction(output_device, output_stream_params),
+            latency_frames
+        };
+        self.create_stream(&request, data_callback, state_callback, user_ptr)
+    }
+
+    fn register_device_collection_changed(
+        &self,
+        devtype: DeviceType,
+        cb: Option<ffi::cubeb_device_collection_changed_callback>,
+        user_ptr: *mut c_void,
+    ) -> Result<()> {
+        let callback = cb.map(|cb| (cb, user_ptr as usize));
+        self.collection_changed.lock().unwrap().callback = callback;
+        if cb.is_none() {
+            // The protocol can't unregister; the server's events are
+            // dropped here instead.
+            return Ok(());
+        }
+        self.call_ok(&Request::RegisterDeviceCollectionChanged(devtype.bits()))
+    }
+}
+
+struct Audio {
+    input: Option<Consumer>,
+    output: Option<Producer>,
+    input_buffer: Vec<u8>,
+    output_buffer: Vec<u8>
+}
+
+// State shared between a stream and its event thread.
+struct Shared {
+    shm: StreamShm,
+    audio: Mutex<Audio>,
+    events: UnixStream,
+    period: usize,
+    input_frame: usize,
+    output_frame: usize,
+    running: AtomicBool,
+    stream: AtomicPtr<ffi::cubeb_stream>,
+    data_callback: ffi::cubeb_data_callback,
+    state_callback: ffi::cubeb_state_callback,
+    user_ptr: usize,
+    device_changed: Mutex<Option<ffi::cubeb_device_changed_callback>>
+}
+
+impl Shared {
+    fn notify(&self, state: ffi::cubeb_state) {
+        let stream = self.stream.load(Ordering::Acquire);
+        (self.state_callback)(stream, self.user_ptr as *mut c_void, state);
+    }
+
+    fn run(&self) {
+        while let Ok(event) = sys::recv_event(&self.events) {
+            match event {
+                Event::Wake => self.service(),
+                Event::Prefill(seq) => {
+                    self.service();
+                    let _ = sys::send_event(&self.events, Event::Prefilled(seq));
+                },
+                // Unless the stream was stopped meanwhile.
+                Event::State(state) if self.running.swap(false, Ordering::AcqRel) => {
+                    self.notify(state);
+                },
+                Event::DeviceChanged => {
+                    if let Some(callback) = *self.device_changed.lock().unwrap() {
+                        callback(self.user_ptr as *mut c_void);
+                    }
+                },
+                _ => {},
+            }
+        }
+    }
+
+    // Run the data callback until two periods of output are queued, or
+    // until all captured input is consumed for input only streams.
+    fn service(&self) {
+        let mut audio = self.audio.lock().unwrap();
+        let audio = &mut *audio;
+        let period = self.period;
+        while self.running.load(Ordering::Acquire) && self.shm.flags().load(Ordering::Acquire) == 0 {
+            if let Some(ref output) = audio.output {
+                let queued = output.capacity() - output.available();
+                if queued >= 2 * period * self.output_frame {
+                    break;
+                }
+            }
+            if let Some(ref mut input) = audio.input {
+                let available = input.available() / self.input_frame;
+                if audio.output.is_none() && available < period {
+                    break;
+                }
+                // Duplex streams don't wait for input; they get silence.
+                let len = cmp::min(available, period) * self.input_frame;
+                input.read(&mut audio.input_buffer[..len]);
+                for x in audio.input_buffer[len..].iter_mut() {
+                    *x = 0;
+                }
+            }
+
+            let input_ptr = if audio.input.is_some() {
+                audio.input_buffer.as_ptr() as *const c_void
+            } else {
+                ptr::null()
+            };
+            let output_ptr = if audio.output.is_some() {
+                audio.output_buffer.as_mut_ptr() as *mut c_void
+            } else {
+                ptr::null_mut()
+            };
+            let stream = self.stream.load(Ordering::Acquire);
+            let got = (self.data_callback)(
+                stream,
+                self.user_ptr as *mut c_void,
+                input_ptr,
+                output_ptr,
+                period as c_long
+            );
+            if got < 0 || got as usize > period {
+                self.shm.flags().fetch_or(FLAG_ERROR, Ordering::AcqRel);
+                if self.running.swap(false, Ordering::AcqRel) {
+                    self.notify(ffi::CUBEB_STATE_ERROR);
+                }
+                break;
+            }
+            let got = got as usize;
+            if let Some(ref mut output) = audio.output {
+                output.write(&audio.output_buffer[..got * self.output_frame]);
+            }
+            if got < period {
+                // The server drains what was written and reports back.
+                self.shm.flags().fetch_or(FLAG_DRAINING, Ordering::AcqRel);
+                break;
+            }
+        }
+    }
+}
+
+/// Remote backend stream.
+///
+/// The first field points at the owning context, as libcubeb requires.
+#[repr(C)]
+pub struct ClientStream {
+    context: *const ClientContext,
+    id: u32,
+    shared: Arc<Shared>,
+    thread: Option<JoinHandle<()>>
+}
+
+impl ClientStream {
+    fn context(&self) -> &ClientContext {
+        unsafe { &*self.context }
+    }
+}
+
+impl Drop for ClientStream {
+    fn drop(&mut self) {
+        self.shared.running.store(false, Ordering::Release);
+        let _ = self.context().call(&Request::StreamDestroy(self.id));
+        let _ = self.shared.events.shutdown(Shutdown::Both);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Stream for ClientStream {
+    fn start(&self) -> Result<()> {
+        {
+            let mut audio = self.shared.audio.lock().unwrap();
+            if let Some(ref mut input) = audio.input {
+                input.clear();
+            }
+            self.shared.shm.flags().store(0, Ordering::Release);
+        }
+        self.shared.running.store(true, Ordering::Release);
+        self.shared.notify(ffi::CUBEB_STATE_STARTED);
+        if let Err(e) = self.context().call_ok(&Request::StreamStart(self.id)) {
+            if self.shared.running.swap(false, Ordering::AcqRel) {
+                self.shared.notify(ffi::CUBEB_STATE_ERROR);
+            }
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    fn stop(&self) -> Result<()> {
+        self.shared.running.store(false, Ordering::Release);
+        // Wait out a data callback in progress. Don't hold the lock across
+        // the request, the server may be waiting on our event thread.
+        drop(self.shared.audio.lock().unwrap());
+        self.context().call_ok(&Request::StreamStop(self.id))?;
+        self.shared.notify(ffi::CUBEB_STATE_STOPPED);
+        Ok(())
+    }
+
+    fn reset_default_device(&self) -> Result<()> {
+        self.context()
+            .call_ok(&Request::StreamResetDefaultDevice(self.id))
+    }
+
+    fn position(&self) -> Result<u64> {
+        match self.context().call(&Request::StreamGetPosition(self.id))? {
+            Response::U64(position) => Ok(position),
+            _ => unexpected(),
+        }
+    }
+
+    fn latency(&self) -> Result<u32> {
+        self.context().call_u32(&Request::StreamGetLatency(self.id))
+    }
+
+    fn set_volume(&self, volume: f32) -> Result<()> {
+        self.context()
+            .call_ok(&Request::StreamSetVolume(self.id, volume))
+    }
+
+    fn set_panning(&self, panning: f32) -> Result<()> {
+        self.context()
+            .call_ok(&Request::StreamSetPanning(self.id, panning))
+    }
+
+    fn current_device(&self) -> Result<*const ffi::cubeb_device> {
+        match self.context().call(&Request::StreamGetCurrentDevice(self.id))? {
+            Response::CurrentDevice {
+                input_name,
+                output_name
+            } => {
+                let device = Box::new(ffi::cubeb_device {
+                    output_name: util::into_c_string(output_name),
+                    input_name: util::into_c_string(input_name)
+                });
+                Ok(Box::into_raw(device))
+            },
+            _ => unexpected(),
+        }
+    }
+
+    fn device_destroy(&self, device: *const ffi::cubeb_device) -> Result<()> {
+        if device.is_null() {
+            return Err(Error::from(ErrorCode::InvalidParameter));
+        }
+        unsafe {
+            let device = Box::from_raw(device as *mut ffi::cubeb_device);
+            util::free_c_string(device.output_name);
+            util::free_c_string(device.input_name);
+        }
+        Ok(())
+    }
+
+    fn register_device_changed_callback(
+        &self,
+        device_changed_callback: Option<ffi::cubeb_device_changed_callback>,
+    ) -> Result<()> {
+        *self.shared.device_changed.lock().unwrap() = device_changed_callback;
+        if device_changed_callback.is_none() {
+            return Ok(());
+        }
+        self.context()
+            .call_ok(&Request::StreamRegisterDeviceChanged(self.id))
+    }
+}
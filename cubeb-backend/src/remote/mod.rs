@@ -0,0 +1,21 @@
+// Copyright © 2017 Mozilla Foundation
+//
+// This program is made available under an ISC-style license.  See the
+// accompanying file LICENSE for details.
+
+//! A backend that plays and records through a server process.
+//!
+//! The client backend forwards every context and stream operation over
+//! a Unix domain socket to a `Server`, which runs them against any other
+//! backend. Audio moves through rings in shared memory, and only small
+//! notifications cross the socket while a stream runs. Sandboxed
+//! processes use it to keep audio devices out of reach.
+
+mod client;
+pub mod protocol;
+mod server;
+mod shm;
+mod sys;
+
+pub use self::client::{ClientContext, ClientStream, OPS, SOCKET_ENV};
+pub use self::server::{serve, Server};
@@ -0,0 +1,530 @@
+// Copyright © 2017 Mozilla Foundation
+//
+// This program is made available under an ISC-style license.  See the
+// accompanying file LICENSE for details.
+
+//! Wire format of the remote backend.
+//!
+//! Requests and responses travel over the connection socket as
+//! little endian messages prefixed with their `u32` length. The first
+//! request on a connection is always `Request::Hello`, whose encoding
+//! never changes, and the server answers with its own `Response::Hello`
+//! or refuses the connection with `CUBEB_ERROR_NOT_SUPPORTED`. Bump
+//! `PROTOCOL_VERSION` whenever any other encoding changes.
+//!
+//! Responses that hand over sockets or shared memory are followed by a
+//! single byte carrying the descriptors as `SCM_RIGHTS`.
+//!
+//! Notifications travel separately, as fixed size `Event`s on a socket
+//! per context and a socket per stream.
+
+use cubeb_core::ffi;
+use device::{ReadFields, WriteFields};
+use std::io::{self, Read, Write};
+
+pub use device::DeviceInfo;
+
+/// Version of the protocol spoken by this crate.
+pub const PROTOCOL_VERSION: u32 = 2;
+
+const HELLO_MAGIC: [u8; 4] = *b"CBRM";
+
+/// Upper bound on the size of a single message.
+pub const MAX_MESSAGE_LEN: usize = 1 << 20;
+
+/// Upper bound on the channel count of a stream.
+pub const MAX_CHANNELS: u32 = 256;
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Parameters of one direction of a stream.
+#[derive(Clone, Copy, Debug)]
+pub struct StreamDirection {
+    /// An id from `Response::Devices`, or 0 for the default device.
+    pub devid: u64,
+    pub params: ffi::cubeb_stream_params
+}
+
+/// Bytes per frame of `params`, or `None` if the protocol can't carry
+/// them.
+pub fn frame_size(params: &ffi::cubeb_stream_params) -> Option<usize> {
+    let sample = match params.format {
+        ffi::CUBEB_SAMPLE_S16LE | ffi::CUBEB_SAMPLE_S16BE => 2,
+        ffi::CUBEB_SAMPLE_FLOAT32LE | ffi::CUBEB_SAMPLE_FLOAT32BE => 4,
+        _ => return None,
+    };
+    if params.channels == 0 || params.channels > MAX_CHANNELS {
+        return None;
+    }
+    Some(sample * params.channels as usize)
+}
+
+#[derive(Clone, Debug)]
+pub enum Request {
+    Hello { version: u32 },
+    ContextInit { name: Option<String> },
+    BackendId,
+    MaxChannelCount,
+    MinLatency(ffi::cubeb_stream_params),
+    PreferredSampleRate,
+    PreferredChannelLayout,
+    EnumerateDevices(ffi::cubeb_device_type),
+    RegisterDeviceCollectionChanged(ffi::cubeb_device_type),
+    StreamInit {
+        name: Option<String>,
+        input: Option<StreamDirection>,
+        output: Option<StreamDirection>,
+        latency_frames: u32
+    },
+    StreamDestroy(u32),
+    StreamStart(u32),
+    StreamStop(u32),
+    StreamResetDefaultDevice(u32),
+    StreamGetPosition(u32),
+    StreamGetLatency(u32),
+    StreamSetVolume(u32, f32),
+    StreamSetPanning(u32, f32),
+    StreamGetCurrentDevice(u32),
+    StreamRegisterDeviceChanged(u32)
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Response {
+    Hello { version: u32 },
+    Ok,
+    Error(ffi::cubeb_error_code),
+    BackendId(String),
+    U32(u32),
+    U64(u64),
+    Devices(Vec<DeviceInfo>),
+    /// Followed by the context's event socket.
+    ContextCreated,
+    /// Followed by the stream's shared memory and event socket. The rings
+    /// are sized for the latency the backend granted.
+    StreamCreated { id: u32, latency_frames: u32 },
+    CurrentDevice {
+        input_name: Option<String>,
+        output_name: Option<String>
+    }
+}
+
+/// Notification sent over an event socket.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Event {
+    /// Server to client: the server consumed or produced audio.
+    Wake,
+    /// Server to client: fill the output ring before the stream starts.
+    /// Numbered so a late reply can't be taken for a later one.
+    Prefill(u32),
+    /// Client to server: the output ring is filled for the `Prefill`
+    /// with the same number.
+    Prefilled(u32),
+    /// Server to client: the backend stream changed state.
+    State(ffi::cubeb_state),
+    /// Server to client: the stream's device changed.
+    DeviceChanged,
+    /// Server to client: the device collection changed.
+    CollectionChanged
+}
+
+/// Size of an encoded `Event`.
+pub const EVENT_LEN: usize = 8;
+
+impl Event {
+    pub fn encode(&self) -> [u8; EVENT_LEN] {
+        let (kind, arg): (u32, i32) = match *self {
+            Event::Wake => (1, 0),
+            Event::Prefill(seq) => (2, seq as i32),
+            Event::Prefilled(seq) => (3, seq as i32),
+            Event::State(state) => (4, state),
+            Event::DeviceChanged => (5, 0),
+            Event::CollectionChanged => (6, 0),
+        };
+        let mut buf = [0; EVENT_LEN];
+        buf[..4].copy_from_slice(&kind.to_le_bytes());
+        buf[4..].copy_from_slice(&arg.to_le_bytes());
+        buf
+    }
+
+    pub fn decode(buf: &[u8; EVENT_LEN]) -> io::Result<Event> {
+        let kind = u32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]]);
+        let arg = i32::from_le_bytes([buf[4], buf[5], buf[6], buf[7]]);
+        Ok(match kind {
+            1 => Event::Wake,
+            2 => Event::Prefill(arg as u32),
+            3 => Event::Prefilled(arg as u32),
+            4 => Event::State(arg),
+            5 => Event::DeviceChanged,
+            6 => Event::CollectionChanged,
+            _ => return Err(invalid_data("unknown event")),
+        })
+    }
+}
+
+struct Encoder {
+    buf: Vec<u8>
+}
+
+impl Encoder {
+    fn new(tag: u8) -> Encoder {
+        Encoder {
+            buf: vec![tag]
+        }
+    }
+
+    fn u32(&mut self, x: u32) -> &mut Self {
+        self.buf.extend_from_slice(&x.to_le_bytes());
+        self
+    }
+
+    fn i32(&mut self, x: i32) -> &mut Self {
+        self.u32(x as u32)
+    }
+
+    fn u64(&mut self, x: u64) -> &mut Self {
+        self.buf.extend_from_slice(&x.to_le_bytes());
+        self
+    }
+
+    fn f32(&mut self, x: f32) -> &mut Self {
+        self.u32(x.to_bits())
+    }
+
+    fn string(&mut self, s: &Option<String>) -> &mut Self {
+        match *s {
+            Some(ref s) => {
+                self.buf.push(1);
+                self.u32(s.len() as u32);
+                self.buf.extend_from_slice(s.as_bytes());
+            },
+            None => self.buf.push(0),
+        }
+        self
+    }
+
+    fn params(&mut self, p: &ffi::cubeb_stream_params) -> &mut Self {
+        self.i32(p.format as i32).u32(p.rate).u32(p.channels).i32(p.layout)
+    }
+
+    fn direction(&mut self, d: &Option<StreamDirection>) -> &mut Self {
+        match *d {
+            Some(ref d) => {
+                self.buf.push(1);
+                self.u64(d.devid).params(&d.params);
+            },
+            None => self.buf.push(0),
+        }
+        self
+    }
+
+    fn finish(&mut self) -> Vec<u8> {
+        ::std::mem::take(&mut self.buf)
+    }
+}
+
+impl WriteFields for Encoder {
+    fn write_u32(&mut self, x: u32) {
+        self.u32(x);
+    }
+
+    fn write_i32(&mut self, x: i32) {
+        self.i32(x);
+    }
+
+    fn write_u64(&mut self, x: u64) {
+        self.u64(x);
+    }
+
+    fn write_string(&mut self, s: &Option<String>) {
+        self.string(s);
+    }
+}
+
+struct Decoder<'a> {
+    buf: &'a [u8]
+}
+
+impl<'a> Decoder<'a> {
+    fn bytes(&mut self, n: usize) -> io::Result<&'a [u8]> {
+        if self.buf.len() < n {
+            return Err(invalid_data("truncated message"));
+        }
+        let (head, tail) = self.buf.split_at(n);
+        self.buf = tail;
+        Ok(head)
+    }
+
+    fn u8(&mut self) -> io::Result<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        let b = self.bytes(4)?;
+        Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    fn i32(&mut self) -> io::Result<i32> {
+        Ok(self.u32()? as i32)
+    }
+
+    fn u64(&mut self) -> io::Result<u64> {
+        let b = self.bytes(8)?;
+        let mut x = [0; 8];
+        x.copy_from_slice(b);
+        Ok(u64::from_le_bytes(x))
+    }
+
+    fn f32(&mut self) -> io::Result<f32> {
+        Ok(f32::from_bits(self.u32()?))
+    }
+
+    fn present(&mut self) -> io::Result<bool> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(invalid_data("invalid option tag")),
+        }
+    }
+
+    fn string(&mut self) -> io::Result<Option<String>> {
+        if !self.present()? {
+            return Ok(None);
+        }
+        let len = self.u32()? as usize;
+        let bytes = self.bytes(len)?;
+        String::from_utf8(bytes.to_vec())
+            .map(Some)
+            .map_err(|_| invalid_data("invalid string"))
+    }
+
+    fn params(&mut self) -> io::Result<ffi::cubeb_stream_params> {
+        Ok(ffi::cubeb_stream_params {
+            format: self.i32()? as _,
+            rate: self.u32()?,
+            channels: self.u32()?,
+            layout: self.i32()? as _
+        })
+    }
+
+    fn direction(&mut self) -> io::Result<Option<StreamDirection>> {
+        if !self.present()? {
+            return Ok(None);
+        }
+        Ok(Some(StreamDirection {
+            devid: self.u64()?,
+            params: self.params()?
+        }))
+    }
+
+    fn finish<T>(&self, value: T) -> io::Result<T> {
+        if self.buf.is_empty() {
+            Ok(value)
+        } else {
+            Err(invalid_data("trailing bytes in message"))
+        }
+    }
+}
+
+impl<'a> ReadFields for Decoder<'a> {
+    fn read_u32(&mut self) -> io::Result<u32> {
+        self.u32()
+    }
+
+    fn read_i32(&mut self) -> io::Result<i32> {
+        self.i32()
+    }
+
+    fn read_u64(&mut self) -> io::Result<u64> {
+        self.u64()
+    }
+
+    fn read_string(&mut self) -> io::Result<Option<String>> {
+        self.string()
+    }
+}
+
+impl Request {
+    pub fn encode(&self) -> Vec<u8> {
+        match *self {
+            Request::Hello { version } => {
+                let mut e = Encoder::new(1);
+                e.buf.extend_from_slice(&HELLO_MAGIC);
+                e.u32(version).finish()
+            },
+            Request::ContextInit { ref name } => Encoder::new(2).string(name).finish(),
+            Request::BackendId => Encoder::new(3).finish(),
+            Request::MaxChannelCount => Encoder::new(4).finish(),
+            Request::MinLatency(ref params) => Encoder::new(5).params(params).finish(),
+            Request::PreferredSampleRate => Encoder::new(6).finish(),
+            Request::PreferredChannelLayout => Encoder::new(7).finish(),
+            Request::EnumerateDevices(devtype) => Encoder::new(8).i32(devtype as i32).finish(),
+            Request::RegisterDeviceCollectionChanged(devtype) => {
+                Encoder::new(9).i32(devtype as i32).finish()
+            },
+            Request::StreamInit {
+                ref name,
+                ref input,
+                ref output,
+                latency_frames
+            } => Encoder::new(10)
+                .string(name)
+                .direction(input)
+                .direction(output)
+                .u32(latency_frames)
+                .finish(),
+            Request::StreamDestroy(id) => Encoder::new(11).u32(id).finish(),
+            Request::StreamStart(id) => Encoder::new(12).u32(id).finish(),
+            Request::StreamStop(id) => Encoder::new(13).u32(id).finish(),
+            Request::StreamResetDefaultDevice(id) => Encoder::new(14).u32(id).finish(),
+            Request::StreamGetPosition(id) => Encoder::new(15).u32(id).finish(),
+            Request::StreamGetLatency(id) => Encoder::new(16).u32(id).finish(),
+            Request::StreamSetVolume(id, volume) => {
+                Encoder::new(17).u32(id).f32(volume).finish()
+            },
+            Request::StreamSetPanning(id, panning) => {
+                Encoder::new(18).u32(id).f32(panning).finish()
+            },
+            Request::StreamGetCurrentDevice(id) => Encoder::new(19).u32(id).finish(),
+            Request::StreamRegisterDeviceChanged(id) => Encoder::new(20).u32(id).finish(),
+        }
+    }
+
+    pub fn decode(buf: &[u8]) -> io::Result<Request> {
+        let mut d = Decoder {
+            buf
+        };
+        let request = match d.u8()? {
+            1 => {
+                if d.bytes(4)? != HELLO_MAGIC {
+                    return Err(invalid_data("bad hello magic"));
+                }
+                Request::Hello {
+                    version: d.u32()?
+                }
+            },
+            2 => Request::ContextInit {
+                name: d.string()?
+            },
+            3 => Request::BackendId,
+            4 => Request::MaxChannelCount,
+            5 => Request::MinLatency(d.params()?),
+            6 => Request::PreferredSampleRate,
+            7 => Request::PreferredChannelLayout,
+            8 => Request::EnumerateDevices(d.i32()? as _),
+            9 => Request::RegisterDeviceCollectionChanged(d.i32()? as _),
+            10 => Request::StreamInit {
+                name: d.string()?,
+                input: d.direction()?,
+                output: d.direction()?,
+                latency_frames: d.u32()?
+            },
+            11 => Request::StreamDestroy(d.u32()?),
+            12 => Request::StreamStart(d.u32()?),
+            13 => Request::StreamStop(d.u32()?),
+            14 => Request::StreamResetDefaultDevice(d.u32()?),
+            15 => Request::StreamGetPosition(d.u32()?),
+            16 => Request::StreamGetLatency(d.u32()?),
+            17 => Request::StreamSetVolume(d.u32()?, d.f32()?),
+            18 => Request::StreamSetPanning(d.u32()?, d.f32()?),
+            19 => Request::StreamGetCurrentDevice(d.u32()?),
+            20 => Request::StreamRegisterDeviceChanged(d.u32()?),
+            _ => return Err(invalid_data("unknown request")),
+        };
+        d.finish(request)
+    }
+}
+
+impl Response {
+    pub fn encode(&self) -> Vec<u8> {
+        match *self {
+            Response::Hello { version } => Encoder::new(1).u32(version).finish(),
+            Response::Ok => Encoder::new(2).finish(),
+            Response::Error(code) => Encoder::new(3).i32(code).finish(),
+            Response::BackendId(ref id) => {
+                Encoder::new(4).string(&Some(id.clone())).finish()
+            },
+            Response::U32(x) => Encoder::new(5).u32(x).finish(),
+            Response::U64(x) => Encoder::new(6).u64(x).finish(),
+            Response::Devices(ref devices) => {
+                let mut e = Encoder::new(7);
+                e.u32(devices.len() as u32);
+                for d in devices {
+                    d.write(&mut e);
+                }
+                e.finish()
+            },
+            Response::ContextCreated => Encoder::new(8).finish(),
+            Response::StreamCreated {
+                id,
+                latency_frames
+            } => Encoder::new(9).u32(id).u32(latency_frames).finish(),
+            Response::CurrentDevice {
+                ref input_name,
+                ref output_name
+            } => Encoder::new(10).string(input_name).string(output_name).finish(),
+        }
+    }
+
+    pub fn decode(buf: &[u8]) -> io::Result<Response> {
+        let mut d = Decoder {
+            buf
+        };
+        let response = match d.u8()? {
+            1 => Response::Hello {
+                version: d.u32()?
+            },
+            2 => Response::Ok,
+            3 => Response::Error(d.i32()? as _),
+            4 => Response::BackendId(d.string()?.ok_or_else(|| invalid_data("missing id"))?),
+            5 => Response::U32(d.u32()?),
+            6 => Response::U64(d.u64()?),
+            7 => {
+                let count = d.u32()? as usize;
+                // Don't trust the count for the allocation.
+                let mut devices = Vec::with_capacity(count.min(64));
+                for _ in 0..count {
+                    devices.push(DeviceInfo::read(&mut d)?);
+                }
+                Response::Devices(devices)
+            },
+            8 => Response::ContextCreated,
+            9 => Response::StreamCreated {
+                id: d.u32()?,
+                latency_frames: d.u32()?
+            },
+            10 => Response::CurrentDevice {
+                input_name: d.string()?,
+                output_name: d.string()?
+            },
+            _ => return Err(invalid_data("unknown response")),
+        };
+        d.finish(response)
+    }
+}
+
+/// Write one length prefixed message.
+pub fn write_message<W: Write>(w: &mut W, payload: &[u8]) -> io::Result<()> {
+    if payload.len() > MAX_MESSAGE_LEN {
+        return Err(invalid_data("message too long"));
+    }
+    let mut buf = Vec::with_capacity(4 + payload.len());
+    buf.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    buf.extend_from_slice(payload);
+    w.write_all(&buf)
+}
+
+/// Read one length prefixed message. Doesn't read past its end, so
+/// descriptors sent after it stay in the socket.
+pub fn read_message<R: Read>(r: &mut R) -> io::Result<Vec<u8>> {
+    let mut len = [0; 4];
+    r.read_exact(&mut len)?;
+    let len = u32::from_le_bytes(len) as usize;
+    if len > MAX_MESSAGE_LEN {
+        return Err(invalid_data("message too long"));
+    }
+    let mut buf = vec![0; len];
+    r.read_exact(&mut buf)?;
+    Ok(buf)
+}
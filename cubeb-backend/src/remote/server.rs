@@ -0,0 +1,735 @@
+// Copyright © 2017 Mozilla Foundation
+//
+// This program is made available under an ISC-style license.  See the
+// accompanying file LICENSE for details.
+
+//! Server end of the remote backend.
+//!
+//! Each connection gets its own context of the underlying backend. The
+//! backend's audio callbacks only touch the shared rings and poke the
+//! client through the stream's event socket; the client runs the
+//! application's callbacks in its own process.
+
+use Ops;
+use cubeb_core::{DeviceId, DeviceType, Error, ErrorCode, Result, StreamParams};
+use cubeb_core::binding::Binding;
+use cubeb_core::ffi;
+use raw::{RawContext, RawStream};
+use remote::protocol::{self, frame_size, DeviceInfo, Event, Request, Response,
+                       StreamDirection, PROTOCOL_VERSION};
+use remote::shm::{StreamShm, FLAG_DRAINING, FLAG_ERROR};
+use remote::sys::{self, EventSender};
+use ring::{Consumer, Producer};
+use std::cell::{Cell, UnsafeCell};
+use std::collections::HashMap;
+use std::ffi::{CStr, CString};
+use std::io;
+use std::os::fd::{AsRawFd, OwnedFd};
+use std::os::raw::{c_char, c_long, c_void};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::{ptr, slice};
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Largest latency, in frames, a client may request. Bounds the size of
+/// the shared memory.
+const MAX_LATENCY: u32 = 1 << 16;
+/// How long `stream_start` waits for the client to fill the output ring.
+const PREFILL_TIMEOUT: Duration = Duration::from_secs(1);
+
+type InitFn = dyn Fn(Option<&CStr>) -> Result<RawContext> + Send + Sync;
+
+/// Listens for clients and serves each on its own thread.
+pub struct Server {
+    listener: UnixListener,
+    init: Arc<InitFn>
+}
+
+impl Server {
+    /// Listen at `path`, creating a context with `init` for every client.
+    pub fn bind<P, F>(path: P, init: F) -> io::Result<Server>
+    where
+        P: AsRef<Path>,
+        F: Fn(Option<&CStr>) -> Result<RawContext> + Send + Sync + 'static,
+    {
+        Ok(Server {
+            listener: UnixListener::bind(path)?,
+            init: Arc::new(init)
+        })
+    }
+
+    /// Listen at `path`, serving the backend implementing `ops`.
+    pub fn with_ops<P: AsRef<Path>>(path: P, ops: &'static Ops) -> io::Result<Server> {
+        Server::bind(path, move |name| RawContext::init(ops, name))
+    }
+
+    /// Accept clients until the listener fails.
+    pub fn run(&self) -> io::Result<()> {
+        for conn in self.listener.incoming() {
+            let conn = conn?;
+            let init = self.init.clone();
+            thread::spawn(move || serve(conn, |name| init(name)));
+        }
+        Ok(())
+    }
+}
+
+/// Serve one client until it disconnects.
+pub fn serve<F>(mut conn: UnixStream, init: F) -> io::Result<()>
+where
+    F: Fn(Option<&CStr>) -> Result<RawContext>,
+{
+    match Request::decode(&protocol::read_message(&mut conn)?)? {
+        Request::Hello {
+            version
+        } if version == PROTOCOL_VERSION => {
+            let hello = Response::Hello {
+                version: PROTOCOL_VERSION
+            };
+            protocol::write_message(&mut conn, &hello.encode())?;
+        },
+        _ => {
+            let refused = Response::Error(ffi::CUBEB_ERROR_NOT_SUPPORTED);
+            return protocol::write_message(&mut conn, &refused.encode());
+        },
+    }
+
+    let mut connection = Connection {
+        streams: HashMap::new(),
+        next_id: 0,
+        context: None
+    };
+    loop {
+        let message = match protocol::read_message(&mut conn) {
+            Ok(message) => message,
+            Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        let (response, fds) = match connection.handle(Request::decode(&message)?, &init) {
+            Ok(reply) => reply,
+            Err(e) => (Response::Error(e.raw_code()), Vec::new()),
+        };
+        protocol::write_message(&mut conn, &response.encode())?;
+        if !fds.is_empty() {
+            let raw: Vec<_> = fds.iter().map(|fd| fd.as_raw_fd()).collect();
+            sys::send_fds(&conn, &raw)?;
+        }
+    }
+}
+
+fn io_error(e: io::Error) -> Error {
+    Error::new().with_source(e)
+}
+
+fn c_string(s: *const c_char) -> Option<String> {
+    if s.is_null() {
+        None
+    } else {
+        Some(unsafe { CStr::from_ptr(s) }.to_string_lossy().into_owned())
+    }
+}
+
+struct ServerContext {
+    raw: RawContext,
+    // Target of the collection changed callback.
+    events: Box<UnixStream>,
+    // The collection last enumerated for each device type. Devids can
+    // point into their collection, so it lives while clients may name
+    // its devices.
+    collections: HashMap<ffi::cubeb_device_type, Collection>,
+    // What the ids the client sees stand for. Ids aren't reused, and 0
+    // is the default device.
+    devices: HashMap<u64, ffi::cubeb_devid>,
+    next_device: u64
+}
+
+struct Collection {
+    raw: ffi::cubeb_device_collection,
+    ids: Vec<u64>
+}
+
+impl ServerContext {
+    // The devid a client named, if it was enumerated and still is.
+    fn device(&self, id: u64) -> Result<DeviceId> {
+        let devid = if id == 0 {
+            ptr::null()
+        } else {
+            *self.devices
+                .get(&id)
+                .ok_or_else(|| Error::from(ErrorCode::InvalidParameter))?
+        };
+        Ok(unsafe { DeviceId::from_raw(devid) })
+    }
+
+    // Drop a collection and the ids of its devices.
+    fn forget(&mut self, mut collection: Collection) {
+        for id in &collection.ids {
+            self.devices.remove(id);
+        }
+        let _ = self.raw.device_collection_destroy(&mut collection.raw);
+    }
+}
+
+impl Drop for ServerContext {
+    fn drop(&mut self) {
+        let collections: Vec<_> = self.collections.drain().map(|(_, c)| c).collect();
+        for collection in collections {
+            self.forget(collection);
+        }
+    }
+}
+
+struct Connection {
+    // Streams must be destroyed before their context.
+    streams: HashMap<u32, ServerStream>,
+    next_id: u32,
+    context: Option<ServerContext>
+}
+
+struct ServerStream {
+    raw: RawStream,
+    // Referenced by the backend's callbacks until `raw` is destroyed.
+    state: Box<StreamState>,
+    // Number of the last `Prefill` sent.
+    prefill: Cell<u32>
+}
+
+struct StreamState {
+    audio: UnsafeCell<Audio>,
+    input_frame: usize,
+    output_frame: usize,
+    // Written by the RPC thread, the audio thread and the state callback.
+    events: EventSender
+}
+
+struct Audio {
+    input: Option<Producer>,
+    output: Option<Consumer>,
+    // Holds the rings above, so it's dropped last. Sized and set once the
+    // backend granted its latency, before the stream can start.
+    shm: Option<StreamShm>
+}
+
+impl Connection {
+    fn context(&self) -> Result<&ServerContext> {
+        self.context
+            .as_ref()
+            .ok_or_else(|| Error::from(ErrorCode::InvalidParameter))
+    }
+
+    fn context_mut(&mut self) -> Result<&mut ServerContext> {
+        self.context
+            .as_mut()
+            .ok_or_else(|| Error::from(ErrorCode::InvalidParameter))
+    }
+
+    fn stream(&self, id: u32) -> Result<&ServerStream> {
+        self.streams
+            .get(&id)
+            .ok_or_else(|| Error::from(ErrorCode::InvalidParameter))
+    }
+
+    fn handle<F>(&mut self, request: Request, init: &F) -> Result<(Response, Vec<OwnedFd>)>
+    where
+        F: Fn(Option<&CStr>) -> Result<RawContext>,
+    {
+        let response = match request {
+            Request::Hello {
+                ..
+            } => return Err(Error::from(ErrorCode::InvalidParameter)),
+            Request::ContextInit {
+                name
+            } => return self.context_init(name, init),
+            Request::BackendId => {
+                let id = self.context()?.raw.backend_id();
+                Response::BackendId(id.to_string_lossy().into_owned())
+            },
+            Request::MaxChannelCount => Response::U32(self.context()?.raw.max_channel_count()?),
+            Request::MinLatency(params) => {
+                let params = unsafe { StreamParams::from_raw(&params as *const _) };
+                Response::U32(self.context()?.raw.min_latency(&params)?)
+            },
+            Request::PreferredSampleRate => {
+                Response::U32(self.context()?.raw.preferred_sample_rate()?)
+            },
+            Request::PreferredChannelLayout => {
+                Response::U32(self.context()?.raw.preferred_channel_layout()? as u32)
+            },
+            Request::EnumerateDevices(devtype) => {
+                Response::Devices(self.enumerate_devices(devtype)?)
+            },
+            Request::RegisterDeviceCollectionChanged(devtype) => {
+                let context = self.context()?;
+                let events = &*context.events as *const UnixStream as *mut c_void;
+                unsafe {
+                    context.raw.register_device_collection_changed(
+                        DeviceType::from_bits_truncate(devtype),
+                        Some(collection_changed_cb),
+                        events
+                    )?;
+                }
+                Response::Ok
+            },
+            Request::StreamInit {
+                name,
+                input,
+                output,
+                latency_frames
+            } => return self.stream_init(name, input, output, latency_frames),
+            Request::StreamDestroy(id) => {
+                self.streams
+                    .remove(&id)
+                    .ok_or_else(|| Error::from(ErrorCode::InvalidParameter))?;
+                Response::Ok
+            },
+            Request::StreamStart(id) => {
+                self.stream_start(id)?;
+                Response::Ok
+            },
+            Request::StreamStop(id) => {
+                self.stream(id)?.raw.stop()?;
+                Response::Ok
+            },
+            Request::StreamResetDefaultDevice(id) => {
+                self.stream(id)?.raw.reset_default_device()?;
+                Response::Ok
+            },
+            Request::StreamGetPosition(id) => Response::U64(self.stream(id)?.raw.position()?),
+            Request::StreamGetLatency(id) => Response::U32(self.stream(id)?.raw.latency()?),
+            Request::StreamSetVolume(id, volume) => {
+                self.stream(id)?.raw.set_volume(volume)?;
+                Response::Ok
+            },
+            Request::StreamSetPanning(id, panning) => {
+                self.stream(id)?.raw.set_panning(panning)?;
+                Response::Ok
+            },
+            Request::StreamGetCurrentDevice(id) => {
+                let raw = &self.stream(id)?.raw;
+                let device = raw.current_device()?;
+                let response = unsafe {
+                    Response::CurrentDevice {
+                        input_name: c_string((*device).input_name),
+                        output_name: c_string((*device).output_name)
+                    }
+                };
+                unsafe { raw.device_destroy(device)? };
+                response
+            },
+            Request::StreamRegisterDeviceChanged(id) => {
+                self.stream(id)?
+                    .raw
+                    .register_device_changed_callback(Some(device_changed_cb))?;
+                Response::Ok
+            },
+        };
+        Ok((response, Vec::new()))
+    }
+
+    fn context_init<F>(&mut self, name: Option<String>, init: &F) -> Result<(Response, Vec<OwnedFd>)>
+    where
+        F: Fn(Option<&CStr>) -> Result<RawContext>,
+    {
+        if self.context.is_some() {
+            return Err(Error::from(ErrorCode::InvalidParameter));
+        }
+        let name = match name {
+            Some(name) => Some(CString::new(name)?),
+            None => None,
+        };
+        let raw = init(name.as_deref())?;
+        let (events, client_events) = UnixStream::pair().map_err(io_error)?;
+        self.context = Some(ServerContext {
+            raw,
+            events: Box::new(events),
+            collections: HashMap::new(),
+            devices: HashMap::new(),
+            next_device: 1
+        });
+        Ok((Response::ContextCreated, vec![OwnedFd::from(client_events)]))
+    }
+
+    // Devids stay on this side: the client gets ids for them, valid until
+    // devices of the same type are enumerated again.
+    fn enumerate_devices(&mut self, devtype: ffi::cubeb_device_type) -> Result<Vec<DeviceInfo>> {
+        let context = self.context_mut()?;
+        let collection = context
+            .raw
+            .enumerate_devices(DeviceType::from_bits_truncate(devtype))?;
+        if let Some(old) = context.collections.remove(&devtype) {
+            context.forget(old);
+        }
+        let infos = if collection.device.is_null() {
+            &[][..]
+        } else {
+            unsafe { slice::from_raw_parts(collection.device, collection.count) }
+        };
+        let first = context.next_device;
+        context.next_device += infos.len() as u64;
+        let ids: Vec<u64> = (first..context.next_device).collect();
+        for (&id, d) in ids.iter().zip(infos) {
+            context.devices.insert(id, d.devid);
+        }
+        let devices = infos
+            .iter()
+            .zip(&ids)
+            .map(|(d, &devid)| DeviceInfo {
+                devid,
+                ..unsafe { DeviceInfo::from_ffi(d) }
+            })
+            .collect();
+        context.collections.insert(devtype, Collection {
+            raw: collection,
+            ids
+        });
+        Ok(devices)
+    }
+
+    fn stream_init(
+        &mut self,
+        name: Option<String>,
+        input: Option<StreamDirection>,
+        output: Option<StreamDirection>,
+        latency_frames: u32,
+    ) -> Result<(Response, Vec<OwnedFd>)> {
+        let input_frame = input.as_ref().map_or(Some(0), |d| frame_size(&d.params));
+        let output_frame = output.as_ref().map_or(Some(0), |d| frame_size(&d.params));
+        let (input_frame, output_frame) = match (input_frame, output_frame) {
+            (Some(i), Some(o)) => (i, o),
+            _ => return Err(Error::from(ErrorCode::InvalidFormat)),
+        };
+        if (input.is_none() && output.is_none()) || latency_frames == 0 ||
+            latency_frames > MAX_LATENCY
+        {
+            return Err(Error::from(ErrorCode::InvalidParameter));
+        }
+        let name = match name {
+            Some(name) => Some(CString::new(name)?),
+            None => None,
+        };
+        let (input_device, output_device) = {
+            let context = self.context()?;
+            let device = |d: &Option<StreamDirection>| {
+                context.device(d.as_ref().map_or(0, |d| d.devid))
+            };
+            (device(&input)?, device(&output)?)
+        };
+
+        let (events, client_events) = UnixStream::pair().map_err(io_error)?;
+        let state = Box::new(StreamState {
+            audio: UnsafeCell::new(Audio {
+                input: None,
+                output: None,
+                shm: None
+            }),
+            input_frame,
+            output_frame,
+            events: EventSender::new(events)
+        });
+
+        let raw = unsafe {
+            self.context()?.raw.stream_init(
+                name.as_deref(),
+                input_device,
+                input.as_ref().map(|d| &d.params),
+                output_device,
+                output.as_ref().map(|d| &d.params),
+                latency_frames,
+                data_cb,
+                state_cb,
+                &*state as *const StreamState as *mut c_void
+            )?
+        };
+
+        // Backends can raise the latency, and the client runs a period of
+        // what they granted at a time.
+        let latency_frames = match raw.latency() {
+            Ok(latency) if latency > 0 => latency,
+            _ => latency_frames,
+        };
+        if latency_frames > MAX_LATENCY {
+            return Err(Error::from(ErrorCode::InvalidParameter));
+        }
+        // Room for four periods.
+        let ring_size = |frame: usize| if frame == 0 {
+            0
+        } else {
+            (4 * latency_frames as usize * frame).next_power_of_two()
+        };
+        let shm = StreamShm::create(ring_size(input_frame), ring_size(output_frame))
+            .map_err(io_error)?;
+        let shm_file = shm.file().try_clone().map_err(io_error)?;
+        // The backend doesn't call back before the stream starts.
+        unsafe {
+            *state.audio.get() = Audio {
+                input: shm.input().map(|(producer, _)| producer),
+                output: shm.output().map(|(_, consumer)| consumer),
+                shm: Some(shm)
+            };
+        }
+
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+        self.streams.insert(id, ServerStream {
+            raw,
+            state,
+            prefill: Cell::new(0)
+        });
+        Ok((
+            Response::StreamCreated {
+                id,
+                latency_frames
+            },
+            vec![OwnedFd::from(shm_file), OwnedFd::from(client_events)]
+        ))
+    }
+
+    fn stream_start(&self, id: u32) -> Result<()> {
+        let stream = self.stream(id)?;
+        let events = &stream.state.events;
+        // Let the client fill the output ring so the stream doesn't start
+        // with an underrun. Start anyway if the client is too slow, and
+        // skip replies to earlier requests that came too late.
+        let seq = stream.prefill.get().wrapping_add(1);
+        stream.prefill.set(seq);
+        events.send(Event::Prefill(seq)).map_err(io_error)?;
+        let deadline = Instant::now() + PREFILL_TIMEOUT;
+        loop {
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+            match sys::recv_event_timeout(events.socket(), deadline - now) {
+                Ok(Some(Event::Prefilled(n))) if n == seq => break,
+                Ok(Some(_)) => {},
+                Ok(None) | Err(_) => break,
+            }
+        }
+        stream.raw.start()
+    }
+}
+
+extern "C" fn data_cb(
+    _: *mut ffi::cubeb_stream,
+    user_ptr: *mut c_void,
+    input: *const c_void,
+    output: *mut c_void,
+    nframes: c_long,
+) -> c_long {
+    let state = unsafe { &*(user_ptr as *const StreamState) };
+    // Only the audio thread touches the ring ends.
+    let audio = unsafe { &mut *state.audio.get() };
+    let flags = match audio.shm {
+        Some(ref shm) => shm.flags().load(Ordering::Acquire),
+        None => return ffi::CUBEB_ERROR as c_long,
+    };
+    if flags & FLAG_ERROR != 0 {
+        return ffi::CUBEB_ERROR as c_long;
+    }
+    let nframes = nframes.max(0) as usize;
+
+    if let (Some(producer), false) = (audio.input.as_mut(), input.is_null()) {
+        let len = nframes * state.input_frame;
+        let buffer = unsafe { slice::from_raw_parts(input as *const u8, len) };
+        // On overrun the newest audio is dropped.
+        producer.write(buffer);
+    }
+
+    let mut frames = nframes;
+    match (audio.output.as_mut(), output.is_null()) {
+        (Some(consumer), false) => {
+            let len = nframes * state.output_frame;
+            let buffer = unsafe { slice::from_raw_parts_mut(output as *mut u8, len) };
+            let got = consumer.read(buffer);
+            for x in buffer[got..].iter_mut() {
+                *x = 0;
+            }
+            if flags & FLAG_DRAINING != 0 {
+                frames = got / state.output_frame;
+            }
+        },
+        _ => {
+            if flags & FLAG_DRAINING != 0 {
+                frames = 0;
+            }
+        },
+    }
+
+    let _ = state.events.try_send(Event::Wake);
+    frames as c_long
+}
+
+extern "C" fn state_cb(_: *mut ffi::cubeb_stream, user_ptr: *mut c_void, state: ffi::cubeb_state) {
+    let stream = unsafe { &*(user_ptr as *const StreamState) };
+    // The client reports started and stopped itself, in order with its
+    // own calls.
+    if state == ffi::CUBEB_STATE_DRAINED || state == ffi::CUBEB_STATE_ERROR {
+        let _ = stream.events.send(Event::State(state));
+    }
+}
+
+extern "C" fn device_changed_cb(user_ptr: *mut c_void) {
+    let stream = unsafe { &*(user_ptr as *const StreamState) };
+    let _ = stream.events.send(Event::DeviceChanged);
+}
+
+extern "C" fn collection_changed_cb(_: *mut ffi::cubeb, user_ptr: *mut c_void) {
+    let events = unsafe { &*(user_ptr as *const UnixStream) };
+    let _ = sys::send_event(events, Event::CollectionChanged);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use null;
+    use std::os::fd::FromRawFd;
+
+    fn exchange(conn: &mut UnixStream, request: &Request) -> Response {
+        protocol::write_message(conn, &request.encode()).unwrap();
+        read_response(conn)
+    }
+
+    fn read_response(conn: &mut UnixStream) -> Response {
+        Response::decode(&protocol::read_message(conn).unwrap()).unwrap()
+    }
+
+    fn recv_fds(conn: &UnixStream, count: usize) -> Vec<OwnedFd> {
+        let fds = sys::recv_fds(conn, count).unwrap();
+        fds.into_iter().map(|fd| unsafe { OwnedFd::from_raw_fd(fd) }).collect()
+    }
+
+    fn recv_prefill(events: &UnixStream) -> u32 {
+        loop {
+            if let Event::Prefill(seq) = sys::recv_event(events).unwrap() {
+                return seq;
+            }
+        }
+    }
+
+    // A connection to a server of the null backend, with a context.
+    fn connect() -> UnixStream {
+        let (server, mut conn) = UnixStream::pair().unwrap();
+        thread::spawn(move || serve(server, |name| RawContext::init(&null::OPS, name)));
+        let hello = Request::Hello {
+            version: PROTOCOL_VERSION
+        };
+        exchange(&mut conn, &hello);
+        let init = Request::ContextInit {
+            name: None
+        };
+        assert_eq!(exchange(&mut conn, &init), Response::ContextCreated);
+        recv_fds(&conn, 1);
+        conn
+    }
+
+    fn output_init(devid: u64) -> Request {
+        output_init_with_latency(devid, 480)
+    }
+
+    fn output_init_with_latency(devid: u64, latency_frames: u32) -> Request {
+        let output = StreamDirection {
+            devid,
+            params: ffi::cubeb_stream_params {
+                format: ffi::CUBEB_SAMPLE_FLOAT32NE,
+                rate: 48000,
+                channels: 2,
+                layout: ffi::CUBEB_LAYOUT_STEREO
+            }
+        };
+        Request::StreamInit {
+            name: None,
+            input: None,
+            output: Some(output),
+            latency_frames
+        }
+    }
+
+    #[test]
+    fn test_rings_fit_granted_latency() {
+        let mut conn = connect();
+        // The null backend raises this.
+        let init = output_init_with_latency(0, 16);
+        let (id, latency_frames) = match exchange(&mut conn, &init) {
+            Response::StreamCreated {
+                id,
+                latency_frames
+            } => (id, latency_frames),
+            r => panic!("unexpected {:?}", r),
+        };
+        assert!(latency_frames > 16, "granted {}", latency_frames);
+        let shm = recv_fds(&conn, 2).remove(0);
+        let shm = StreamShm::open(shm.into()).unwrap();
+        let (producer, _) = shm.output().unwrap();
+        assert!(producer.capacity() >= 4 * latency_frames as usize * 8);
+        assert_eq!(exchange(&mut conn, &Request::StreamDestroy(id)), Response::Ok);
+    }
+
+    #[test]
+    fn test_device_ids_are_checked() {
+        let mut conn = connect();
+        let enumerate = Request::EnumerateDevices(ffi::CUBEB_DEVICE_TYPE_OUTPUT);
+        let devices = match exchange(&mut conn, &enumerate) {
+            Response::Devices(devices) => devices,
+            r => panic!("unexpected {:?}", r),
+        };
+        assert!(!devices.is_empty());
+        let unknown = devices.iter().map(|d| d.devid).max().unwrap() + 1;
+        assert_eq!(
+            exchange(&mut conn, &output_init(unknown)),
+            Response::Error(ffi::CUBEB_ERROR_INVALID_PARAMETER)
+        );
+
+        let id = match exchange(&mut conn, &output_init(devices[0].devid)) {
+            Response::StreamCreated {
+                id,
+                ..
+            } => id,
+            r => panic!("unexpected {:?}", r),
+        };
+        recv_fds(&conn, 2);
+        assert_eq!(exchange(&mut conn, &Request::StreamDestroy(id)), Response::Ok);
+
+        // Enumerating again retires the earlier ids.
+        exchange(&mut conn, &enumerate);
+        assert_eq!(
+            exchange(&mut conn, &output_init(devices[0].devid)),
+            Response::Error(ffi::CUBEB_ERROR_INVALID_PARAMETER)
+        );
+    }
+
+    #[test]
+    fn test_late_prefilled_is_ignored() {
+        let mut conn = connect();
+        let id = match exchange(&mut conn, &output_init(0)) {
+            Response::StreamCreated {
+                id,
+                ..
+            } => id,
+            r => panic!("unexpected {:?}", r),
+        };
+        let events = UnixStream::from(recv_fds(&conn, 2).pop().unwrap());
+
+        // Don't answer the first prefill until the server gave up on it.
+        protocol::write_message(&mut conn, &Request::StreamStart(id).encode()).unwrap();
+        let first = recv_prefill(&events);
+        assert_eq!(read_response(&mut conn), Response::Ok);
+        sys::send_event(&events, Event::Prefilled(first)).unwrap();
+        assert_eq!(exchange(&mut conn, &Request::StreamStop(id)), Response::Ok);
+
+        // The late reply doesn't satisfy the next start.
+        protocol::write_message(&mut conn, &Request::StreamStart(id).encode()).unwrap();
+        let second = recv_prefill(&events);
+        assert_ne!(first, second);
+        conn.set_read_timeout(Some(Duration::from_millis(200))).unwrap();
+        assert!(protocol::read_message(&mut conn).is_err());
+        conn.set_read_timeout(None).unwrap();
+        sys::send_event(&events, Event::Prefilled(second)).unwrap();
+        assert_eq!(read_response(&mut conn), Response::Ok);
+
+        assert_eq!(exchange(&mut conn, &Request::StreamStop(id)), Response::Ok);
+        assert_eq!(exchange(&mut conn, &Request::StreamDestroy(id)), Response::Ok);
+    }
+}
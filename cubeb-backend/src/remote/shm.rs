@@ -0,0 +1,135 @@
+// Copyright © 2017 Mozilla Foundation
+//
+// This program is made available under an ISC-style license.  See the
+// accompanying file LICENSE for details.
+
+//! Memory shared by the client and server ends of a stream.
+//!
+//! A header holds the state of two rings, followed by their storage:
+//! captured audio flows from server to client through the input ring,
+//! rendered audio from client to server through the output ring.
+
+use remote::protocol::PROTOCOL_VERSION;
+use remote::sys::SharedMem;
+use ring::{self, Consumer, Producer, RingHeader, MAX_CAPACITY};
+use std::fs::File;
+use std::io;
+use std::mem;
+use std::sync::atomic::AtomicU32;
+
+const SHM_MAGIC: u32 = 0x4853_4243; // "CBSH"
+
+/// Set by the client once its data callback returned fewer frames than
+/// requested. The output ring holds the last of the audio.
+pub const FLAG_DRAINING: u32 = 0x1;
+/// Set by the client once its data callback failed.
+pub const FLAG_ERROR: u32 = 0x2;
+
+#[repr(C)]
+struct Header {
+    magic: u32,
+    version: u32,
+    input_capacity: u32,
+    output_capacity: u32,
+    flags: AtomicU32,
+    input: RingHeader,
+    output: RingHeader
+}
+
+const DATA_OFFSET: usize = 64;
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn valid_capacity(capacity: usize) -> bool {
+    capacity == 0 || (capacity.is_power_of_two() && capacity <= MAX_CAPACITY)
+}
+
+pub struct StreamShm {
+    mem: SharedMem,
+    input_capacity: usize,
+    output_capacity: usize
+}
+
+impl StreamShm {
+    /// Create the memory for rings of the given capacities, in bytes. A
+    /// capacity of 0 omits the ring.
+    pub fn create(input_capacity: usize, output_capacity: usize) -> io::Result<StreamShm> {
+        assert!(mem::size_of::<Header>() <= DATA_OFFSET);
+        if !valid_capacity(input_capacity) || !valid_capacity(output_capacity) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "invalid capacity"));
+        }
+        let mem = SharedMem::create(DATA_OFFSET + input_capacity + output_capacity)?;
+        unsafe {
+            let header = mem.as_ptr() as *mut Header;
+            (*header).magic = SHM_MAGIC;
+            (*header).version = PROTOCOL_VERSION;
+            (*header).input_capacity = input_capacity as u32;
+            (*header).output_capacity = output_capacity as u32;
+        }
+        Ok(StreamShm {
+            mem,
+            input_capacity,
+            output_capacity
+        })
+    }
+
+    /// Map memory created by the other end and check its layout.
+    pub fn open(file: File) -> io::Result<StreamShm> {
+        let mem = SharedMem::open(file)?;
+        if mem.len() < DATA_OFFSET {
+            return Err(invalid_data("shared memory too small"));
+        }
+        let header = unsafe { &*(mem.as_ptr() as *const Header) };
+        if header.magic != SHM_MAGIC || header.version != PROTOCOL_VERSION {
+            return Err(invalid_data("incompatible shared memory"));
+        }
+        let input_capacity = header.input_capacity as usize;
+        let output_capacity = header.output_capacity as usize;
+        if !valid_capacity(input_capacity) || !valid_capacity(output_capacity) ||
+            DATA_OFFSET + input_capacity + output_capacity > mem.len()
+        {
+            return Err(invalid_data("invalid shared memory layout"));
+        }
+        Ok(StreamShm {
+            mem,
+            input_capacity,
+            output_capacity
+        })
+    }
+
+    fn header(&self) -> &Header {
+        unsafe { &*(self.mem.as_ptr() as *const Header) }
+    }
+
+    /// `FLAG_*` bits, written by the client.
+    pub fn flags(&self) -> &AtomicU32 {
+        &self.header().flags
+    }
+
+    /// The input ring, if the stream has one. Each end must use only one
+    /// side of the ring, and must not outlive `self`.
+    pub fn input(&self) -> Option<(Producer, Consumer)> {
+        if self.input_capacity == 0 {
+            return None;
+        }
+        let data = unsafe { self.mem.as_ptr().add(DATA_OFFSET) };
+        Some(unsafe { ring::from_raw(&self.header().input, data, self.input_capacity) })
+    }
+
+    /// The output ring, if the stream has one. Each end must use only one
+    /// side of the ring, and must not outlive `self`.
+    pub fn output(&self) -> Option<(Producer, Consumer)> {
+        if self.output_capacity == 0 {
+            return None;
+        }
+        let offset = DATA_OFFSET + self.input_capacity;
+        let data = unsafe { self.mem.as_ptr().add(offset) };
+        Some(unsafe { ring::from_raw(&self.header().output, data, self.output_capacity) })
+    }
+
+    pub fn file(&self) -> &File {
+        self.mem.file()
+    }
+}
@@ -0,0 +1,293 @@
+// Copyright © 2017 Mozilla Foundation
+//
+// This program is made available under an ISC-style license.  See the
+// accompanying file LICENSE for details.
+
+//! Descriptor passing, event sockets and shared memory.
+
+use libc;
+use remote::protocol::{Event, EVENT_LEN};
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read};
+use std::mem;
+use std::os::raw::c_void;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::process;
+use std::ptr;
+use std::sync::{Mutex, PoisonError, TryLockError};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+// Never raise SIGPIPE when the peer has gone away.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+const SEND_FLAGS: libc::c_int = libc::MSG_NOSIGNAL;
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+const SEND_FLAGS: libc::c_int = 0;
+
+/// Send `fds` attached to a single byte.
+pub fn send_fds(sock: &UnixStream, fds: &[RawFd]) -> io::Result<()> {
+    let mut byte = [0u8];
+    let mut iov = libc::iovec {
+        iov_base: byte.as_mut_ptr() as *mut c_void,
+        iov_len: 1
+    };
+    let data_len = mem::size_of_val(fds) as libc::c_uint;
+    let space = unsafe { libc::CMSG_SPACE(data_len) } as usize;
+    // u64 keeps the control buffer aligned for cmsghdr.
+    let mut control = vec![0u64; space.div_ceil(8)];
+    unsafe {
+        let mut msg: libc::msghdr = mem::zeroed();
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr() as *mut c_void;
+        msg.msg_controllen = space as _;
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        (*cmsg).cmsg_level = libc::SOL_SOCKET;
+        (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+        (*cmsg).cmsg_len = libc::CMSG_LEN(data_len) as _;
+        ptr::copy_nonoverlapping(fds.as_ptr(), libc::CMSG_DATA(cmsg) as *mut RawFd, fds.len());
+        if libc::sendmsg(sock.as_raw_fd(), &msg, SEND_FLAGS) != 1 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// Receive exactly `count` descriptors sent by `send_fds`. The caller
+/// owns the result.
+pub fn recv_fds(sock: &UnixStream, count: usize) -> io::Result<Vec<RawFd>> {
+    let mut byte = [0u8];
+    let mut iov = libc::iovec {
+        iov_base: byte.as_mut_ptr() as *mut c_void,
+        iov_len: 1
+    };
+    let data_len = (count * mem::size_of::<RawFd>()) as libc::c_uint;
+    let space = unsafe { libc::CMSG_SPACE(data_len) } as usize;
+    let mut control = vec![0u64; space.div_ceil(8)];
+    let mut fds = Vec::with_capacity(count);
+    unsafe {
+        let mut msg: libc::msghdr = mem::zeroed();
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr() as *mut c_void;
+        msg.msg_controllen = space as _;
+        match libc::recvmsg(sock.as_raw_fd(), &mut msg, 0) {
+            1 => {},
+            0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+            _ => return Err(io::Error::last_os_error()),
+        }
+        let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+        while !cmsg.is_null() {
+            if (*cmsg).cmsg_level == libc::SOL_SOCKET && (*cmsg).cmsg_type == libc::SCM_RIGHTS {
+                let data = libc::CMSG_DATA(cmsg) as *const RawFd;
+                let len = (*cmsg).cmsg_len as usize - (data as usize - cmsg as usize);
+                for i in 0..len / mem::size_of::<RawFd>() {
+                    fds.push(ptr::read_unaligned(data.add(i)));
+                }
+            }
+            cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+        }
+        if fds.len() != count || msg.msg_flags & libc::MSG_CTRUNC != 0 {
+            for fd in fds {
+                libc::close(fd);
+            }
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "missing descriptors"));
+        }
+    }
+    Ok(fds)
+}
+
+/// Send `event`, blocking while the socket is full.
+pub fn send_event(sock: &UnixStream, event: Event) -> io::Result<()> {
+    send_event_flags(sock, event, 0)
+}
+
+/// Send `event` unless the socket is full. Safe to call on an audio
+/// thread.
+pub fn try_send_event(sock: &UnixStream, event: Event) -> io::Result<()> {
+    send_event_flags(sock, event, libc::MSG_DONTWAIT)
+}
+
+fn send_event_flags(sock: &UnixStream, event: Event, mut flags: libc::c_int)
+    -> io::Result<()> {
+    let buf = event.encode();
+    let mut sent = 0;
+    while sent < buf.len() {
+        let r = unsafe {
+            libc::send(
+                sock.as_raw_fd(),
+                buf[sent..].as_ptr() as *const c_void,
+                buf.len() - sent,
+                flags | SEND_FLAGS
+            )
+        };
+        if r >= 0 {
+            sent += r as usize;
+            continue;
+        }
+        let err = io::Error::last_os_error();
+        match err.kind() {
+            io::ErrorKind::Interrupted => {},
+            // Finish a partially sent event, or the stream is corrupt.
+            io::ErrorKind::WouldBlock if sent > 0 => flags = 0,
+            _ => return Err(err),
+        }
+    }
+    Ok(())
+}
+
+/// Block until the next event arrives.
+pub fn recv_event(mut sock: &UnixStream) -> io::Result<Event> {
+    let mut buf = [0; EVENT_LEN];
+    sock.read_exact(&mut buf)?;
+    Event::decode(&buf)
+}
+
+/// Wait up to `timeout` for the next event. Only the wait for the first
+/// byte times out, so an event is never left half read.
+pub fn recv_event_timeout(sock: &UnixStream, timeout: Duration) -> io::Result<Option<Event>> {
+    let mut fd = libc::pollfd {
+        fd: sock.as_raw_fd(),
+        events: libc::POLLIN,
+        revents: 0
+    };
+    let ms = timeout.as_millis().min(libc::c_int::MAX as u128) as libc::c_int;
+    loop {
+        match unsafe { libc::poll(&mut fd, 1, ms) } {
+            0 => return Ok(None),
+            r if r > 0 => return recv_event(sock).map(Some),
+            _ => {
+                let err = io::Error::last_os_error();
+                if err.kind() != io::ErrorKind::Interrupted {
+                    return Err(err);
+                }
+            },
+        }
+    }
+}
+
+/// The sending end of an event socket written from several threads.
+///
+/// Each event is sent whole under a lock, so events from different
+/// threads never interleave on the socket.
+pub struct EventSender {
+    sock: UnixStream,
+    lock: Mutex<()>
+}
+
+impl EventSender {
+    pub fn new(sock: UnixStream) -> EventSender {
+        EventSender {
+            sock,
+            lock: Mutex::new(())
+        }
+    }
+
+    pub fn socket(&self) -> &UnixStream {
+        &self.sock
+    }
+
+    /// Send `event`, blocking while another thread sends or the socket
+    /// is full.
+    pub fn send(&self, event: Event) -> io::Result<()> {
+        let _guard = self.lock.lock().unwrap_or_else(PoisonError::into_inner);
+        send_event(&self.sock, event)
+    }
+
+    /// Send `event` unless another thread is sending or the socket is
+    /// full. Safe to call on an audio thread.
+    pub fn try_send(&self, event: Event) -> io::Result<()> {
+        let _guard = match self.lock.try_lock() {
+            Ok(guard) => guard,
+            Err(TryLockError::Poisoned(e)) => e.into_inner(),
+            Err(TryLockError::WouldBlock) => return Err(io::ErrorKind::WouldBlock.into()),
+        };
+        try_send_event(&self.sock, event)
+    }
+}
+
+/// A shared memory mapping backed by an unlinked file.
+pub struct SharedMem {
+    file: File,
+    ptr: *mut u8,
+    len: usize
+}
+
+unsafe impl Send for SharedMem {}
+unsafe impl Sync for SharedMem {}
+
+impl SharedMem {
+    /// Create a zeroed mapping of `len` bytes.
+    pub fn create(len: usize) -> io::Result<SharedMem> {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let mut path = env::temp_dir();
+        path.push(format!(
+            "cubeb-remote-{}-{}",
+            process::id(),
+            COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&path)?;
+        // Only the descriptor is shared; nobody else can open the file.
+        fs::remove_file(&path)?;
+        file.set_len(len as u64)?;
+        SharedMem::map(file, len)
+    }
+
+    /// Map a file received from the creator.
+    pub fn open(file: File) -> io::Result<SharedMem> {
+        let len = file.metadata()?.len() as usize;
+        SharedMem::map(file, len)
+    }
+
+    fn map(file: File, len: usize) -> io::Result<SharedMem> {
+        if len == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "empty mapping"));
+        }
+        let ptr = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED,
+                file.as_raw_fd(),
+                0
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(SharedMem {
+            file,
+            ptr: ptr as *mut u8,
+            len
+        })
+    }
+
+    pub fn as_ptr(&self) -> *mut u8 {
+        self.ptr
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn file(&self) -> &File {
+        &self.file
+    }
+}
+
+impl Drop for SharedMem {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.ptr as *mut c_void, self.len);
+        }
+    }
+}
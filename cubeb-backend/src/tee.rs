@@ -0,0 +1,531 @@
+// Copyright © 2017 Mozilla Foundation
+//
+// This program is made available under an ISC-style license.  See the
+// accompanying file LICENSE for details.
+
+//! A backend that wraps another and copies what output streams play.
+//!
+//! Contexts created by `TeeContext::wrap` forward every operation to the
+//! wrapped backend. Each output stream also opens a secondary `Sink`,
+//! which receives every buffer the stream renders, scaled by the stream
+//! volume the way `driver::apply_volume` scales it, for QA capture or
+//! for streaming what the user hears.
+//!
+//! The audio thread only copies each buffer into a ring allocated when
+//! the stream is created, and wakes a thread that writes the ring to the
+//! sink, so a slow sink never delays the stream. Copies reach the sink as
+//! soon as that thread is scheduled; when the sink falls more than
+//! `BACKLOG_PERIODS` periods behind, buffers are dropped from the copy
+//! and counted by `TeeStream::dropped_frames`.
+
+use {Context, Ops, Stream};
+use cubeb_core::{DeviceId, DeviceType, Error, ErrorCode, Result, SampleFormat,
+                 StreamParams};
+use cubeb_core::binding::Binding;
+use cubeb_core::ffi;
+use driver::{self, Sink};
+use null;
+use raw::{RawContext, RawStream};
+use ring::{self, Consumer, Producer};
+use std::{env, ptr, slice};
+use std::cell::UnsafeCell;
+use std::ffi::CStr;
+use std::os::raw::{c_long, c_void};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicPtr, AtomicU32, AtomicU64, Ordering};
+use std::thread::{self, JoinHandle, Thread};
+use wav::{WavSpec, WavWriter};
+
+pub const OPS: Ops = capi_new!(TeeContext, TeeStream);
+
+/// Environment variable naming the WAV file `TeeContext::init` copies
+/// output streams to.
+pub const TEE_ENV: &str = "CUBEB_TEE_FILE";
+
+/// Periods of audio the copy can fall behind before buffers are dropped.
+pub const BACKLOG_PERIODS: usize = 16;
+
+// Smallest period used to size the ring, for streams with tiny latencies
+// whose callbacks ask for more frames.
+const MIN_PERIOD: usize = 1024;
+
+// Bytes copied per pass on the audio thread.
+const SCRATCH_LEN: usize = 4096;
+
+/// A sink fed by a closure, called with interleaved audio and its frame
+/// count.
+pub struct FnSink<F>(pub F);
+
+impl<F> Sink for FnSink<F>
+where
+    F: FnMut(&[u8], usize) -> Result<()> + Send,
+{
+    fn write(&mut self, buffer: &[u8], frames: usize) -> Result<()> {
+        (self.0)(buffer, frames)
+    }
+}
+
+/// Fills the ring, failing without writing anything when the whole
+/// buffer doesn't fit.
+impl Sink for Producer {
+    fn write(&mut self, buffer: &[u8], _frames: usize) -> Result<()> {
+        if self.available() < buffer.len() {
+            return Err(Error::new());
+        }
+        Producer::write(self, buffer);
+        Ok(())
+    }
+}
+
+/// Opens the secondary sink of an output stream.
+pub type OpenSink = dyn Fn(&StreamParams) -> Result<Box<dyn Sink>> + Send + Sync;
+
+type CollectionChanged = (ffi::cubeb_device_collection_changed_callback, *mut c_void);
+
+#[repr(C)]
+pub struct TeeContext {
+    ops: *const Ops,
+    inner: RawContext,
+    open_sink: Box<OpenSink>,
+    collection_changed: Mutex<Option<CollectionChanged>>
+}
+
+impl TeeContext {
+    /// Wrap `inner`, copying every output stream into the sink
+    /// `open_sink` returns for its parameters. Streams fail to initialize
+    /// when it fails.
+    pub fn wrap<F>(inner: RawContext, open_sink: F) -> *mut ffi::cubeb
+    where
+        F: Fn(&StreamParams) -> Result<Box<dyn Sink>> + Send + Sync + 'static,
+    {
+        let ctx = Box::new(TeeContext {
+            ops: &OPS as *const _,
+            inner,
+            open_sink: Box::new(open_sink),
+            collection_changed: Mutex::new(None)
+        });
+        Box::into_raw(ctx) as *mut _
+    }
+}
+
+impl Context for TeeContext {
+    /// Wrap the null backend, copying output streams to the WAV file
+    /// named by `TEE_ENV`. Each output stream recreates the file.
+    fn init(context_name: Option<&CStr>) -> Result<*mut ffi::cubeb> {
+        let path = env::var_os(TEE_ENV).ok_or_else(|| Error::from(ErrorCode::NotSupported))?;
+        let inner = RawContext::init(&null::OPS, context_name)?;
+        Ok(TeeContext::wrap(inner, move |params| {
+            let writer = WavWriter::create(&path, WavSpec::from_params(params))
+                .map_err(|_| Error::new())?;
+            Ok(Box::new(writer) as Box<dyn Sink>)
+        }))
+    }
+
+    fn backend_id(&self) -> &'static CStr {
+        unsafe { CStr::from_ptr(b"tee\0".as_ptr() as *const _) }
+    }
+
+    fn max_channel_count(&self) -> Result<u32> {
+        self.inner.max_channel_count()
+    }
+
+    fn min_latency(&self, params: &StreamParams) -> Result<u32> {
+        self.inner.min_latency(params)
+    }
+
+    fn preferred_sample_rate(&self) -> Result<u32> {
+        self.inner.preferred_sample_rate()
+    }
+
+    fn preferred_channel_layout(&self) -> Result<ffi::cubeb_channel_layout> {
+        self.inner.preferred_channel_layout()
+    }
+
+    fn enumerate_devices(
+        &self,
+        devtype: DeviceType,
+    ) -> Result<ffi::cubeb_device_collection> {
+        self.inner.enumerate_devices(devtype)
+    }
+
+    #[allow(clippy::not_unsafe_ptr_arg_deref)]
+    fn device_collection_destroy(
+        &self,
+        collection: *mut ffi::cubeb_device_collection,
+    ) {
+        let _ = self.inner
+            .device_collection_destroy(unsafe { &mut *collection });
+    }
+
+    fn stream_init(
+        &self,
+        stream_name: Option<&CStr>,
+        input_device: DeviceId,
+        input_stream_params: Option<&ffi::cubeb_stream_params>,
+        output_device: DeviceId,
+        output_stream_params: Option<&ffi::cubeb_stream_params>,
+        latency_frames: u32,
+        data_callback: ffi::cubeb_data_callback,
+        state_callback: ffi::cubeb_state_callback,
+        user_ptr: *mut c_void,
+    ) -> Result<*mut ffi::cubeb_stream> {
+        let shared = Arc::new(Shared::new());
+        let (tap, thread) = match output_stream_params {
+            Some(p) => {
+                let params = unsafe { StreamParams::from_raw(p) };
+                let sink = (self.open_sink)(&params)?;
+                let frame_size = driver::frame_size(&params);
+                let period = (latency_frames as usize).max(MIN_PERIOD);
+                let capacity = (period * frame_size * BACKLOG_PERIODS).min(ring::MAX_CAPACITY);
+                let (producer, consumer) = ring::ring(capacity);
+                let copier = Copier {
+                    consumer,
+                    sink,
+                    format: params.format(),
+                    channels: params.channels() as usize,
+                    frame_size,
+                    shared: shared.clone()
+                };
+                let thread = thread::Builder::new()
+                    .name("cubeb tee".to_string())
+                    .spawn(move || copier.run())
+                    .map_err(|_| Error::new())?;
+                let tap = Tap {
+                    producer,
+                    scratch: [0; SCRATCH_LEN],
+                    format: params.format(),
+                    frame_size,
+                    thread: thread.thread().clone()
+                };
+                (Some(tap), Some(thread))
+            },
+            None => (None, None),
+        };
+
+        let state = Box::new(StreamState {
+            stream: AtomicPtr::new(ptr::null_mut()),
+            shared,
+            copier: thread.as_ref().map(|t| t.thread().clone()),
+            tap: UnsafeCell::new(tap),
+            data_callback,
+            state_callback,
+            user_ptr,
+            device_changed: Mutex::new(None)
+        });
+        let inner = unsafe {
+            self.inner.stream_init(
+                stream_name,
+                input_device,
+                input_stream_params,
+                output_device,
+                output_stream_params,
+                latency_frames,
+                data_cb,
+                state_cb,
+                &*state as *const StreamState as *mut c_void
+            )
+        };
+        let inner = match inner {
+            Ok(inner) => inner,
+            Err(e) => {
+                stop_copier(&state, thread);
+                return Err(e);
+            },
+        };
+
+        let stm = Box::into_raw(Box::new(TeeStream {
+            context: self,
+            inner: Some(inner),
+            state,
+            thread
+        }));
+        unsafe { (*stm).state.stream.store(stm as *mut _, Ordering::Release) };
+        Ok(stm as *mut _)
+    }
+
+    fn register_device_collection_changed(
+        &self,
+        devtype: DeviceType,
+        cb: Option<ffi::cubeb_device_collection_changed_callback>,
+        user_ptr: *mut c_void,
+    ) -> Result<()> {
+        *self.collection_changed.lock().unwrap() = cb.map(|cb| (cb, user_ptr));
+        let this = self as *const TeeContext as *mut c_void;
+        let forward = cb.map(|_| collection_changed_cb as _);
+        unsafe {
+            self.inner
+                .register_device_collection_changed(devtype, forward, this)
+        }
+    }
+}
+
+// Shared by the audio thread, the copier and the stream.
+struct Shared {
+    volume: AtomicU32,
+    dropped_frames: AtomicU64,
+    drained: AtomicBool,
+    closed: AtomicBool
+}
+
+impl Shared {
+    fn new() -> Shared {
+        Shared {
+            volume: AtomicU32::new(1.0f32.to_bits()),
+            dropped_frames: AtomicU64::new(0),
+            drained: AtomicBool::new(false),
+            closed: AtomicBool::new(false)
+        }
+    }
+
+    fn volume(&self) -> f32 {
+        f32::from_bits(self.volume.load(Ordering::Relaxed))
+    }
+}
+
+// What the audio thread needs to copy a buffer, allocated up front.
+struct Tap {
+    producer: Producer,
+    scratch: [u8; SCRATCH_LEN],
+    format: SampleFormat,
+    frame_size: usize,
+    thread: Thread
+}
+
+impl Tap {
+    fn push(&mut self, shared: &Shared, buffer: &[u8], frames: usize) {
+        if self.producer.available() < buffer.len() {
+            shared.dropped_frames.fetch_add(frames as u64, Ordering::Relaxed);
+            return;
+        }
+        let volume = shared.volume();
+        // A multiple of every sample size, so chunks hold whole samples.
+        for chunk in buffer.chunks(SCRATCH_LEN) {
+            let scratch = &mut self.scratch[..chunk.len()];
+            scratch.copy_from_slice(chunk);
+            driver::apply_volume(self.format, scratch, volume);
+            self.producer.write(scratch);
+        }
+        self.thread.unpark();
+    }
+}
+
+// Writes the ring to the sink, on its own thread.
+struct Copier {
+    consumer: Consumer,
+    sink: Box<dyn Sink>,
+    format: SampleFormat,
+    channels: usize,
+    frame_size: usize,
+    shared: Arc<Shared>
+}
+
+impl Copier {
+    fn run(mut self) {
+        let mut buffer = vec![0u8; self.consumer.capacity()];
+        let sink_format = self.sink.format().unwrap_or(self.format);
+        let mut converted = if sink_format == self.format {
+            Vec::new()
+        } else {
+            vec![0u8; self.consumer.capacity() / driver::sample_size(self.format) *
+                          driver::sample_size(sink_format)]
+        };
+        let mut failed = false;
+        loop {
+            // Read before copying, so whatever preceded them is copied.
+            let closed = self.shared.closed.load(Ordering::Acquire);
+            let drained = self.shared.drained.swap(false, Ordering::AcqRel);
+
+            loop {
+                let len = self.consumer.available() / self.frame_size * self.frame_size;
+                if len == 0 {
+                    break;
+                }
+                self.consumer.read(&mut buffer[..len]);
+                let frames = len / self.frame_size;
+                if failed {
+                    self.shared.dropped_frames.fetch_add(frames as u64, Ordering::Relaxed);
+                    continue;
+                }
+                let written = if converted.is_empty() {
+                    self.sink.write(&buffer[..len], frames)
+                } else {
+                    let out_len = frames * self.channels * driver::sample_size(sink_format);
+                    let out = &mut converted[..out_len];
+                    driver::convert(self.format, &buffer[..len], sink_format, out);
+                    self.sink.write(out, frames)
+                };
+                if written.is_err() {
+                    failed = true;
+                    self.shared.dropped_frames.fetch_add(frames as u64, Ordering::Relaxed);
+                }
+            }
+
+            if drained && !failed {
+                failed = self.sink.drain().is_err();
+            }
+            if closed {
+                return;
+            }
+            // A sink that blocks may have used up the wake-up, so only
+            // park with nothing left to do.
+            if !self.shared.closed.load(Ordering::Acquire) &&
+                !self.shared.drained.load(Ordering::Acquire) &&
+                self.consumer.available() < self.frame_size
+            {
+                thread::park();
+            }
+        }
+    }
+}
+
+// Shared with the wrapped stream's callbacks.
+struct StreamState {
+    stream: AtomicPtr<ffi::cubeb_stream>,
+    shared: Arc<Shared>,
+    copier: Option<Thread>,
+    // Only used by the data callback.
+    tap: UnsafeCell<Option<Tap>>,
+    data_callback: ffi::cubeb_data_callback,
+    state_callback: ffi::cubeb_state_callback,
+    user_ptr: *mut c_void,
+    device_changed: Mutex<Option<ffi::cubeb_device_changed_callback>>
+}
+
+fn stop_copier(state: &StreamState, thread: Option<JoinHandle<()>>) {
+    state.shared.closed.store(true, Ordering::Release);
+    if let Some(thread) = thread {
+        thread.thread().unpark();
+        let _ = thread.join();
+    }
+}
+
+/// Stream copying its output to a secondary sink.
+///
+/// The first field points at the owning context, as libcubeb requires.
+#[repr(C)]
+pub struct TeeStream {
+    context: *const TeeContext,
+    // Destroyed first, so no callback runs once the copier stops.
+    inner: Option<RawStream>,
+    state: Box<StreamState>,
+    thread: Option<JoinHandle<()>>
+}
+
+impl TeeStream {
+    /// Frames left out of the copy because the sink fell behind or
+    /// failed.
+    pub fn dropped_frames(&self) -> u64 {
+        self.state.shared.dropped_frames.load(Ordering::Relaxed)
+    }
+
+    fn inner(&self) -> &RawStream {
+        self.inner.as_ref().unwrap()
+    }
+}
+
+impl Drop for TeeStream {
+    fn drop(&mut self) {
+        self.inner = None;
+        stop_copier(&self.state, self.thread.take());
+    }
+}
+
+impl Stream for TeeStream {
+    fn start(&self) -> Result<()> {
+        self.inner().start()
+    }
+
+    fn stop(&self) -> Result<()> {
+        self.inner().stop()
+    }
+
+    fn reset_default_device(&self) -> Result<()> {
+        self.inner().reset_default_device()
+    }
+
+    fn position(&self) -> Result<u64> {
+        self.inner().position()
+    }
+
+    fn latency(&self) -> Result<u32> {
+        self.inner().latency()
+    }
+
+    fn set_volume(&self, volume: f32) -> Result<()> {
+        self.inner().set_volume(volume)?;
+        self.state
+            .shared
+            .volume
+            .store(volume.to_bits(), Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn set_panning(&self, panning: f32) -> Result<()> {
+        self.inner().set_panning(panning)
+    }
+
+    fn current_device(&self) -> Result<*const ffi::cubeb_device> {
+        self.inner().current_device()
+    }
+
+    #[allow(clippy::not_unsafe_ptr_arg_deref)]
+    fn device_destroy(&self, device: *const ffi::cubeb_device) -> Result<()> {
+        unsafe { self.inner().device_destroy(device) }
+    }
+
+    fn register_device_changed_callback(
+        &self,
+        device_changed_callback: Option<ffi::cubeb_device_changed_callback>,
+    ) -> Result<()> {
+        *self.state.device_changed.lock().unwrap() = device_changed_callback;
+        let forward = device_changed_callback.map(|_| device_changed_cb as _);
+        self.inner().register_device_changed_callback(forward)
+    }
+}
+
+extern "C" fn data_cb(
+    _: *mut ffi::cubeb_stream,
+    user_ptr: *mut c_void,
+    input_buffer: *const c_void,
+    output_buffer: *mut c_void,
+    nframes: c_long,
+) -> c_long {
+    let state = unsafe { &*(user_ptr as *const StreamState) };
+    let stream = state.stream.load(Ordering::Acquire);
+    let got = (state.data_callback)(stream, state.user_ptr, input_buffer, output_buffer, nframes);
+
+    let tap = unsafe { &mut *state.tap.get() };
+    if let Some(ref mut tap) = *tap {
+        if !output_buffer.is_null() && got > 0 && got <= nframes {
+            let frames = got as usize;
+            let len = frames * tap.frame_size;
+            let output = unsafe { slice::from_raw_parts(output_buffer as *const u8, len) };
+            tap.push(&state.shared, output, frames);
+        }
+    }
+    got
+}
+
+extern "C" fn state_cb(_: *mut ffi::cubeb_stream, user_ptr: *mut c_void, s: ffi::cubeb_state) {
+    let state = unsafe { &*(user_ptr as *const StreamState) };
+    if let (ffi::CUBEB_STATE_DRAINED, Some(copier)) = (s, state.copier.as_ref()) {
+        state.shared.drained.store(true, Ordering::Release);
+        copier.unpark();
+    }
+    let stream = state.stream.load(Ordering::Acquire);
+    (state.state_callback)(stream, state.user_ptr, s);
+}
+
+extern "C" fn device_changed_cb(user_ptr: *mut c_void) {
+    let state = unsafe { &*(user_ptr as *const StreamState) };
+    if let Some(callback) = *state.device_changed.lock().unwrap() {
+        callback(state.user_ptr);
+    }
+}
+
+extern "C" fn collection_changed_cb(_: *mut ffi::cubeb, user_ptr: *mut c_void) {
+    let context = unsafe { &*(user_ptr as *const TeeContext) };
+    if let Some((callback, user_ptr)) = *context.collection_changed.lock().unwrap() {
+        callback(context as *const _ as *mut _, user_ptr);
+    }
+}
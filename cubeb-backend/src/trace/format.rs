@@ -0,0 +1,585 @@
+// Copyright © 2017 Mozilla Foundation
+//
+// This program is made available under an ISC-style license.  See the
+// accompanying file LICENSE for details.
+
+//! Trace file format.
+//!
+//! A trace starts with `MAGIC` and the format version, followed by one
+//! record per event until the end of the file. Integers are LEB128
+//! varints, zigzag encoded when signed, so records describing calls and
+//! callbacks take a handful of bytes; recorded buffer contents dominate
+//! the size of traces that include them. Bump `TRACE_VERSION` whenever
+//! an encoding changes.
+
+use cubeb_core::ffi;
+use device::{ReadFields, WriteFields};
+use std::io::{self, Read, Write};
+
+pub use device::DeviceInfo;
+
+const MAGIC: [u8; 4] = *b"CBTR";
+
+/// Version of the trace format written by this crate.
+pub const TRACE_VERSION: u32 = 1;
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Stream parameters as recorded.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Params {
+    pub format: ffi::cubeb_sample_format,
+    pub rate: u32,
+    pub channels: u32,
+    pub layout: ffi::cubeb_channel_layout
+}
+
+impl<'a> From<&'a ffi::cubeb_stream_params> for Params {
+    fn from(p: &'a ffi::cubeb_stream_params) -> Params {
+        Params {
+            format: p.format,
+            rate: p.rate,
+            channels: p.channels,
+            layout: p.layout
+        }
+    }
+}
+
+/// Parameters of one direction of a recorded stream.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Direction {
+    pub devid: u64,
+    pub params: Params
+}
+
+/// An operation on a context or stream, with the values it returned.
+/// Values are zero when the operation failed.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Op {
+    MaxChannelCount(u32),
+    MinLatency { params: Params, latency: u32 },
+    PreferredSampleRate(u32),
+    PreferredChannelLayout(ffi::cubeb_channel_layout),
+    EnumerateDevices {
+        devtype: ffi::cubeb_device_type,
+        devices: Vec<DeviceInfo>
+    },
+    /// `stream` numbers the streams of the context from 0, failed
+    /// attempts included.
+    StreamInit {
+        stream: u32,
+        name: Option<String>,
+        input: Option<Direction>,
+        output: Option<Direction>,
+        latency_frames: u32
+    },
+    RegisterDeviceCollectionChanged(ffi::cubeb_device_type),
+    StreamDestroy,
+    StreamStart,
+    StreamStop,
+    StreamResetDefaultDevice,
+    StreamGetPosition(u64),
+    StreamGetLatency(u32),
+    StreamSetVolume(f32),
+    StreamSetPanning(f32),
+    StreamGetCurrentDevice {
+        input_name: Option<String>,
+        output_name: Option<String>
+    },
+    StreamRegisterDeviceChanged
+}
+
+impl Op {
+    /// Whether `self` and `other` are the same operation, with the same
+    /// arguments, regardless of what they returned.
+    pub fn same_call(&self, other: &Op) -> bool {
+        use self::Op::*;
+        match (self, other) {
+            (MinLatency { params: a, .. }, MinLatency { params: b, .. }) => a == b,
+            (EnumerateDevices { devtype: a, .. }, EnumerateDevices { devtype: b, .. }) => a == b,
+            (
+                StreamInit {
+                    input: a_input,
+                    output: a_output,
+                    latency_frames: a_latency,
+                    ..
+                },
+                StreamInit {
+                    input: b_input,
+                    output: b_output,
+                    latency_frames: b_latency,
+                    ..
+                },
+            ) => a_input == b_input && a_output == b_output && a_latency == b_latency,
+            (RegisterDeviceCollectionChanged(a), RegisterDeviceCollectionChanged(b)) => a == b,
+            (StreamSetVolume(a), StreamSetVolume(b)) |
+            (StreamSetPanning(a), StreamSetPanning(b)) => a == b,
+            _ => ::std::mem::discriminant(self) == ::std::mem::discriminant(other),
+        }
+    }
+}
+
+/// A callback from the wrapped backend.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Callback {
+    /// `input` and `output` hold the buffer contents, after the
+    /// application's callback ran, when the trace records them.
+    Data {
+        nframes: u32,
+        returned: i64,
+        duration_us: u64,
+        input: Option<Vec<u8>>,
+        output: Option<Vec<u8>>
+    },
+    State(ffi::cubeb_state),
+    DeviceChanged,
+    DeviceCollectionChanged
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Event {
+    /// The context was created. Always the first record of a trace.
+    Init { backend_id: String },
+    /// An operation returned. `stream` is `None` for operations on the
+    /// context.
+    Call {
+        stream: Option<u32>,
+        result: ffi::cubeb_error_code,
+        op: Op
+    },
+    /// A callback returned. `started` and `completed` count the
+    /// operations on the same context or stream that had started and
+    /// returned when the callback was made, which places the callback
+    /// relative to the application's calls.
+    Callback {
+        stream: Option<u32>,
+        started: u32,
+        completed: u32,
+        callback: Callback
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Record {
+    /// Microseconds since the trace started.
+    pub time_us: u64,
+    pub event: Event
+}
+
+const EVENT_INIT: u8 = 0;
+const EVENT_CONTEXT_CALL: u8 = 1;
+const EVENT_STREAM_CALL: u8 = 2;
+const EVENT_CONTEXT_CALLBACK: u8 = 3;
+const EVENT_STREAM_CALLBACK: u8 = 4;
+
+struct Encoder<'a> {
+    buf: &'a mut Vec<u8>
+}
+
+impl<'a> Encoder<'a> {
+    fn u8(&mut self, x: u8) -> &mut Self {
+        self.buf.push(x);
+        self
+    }
+
+    fn u64(&mut self, mut x: u64) -> &mut Self {
+        while x >= 0x80 {
+            self.buf.push(x as u8 | 0x80);
+            x >>= 7;
+        }
+        self.buf.push(x as u8);
+        self
+    }
+
+    fn u32(&mut self, x: u32) -> &mut Self {
+        self.u64(u64::from(x))
+    }
+
+    fn i64(&mut self, x: i64) -> &mut Self {
+        self.u64(((x << 1) ^ (x >> 63)) as u64)
+    }
+
+    fn i32(&mut self, x: i32) -> &mut Self {
+        self.i64(i64::from(x))
+    }
+
+    fn f32(&mut self, x: f32) -> &mut Self {
+        self.buf.extend_from_slice(&x.to_bits().to_le_bytes());
+        self
+    }
+
+    fn bytes(&mut self, b: &Option<Vec<u8>>) -> &mut Self {
+        match *b {
+            Some(ref b) => {
+                self.u8(1).u64(b.len() as u64);
+                self.buf.extend_from_slice(b);
+            },
+            None => {
+                self.u8(0);
+            },
+        }
+        self
+    }
+
+    fn string(&mut self, s: &Option<String>) -> &mut Self {
+        self.bytes(&s.as_ref().map(|s| s.as_bytes().to_vec()))
+    }
+
+    fn params(&mut self, p: &Params) -> &mut Self {
+        self.i32(p.format as i32)
+            .u32(p.rate)
+            .u32(p.channels)
+            .i32(p.layout)
+    }
+
+    fn direction(&mut self, d: &Option<Direction>) -> &mut Self {
+        match *d {
+            Some(ref d) => self.u8(1).u64(d.devid).params(&d.params),
+            None => self.u8(0),
+        }
+    }
+
+    fn op(&mut self, op: &Op) -> &mut Self {
+        match *op {
+            Op::MaxChannelCount(x) => self.u8(0).u32(x),
+            Op::MinLatency { ref params, latency } => self.u8(1).params(params).u32(latency),
+            Op::PreferredSampleRate(x) => self.u8(2).u32(x),
+            Op::PreferredChannelLayout(x) => self.u8(3).i32(x),
+            Op::EnumerateDevices { devtype, ref devices } => {
+                self.u8(4).i32(devtype as i32).u64(devices.len() as u64);
+                for d in devices {
+                    d.write(self);
+                }
+                self
+            },
+            Op::StreamInit {
+                stream,
+                ref name,
+                ref input,
+                ref output,
+                latency_frames
+            } => self.u8(5)
+                .u32(stream)
+                .string(name)
+                .direction(input)
+                .direction(output)
+                .u32(latency_frames),
+            Op::RegisterDeviceCollectionChanged(x) => self.u8(6).i32(x as i32),
+            Op::StreamDestroy => self.u8(7),
+            Op::StreamStart => self.u8(8),
+            Op::StreamStop => self.u8(9),
+            Op::StreamResetDefaultDevice => self.u8(10),
+            Op::StreamGetPosition(x) => self.u8(11).u64(x),
+            Op::StreamGetLatency(x) => self.u8(12).u32(x),
+            Op::StreamSetVolume(x) => self.u8(13).f32(x),
+            Op::StreamSetPanning(x) => self.u8(14).f32(x),
+            Op::StreamGetCurrentDevice {
+                ref input_name,
+                ref output_name
+            } => self.u8(15).string(input_name).string(output_name),
+            Op::StreamRegisterDeviceChanged => self.u8(16),
+        }
+    }
+
+    fn callback(&mut self, callback: &Callback) -> &mut Self {
+        match *callback {
+            Callback::Data {
+                nframes,
+                returned,
+                duration_us,
+                ref input,
+                ref output
+            } => self.u8(0)
+                .u32(nframes)
+                .i64(returned)
+                .u64(duration_us)
+                .bytes(input)
+                .bytes(output),
+            Callback::State(state) => self.u8(1).i32(state),
+            Callback::DeviceChanged => self.u8(2),
+            Callback::DeviceCollectionChanged => self.u8(3),
+        }
+    }
+}
+
+impl<'a> WriteFields for Encoder<'a> {
+    fn write_u32(&mut self, x: u32) {
+        self.u32(x);
+    }
+
+    fn write_i32(&mut self, x: i32) {
+        self.i32(x);
+    }
+
+    fn write_u64(&mut self, x: u64) {
+        self.u64(x);
+    }
+
+    fn write_string(&mut self, s: &Option<String>) {
+        self.string(s);
+    }
+}
+
+struct Decoder<'a> {
+    buf: &'a [u8]
+}
+
+impl<'a> Decoder<'a> {
+    fn take(&mut self, n: usize) -> io::Result<&'a [u8]> {
+        if self.buf.len() < n {
+            return Err(invalid_data("truncated trace"));
+        }
+        let (head, tail) = self.buf.split_at(n);
+        self.buf = tail;
+        Ok(head)
+    }
+
+    fn u8(&mut self) -> io::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u64(&mut self) -> io::Result<u64> {
+        let mut x = 0u64;
+        for shift in (0..64).step_by(7) {
+            let b = self.u8()?;
+            x |= u64::from(b & 0x7f) << shift;
+            if b & 0x80 == 0 {
+                return Ok(x);
+            }
+        }
+        Err(invalid_data("invalid varint"))
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        let x = self.u64()?;
+        if x > u64::from(u32::MAX) {
+            return Err(invalid_data("integer out of range"));
+        }
+        Ok(x as u32)
+    }
+
+    fn i64(&mut self) -> io::Result<i64> {
+        let x = self.u64()?;
+        Ok((x >> 1) as i64 ^ -((x & 1) as i64))
+    }
+
+    fn i32(&mut self) -> io::Result<i32> {
+        let x = self.i64()?;
+        if x < i64::from(i32::MIN) || x > i64::from(i32::MAX) {
+            return Err(invalid_data("integer out of range"));
+        }
+        Ok(x as i32)
+    }
+
+    fn f32(&mut self) -> io::Result<f32> {
+        let b = self.take(4)?;
+        Ok(f32::from_bits(u32::from_le_bytes([b[0], b[1], b[2], b[3]])))
+    }
+
+    fn present(&mut self) -> io::Result<bool> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(invalid_data("invalid option tag")),
+        }
+    }
+
+    fn bytes(&mut self) -> io::Result<Option<Vec<u8>>> {
+        if !self.present()? {
+            return Ok(None);
+        }
+        let len = self.u64()?;
+        if len > self.buf.len() as u64 {
+            return Err(invalid_data("truncated trace"));
+        }
+        Ok(Some(self.take(len as usize)?.to_vec()))
+    }
+
+    fn string(&mut self) -> io::Result<Option<String>> {
+        match self.bytes()? {
+            Some(b) => String::from_utf8(b)
+                .map(Some)
+                .map_err(|_| invalid_data("invalid string")),
+            None => Ok(None),
+        }
+    }
+
+    fn params(&mut self) -> io::Result<Params> {
+        Ok(Params {
+            format: self.i32()? as _,
+            rate: self.u32()?,
+            channels: self.u32()?,
+            layout: self.i32()? as _
+        })
+    }
+
+    fn direction(&mut self) -> io::Result<Option<Direction>> {
+        if !self.present()? {
+            return Ok(None);
+        }
+        Ok(Some(Direction {
+            devid: self.u64()?,
+            params: self.params()?
+        }))
+    }
+
+    fn op(&mut self) -> io::Result<Op> {
+        Ok(match self.u8()? {
+            0 => Op::MaxChannelCount(self.u32()?),
+            1 => Op::MinLatency {
+                params: self.params()?,
+                latency: self.u32()?
+            },
+            2 => Op::PreferredSampleRate(self.u32()?),
+            3 => Op::PreferredChannelLayout(self.i32()? as _),
+            4 => {
+                let devtype = self.i32()? as _;
+                let count = self.u64()?;
+                if count > self.buf.len() as u64 {
+                    return Err(invalid_data("truncated trace"));
+                }
+                let devices = (0..count)
+                    .map(|_| DeviceInfo::read(self))
+                    .collect::<io::Result<_>>()?;
+                Op::EnumerateDevices { devtype, devices }
+            },
+            5 => Op::StreamInit {
+                stream: self.u32()?,
+                name: self.string()?,
+                input: self.direction()?,
+                output: self.direction()?,
+                latency_frames: self.u32()?
+            },
+            6 => Op::RegisterDeviceCollectionChanged(self.i32()? as _),
+            7 => Op::StreamDestroy,
+            8 => Op::StreamStart,
+            9 => Op::StreamStop,
+            10 => Op::StreamResetDefaultDevice,
+            11 => Op::StreamGetPosition(self.u64()?),
+            12 => Op::StreamGetLatency(self.u32()?),
+            13 => Op::StreamSetVolume(self.f32()?),
+            14 => Op::StreamSetPanning(self.f32()?),
+            15 => Op::StreamGetCurrentDevice {
+                input_name: self.string()?,
+                output_name: self.string()?
+            },
+            16 => Op::StreamRegisterDeviceChanged,
+            _ => return Err(invalid_data("unknown operation")),
+        })
+    }
+
+    fn callback(&mut self) -> io::Result<Callback> {
+        Ok(match self.u8()? {
+            0 => Callback::Data {
+                nframes: self.u32()?,
+                returned: self.i64()?,
+                duration_us: self.u64()?,
+                input: self.bytes()?,
+                output: self.bytes()?
+            },
+            1 => Callback::State(self.i32()? as _),
+            2 => Callback::DeviceChanged,
+            3 => Callback::DeviceCollectionChanged,
+            _ => return Err(invalid_data("unknown callback")),
+        })
+    }
+
+    fn record(&mut self) -> io::Result<Record> {
+        let time_us = self.u64()?;
+        let event = match self.u8()? {
+            EVENT_INIT => Event::Init {
+                backend_id: self.string()?.unwrap_or_default()
+            },
+            tag @ EVENT_CONTEXT_CALL | tag @ EVENT_STREAM_CALL => Event::Call {
+                stream: if tag == EVENT_STREAM_CALL { Some(self.u32()?) } else { None },
+                result: self.i32()?,
+                op: self.op()?
+            },
+            tag @ EVENT_CONTEXT_CALLBACK | tag @ EVENT_STREAM_CALLBACK => Event::Callback {
+                stream: if tag == EVENT_STREAM_CALLBACK { Some(self.u32()?) } else { None },
+                started: self.u32()?,
+                completed: self.u32()?,
+                callback: self.callback()?
+            },
+            _ => return Err(invalid_data("unknown event")),
+        };
+        Ok(Record { time_us, event })
+    }
+}
+
+impl<'a> ReadFields for Decoder<'a> {
+    fn read_u32(&mut self) -> io::Result<u32> {
+        self.u32()
+    }
+
+    fn read_i32(&mut self) -> io::Result<i32> {
+        self.i32()
+    }
+
+    fn read_u64(&mut self) -> io::Result<u64> {
+        self.u64()
+    }
+
+    fn read_string(&mut self) -> io::Result<Option<String>> {
+        self.string()
+    }
+}
+
+impl Record {
+    /// Append the encoding of `self` to `buf`.
+    pub fn encode(&self, buf: &mut Vec<u8>) {
+        let mut e = Encoder { buf };
+        e.u64(self.time_us);
+        match self.event {
+            Event::Init { ref backend_id } => {
+                e.u8(EVENT_INIT).string(&Some(backend_id.clone()));
+            },
+            Event::Call { stream, result, ref op } => {
+                match stream {
+                    Some(stream) => e.u8(EVENT_STREAM_CALL).u32(stream),
+                    None => e.u8(EVENT_CONTEXT_CALL),
+                };
+                e.i32(result).op(op);
+            },
+            Event::Callback {
+                stream,
+                started,
+                completed,
+                ref callback
+            } => {
+                match stream {
+                    Some(stream) => e.u8(EVENT_STREAM_CALLBACK).u32(stream),
+                    None => e.u8(EVENT_CONTEXT_CALLBACK),
+                };
+                e.u32(started).u32(completed).callback(callback);
+            },
+        }
+    }
+}
+
+/// Write the trace header.
+pub fn write_header<W: Write>(w: &mut W) -> io::Result<()> {
+    let mut header = MAGIC.to_vec();
+    header.extend_from_slice(&TRACE_VERSION.to_le_bytes());
+    w.write_all(&header)
+}
+
+/// Read a whole trace.
+pub fn read_trace<R: Read>(r: &mut R) -> io::Result<Vec<Record>> {
+    let mut buf = Vec::new();
+    r.read_to_end(&mut buf)?;
+    if buf.len() < 8 || buf[..4] != MAGIC {
+        return Err(invalid_data("not a cubeb trace"));
+    }
+    if buf[4..8] != TRACE_VERSION.to_le_bytes() {
+        return Err(invalid_data("unsupported trace version"));
+    }
+    let mut d = Decoder { buf: &buf[8..] };
+    let mut records = Vec::new();
+    while !d.buf.is_empty() {
+        records.push(d.record()?);
+    }
+    Ok(records)
+}
@@ -0,0 +1,34 @@
+// Copyright © 2017 Mozilla Foundation
+//
+// This program is made available under an ISC-style license.  See the
+// accompanying file LICENSE for details.
+
+//! Record and replay the traffic between an application and a backend.
+//!
+//! `TraceContext::wrap` records every operation on the wrapped backend,
+//! with its arguments and result, and every callback with its size and
+//! timing, optionally including buffer contents, into a compact binary
+//! trace. `Replay` runs a trace back against the application's callbacks
+//! deterministically, without any device, so audio bugs that depend on
+//! callback timing or device behaviour can be reproduced from a trace a
+//! user sends.
+
+use cubeb_core::StreamParams;
+use cubeb_core::binding::Binding;
+use cubeb_core::ffi;
+use driver;
+
+pub mod format;
+mod record;
+mod replay;
+
+pub use self::record::{Config, TraceContext, TraceStream, TraceWriter, OPS, TRACE_ENV};
+pub use self::replay::{Replay, ReplayContext, ReplayStream, REPLAY_ENV};
+
+/// Operations of the replay backend.
+pub const REPLAY_OPS: ::Ops = self::replay::OPS;
+
+// Bytes per frame of one direction of a stream, 0 if it's unused.
+fn frame_size(params: Option<&ffi::cubeb_stream_params>) -> usize {
+    params.map_or(0, |p| driver::frame_size(&unsafe { StreamParams::from_raw(p) }))
+}
@@ -0,0 +1,797 @@
+// Copyright © 2017 Mozilla Foundation
+//
+// This program is made available under an ISC-style license.  See the
+// accompanying file LICENSE for details.
+
+//! Recording side of the trace backend.
+//!
+//! Calls are recorded by the thread making them, but callbacks often
+//! run on an audio thread, which mustn't lock, allocate or touch files.
+//! Each stream queues its callbacks, and their buffers when the trace
+//! holds them, in a ring allocated when the stream is created. A writer
+//! thread encodes them and writes the file. Callbacks that don't fit in
+//! the ring are dropped from the trace and counted by
+//! `TraceWriter::dropped_callbacks`.
+
+use {Context, Ops, Stream};
+use cubeb_core::{DeviceId, DeviceType, Error, ErrorCode, Result, StreamParams};
+use cubeb_core::binding::Binding;
+use cubeb_core::ffi;
+use null;
+use raw::{RawContext, RawStream};
+use ring::{self, Consumer, Producer};
+use std::{env, io, mem, ptr, slice};
+use std::ffi::CStr;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::mem::ManuallyDrop;
+use std::os::raw::{c_long, c_void};
+use std::path::Path;
+use std::sync::{Arc, Condvar, Mutex, PoisonError};
+use std::sync::atomic::{AtomicPtr, AtomicU32, AtomicU64, Ordering};
+use std::thread::{self, JoinHandle, Thread};
+use std::time::Instant;
+use trace::format::{self, Callback, DeviceInfo, Direction, Event, Op, Params, Record};
+
+pub const OPS: Ops = capi_new!(TraceContext, TraceStream);
+
+/// Environment variable naming the file `TraceContext::init` writes.
+pub const TRACE_ENV: &str = "CUBEB_TRACE_FILE";
+
+/// Callbacks a stream can queue ahead of the writer thread before more
+/// are dropped.
+pub const BACKLOG_CALLBACKS: usize = 64;
+
+// Smallest period used to size the ring when buffers are recorded, for
+// streams with tiny latencies whose callbacks ask for more frames.
+const MIN_PERIOD: usize = 1024;
+
+/// What to record besides calls and callbacks.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Config {
+    /// Record the contents of every data callback buffer. The audio
+    /// thread copies them into the stream's ring.
+    pub buffers: bool
+}
+
+// Records waiting for the writer thread, and its progress.
+struct Queue {
+    records: Vec<Record>,
+    // Rings of new streams, and the ids of destroyed ones.
+    opened: Vec<(u32, Consumer)>,
+    closed: Vec<u32>,
+    flush: bool,
+    // Passes of the writer thread started and finished.
+    started: u64,
+    finished: u64,
+    error: Option<io::Error>,
+    done: bool
+}
+
+// Shared by the traced threads and the writer thread.
+struct Shared {
+    queue: Mutex<Queue>,
+    passed: Condvar,
+    dropped: AtomicU64
+}
+
+/// Destination of a trace, shared by a context and its streams.
+pub struct TraceWriter {
+    shared: Arc<Shared>,
+    thread: Option<JoinHandle<()>>,
+    writer: Thread,
+    start: Instant,
+    config: Config
+}
+
+impl TraceWriter {
+    /// Write a trace to `w`.
+    pub fn new(w: Box<dyn Write + Send>, config: Config) -> io::Result<TraceWriter> {
+        let mut w = BufWriter::new(w);
+        format::write_header(&mut w)?;
+        let shared = Arc::new(Shared {
+            queue: Mutex::new(Queue {
+                records: Vec::new(),
+                opened: Vec::new(),
+                closed: Vec::new(),
+                flush: false,
+                started: 0,
+                finished: 0,
+                error: None,
+                done: false
+            }),
+            passed: Condvar::new(),
+            dropped: AtomicU64::new(0)
+        });
+        let writer = {
+            let shared = shared.clone();
+            thread::Builder::new()
+                .name("cubeb trace".into())
+                .spawn(move || write_trace(w, &shared))?
+        };
+        Ok(TraceWriter {
+            shared,
+            writer: writer.thread().clone(),
+            thread: Some(writer),
+            start: Instant::now(),
+            config
+        })
+    }
+
+    /// Write a trace to a new file at `path`.
+    pub fn create<P: AsRef<Path>>(path: P, config: Config) -> io::Result<TraceWriter> {
+        TraceWriter::new(Box::new(File::create(path)?), config)
+    }
+
+    /// Wait for everything recorded so far to be written and flushed,
+    /// reporting the first error met while writing the trace.
+    pub fn flush(&self) -> io::Result<()> {
+        let mut queue = self.shared.queue.lock().unwrap();
+        let pass = queue.started + 1;
+        queue.flush = true;
+        self.writer.unpark();
+        while queue.finished < pass {
+            queue = self.shared.passed.wait(queue).unwrap();
+        }
+        match queue.error.take() {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    /// Number of stream callbacks left out of the trace because the
+    /// writer thread fell behind.
+    pub fn dropped_callbacks(&self) -> u64 {
+        self.shared.dropped.load(Ordering::Relaxed)
+    }
+
+    fn time_us(&self) -> u64 {
+        self.start.elapsed().as_micros() as u64
+    }
+
+    // Tracing never fails the traced backend: errors are kept for
+    // `flush` and the rest of the trace is dropped.
+    fn log<F: FnOnce() -> Event>(&self, event: F) {
+        let mut queue = self.shared.queue.lock().unwrap();
+        let record = Record {
+            time_us: self.time_us(),
+            event: event()
+        };
+        queue.records.push(record);
+        drop(queue);
+        self.writer.unpark();
+    }
+
+    fn call<T, F>(&self, calls: &Calls, stream: Option<u32>, r: Result<T>, op: F) -> Result<T>
+    where
+        F: FnOnce(Option<&T>) -> Op,
+    {
+        // Counted under the lock, so the counts seen by callbacks agree
+        // with the order of the records.
+        self.log(|| {
+            calls.completed.fetch_add(1, Ordering::AcqRel);
+            Event::Call {
+                stream,
+                result: r.as_ref().err().map_or(ffi::CUBEB_OK, |e| e.raw_code()),
+                op: op(r.as_ref().ok())
+            }
+        });
+        r
+    }
+
+    fn callback(&self, calls: &Calls, stream: Option<u32>, callback: Callback) {
+        self.log(|| Event::Callback {
+            stream,
+            started: calls.started.load(Ordering::Acquire),
+            completed: calls.completed.load(Ordering::Acquire),
+            callback
+        });
+    }
+
+    // Hand the ring of stream `id` to the writer thread.
+    fn open_stream(&self, id: u32, consumer: Consumer) {
+        self.shared.queue.lock().unwrap().opened.push((id, consumer));
+    }
+
+    // Write what's left in the ring of stream `id`, then drop it.
+    fn close_stream(&self, id: u32) {
+        self.shared.queue.lock().unwrap().closed.push(id);
+        self.writer.unpark();
+    }
+}
+
+impl Drop for TraceWriter {
+    fn drop(&mut self) {
+        self.shared.queue.lock().unwrap().done = true;
+        if let Some(thread) = self.thread.take() {
+            thread.thread().unpark();
+            let _ = thread.join();
+        }
+    }
+}
+
+// Header of a callback in a stream's ring, followed by the input and
+// output buffers when they're recorded.
+#[derive(Default)]
+struct Queued {
+    kind: u32,
+    started: u32,
+    completed: u32,
+    // The frame count of a data callback, or the new state.
+    value: i32,
+    time_us: u64,
+    returned: i64,
+    duration_us: u64,
+    // `NO_BUFFER` when the buffer isn't recorded.
+    input_len: u32,
+    output_len: u32
+}
+
+const QUEUED_DATA: u32 = 0;
+const QUEUED_STATE: u32 = 1;
+const QUEUED_DEVICE_CHANGED: u32 = 2;
+const QUEUED_LEN: usize = 48;
+const NO_BUFFER: u32 = u32::MAX;
+
+impl Queued {
+    fn encode(&self) -> [u8; QUEUED_LEN] {
+        let mut buf = [0; QUEUED_LEN];
+        buf[0..4].copy_from_slice(&self.kind.to_ne_bytes());
+        buf[4..8].copy_from_slice(&self.started.to_ne_bytes());
+        buf[8..12].copy_from_slice(&self.completed.to_ne_bytes());
+        buf[12..16].copy_from_slice(&self.value.to_ne_bytes());
+        buf[16..24].copy_from_slice(&self.time_us.to_ne_bytes());
+        buf[24..32].copy_from_slice(&self.returned.to_ne_bytes());
+        buf[32..40].copy_from_slice(&self.duration_us.to_ne_bytes());
+        buf[40..44].copy_from_slice(&self.input_len.to_ne_bytes());
+        buf[44..48].copy_from_slice(&self.output_len.to_ne_bytes());
+        buf
+    }
+
+    fn decode(buf: &[u8; QUEUED_LEN]) -> Queued {
+        let u32_at = |i: usize| u32::from_ne_bytes([buf[i], buf[i + 1], buf[i + 2], buf[i + 3]]);
+        let u64_at = |i: usize| {
+            let mut b = [0; 8];
+            b.copy_from_slice(&buf[i..i + 8]);
+            u64::from_ne_bytes(b)
+        };
+        Queued {
+            kind: u32_at(0),
+            started: u32_at(4),
+            completed: u32_at(8),
+            value: u32_at(12) as i32,
+            time_us: u64_at(16),
+            returned: u64_at(24) as i64,
+            duration_us: u64_at(32),
+            input_len: u32_at(40),
+            output_len: u32_at(44)
+        }
+    }
+}
+
+// Read one queued callback of stream `id` off `consumer`.
+fn dequeue(id: u32, consumer: &mut Consumer) -> Option<Record> {
+    if consumer.available() < QUEUED_LEN {
+        return None;
+    }
+    let mut header = [0; QUEUED_LEN];
+    consumer.read(&mut header);
+    let q = Queued::decode(&header);
+    let mut read = |len: u32| if len == NO_BUFFER {
+        None
+    } else {
+        let mut buf = vec![0; len as usize];
+        consumer.read(&mut buf);
+        Some(buf)
+    };
+    let (input, output) = (read(q.input_len), read(q.output_len));
+    let callback = match q.kind {
+        QUEUED_DATA => Callback::Data {
+            nframes: q.value as u32,
+            returned: q.returned,
+            duration_us: q.duration_us,
+            input,
+            output
+        },
+        QUEUED_STATE => Callback::State(q.value),
+        _ => Callback::DeviceChanged,
+    };
+    Some(Record {
+        time_us: q.time_us,
+        event: Event::Callback {
+            stream: Some(id),
+            started: q.started,
+            completed: q.completed,
+            callback
+        }
+    })
+}
+
+// Body of the writer thread.
+fn write_trace(mut w: BufWriter<Box<dyn Write + Send>>, shared: &Shared) {
+    let mut streams: Vec<(u32, Consumer)> = Vec::new();
+    let mut buf = Vec::new();
+    let mut failed = false;
+    loop {
+        let (records, closed, flush, pass, done) = {
+            let mut queue = shared.queue.lock().unwrap();
+            queue.started += 1;
+            streams.append(&mut queue.opened);
+            (
+                mem::take(&mut queue.records),
+                mem::take(&mut queue.closed),
+                mem::replace(&mut queue.flush, false),
+                queue.started,
+                queue.done
+            )
+        };
+        let mut busy = !records.is_empty();
+        let mut error = None;
+        let mut write = |record: &Record| {
+            if failed {
+                return;
+            }
+            buf.clear();
+            record.encode(&mut buf);
+            if let Err(e) = w.write_all(&buf) {
+                failed = true;
+                error = Some(e);
+            }
+        };
+        for record in &records {
+            write(record);
+        }
+        for &mut (id, ref mut consumer) in streams.iter_mut() {
+            while let Some(record) = dequeue(id, consumer) {
+                busy = true;
+                write(&record);
+            }
+        }
+        streams.retain(|&(id, _)| !closed.contains(&id));
+        if (flush || done) && !failed {
+            if let Err(e) = w.flush() {
+                failed = true;
+                error = Some(e);
+            }
+        }
+
+        let mut queue = shared.queue.lock().unwrap();
+        queue.finished = pass;
+        if queue.error.is_none() {
+            queue.error = error;
+        }
+        shared.passed.notify_all();
+        if done {
+            return;
+        }
+        drop(queue);
+        if !busy {
+            thread::park();
+        }
+    }
+}
+
+// Operations started and completed on a context or stream.
+#[derive(Default)]
+struct Calls {
+    started: AtomicU32,
+    completed: AtomicU32
+}
+
+impl Calls {
+    fn begin(&self) {
+        self.started.fetch_add(1, Ordering::AcqRel);
+    }
+}
+
+type CollectionChanged = (ffi::cubeb_device_collection_changed_callback, *mut c_void);
+
+/// Context recording a trace of the wrapped backend.
+#[repr(C)]
+pub struct TraceContext {
+    ops: *const Ops,
+    inner: RawContext,
+    trace: Arc<TraceWriter>,
+    calls: Calls,
+    next_stream: AtomicU32,
+    collection_changed: Mutex<Option<CollectionChanged>>
+}
+
+impl TraceContext {
+    /// Wrap `inner`, recording into `trace`.
+    pub fn wrap(inner: RawContext, trace: TraceWriter) -> *mut ffi::cubeb {
+        let backend_id = inner.backend_id().to_string_lossy().into_owned();
+        trace.log(|| Event::Init { backend_id });
+        let ctx = Box::new(TraceContext {
+            ops: &OPS as *const _,
+            inner,
+            trace: Arc::new(trace),
+            calls: Calls::default(),
+            next_stream: AtomicU32::new(0),
+            collection_changed: Mutex::new(None)
+        });
+        Box::into_raw(ctx) as *mut _
+    }
+
+    fn call<T, F>(&self, r: Result<T>, op: F) -> Result<T>
+    where
+        F: FnOnce(Option<&T>) -> Op,
+    {
+        self.trace.call(&self.calls, None, r, op)
+    }
+}
+
+impl Context for TraceContext {
+    /// Trace the null backend into the file named by `TRACE_ENV`.
+    fn init(context_name: Option<&CStr>) -> Result<*mut ffi::cubeb> {
+        let path = env::var_os(TRACE_ENV).ok_or_else(|| Error::from(ErrorCode::NotSupported))?;
+        let trace = TraceWriter::create(path, Config::default())
+            .map_err(|e| Error::new().with_source(e))?;
+        let inner = RawContext::init(&null::OPS, context_name)?;
+        Ok(TraceContext::wrap(inner, trace))
+    }
+
+    fn backend_id(&self) -> &'static CStr {
+        unsafe { CStr::from_ptr(b"trace\0".as_ptr() as *const _) }
+    }
+
+    fn max_channel_count(&self) -> Result<u32> {
+        self.calls.begin();
+        let r = self.inner.max_channel_count();
+        self.call(r, |x| Op::MaxChannelCount(x.cloned().unwrap_or(0)))
+    }
+
+    fn min_latency(&self, params: &StreamParams) -> Result<u32> {
+        self.calls.begin();
+        let r = self.inner.min_latency(params);
+        self.call(r, |x| Op::MinLatency {
+            params: Params::from(unsafe { &*params.raw() }),
+            latency: x.cloned().unwrap_or(0)
+        })
+    }
+
+    fn preferred_sample_rate(&self) -> Result<u32> {
+        self.calls.begin();
+        let r = self.inner.preferred_sample_rate();
+        self.call(r, |x| Op::PreferredSampleRate(x.cloned().unwrap_or(0)))
+    }
+
+    fn preferred_channel_layout(&self) -> Result<ffi::cubeb_channel_layout> {
+        self.calls.begin();
+        let r = self.inner.preferred_channel_layout();
+        self.call(r, |x| Op::PreferredChannelLayout(x.cloned().unwrap_or(0)))
+    }
+
+    fn enumerate_devices(
+        &self,
+        devtype: DeviceType,
+    ) -> Result<ffi::cubeb_device_collection> {
+        self.calls.begin();
+        let r = self.inner.enumerate_devices(devtype);
+        self.call(r, |x| Op::EnumerateDevices {
+            devtype: devtype.bits(),
+            devices: x.map_or(Vec::new(), |c| unsafe { DeviceInfo::from_collection(c) })
+        })
+    }
+
+    #[allow(clippy::not_unsafe_ptr_arg_deref)]
+    fn device_collection_destroy(
+        &self,
+        collection: *mut ffi::cubeb_device_collection,
+    ) {
+        let _ = self.inner
+            .device_collection_destroy(unsafe { &mut *collection });
+    }
+
+    fn stream_init(
+        &self,
+        stream_name: Option<&CStr>,
+        input_device: DeviceId,
+        input_stream_params: Option<&ffi::cubeb_stream_params>,
+        output_device: DeviceId,
+        output_stream_params: Option<&ffi::cubeb_stream_params>,
+        latency_frames: u32,
+        data_callback: ffi::cubeb_data_callback,
+        state_callback: ffi::cubeb_state_callback,
+        user_ptr: *mut c_void,
+    ) -> Result<*mut ffi::cubeb_stream> {
+        self.calls.begin();
+        let id = self.next_stream.fetch_add(1, Ordering::AcqRel);
+        let input_frame = super::frame_size(input_stream_params);
+        let output_frame = super::frame_size(output_stream_params);
+        let buffers = if self.trace.config.buffers {
+            (latency_frames as usize).max(MIN_PERIOD) * (input_frame + output_frame)
+        } else {
+            0
+        };
+        let capacity = (BACKLOG_CALLBACKS * (QUEUED_LEN + buffers)).min(ring::MAX_CAPACITY);
+        let (producer, consumer) = ring::ring(capacity);
+        let state = Box::new(StreamState {
+            id,
+            trace: self.trace.clone(),
+            calls: Calls::default(),
+            stream: AtomicPtr::new(ptr::null_mut()),
+            producer: Mutex::new(producer),
+            input_frame,
+            output_frame,
+            data_callback,
+            state_callback,
+            user_ptr,
+            device_changed: Mutex::new(None)
+        });
+        let r = unsafe {
+            self.inner.stream_init(
+                stream_name,
+                input_device,
+                input_stream_params,
+                output_device,
+                output_stream_params,
+                latency_frames,
+                data_cb,
+                state_cb,
+                &*state as *const StreamState as *mut c_void
+            )
+        };
+        let r = r.map(|inner| {
+            self.trace.open_stream(id, consumer);
+            let stm = Box::into_raw(Box::new(TraceStream {
+                context: self,
+                inner: ManuallyDrop::new(inner),
+                state
+            }));
+            unsafe { (*stm).state.stream.store(stm as *mut _, Ordering::Release) };
+            stm as *mut ffi::cubeb_stream
+        });
+
+        let direction = |devid: DeviceId, params: Option<&ffi::cubeb_stream_params>| {
+            params.map(|p| Direction {
+                devid: devid.raw() as usize as u64,
+                params: Params::from(p)
+            })
+        };
+        self.call(r, |_| Op::StreamInit {
+            stream: id,
+            name: stream_name.map(|n| n.to_string_lossy().into_owned()),
+            input: direction(input_device, input_stream_params),
+            output: direction(output_device, output_stream_params),
+            latency_frames
+        })
+    }
+
+    fn register_device_collection_changed(
+        &self,
+        devtype: DeviceType,
+        cb: Option<ffi::cubeb_device_collection_changed_callback>,
+        user_ptr: *mut c_void,
+    ) -> Result<()> {
+        self.calls.begin();
+        *self.collection_changed.lock().unwrap() = cb.map(|cb| (cb, user_ptr));
+        let this = self as *const TraceContext as *mut c_void;
+        let forward = cb.map(|_| collection_changed_cb as _);
+        let r = unsafe {
+            self.inner
+                .register_device_collection_changed(devtype, forward, this)
+        };
+        self.call(r, |_| Op::RegisterDeviceCollectionChanged(devtype.bits()))
+    }
+}
+
+// Shared with the wrapped stream's callbacks.
+struct StreamState {
+    id: u32,
+    trace: Arc<TraceWriter>,
+    calls: Calls,
+    stream: AtomicPtr<ffi::cubeb_stream>,
+    // Held by whichever callback is queuing, for as long as a copy.
+    producer: Mutex<Producer>,
+    input_frame: usize,
+    output_frame: usize,
+    data_callback: ffi::cubeb_data_callback,
+    state_callback: ffi::cubeb_state_callback,
+    user_ptr: *mut c_void,
+    device_changed: Mutex<Option<ffi::cubeb_device_changed_callback>>
+}
+
+impl StreamState {
+    // Queue a callback for the writer thread. Data callbacks never wait
+    // for the ring: if another callback is queuing, theirs is dropped.
+    fn queue(&self, mut queued: Queued, input: Option<&[u8]>, output: Option<&[u8]>) {
+        let trace = &self.trace;
+        queued.time_us = trace.time_us();
+        queued.started = self.calls.started.load(Ordering::Acquire);
+        queued.completed = self.calls.completed.load(Ordering::Acquire);
+        let len = |b: Option<&[u8]>| b.map_or(NO_BUFFER, |b| b.len() as u32);
+        queued.input_len = len(input);
+        queued.output_len = len(output);
+
+        let producer = if queued.kind == QUEUED_DATA {
+            self.producer.try_lock().ok()
+        } else {
+            Some(self.producer.lock().unwrap_or_else(PoisonError::into_inner))
+        };
+        let header = queued.encode();
+        let bufs = [&header[..], input.unwrap_or(&[]), output.unwrap_or(&[])];
+        let queued = match producer {
+            Some(mut producer) => producer.write_all(&bufs),
+            None => false,
+        };
+        if queued {
+            trace.writer.unpark();
+        } else {
+            trace.shared.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Stream recording a trace of the wrapped stream.
+///
+/// The first field points at the owning context, as libcubeb requires.
+#[repr(C)]
+pub struct TraceStream {
+    context: *const TraceContext,
+    // Destroyed by hand, before `state`, so the destruction is traced.
+    inner: ManuallyDrop<RawStream>,
+    state: Box<StreamState>
+}
+
+impl TraceStream {
+    fn call<T, F>(&self, r: Result<T>, op: F) -> Result<T>
+    where
+        F: FnOnce(Option<&T>) -> Op,
+    {
+        let state = &self.state;
+        state.trace.call(&state.calls, Some(state.id), r, op)
+    }
+}
+
+impl Drop for TraceStream {
+    fn drop(&mut self) {
+        self.state.calls.begin();
+        unsafe { ManuallyDrop::drop(&mut self.inner) };
+        let _ = self.call(Ok(()), |_| Op::StreamDestroy);
+        self.state.trace.close_stream(self.state.id);
+    }
+}
+
+impl Stream for TraceStream {
+    fn start(&self) -> Result<()> {
+        self.state.calls.begin();
+        let r = self.inner.start();
+        self.call(r, |_| Op::StreamStart)
+    }
+
+    fn stop(&self) -> Result<()> {
+        self.state.calls.begin();
+        let r = self.inner.stop();
+        self.call(r, |_| Op::StreamStop)
+    }
+
+    fn reset_default_device(&self) -> Result<()> {
+        self.state.calls.begin();
+        let r = self.inner.reset_default_device();
+        self.call(r, |_| Op::StreamResetDefaultDevice)
+    }
+
+    fn position(&self) -> Result<u64> {
+        self.state.calls.begin();
+        let r = self.inner.position();
+        self.call(r, |x| Op::StreamGetPosition(x.cloned().unwrap_or(0)))
+    }
+
+    fn latency(&self) -> Result<u32> {
+        self.state.calls.begin();
+        let r = self.inner.latency();
+        self.call(r, |x| Op::StreamGetLatency(x.cloned().unwrap_or(0)))
+    }
+
+    fn set_volume(&self, volume: f32) -> Result<()> {
+        self.state.calls.begin();
+        let r = self.inner.set_volume(volume);
+        self.call(r, |_| Op::StreamSetVolume(volume))
+    }
+
+    fn set_panning(&self, panning: f32) -> Result<()> {
+        self.state.calls.begin();
+        let r = self.inner.set_panning(panning);
+        self.call(r, |_| Op::StreamSetPanning(panning))
+    }
+
+    fn current_device(&self) -> Result<*const ffi::cubeb_device> {
+        self.state.calls.begin();
+        let r = self.inner.current_device();
+        self.call(r, |device| {
+            let device = device.map(|&d| unsafe { &*d });
+            Op::StreamGetCurrentDevice {
+                input_name: device.and_then(|d| unsafe { ::util::from_c_string(d.input_name) }),
+                output_name: device.and_then(|d| unsafe { ::util::from_c_string(d.output_name) })
+            }
+        })
+    }
+
+    #[allow(clippy::not_unsafe_ptr_arg_deref)]
+    fn device_destroy(&self, device: *const ffi::cubeb_device) -> Result<()> {
+        unsafe { self.inner.device_destroy(device) }
+    }
+
+    fn register_device_changed_callback(
+        &self,
+        device_changed_callback: Option<ffi::cubeb_device_changed_callback>,
+    ) -> Result<()> {
+        self.state.calls.begin();
+        *self.state.device_changed.lock().unwrap() = device_changed_callback;
+        let forward = device_changed_callback.map(|_| device_changed_cb as _);
+        let r = self.inner.register_device_changed_callback(forward);
+        self.call(r, |_| Op::StreamRegisterDeviceChanged)
+    }
+}
+
+extern "C" fn data_cb(
+    _: *mut ffi::cubeb_stream,
+    user_ptr: *mut c_void,
+    input_buffer: *const c_void,
+    output_buffer: *mut c_void,
+    nframes: c_long,
+) -> c_long {
+    let state = unsafe { &*(user_ptr as *const StreamState) };
+    let stream = state.stream.load(Ordering::Acquire);
+    let start = Instant::now();
+    let returned: c_long =
+        (state.data_callback)(stream, state.user_ptr, input_buffer, output_buffer, nframes);
+    let duration_us = start.elapsed().as_micros() as u64;
+
+    let frames = nframes.max(0) as usize;
+    let buffer = |buffer: *const c_void, frame: usize| if state.trace.config.buffers &&
+        !buffer.is_null()
+    {
+        Some(unsafe { slice::from_raw_parts(buffer as *const u8, frames * frame) })
+    } else {
+        None
+    };
+    let queued = Queued {
+        kind: QUEUED_DATA,
+        value: frames as i32,
+        returned: returned as i64,
+        duration_us,
+        ..Default::default()
+    };
+    state.queue(
+        queued,
+        buffer(input_buffer, state.input_frame),
+        buffer(output_buffer, state.output_frame)
+    );
+    returned
+}
+
+extern "C" fn state_cb(_: *mut ffi::cubeb_stream, user_ptr: *mut c_void, s: ffi::cubeb_state) {
+    let state = unsafe { &*(user_ptr as *const StreamState) };
+    let stream = state.stream.load(Ordering::Acquire);
+    (state.state_callback)(stream, state.user_ptr, s);
+    let queued = Queued {
+        kind: QUEUED_STATE,
+        value: s,
+        ..Default::default()
+    };
+    state.queue(queued, None, None);
+}
+
+extern "C" fn device_changed_cb(user_ptr: *mut c_void) {
+    let state = unsafe { &*(user_ptr as *const StreamState) };
+    if let Some(callback) = *state.device_changed.lock().unwrap() {
+        callback(state.user_ptr);
+    }
+    let queued = Queued {
+        kind: QUEUED_DEVICE_CHANGED,
+        ..Default::default()
+    };
+    state.queue(queued, None, None);
+}
+
+extern "C" fn collection_changed_cb(_: *mut ffi::cubeb, user_ptr: *mut c_void) {
+    let context = unsafe { &*(user_ptr as *const TraceContext) };
+    if let Some((callback, user_ptr)) = *context.collection_changed.lock().unwrap() {
+        callback(context as *const _ as *mut _, user_ptr);
+    }
+    context
+        .trace
+        .callback(&context.calls, None, Callback::DeviceCollectionChanged);
+}
@@ -0,0 +1,625 @@
+// Copyright © 2017 Mozilla Foundation
+//
+// This program is made available under an ISC-style license.  See the
+// accompanying file LICENSE for details.
+
+use {Context, Ops, Stream};
+use cubeb_core::{DeviceId, DeviceType, Error, ErrorCode, Result, StreamParams};
+use cubeb_core::binding::Binding;
+use cubeb_core::ffi;
+use std::{env, io, ptr};
+use std::collections::{HashMap, VecDeque};
+use std::ffi::CStr;
+use std::fs::File;
+use std::os::raw::{c_long, c_void};
+use std::path::Path;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use trace::format::{self, Callback, DeviceInfo, Direction, Event, Op, Params, Record};
+use util;
+
+pub const OPS: Ops = capi_new!(ReplayContext, ReplayStream);
+
+/// Environment variable naming the trace `ReplayContext::init` replays.
+pub const REPLAY_ENV: &str = "CUBEB_REPLAY_FILE";
+
+// A recorded callback, placed relative to the application's calls.
+struct Pending {
+    started: u32,
+    completed: u32,
+    callback: Callback
+}
+
+#[derive(Default)]
+struct Script {
+    calls: VecDeque<(ffi::cubeb_error_code, Op)>,
+    callbacks: Vec<Pending>
+}
+
+struct Progress {
+    started: u32,
+    completed: u32,
+    delivered: usize,
+    closed: bool
+}
+
+// Orders the recorded callbacks of a context or stream against the
+// application's calls, as they were ordered when the trace was recorded:
+// a callback is delivered once as many calls have started and completed
+// as had then, and a call returns once every callback recorded before it
+// returned has been delivered.
+struct Sequencer {
+    calls: Mutex<VecDeque<(ffi::cubeb_error_code, Op)>>,
+    callbacks: Vec<Pending>,
+    progress: Mutex<Progress>,
+    changed: Condvar
+}
+
+impl Sequencer {
+    fn new(script: Script) -> Sequencer {
+        Sequencer {
+            calls: Mutex::new(script.calls),
+            callbacks: script.callbacks,
+            progress: Mutex::new(Progress {
+                started: 0,
+                completed: 0,
+                delivered: 0,
+                closed: false
+            }),
+            changed: Condvar::new()
+        }
+    }
+
+    fn begin(&self) -> Option<(ffi::cubeb_error_code, Op)> {
+        self.progress.lock().unwrap().started += 1;
+        self.changed.notify_all();
+        self.calls.lock().unwrap().pop_front()
+    }
+
+    // Complete the call with `result` once the callbacks due first have
+    // been delivered.
+    fn end<T, F: FnOnce() -> T>(&self, result: F) -> T {
+        let mut p = self.progress.lock().unwrap();
+        while let Some(c) = self.callbacks.get(p.delivered) {
+            if p.closed || c.completed > p.completed || c.started > p.started {
+                break;
+            }
+            p = self.changed.wait(p).unwrap();
+        }
+        let result = result();
+        p.completed += 1;
+        self.changed.notify_all();
+        result
+    }
+
+    // The next callback to deliver, once it's due.
+    fn next(&self) -> Option<&Callback> {
+        let mut p = self.progress.lock().unwrap();
+        loop {
+            if p.closed {
+                return None;
+            }
+            let c = self.callbacks.get(p.delivered)?;
+            if p.started >= c.started && p.completed >= c.completed {
+                return Some(&c.callback);
+            }
+            p = self.changed.wait(p).unwrap();
+        }
+    }
+
+    fn delivered(&self) {
+        self.progress.lock().unwrap().delivered += 1;
+        self.changed.notify_all();
+    }
+
+    fn close(&self) {
+        self.progress.lock().unwrap().closed = true;
+        self.changed.notify_all();
+    }
+}
+
+struct Shared {
+    records: Vec<Record>,
+    divergences: Mutex<Vec<String>>
+}
+
+impl Shared {
+    fn diverged(&self, what: String) {
+        self.divergences.lock().unwrap().push(what);
+    }
+}
+
+/// A recorded trace, ready to be replayed against application code.
+///
+/// Contexts created by `context` answer every call with the recorded
+/// result, and deliver the recorded callbacks on a thread per context and
+/// per stream, in the order they were recorded relative to the calls.
+/// Data callbacks are made with the recorded sizes and input buffers, or
+/// silence when the trace doesn't hold them, as fast as the application
+/// consumes them. Where the application departs from the trace, the
+/// call fails and the difference is reported by `divergences`.
+#[derive(Clone)]
+pub struct Replay {
+    shared: Arc<Shared>
+}
+
+impl Replay {
+    pub fn new(records: Vec<Record>) -> Replay {
+        Replay {
+            shared: Arc::new(Shared {
+                records,
+                divergences: Mutex::new(Vec::new())
+            })
+        }
+    }
+
+    /// Load the trace at `path`.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Replay> {
+        let records = format::read_trace(&mut File::open(path)?)?;
+        Ok(Replay::new(records))
+    }
+
+    /// Create a context replaying the trace from its start.
+    pub fn context(&self) -> Result<*mut ffi::cubeb> {
+        let mut records = self.shared.records.iter();
+        match records.next() {
+            Some(&Record {
+                event: Event::Init { .. },
+                ..
+            }) => {},
+            _ => return Err(Error::from(ErrorCode::InvalidFormat)),
+        }
+
+        let mut context = Script::default();
+        let mut streams: HashMap<u32, Script> = HashMap::new();
+        for record in records {
+            match record.event {
+                Event::Init { .. } => return Err(Error::from(ErrorCode::InvalidFormat)),
+                Event::Call { stream, result, ref op } => {
+                    let script = match stream {
+                        Some(id) => streams.entry(id).or_default(),
+                        None => &mut context,
+                    };
+                    script.calls.push_back((result, op.clone()));
+                },
+                Event::Callback {
+                    stream,
+                    started,
+                    completed,
+                    ref callback
+                } => {
+                    let script = match stream {
+                        Some(id) => streams.entry(id).or_default(),
+                        None => &mut context,
+                    };
+                    script.callbacks.push(Pending {
+                        started,
+                        completed,
+                        callback: callback.clone()
+                    });
+                },
+            }
+        }
+
+        let sequencer = Arc::new(Sequencer::new(context));
+        let ctx = Box::into_raw(Box::new(ReplayContext {
+            ops: &OPS as *const _,
+            replay: self.clone(),
+            sequencer: sequencer.clone(),
+            streams: Mutex::new(streams),
+            collection_changed: Mutex::new(None),
+            thread: None
+        }));
+        let this = ctx as usize;
+        let thread = thread::spawn(move || {
+            let context = unsafe { &*(this as *const ReplayContext) };
+            while let Some(callback) = sequencer.next() {
+                if let Callback::DeviceCollectionChanged = *callback {
+                    let collection_changed = *context.collection_changed.lock().unwrap();
+                    if let Some((callback, user_ptr)) = collection_changed {
+                        callback(this as *mut _, user_ptr);
+                    }
+                } else {
+                    context
+                        .replay
+                        .shared
+                        .diverged(format!("context: unexpected callback {:?}", callback));
+                }
+                sequencer.delivered();
+            }
+        });
+        unsafe { (*ctx).thread = Some(thread) };
+        Ok(ctx as *mut _)
+    }
+
+    /// Differences between the trace and the replay so far.
+    pub fn divergences(&self) -> Vec<String> {
+        self.shared.divergences.lock().unwrap().clone()
+    }
+
+    // Answer a call with the next recorded call of `sequencer`, if it's
+    // the same.
+    fn call<T, F>(&self, sequencer: &Sequencer, what: &str, op: Op, value: F) -> Result<T>
+    where
+        F: FnOnce(Op) -> T,
+    {
+        let recorded = sequencer.begin();
+        sequencer.end(|| match recorded {
+            Some((result, recorded)) => {
+                if !recorded.same_call(&op) {
+                    self.shared
+                        .diverged(format!("{}: called {:?} instead of {:?}", what, op, recorded));
+                    Err(Error::new())
+                } else if result == ffi::CUBEB_OK {
+                    Ok(value(recorded))
+                } else {
+                    Err(unsafe { Error::from_raw(result) })
+                }
+            },
+            None => {
+                self.shared
+                    .diverged(format!("{}: called {:?} past the end of the trace", what, op));
+                Err(Error::new())
+            },
+        })
+    }
+}
+
+type CollectionChanged = (ffi::cubeb_device_collection_changed_callback, *mut c_void);
+
+/// Context replaying a trace.
+#[repr(C)]
+pub struct ReplayContext {
+    ops: *const Ops,
+    replay: Replay,
+    sequencer: Arc<Sequencer>,
+    // Scripts of the streams not created yet.
+    streams: Mutex<HashMap<u32, Script>>,
+    collection_changed: Mutex<Option<CollectionChanged>>,
+    thread: Option<JoinHandle<()>>
+}
+
+impl ReplayContext {
+    fn call<T, F>(&self, op: Op, value: F) -> Result<T>
+    where
+        F: FnOnce(Op) -> T,
+    {
+        self.replay.call(&self.sequencer, "context", op, value)
+    }
+}
+
+impl Drop for ReplayContext {
+    fn drop(&mut self) {
+        self.sequencer.close();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Context for ReplayContext {
+    /// Replay the trace named by `REPLAY_ENV`.
+    fn init(_context_name: Option<&CStr>) -> Result<*mut ffi::cubeb> {
+        let path = env::var_os(REPLAY_ENV).ok_or_else(|| Error::from(ErrorCode::NotSupported))?;
+        let replay = Replay::open(path).map_err(|_| Error::from(ErrorCode::InvalidFormat))?;
+        replay.context()
+    }
+
+    fn backend_id(&self) -> &'static CStr {
+        unsafe { CStr::from_ptr(b"replay\0".as_ptr() as *const _) }
+    }
+
+    fn max_channel_count(&self) -> Result<u32> {
+        self.call(Op::MaxChannelCount(0), |op| match op {
+            Op::MaxChannelCount(x) => x,
+            _ => unreachable!(),
+        })
+    }
+
+    fn min_latency(&self, params: &StreamParams) -> Result<u32> {
+        let op = Op::MinLatency {
+            params: Params::from(unsafe { &*params.raw() }),
+            latency: 0
+        };
+        self.call(op, |op| match op {
+            Op::MinLatency { latency, .. } => latency,
+            _ => unreachable!(),
+        })
+    }
+
+    fn preferred_sample_rate(&self) -> Result<u32> {
+        self.call(Op::PreferredSampleRate(0), |op| match op {
+            Op::PreferredSampleRate(x) => x,
+            _ => unreachable!(),
+        })
+    }
+
+    fn preferred_channel_layout(&self) -> Result<ffi::cubeb_channel_layout> {
+        self.call(Op::PreferredChannelLayout(0), |op| match op {
+            Op::PreferredChannelLayout(x) => x,
+            _ => unreachable!(),
+        })
+    }
+
+    fn enumerate_devices(
+        &self,
+        devtype: DeviceType,
+    ) -> Result<ffi::cubeb_device_collection> {
+        let op = Op::EnumerateDevices {
+            devtype: devtype.bits(),
+            devices: Vec::new()
+        };
+        self.call(op, |op| match op {
+            Op::EnumerateDevices { devices, .. } => DeviceInfo::into_collection(devices),
+            _ => unreachable!(),
+        })
+    }
+
+    #[allow(clippy::not_unsafe_ptr_arg_deref)]
+    fn device_collection_destroy(
+        &self,
+        collection: *mut ffi::cubeb_device_collection,
+    ) {
+        unsafe { util::destroy_owned_device_collection(collection) }
+    }
+
+    fn stream_init(
+        &self,
+        stream_name: Option<&CStr>,
+        input_device: DeviceId,
+        input_stream_params: Option<&ffi::cubeb_stream_params>,
+        output_device: DeviceId,
+        output_stream_params: Option<&ffi::cubeb_stream_params>,
+        latency_frames: u32,
+        data_callback: ffi::cubeb_data_callback,
+        state_callback: ffi::cubeb_state_callback,
+        user_ptr: *mut c_void,
+    ) -> Result<*mut ffi::cubeb_stream> {
+        let direction = |devid: DeviceId, params: Option<&ffi::cubeb_stream_params>| {
+            params.map(|p| Direction {
+                devid: devid.raw() as usize as u64,
+                params: Params::from(p)
+            })
+        };
+        let op = Op::StreamInit {
+            stream: 0,
+            name: stream_name.map(|n| n.to_string_lossy().into_owned()),
+            input: direction(input_device, input_stream_params),
+            output: direction(output_device, output_stream_params),
+            latency_frames
+        };
+        let id = self.call(op, |op| match op {
+            Op::StreamInit { stream, .. } => stream,
+            _ => unreachable!(),
+        })?;
+
+        let script = self.streams.lock().unwrap().remove(&id).unwrap_or_default();
+        let state = Arc::new(StreamState {
+            id,
+            replay: self.replay.clone(),
+            sequencer: Sequencer::new(script),
+            input_frame: super::frame_size(input_stream_params),
+            output_frame: super::frame_size(output_stream_params),
+            data_callback,
+            state_callback,
+            user_ptr,
+            device_changed: Mutex::new(None)
+        });
+        let stm = Box::into_raw(Box::new(ReplayStream {
+            context: self,
+            state: state.clone(),
+            thread: None
+        }));
+        let this = stm as usize;
+        let thread = thread::spawn(move || {
+            while let Some(callback) = state.sequencer.next() {
+                state.deliver(this as *mut _, callback);
+                state.sequencer.delivered();
+            }
+        });
+        unsafe { (*stm).thread = Some(thread) };
+        Ok(stm as *mut _)
+    }
+
+    fn register_device_collection_changed(
+        &self,
+        devtype: DeviceType,
+        cb: Option<ffi::cubeb_device_collection_changed_callback>,
+        user_ptr: *mut c_void,
+    ) -> Result<()> {
+        *self.collection_changed.lock().unwrap() = cb.map(|cb| (cb, user_ptr));
+        self.call(Op::RegisterDeviceCollectionChanged(devtype.bits()), |_| ())
+    }
+}
+
+struct StreamState {
+    id: u32,
+    replay: Replay,
+    sequencer: Sequencer,
+    input_frame: usize,
+    output_frame: usize,
+    data_callback: ffi::cubeb_data_callback,
+    state_callback: ffi::cubeb_state_callback,
+    user_ptr: *mut c_void,
+    device_changed: Mutex<Option<ffi::cubeb_device_changed_callback>>
+}
+
+// The user pointer is only handed back to the application's callbacks,
+// which libcubeb already calls from other threads.
+unsafe impl Send for StreamState {}
+unsafe impl Sync for StreamState {}
+
+impl StreamState {
+    fn deliver(&self, stream: *mut ffi::cubeb_stream, callback: &Callback) {
+        match *callback {
+            Callback::Data {
+                nframes,
+                returned,
+                ref input,
+                ref output,
+                ..
+            } => self.data(stream, nframes as usize, returned, input, output),
+            Callback::State(state) => (self.state_callback)(stream, self.user_ptr, state),
+            Callback::DeviceChanged => {
+                if let Some(callback) = *self.device_changed.lock().unwrap() {
+                    callback(self.user_ptr);
+                }
+            },
+            Callback::DeviceCollectionChanged => {
+                self.replay
+                    .shared
+                    .diverged(format!("stream {}: unexpected callback {:?}", self.id, callback));
+            },
+        }
+    }
+
+    fn data(
+        &self,
+        stream: *mut ffi::cubeb_stream,
+        nframes: usize,
+        returned: i64,
+        input: &Option<Vec<u8>>,
+        output: &Option<Vec<u8>>,
+    ) {
+        let input_len = nframes * self.input_frame;
+        let mut input_buffer = match *input {
+            Some(ref input) if input.len() == input_len => input.clone(),
+            _ => vec![0u8; input_len],
+        };
+        let mut output_buffer = vec![0u8; nframes * self.output_frame];
+        let buffer = |b: &mut Vec<u8>, frame| if frame == 0 {
+            ptr::null_mut()
+        } else {
+            b.as_mut_ptr() as *mut c_void
+        };
+        let got = (self.data_callback)(
+            stream,
+            self.user_ptr,
+            buffer(&mut input_buffer, self.input_frame),
+            buffer(&mut output_buffer, self.output_frame),
+            nframes as c_long
+        );
+
+        let got = got as i64;
+        if got != returned {
+            self.replay.shared.diverged(format!(
+                "stream {}: data callback returned {} instead of {}",
+                self.id, got, returned
+            ));
+        }
+        if let Some(ref expected) = *output {
+            let len = got.clamp(0, returned.max(0)) as usize * self.output_frame;
+            if expected.len() >= len && output_buffer[..len] != expected[..len] {
+                self.replay.shared.diverged(format!(
+                    "stream {}: data callback rendered different output",
+                    self.id
+                ));
+            }
+        }
+    }
+}
+
+/// Stream replaying a trace.
+///
+/// The first field points at the owning context, as libcubeb requires.
+#[repr(C)]
+pub struct ReplayStream {
+    context: *const ReplayContext,
+    state: Arc<StreamState>,
+    thread: Option<JoinHandle<()>>
+}
+
+impl ReplayStream {
+    fn call<T, F>(&self, op: Op, value: F) -> Result<T>
+    where
+        F: FnOnce(Op) -> T,
+    {
+        let what = format!("stream {}", self.state.id);
+        self.state
+            .replay
+            .call(&self.state.sequencer, &what, op, value)
+    }
+}
+
+impl Drop for ReplayStream {
+    fn drop(&mut self) {
+        let _ = self.call(Op::StreamDestroy, |_| ());
+        self.state.sequencer.close();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Stream for ReplayStream {
+    fn start(&self) -> Result<()> {
+        self.call(Op::StreamStart, |_| ())
+    }
+
+    fn stop(&self) -> Result<()> {
+        self.call(Op::StreamStop, |_| ())
+    }
+
+    fn reset_default_device(&self) -> Result<()> {
+        self.call(Op::StreamResetDefaultDevice, |_| ())
+    }
+
+    fn position(&self) -> Result<u64> {
+        self.call(Op::StreamGetPosition(0), |op| match op {
+            Op::StreamGetPosition(x) => x,
+            _ => unreachable!(),
+        })
+    }
+
+    fn latency(&self) -> Result<u32> {
+        self.call(Op::StreamGetLatency(0), |op| match op {
+            Op::StreamGetLatency(x) => x,
+            _ => unreachable!(),
+        })
+    }
+
+    fn set_volume(&self, volume: f32) -> Result<()> {
+        self.call(Op::StreamSetVolume(volume), |_| ())
+    }
+
+    fn set_panning(&self, panning: f32) -> Result<()> {
+        self.call(Op::StreamSetPanning(panning), |_| ())
+    }
+
+    fn current_device(&self) -> Result<*const ffi::cubeb_device> {
+        let op = Op::StreamGetCurrentDevice {
+            input_name: None,
+            output_name: None
+        };
+        self.call(op, |op| match op {
+            Op::StreamGetCurrentDevice {
+                input_name,
+                output_name
+            } => Box::into_raw(Box::new(ffi::cubeb_device {
+                output_name: util::into_c_string(output_name),
+                input_name: util::into_c_string(input_name)
+            })) as *const _,
+            _ => unreachable!(),
+        })
+    }
+
+    fn device_destroy(&self, device: *const ffi::cubeb_device) -> Result<()> {
+        if device.is_null() {
+            return Err(Error::from(ErrorCode::InvalidParameter));
+        }
+        unsafe {
+            let device = Box::from_raw(device as *mut ffi::cubeb_device);
+            util::free_c_string(device.output_name);
+            util::free_c_string(device.input_name);
+        }
+        Ok(())
+    }
+
+    fn register_device_changed_callback(
+        &self,
+        device_changed_callback: Option<ffi::cubeb_device_changed_callback>,
+    ) -> Result<()> {
+        *self.state.device_changed.lock().unwrap() = device_changed_callback;
+        self.call(Op::StreamRegisterDeviceChanged, |_| ())
+    }
+}
@@ -39,7 +39,7 @@ pub trait Context {
     fn register_device_collection_changed(
         &self,
         devtype: DeviceType,
-        cb: ffi::cubeb_device_collection_changed_callback,
+        cb: Option<ffi::cubeb_device_collection_changed_callback>,
         user_ptr: *mut c_void,
     ) -> Result<()>;
 }
@@ -56,6 +56,6 @@ pub trait Stream {
     fn device_destroy(&self, device: *const ffi::cubeb_device) -> Result<()>;
     fn register_device_changed_callback(
         &self,
-        device_changed_callback: ffi::cubeb_device_changed_callback,
+        device_changed_callback: Option<ffi::cubeb_device_changed_callback>,
     ) -> Result<()>;
 }
@@ -0,0 +1,73 @@
+// Copyright © 2017 Mozilla Foundation
+//
+// This program is made available under an ISC-style license.  See the
+// accompanying file LICENSE for details.
+
+use cubeb_core::{DeviceType, DEVICE_TYPE_OUTPUT};
+use cubeb_core::ffi;
+use std::ffi::{CStr, CString};
+use std::os::raw::c_char;
+use std::{ptr, slice};
+
+/// Device id for the `index`th device of `devtype`. Ids are small tagged
+/// integers, never dereferenced.
+pub fn devid(index: usize, devtype: DeviceType) -> ffi::cubeb_devid {
+    let output = (devtype == DEVICE_TYPE_OUTPUT) as usize;
+    (((index + 1) << 1) | output) as ffi::cubeb_devid
+}
+
+/// Hand `infos` over to the caller of `enumerate_devices`. Release it
+/// with `destroy_device_collection`.
+pub fn device_collection(infos: Vec<ffi::cubeb_device_info>) -> ffi::cubeb_device_collection {
+    let infos = infos.into_boxed_slice();
+    let count = infos.len();
+    ffi::cubeb_device_collection {
+        device: Box::into_raw(infos) as *const ffi::cubeb_device_info,
+        count
+    }
+}
+
+pub unsafe fn destroy_device_collection(collection: *mut ffi::cubeb_device_collection) {
+    let coll = &mut *collection;
+    if !coll.device.is_null() {
+        let infos = slice::from_raw_parts_mut(coll.device as *mut ffi::cubeb_device_info, coll.count);
+        let _ = Box::from_raw(infos);
+    }
+    coll.device = ptr::null();
+    coll.count = 0;
+}
+
+/// Copy the C string `s`, which may be null.
+pub unsafe fn from_c_string(s: *const c_char) -> Option<String> {
+    if s.is_null() {
+        None
+    } else {
+        Some(CStr::from_ptr(s).to_string_lossy().into_owned())
+    }
+}
+
+/// Hand `s` over as a C string, or null. Release it with `free_c_string`.
+pub fn into_c_string(s: Option<String>) -> *const c_char {
+    s.and_then(|s| CString::new(s).ok())
+        .map_or(ptr::null(), |s| s.into_raw() as *const c_char)
+}
+
+pub unsafe fn free_c_string(s: *const c_char) {
+    if !s.is_null() {
+        let _ = CString::from_raw(s as *mut c_char);
+    }
+}
+
+/// Release a collection whose strings come from `into_c_string`.
+pub unsafe fn destroy_owned_device_collection(collection: *mut ffi::cubeb_device_collection) {
+    let coll = &*collection;
+    if !coll.device.is_null() {
+        for d in slice::from_raw_parts(coll.device, coll.count) {
+            free_c_string(d.device_id);
+            free_c_string(d.friendly_name);
+            free_c_string(d.group_id);
+            free_c_string(d.vendor_name);
+        }
+    }
+    destroy_device_collection(collection)
+}
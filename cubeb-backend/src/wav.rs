@@ -0,0 +1,704 @@
+// Copyright © 2017 Mozilla Foundation
+//
+// This program is made available under an ISC-style license.  See the
+// accompanying file LICENSE for details.
+
+//! A backend that renders output streams to RIFF/WAVE files and feeds
+//! input streams from them.
+//!
+//! Every configured file is exposed as a device. Opening an output stream
+//! (re)creates its file, and the header is kept valid after every period,
+//! so the file can be inspected as soon as the stream is stopped or
+//! drained. Streams run faster than real time by default, which makes the
+//! backend suitable for offline rendering and golden-file tests.
+
+use {Context, Ops};
+use cubeb_core::{ChannelLayout, DeviceId, DeviceType, Error, ErrorCode, Result,
+                 SampleFormat, StreamParams, DEVICE_TYPE_INPUT, DEVICE_TYPE_OUTPUT};
+use cubeb_core::binding::Binding;
+use cubeb_core::ffi;
+use driver::{self, CallbackDriverBuilder, DriverStream, Pacing, Sink, Source};
+use std::{env, io, ptr};
+use std::ffi::{CStr, CString};
+use std::fs::File;
+use std::io::{BufReader, Read, Seek, SeekFrom, Write};
+use std::os::raw::c_void;
+use std::path::{Path, PathBuf};
+use util;
+
+pub const OPS: Ops = capi_new!(WavContext, WavStream);
+
+/// WAV backend stream.
+pub type WavStream = DriverStream;
+
+const MIN_RATE: u32 = 1_000;
+const MAX_RATE: u32 = 384_000;
+const MAX_LATENCY: u32 = 96_000;
+
+const WAVE_FORMAT_PCM: u16 = 0x0001;
+const WAVE_FORMAT_IEEE_FLOAT: u16 = 0x0003;
+const WAVE_FORMAT_EXTENSIBLE: u16 = 0xFFFE;
+
+// KSDATAFORMAT_SUBTYPE_* GUIDs, minus the leading format tag.
+const SUBFORMAT_GUID_TAIL: [u8; 14] = [
+    0x00, 0x00, 0x00, 0x00, 0x10, 0x00, 0x80, 0x00, 0x00, 0xAA, 0x00, 0x38, 0x9B, 0x71
+];
+
+const SPEAKER_FRONT_LEFT: u32 = 0x1;
+const SPEAKER_FRONT_RIGHT: u32 = 0x2;
+const SPEAKER_FRONT_CENTER: u32 = 0x4;
+const SPEAKER_LOW_FREQUENCY: u32 = 0x8;
+const SPEAKER_BACK_LEFT: u32 = 0x10;
+const SPEAKER_BACK_RIGHT: u32 = 0x20;
+const SPEAKER_BACK_CENTER: u32 = 0x100;
+const SPEAKER_SIDE_LEFT: u32 = 0x200;
+const SPEAKER_SIDE_RIGHT: u32 = 0x400;
+
+const FRONT: u32 = SPEAKER_FRONT_LEFT | SPEAKER_FRONT_RIGHT;
+const SIDE: u32 = SPEAKER_SIDE_LEFT | SPEAKER_SIDE_RIGHT;
+
+// The speaker order of every cubeb layout matches the order of the mask
+// bits, so samples are written as-is.
+const LAYOUTS: [(ChannelLayout, u32); 18] = [
+    (ChannelLayout::DualMono, FRONT),
+    (ChannelLayout::DualMonoLfe, FRONT | SPEAKER_LOW_FREQUENCY),
+    (ChannelLayout::Mono, SPEAKER_FRONT_CENTER),
+    (ChannelLayout::MonoLfe, SPEAKER_FRONT_CENTER | SPEAKER_LOW_FREQUENCY),
+    (ChannelLayout::Stereo, FRONT),
+    (ChannelLayout::StereoLfe, FRONT | SPEAKER_LOW_FREQUENCY),
+    (ChannelLayout::Layout3F, FRONT | SPEAKER_FRONT_CENTER),
+    (ChannelLayout::Layout3FLfe, FRONT | SPEAKER_FRONT_CENTER | SPEAKER_LOW_FREQUENCY),
+    (ChannelLayout::Layout2F1, FRONT | SPEAKER_BACK_CENTER),
+    (ChannelLayout::Layout2F1Lfe, FRONT | SPEAKER_LOW_FREQUENCY | SPEAKER_BACK_CENTER),
+    (ChannelLayout::Layout3F1, FRONT | SPEAKER_FRONT_CENTER | SPEAKER_BACK_CENTER),
+    (
+        ChannelLayout::Layout3F1Lfe,
+        FRONT | SPEAKER_FRONT_CENTER | SPEAKER_LOW_FREQUENCY | SPEAKER_BACK_CENTER
+    ),
+    (ChannelLayout::Layout2F2, FRONT | SIDE),
+    (ChannelLayout::Layout2F2Lfe, FRONT | SPEAKER_LOW_FREQUENCY | SIDE),
+    (ChannelLayout::Layout3F2, FRONT | SPEAKER_FRONT_CENTER | SIDE),
+    (ChannelLayout::Layout3F2Lfe, FRONT | SPEAKER_FRONT_CENTER | SPEAKER_LOW_FREQUENCY | SIDE),
+    (
+        ChannelLayout::Layout3F3RLfe,
+        FRONT | SPEAKER_FRONT_CENTER | SPEAKER_LOW_FREQUENCY | SPEAKER_BACK_CENTER | SIDE
+    ),
+    (
+        ChannelLayout::Layout3F4Lfe,
+        FRONT | SPEAKER_FRONT_CENTER | SPEAKER_LOW_FREQUENCY | SPEAKER_BACK_LEFT |
+            SPEAKER_BACK_RIGHT | SIDE
+    )
+];
+
+/// `dwChannelMask` of a WAVE_FORMAT_EXTENSIBLE file using `layout`, or 0
+/// for `ChannelLayout::Undefined`.
+pub fn channel_mask(layout: ChannelLayout) -> u32 {
+    LAYOUTS
+        .iter()
+        .find(|&&(l, _)| l == layout)
+        .map_or(0, |&(_, mask)| mask)
+}
+
+/// The layout described by `mask`. Dual mono files read back as stereo.
+pub fn channel_layout(mask: u32) -> ChannelLayout {
+    LAYOUTS
+        .iter()
+        .rev()
+        .find(|&&(_, m)| m == mask)
+        .map_or(ChannelLayout::Undefined, |&(layout, _)| layout)
+}
+
+// The speakers a plain PCM or IEEE float file implies.
+fn default_mask(channels: u32) -> u32 {
+    match channels {
+        1 => SPEAKER_FRONT_CENTER,
+        2 => FRONT,
+        _ => 0,
+    }
+}
+
+// WAVE data is always little endian.
+fn little_endian(format: SampleFormat) -> SampleFormat {
+    match format {
+        SampleFormat::S16LE | SampleFormat::S16BE | SampleFormat::S16NE => SampleFormat::S16LE,
+        _ => SampleFormat::Float32LE,
+    }
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Format of the audio in a WAVE file.
+#[derive(PartialEq, Eq, Clone, Debug, Copy)]
+pub struct WavSpec {
+    /// `SampleFormat::S16LE` or `SampleFormat::Float32LE`.
+    pub format: SampleFormat,
+    pub rate: u32,
+    pub channels: u32,
+    pub layout: ChannelLayout
+}
+
+impl WavSpec {
+    /// The file format for streams using `params`.
+    pub fn from_params(params: &StreamParams) -> WavSpec {
+        WavSpec {
+            format: little_endian(params.format()),
+            rate: params.rate(),
+            channels: params.channels(),
+            layout: params.layout()
+        }
+    }
+
+    fn frame_size(&self) -> usize {
+        driver::sample_size(self.format) * self.channels as usize
+    }
+
+    // The format tag of the samples, ignoring WAVE_FORMAT_EXTENSIBLE.
+    fn tag(&self) -> u16 {
+        match self.format {
+            SampleFormat::S16LE => WAVE_FORMAT_PCM,
+            _ => WAVE_FORMAT_IEEE_FLOAT,
+        }
+    }
+
+    // Speaker mask to record, when the plain header can't express it.
+    fn extensible_mask(&self) -> Option<u32> {
+        let mask = channel_mask(self.layout);
+        let mask = if mask.count_ones() == self.channels {
+            mask
+        } else {
+            0
+        };
+        if self.channels > 2 || (mask != 0 && mask != default_mask(self.channels)) {
+            Some(mask)
+        } else {
+            None
+        }
+    }
+}
+
+/// Writes interleaved little endian audio to a WAVE file.
+///
+/// The header is rewritten after every `write`, so the file is complete
+/// at any point.
+pub struct WavWriter {
+    file: File,
+    spec: WavSpec,
+    frames: u64,
+    data_len: u64,
+    header_len: u64,
+    fact_offset: Option<u64>
+}
+
+impl WavWriter {
+    /// Create, or truncate, the file at `path`.
+    pub fn create<P: AsRef<Path>>(path: P, spec: WavSpec) -> io::Result<WavWriter> {
+        let spec = WavSpec {
+            format: little_endian(spec.format),
+            ..spec
+        };
+        let file = File::create(path)?;
+        let mut writer = WavWriter {
+            file,
+            spec,
+            frames: 0,
+            data_len: 0,
+            header_len: 0,
+            fact_offset: None
+        };
+        writer.write_header()?;
+        Ok(writer)
+    }
+
+    pub fn spec(&self) -> WavSpec {
+        self.spec
+    }
+
+    /// Number of frames written so far.
+    pub fn frames(&self) -> u64 {
+        self.frames
+    }
+
+    fn write_header(&mut self) -> io::Result<()> {
+        let spec = self.spec;
+        let bits = driver::sample_size(spec.format) as u16 * 8;
+        let block_align = spec.frame_size() as u16;
+        let mask = spec.extensible_mask();
+        let float = spec.tag() == WAVE_FORMAT_IEEE_FLOAT;
+
+        let mut fmt = Vec::with_capacity(40);
+        let tag = if mask.is_some() {
+            WAVE_FORMAT_EXTENSIBLE
+        } else {
+            spec.tag()
+        };
+        fmt.extend_from_slice(&tag.to_le_bytes());
+        fmt.extend_from_slice(&(spec.channels as u16).to_le_bytes());
+        fmt.extend_from_slice(&spec.rate.to_le_bytes());
+        fmt.extend_from_slice(&(spec.rate * u32::from(block_align)).to_le_bytes());
+        fmt.extend_from_slice(&block_align.to_le_bytes());
+        fmt.extend_from_slice(&bits.to_le_bytes());
+        if let Some(mask) = mask {
+            fmt.extend_from_slice(&22u16.to_le_bytes());
+            fmt.extend_from_slice(&bits.to_le_bytes());
+            fmt.extend_from_slice(&mask.to_le_bytes());
+            fmt.extend_from_slice(&spec.tag().to_le_bytes());
+            fmt.extend_from_slice(&SUBFORMAT_GUID_TAIL);
+        } else if float {
+            fmt.extend_from_slice(&0u16.to_le_bytes());
+        }
+
+        let mut header = Vec::with_capacity(80);
+        header.extend_from_slice(b"RIFF\0\0\0\0WAVEfmt ");
+        header.extend_from_slice(&(fmt.len() as u32).to_le_bytes());
+        header.extend_from_slice(&fmt);
+        // Non-PCM files carry the frame count in a fact chunk.
+        if float || mask.is_some() {
+            header.extend_from_slice(b"fact\x04\0\0\0");
+            self.fact_offset = Some(header.len() as u64);
+            header.extend_from_slice(&[0; 4]);
+        }
+        header.extend_from_slice(b"data\0\0\0\0");
+        self.header_len = header.len() as u64;
+
+        self.file.write_all(&header)?;
+        self.update_header()
+    }
+
+    fn update_header(&mut self) -> io::Result<()> {
+        let clamp = |x: u64| x.min(u64::from(u32::MAX)) as u32;
+        let riff_len = clamp(self.header_len - 8 + self.data_len);
+        self.file.seek(SeekFrom::Start(4))?;
+        self.file.write_all(&riff_len.to_le_bytes())?;
+        if let Some(offset) = self.fact_offset {
+            self.file.seek(SeekFrom::Start(offset))?;
+            self.file.write_all(&clamp(self.frames).to_le_bytes())?;
+        }
+        self.file.seek(SeekFrom::Start(self.header_len - 4))?;
+        self.file.write_all(&clamp(self.data_len).to_le_bytes())?;
+        self.file.seek(SeekFrom::End(0))?;
+        Ok(())
+    }
+
+    /// Append `frames` frames of interleaved audio in the file's format.
+    pub fn write(&mut self, buffer: &[u8], frames: usize) -> io::Result<()> {
+        let len = frames * self.spec.frame_size();
+        self.file.write_all(&buffer[..len])?;
+        self.frames += frames as u64;
+        self.data_len += len as u64;
+        self.update_header()
+    }
+}
+
+impl Sink for WavWriter {
+    fn format(&self) -> Option<SampleFormat> {
+        Some(self.spec.format)
+    }
+
+    fn write(&mut self, buffer: &[u8], frames: usize) -> Result<()> {
+        WavWriter::write(self, buffer, frames).map_err(|e| Error::new().with_source(e))
+    }
+
+    fn drain(&mut self) -> Result<()> {
+        self.file.sync_data().map_err(|e| Error::new().with_source(e))
+    }
+}
+
+/// Reads interleaved little endian audio from a WAVE file.
+pub struct WavReader {
+    reader: BufReader<File>,
+    spec: WavSpec,
+    remaining: u64
+}
+
+impl WavReader {
+    /// Open the file at `path` and parse its header. Fails with
+    /// `io::ErrorKind::InvalidData` for files that aren't 16 bit PCM or
+    /// 32 bit float.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<WavReader> {
+        let mut reader = BufReader::new(File::open(path)?);
+        let mut riff = [0; 12];
+        reader.read_exact(&mut riff)?;
+        if &riff[..4] != b"RIFF" || &riff[8..] != b"WAVE" {
+            return Err(invalid_data("not a RIFF/WAVE file"));
+        }
+
+        let mut spec = None;
+        loop {
+            let mut chunk = [0; 8];
+            reader.read_exact(&mut chunk)?;
+            let len = u32::from_le_bytes([chunk[4], chunk[5], chunk[6], chunk[7]]);
+            match &chunk[..4] {
+                b"fmt " => {
+                    let mut fmt = vec![0; len as usize];
+                    reader.read_exact(&mut fmt)?;
+                    spec = Some(parse_fmt(&fmt)?);
+                },
+                b"data" => {
+                    let spec = spec.ok_or_else(|| invalid_data("data chunk before fmt chunk"))?;
+                    return Ok(WavReader {
+                        reader,
+                        spec,
+                        remaining: u64::from(len)
+                    });
+                },
+                _ => {
+                    reader.seek(SeekFrom::Current(i64::from(len)))?;
+                },
+            }
+            // Chunks are padded to an even length.
+            if len % 2 == 1 {
+                reader.seek(SeekFrom::Current(1))?;
+            }
+        }
+    }
+
+    pub fn spec(&self) -> WavSpec {
+        self.spec
+    }
+
+    /// Read up to `frames` frames into `buffer` and return the number of
+    /// frames read, which is less than `frames` only at the end of the
+    /// data.
+    pub fn read(&mut self, buffer: &mut [u8], frames: usize) -> io::Result<usize> {
+        let frame_size = self.spec.frame_size();
+        let want = (frames * frame_size).min(self.remaining as usize);
+        let want = want - want % frame_size;
+        let mut filled = 0;
+        while filled < want {
+            match self.reader.read(&mut buffer[filled..want]) {
+                Ok(0) => break,
+                Ok(n) => filled += n,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {},
+                Err(e) => return Err(e),
+            }
+        }
+        self.remaining -= filled as u64;
+        Ok(filled / frame_size)
+    }
+}
+
+impl Source for WavReader {
+    fn format(&self) -> Option<SampleFormat> {
+        Some(self.spec.format)
+    }
+
+    fn read(&mut self, buffer: &mut [u8], frames: usize) -> Result<usize> {
+        WavReader::read(self, buffer, frames).map_err(|e| Error::new().with_source(e))
+    }
+}
+
+fn parse_fmt(fmt: &[u8]) -> io::Result<WavSpec> {
+    if fmt.len() < 16 {
+        return Err(invalid_data("short fmt chunk"));
+    }
+    let u16_at = |i: usize| u16::from_le_bytes([fmt[i], fmt[i + 1]]);
+    let u32_at = |i: usize| u32::from_le_bytes([fmt[i], fmt[i + 1], fmt[i + 2], fmt[i + 3]]);
+    let channels = u32::from(u16_at(2));
+    let rate = u32_at(4);
+    let bits = u16_at(14);
+
+    let (tag, mask) = if u16_at(0) == WAVE_FORMAT_EXTENSIBLE {
+        if fmt.len() < 40 || fmt[26..40] != SUBFORMAT_GUID_TAIL {
+            return Err(invalid_data("unsupported WAVE_FORMAT_EXTENSIBLE subformat"));
+        }
+        (u16_at(24), u32_at(20))
+    } else {
+        (u16_at(0), default_mask(channels))
+    };
+    let format = match (tag, bits) {
+        (WAVE_FORMAT_PCM, 16) => SampleFormat::S16LE,
+        (WAVE_FORMAT_IEEE_FLOAT, 32) => SampleFormat::Float32LE,
+        _ => return Err(invalid_data("unsupported sample format")),
+    };
+    if channels == 0 || rate == 0 {
+        return Err(invalid_data("invalid fmt chunk"));
+    }
+    Ok(WavSpec {
+        format,
+        rate,
+        channels,
+        layout: channel_layout(mask)
+    })
+}
+
+fn io_error(e: io::Error) -> Error {
+    let error = match e.kind() {
+        io::ErrorKind::NotFound | io::ErrorKind::PermissionDenied => {
+            Error::from(ErrorCode::DeviceUnavailable)
+        },
+        io::ErrorKind::InvalidData | io::ErrorKind::UnexpectedEof => {
+            Error::from(ErrorCode::InvalidFormat)
+        },
+        _ => Error::new(),
+    };
+    error.with_source(e)
+}
+
+/// WAV backend configuration.
+#[derive(Clone, Debug)]
+pub struct Config {
+    /// Files read by input streams. The first is the default device.
+    pub input_files: Vec<PathBuf>,
+    /// Files written by output streams. The first is the default device.
+    pub output_files: Vec<PathBuf>,
+    pub max_channels: u32,
+    pub preferred_rate: u32,
+    pub min_latency: u32,
+    pub pacing: Pacing
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            input_files: Vec::new(),
+            output_files: Vec::new(),
+            max_channels: 8,
+            preferred_rate: 48_000,
+            min_latency: 256,
+            pacing: Pacing::Unpaced
+        }
+    }
+}
+
+impl Config {
+    /// The default configuration, with the files listed in the
+    /// `CUBEB_WAV_INPUT` and `CUBEB_WAV_OUTPUT` environment variables,
+    /// separated like `PATH`. This is what `cubeb_init` uses.
+    pub fn from_env() -> Config {
+        let files = |var| {
+            env::var_os(var)
+                .map(|paths| env::split_paths(&paths).collect())
+                .unwrap_or_default()
+        };
+        Config {
+            input_files: files("CUBEB_WAV_INPUT"),
+            output_files: files("CUBEB_WAV_OUTPUT"),
+            ..Default::default()
+        }
+    }
+}
+
+struct DeviceEntry {
+    path: PathBuf,
+    devtype: DeviceType,
+    devid: ffi::cubeb_devid,
+    device_id: CString,
+    friendly_name: CString
+}
+
+#[repr(C)]
+pub struct WavContext {
+    ops: *const Ops,
+    config: Config,
+    devices: Vec<DeviceEntry>
+}
+
+impl WavContext {
+    /// Create a WAV backend context using `config`.
+    pub fn init_with_config(config: Config) -> Result<*mut ffi::cubeb> {
+        let entries = |files: &[PathBuf], devtype: DeviceType| -> Result<Vec<DeviceEntry>> {
+            files
+                .iter()
+                .enumerate()
+                .map(|(i, path)| {
+                    let name = path.file_name().unwrap_or(path.as_os_str());
+                    Ok(DeviceEntry {
+                        path: path.clone(),
+                        devtype,
+                        devid: util::devid(i, devtype),
+                        device_id: CString::new(path.to_string_lossy().into_owned())?,
+                        friendly_name: CString::new(name.to_string_lossy().into_owned())?
+                    })
+                })
+                .collect()
+        };
+        let mut devices = entries(&config.input_files, DEVICE_TYPE_INPUT)?;
+        devices.extend(entries(&config.output_files, DEVICE_TYPE_OUTPUT)?);
+
+        let ctx = Box::new(WavContext {
+            ops: &OPS as *const _,
+            config,
+            devices
+        });
+        Ok(Box::into_raw(ctx) as *mut _)
+    }
+
+    // Resolve a requested device, where null selects the default.
+    fn find_device(&self, devid: DeviceId, devtype: DeviceType) -> Result<&DeviceEntry> {
+        let mut devices = self.devices.iter().filter(|d| d.devtype == devtype);
+        let found = if devid.raw().is_null() {
+            devices.next()
+        } else {
+            devices.find(|d| d.devid == devid.raw())
+        };
+        found.ok_or_else(|| Error::from(ErrorCode::DeviceUnavailable))
+    }
+
+    fn check_params(&self, params: &ffi::cubeb_stream_params) -> Result<StreamParams> {
+        let valid_format = matches!(
+            params.format,
+            ffi::CUBEB_SAMPLE_S16LE |
+                ffi::CUBEB_SAMPLE_S16BE |
+                ffi::CUBEB_SAMPLE_FLOAT32LE |
+                ffi::CUBEB_SAMPLE_FLOAT32BE
+        );
+        if !valid_format || params.channels == 0 || params.channels > self.config.max_channels ||
+            params.rate < MIN_RATE || params.rate > MAX_RATE
+        {
+            return Err(Error::from(ErrorCode::InvalidFormat));
+        }
+        Ok(unsafe { StreamParams::from_raw(params as *const _) })
+    }
+
+    fn device_info(&self, d: &DeviceEntry) -> ffi::cubeb_device_info {
+        let first = self.devices
+            .iter()
+            .find(|e| e.devtype == d.devtype)
+            .is_some_and(|e| e.devid == d.devid);
+        let mut info = ffi::cubeb_device_info {
+            devid: d.devid,
+            device_id: d.device_id.as_ptr(),
+            friendly_name: d.friendly_name.as_ptr(),
+            group_id: d.device_id.as_ptr(),
+            vendor_name: ptr::null(),
+            device_type: d.devtype.bits(),
+            state: ffi::CUBEB_DEVICE_STATE_ENABLED,
+            preferred: if first {
+                ffi::CUBEB_DEVICE_PREF_ALL
+            } else {
+                ffi::CUBEB_DEVICE_PREF_NONE
+            },
+            format: ffi::CUBEB_DEVICE_FMT_ALL,
+            default_format: ffi::CUBEB_DEVICE_FMT_F32LE,
+            max_channels: self.config.max_channels,
+            default_rate: self.config.preferred_rate,
+            max_rate: MAX_RATE,
+            min_rate: MIN_RATE,
+            latency_lo: self.config.min_latency,
+            latency_hi: MAX_LATENCY
+        };
+        // Input devices only offer what the file contains.
+        if d.devtype == DEVICE_TYPE_INPUT {
+            match WavReader::open(&d.path) {
+                Ok(reader) => {
+                    let spec = reader.spec();
+                    info.default_format = match spec.format {
+                        SampleFormat::S16LE => ffi::CUBEB_DEVICE_FMT_S16LE,
+                        _ => ffi::CUBEB_DEVICE_FMT_F32LE,
+                    };
+                    info.max_channels = spec.channels;
+                    info.default_rate = spec.rate;
+                    info.min_rate = spec.rate;
+                    info.max_rate = spec.rate;
+                },
+                Err(_) => {
+                    info.state = ffi::CUBEB_DEVICE_STATE_UNPLUGGED;
+                    info.max_channels = 0;
+                },
+            }
+        }
+        info
+    }
+}
+
+impl Context for WavContext {
+    fn init(_context_name: Option<&CStr>) -> Result<*mut ffi::cubeb> {
+        WavContext::init_with_config(Config::from_env())
+    }
+
+    fn backend_id(&self) -> &'static CStr {
+        unsafe { CStr::from_ptr(b"wav\0".as_ptr() as *const _) }
+    }
+
+    fn max_channel_count(&self) -> Result<u32> {
+        Ok(self.config.max_channels)
+    }
+
+    fn min_latency(&self, _params: &StreamParams) -> Result<u32> {
+        Ok(self.config.min_latency)
+    }
+
+    fn preferred_sample_rate(&self) -> Result<u32> {
+        Ok(self.config.preferred_rate)
+    }
+
+    fn preferred_channel_layout(&self) -> Result<ffi::cubeb_channel_layout> {
+        Ok(ChannelLayout::Stereo as _)
+    }
+
+    fn enumerate_devices(
+        &self,
+        devtype: DeviceType,
+    ) -> Result<ffi::cubeb_device_collection> {
+        let infos = self.devices
+            .iter()
+            .filter(|d| devtype.contains(d.devtype))
+            .map(|d| self.device_info(d))
+            .collect();
+        Ok(util::device_collection(infos))
+    }
+
+    #[allow(clippy::not_unsafe_ptr_arg_deref)]
+    fn device_collection_destroy(
+        &self,
+        collection: *mut ffi::cubeb_device_collection,
+    ) {
+        unsafe { util::destroy_device_collection(collection) }
+    }
+
+    fn stream_init(
+        &self,
+        _stream_name: Option<&CStr>,
+        input_device: DeviceId,
+        input_stream_params: Option<&ffi::cubeb_stream_params>,
+        output_device: DeviceId,
+        output_stream_params: Option<&ffi::cubeb_stream_params>,
+        latency_frames: u32,
+        data_callback: ffi::cubeb_data_callback,
+        state_callback: ffi::cubeb_state_callback,
+        user_ptr: *mut c_void,
+    ) -> Result<*mut ffi::cubeb_stream> {
+        let mut builder = CallbackDriverBuilder::new();
+        let latency = latency_frames.max(self.config.min_latency).min(MAX_LATENCY);
+        let mut input_name = None;
+        let mut output_name = None;
+
+        if let Some(params) = input_stream_params {
+            let device = self.find_device(input_device, DEVICE_TYPE_INPUT)?;
+            let params = self.check_params(params)?;
+            let reader = WavReader::open(&device.path).map_err(io_error)?;
+            let spec = reader.spec();
+            if spec.rate != params.rate() || spec.channels != params.channels() {
+                return Err(Error::from(ErrorCode::InvalidFormat));
+            }
+            builder.input(&params, reader);
+            input_name = Some(device.friendly_name.clone());
+        }
+        if let Some(params) = output_stream_params {
+            let device = self.find_device(output_device, DEVICE_TYPE_OUTPUT)?;
+            let params = self.check_params(params)?;
+            let writer = WavWriter::create(&device.path, WavSpec::from_params(&params))
+                .map_err(io_error)?;
+            builder.output(&params, writer);
+            output_name = Some(device.friendly_name.clone());
+        }
+
+        let driver = builder
+            .period(latency)
+            .pacing(self.config.pacing)
+            .build(data_callback, state_callback, user_ptr)?;
+        Ok(DriverStream::new(self, driver, latency, input_name, output_name).into_raw())
+    }
+
+    fn register_device_collection_changed(
+        &self,
+        _devtype: DeviceType,
+        _cb: Option<ffi::cubeb_device_collection_changed_callback>,
+        _user_ptr: *mut c_void,
+    ) -> Result<()> {
+        // The file list is fixed for the life of the context.
+        Ok(())
+    }
+}
@@ -0,0 +1,183 @@
+// Copyright © 2017 Mozilla Foundation
+//
+// This program is made available under an ISC-style license.  See the
+// accompanying file LICENSE for details
+
+// Fixtures shared by the backend tests. Each test binary uses only some
+// of them.
+#![allow(dead_code)]
+
+use cubeb_backend::Ops;
+use cubeb_core::ffi;
+use std::os::raw::{c_long, c_void};
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use std::{ptr, slice, thread};
+
+pub fn temp_path(name: &str) -> PathBuf {
+    let mut path = std::env::temp_dir();
+    path.push(format!("cubeb-test-{}-{}", std::process::id(), name));
+    path
+}
+
+// Plays `output` once, and records whatever the input produces. The
+// stream drains after `limit` frames, if set; past the end of `output`
+// it plays silence.
+#[derive(Default)]
+pub struct User {
+    // Bytes in a frame of `output` and `input`.
+    pub frame_size: usize,
+    pub output: Vec<u8>,
+    pub limit: Option<u64>,
+    pub frames: Mutex<u64>,
+    // The frames asked for by each data callback.
+    pub callbacks: Mutex<Vec<c_long>>,
+    pub input: Mutex<Vec<u8>>,
+    pub states: Mutex<Vec<ffi::cubeb_state>>,
+    pub device_changes: AtomicUsize,
+    pub collection_changes: AtomicUsize
+}
+
+impl User {
+    // Plays all of `output`, then drains.
+    pub fn playing(frame_size: usize, output: Vec<u8>) -> User {
+        User {
+            frame_size,
+            limit: Some((output.len() / frame_size) as u64),
+            output,
+            ..Default::default()
+        }
+    }
+
+    // Records input until the stream stops or drains by itself.
+    pub fn recording(frame_size: usize) -> User {
+        User {
+            frame_size,
+            ..Default::default()
+        }
+    }
+
+    pub fn wait_for_state(&self, state: ffi::cubeb_state) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !self.states.lock().unwrap().contains(&state) {
+            assert!(Instant::now() < deadline, "stream never reached state {}", state);
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+}
+
+pub extern "C" fn data_cb(
+    _: *mut ffi::cubeb_stream,
+    user_ptr: *mut c_void,
+    input_buffer: *const c_void,
+    output_buffer: *mut c_void,
+    nframes: c_long,
+) -> c_long {
+    let user = unsafe { &*(user_ptr as *const User) };
+    user.callbacks.lock().unwrap().push(nframes);
+    if !input_buffer.is_null() {
+        let len = nframes as usize * user.frame_size;
+        let input = unsafe { slice::from_raw_parts(input_buffer as *const u8, len) };
+        user.input.lock().unwrap().extend_from_slice(input);
+    }
+    let mut frames = user.frames.lock().unwrap();
+    let n = match user.limit {
+        Some(limit) => (limit - *frames).min(nframes as u64),
+        None => nframes as u64,
+    };
+    if !output_buffer.is_null() {
+        let len = n as usize * user.frame_size;
+        let output = unsafe { slice::from_raw_parts_mut(output_buffer as *mut u8, len) };
+        let played = (*frames as usize * user.frame_size).min(user.output.len());
+        let rest = &user.output[played..];
+        let m = len.min(rest.len());
+        output[..m].copy_from_slice(&rest[..m]);
+        for x in &mut output[m..] {
+            *x = 0;
+        }
+    }
+    *frames += n;
+    n as c_long
+}
+
+pub extern "C" fn state_cb(
+    _: *mut ffi::cubeb_stream,
+    user_ptr: *mut c_void,
+    state: ffi::cubeb_state,
+) {
+    let user = unsafe { &*(user_ptr as *const User) };
+    user.states.lock().unwrap().push(state);
+}
+
+pub extern "C" fn device_changed_cb(user_ptr: *mut c_void) {
+    let user = unsafe { &*(user_ptr as *const User) };
+    user.device_changes.fetch_add(1, Ordering::SeqCst);
+}
+
+pub extern "C" fn collection_changed_cb(_: *mut ffi::cubeb, user_ptr: *mut c_void) {
+    let user = unsafe { &*(user_ptr as *const User) };
+    user.collection_changes.fetch_add(1, Ordering::SeqCst);
+}
+
+pub fn params(
+    format: ffi::cubeb_sample_format,
+    rate: u32,
+    channels: u32,
+    layout: ffi::cubeb_channel_layout,
+) -> ffi::cubeb_stream_params {
+    ffi::cubeb_stream_params {
+        format,
+        rate,
+        channels,
+        layout
+    }
+}
+
+// Opens a stream on the default devices, calling back into `user`.
+pub fn stream_init(
+    ops: &Ops,
+    c: *mut ffi::cubeb,
+    input: Option<&ffi::cubeb_stream_params>,
+    output: Option<&ffi::cubeb_stream_params>,
+    latency: u32,
+    user: &User,
+) -> Result<*mut ffi::cubeb_stream, i32> {
+    stream_init_on(
+        ops,
+        c,
+        input.map(|p| (ptr::null(), p)),
+        output.map(|p| (ptr::null(), p)),
+        latency,
+        user
+    )
+}
+
+// Opens a stream on the given devices, calling back into `user`.
+pub fn stream_init_on(
+    ops: &Ops,
+    c: *mut ffi::cubeb,
+    input: Option<(ffi::cubeb_devid, &ffi::cubeb_stream_params)>,
+    output: Option<(ffi::cubeb_devid, &ffi::cubeb_stream_params)>,
+    latency: u32,
+    user: &User,
+) -> Result<*mut ffi::cubeb_stream, i32> {
+    let mut s: *mut ffi::cubeb_stream = ptr::null_mut();
+    let r = unsafe {
+        ops.stream_init.unwrap()(
+            c,
+            &mut s,
+            ptr::null(),
+            input.map_or(ptr::null(), |(id, _)| id),
+            input.map_or(ptr::null(), |(_, p)| p as *const _),
+            output.map_or(ptr::null(), |(id, _)| id),
+            output.map_or(ptr::null(), |(_, p)| p as *const _),
+            latency,
+            data_cb,
+            state_cb,
+            user as *const _ as *mut _
+        )
+    };
+    if r == ffi::CUBEB_OK { Ok(s) } else { Err(r) }
+}
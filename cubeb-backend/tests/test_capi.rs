@@ -80,7 +80,7 @@ impl Context for TestContext {
     fn register_device_collection_changed(
         &self,
         _dev_type: DeviceType,
-        _collection_changed_callback: ffi::cubeb_device_collection_changed_callback,
+        _collection_changed_callback: Option<ffi::cubeb_device_collection_changed_callback>,
         _user_ptr: *mut c_void,
     ) -> Result<()> {
         Ok(())
@@ -122,7 +122,7 @@ impl Stream for TestStream {
     }
     fn register_device_changed_callback(
         &self,
-        _: ffi::cubeb_device_changed_callback,
+        _: Option<ffi::cubeb_device_changed_callback>,
     ) -> Result<()> {
         Ok(())
     }
@@ -268,3 +268,13 @@ fn test_ops_stream_device_destroy() {
         OPS.stream_device_destroy.unwrap()(s, 0xDEAD_BEEF as *const _);
     }
 }
+
+#[test]
+fn test_ops_stream_register_device_changed_callback() {
+    extern "C" fn device_changed(_: *mut c_void) {}
+    let mut stm = TestStream {};
+    let s = &mut stm as *mut TestStream as *mut ffi::cubeb_stream;
+    let register = OPS.stream_register_device_changed_callback.unwrap();
+    assert_eq!(unsafe { register(s, Some(device_changed)) }, ffi::CUBEB_OK);
+    assert_eq!(unsafe { register(s, None) }, ffi::CUBEB_OK);
+}
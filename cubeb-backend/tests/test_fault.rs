@@ -0,0 +1,213 @@
+// Copyright © 2017 Mozilla Foundation
+//
+// This program is made available under an ISC-style license.  See the
+// accompanying file LICENSE for details
+
+extern crate cubeb_backend;
+extern crate cubeb_core;
+
+mod common;
+
+use common::{collection_changed_cb, device_changed_cb, User};
+use cubeb_backend::fault::{FaultContext, FaultHandle, PositionFault, OPS};
+use cubeb_backend::null::{self, NullContext};
+use cubeb_backend::raw::{RawContext, RawStream};
+use cubeb_core::{ErrorCode, DEVICE_TYPE_OUTPUT};
+use cubeb_core::ffi;
+use std::ptr;
+use std::sync::atomic::Ordering;
+use std::thread;
+use std::time::{Duration, Instant};
+
+fn init(handle: &FaultHandle) -> *mut ffi::cubeb {
+    let inner = NullContext::init_with_config(null::Config::default()).unwrap();
+    FaultContext::wrap(unsafe { RawContext::from_ptr(inner) }, handle)
+}
+
+fn stream_init(c: *mut ffi::cubeb, user: &User) -> Result<*mut ffi::cubeb_stream, i32> {
+    let params = common::params(ffi::CUBEB_SAMPLE_FLOAT32NE, 48000, 2, ffi::CUBEB_LAYOUT_STEREO);
+    common::stream_init(&OPS, c, None, Some(&params), 256, user)
+}
+
+#[test]
+fn test_fault_stream_init() {
+    let handle = FaultHandle::new();
+    let c = init(&handle);
+    let user = User::default();
+    handle.fail_stream_init(2, ErrorCode::DeviceUnavailable);
+    let s1 = stream_init(c, &user).unwrap();
+    assert_eq!(stream_init(c, &user), Err(ffi::CUBEB_ERROR_DEVICE_UNAVAILABLE));
+    let s2 = stream_init(c, &user).unwrap();
+    assert_eq!(handle.stream_init_calls(), 3);
+    unsafe {
+        OPS.stream_destroy.unwrap()(s1);
+        OPS.stream_destroy.unwrap()(s2);
+        OPS.destroy.unwrap()(c);
+    }
+}
+
+#[test]
+fn test_fault_start_stop() {
+    let handle = FaultHandle::new();
+    let c = init(&handle);
+    let user = User::default();
+    let s = stream_init(c, &user).unwrap();
+    unsafe {
+        handle.fail_next_start(ErrorCode::DeviceUnavailable);
+        assert_eq!(OPS.stream_start.unwrap()(s), ffi::CUBEB_ERROR_DEVICE_UNAVAILABLE);
+        assert!(user.states.lock().unwrap().is_empty());
+        assert_eq!(OPS.stream_start.unwrap()(s), ffi::CUBEB_OK);
+
+        handle.fail_next_stop(ErrorCode::Error);
+        assert_eq!(OPS.stream_stop.unwrap()(s), ffi::CUBEB_ERROR);
+        assert_eq!(OPS.stream_stop.unwrap()(s), ffi::CUBEB_OK);
+        OPS.stream_destroy.unwrap()(s);
+        OPS.destroy.unwrap()(c);
+    }
+    assert_eq!(
+        *user.states.lock().unwrap(),
+        vec![ffi::CUBEB_STATE_STARTED, ffi::CUBEB_STATE_STOPPED]
+    );
+}
+
+#[test]
+fn test_fault_stream_error() {
+    let handle = FaultHandle::new();
+    let c = init(&handle);
+    let user = User::default();
+    let s = stream_init(c, &user).unwrap();
+    unsafe {
+        assert_eq!(OPS.stream_start.unwrap()(s), ffi::CUBEB_OK);
+        handle.inject_stream_error();
+        user.wait_for_state(ffi::CUBEB_STATE_ERROR);
+        OPS.stream_destroy.unwrap()(s);
+        OPS.destroy.unwrap()(c);
+    }
+}
+
+#[test]
+fn test_fault_dropped_callbacks() {
+    let handle = FaultHandle::new();
+    let c = init(&handle);
+    let user = User::default();
+    let s = stream_init(c, &user).unwrap();
+    unsafe {
+        handle.stall_next_callback(Duration::from_millis(20));
+        handle.drop_callbacks(Duration::from_millis(50));
+        assert_eq!(OPS.stream_start.unwrap()(s), ffi::CUBEB_OK);
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while user.callbacks.lock().unwrap().is_empty() {
+            assert!(Instant::now() < deadline);
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(OPS.stream_stop.unwrap()(s), ffi::CUBEB_OK);
+
+        // The stream kept running, without the application hearing of it.
+        assert!(handle.dropped_callbacks() > 0);
+        let mut position = 0u64;
+        assert_eq!(OPS.stream_get_position.unwrap()(s, &mut position), ffi::CUBEB_OK);
+        assert_eq!(position, *user.frames.lock().unwrap() + handle.dropped_callbacks() as u64 * 256);
+        OPS.stream_destroy.unwrap()(s);
+        OPS.destroy.unwrap()(c);
+    }
+}
+
+#[test]
+fn test_fault_notifications() {
+    let handle = FaultHandle::new();
+    let c = init(&handle);
+    let user = User::default();
+    let s = stream_init(c, &user).unwrap();
+    unsafe {
+        handle.fire_device_changed();
+        handle.fire_collection_changed();
+        assert_eq!(user.device_changes.load(Ordering::SeqCst), 0);
+        assert_eq!(user.collection_changes.load(Ordering::SeqCst), 0);
+
+        assert_eq!(
+            OPS.stream_register_device_changed_callback.unwrap()(s, Some(device_changed_cb)),
+            ffi::CUBEB_OK
+        );
+        assert_eq!(
+            OPS.register_device_collection_changed.unwrap()(
+                c,
+                DEVICE_TYPE_OUTPUT.bits(),
+                Some(collection_changed_cb),
+                &user as *const _ as *mut _
+            ),
+            ffi::CUBEB_OK
+        );
+        handle.fire_device_changed();
+        handle.fire_collection_changed();
+        handle.fire_collection_changed();
+        assert_eq!(user.device_changes.load(Ordering::SeqCst), 1);
+        assert_eq!(user.collection_changes.load(Ordering::SeqCst), 2);
+
+        // Unregistering clears the callbacks instead of storing null ones.
+        assert_eq!(
+            OPS.stream_register_device_changed_callback.unwrap()(s, None),
+            ffi::CUBEB_OK
+        );
+        assert_eq!(
+            OPS.register_device_collection_changed.unwrap()(
+                c,
+                DEVICE_TYPE_OUTPUT.bits(),
+                None,
+                ptr::null_mut()
+            ),
+            ffi::CUBEB_OK
+        );
+        handle.fire_device_changed();
+        handle.fire_collection_changed();
+        assert_eq!(user.device_changes.load(Ordering::SeqCst), 1);
+        assert_eq!(user.collection_changes.load(Ordering::SeqCst), 2);
+
+        OPS.stream_destroy.unwrap()(s);
+        handle.fire_device_changed();
+        assert_eq!(user.device_changes.load(Ordering::SeqCst), 1);
+        OPS.destroy.unwrap()(c);
+    }
+    handle.fire_collection_changed();
+    assert_eq!(user.collection_changes.load(Ordering::SeqCst), 2);
+}
+
+#[test]
+fn test_fault_position() {
+    let handle = FaultHandle::new();
+    let c = init(&handle);
+    let user = User::default();
+    let s = stream_init(c, &user).unwrap();
+    let position = || {
+        let mut position = 0u64;
+        let r = unsafe { OPS.stream_get_position.unwrap()(s, &mut position) };
+        (r, position)
+    };
+    assert_eq!(position(), (ffi::CUBEB_OK, 0));
+    handle.corrupt_position(Some(PositionFault::Offset(1000)));
+    assert_eq!(position(), (ffi::CUBEB_OK, 1000));
+    handle.corrupt_position(Some(PositionFault::Offset(-1000)));
+    assert_eq!(position(), (ffi::CUBEB_OK, 0));
+    handle.corrupt_position(Some(PositionFault::Fixed(42)));
+    assert_eq!(position(), (ffi::CUBEB_OK, 42));
+    handle.corrupt_position(Some(PositionFault::Fail(ErrorCode::Error)));
+    assert_eq!(position().0, ffi::CUBEB_ERROR);
+    handle.corrupt_position(None);
+    assert_eq!(position(), (ffi::CUBEB_OK, 0));
+    unsafe {
+        OPS.stream_destroy.unwrap()(s);
+        OPS.destroy.unwrap()(c);
+    }
+}
+
+#[test]
+fn test_fault_error_names_operation() {
+    let handle = FaultHandle::new();
+    let c = unsafe { RawContext::from_ptr(init(&handle)) };
+    let user = User::default();
+    let s = unsafe { RawStream::from_ptr(stream_init(c.as_ptr(), &user).unwrap()) };
+    handle.corrupt_position(Some(PositionFault::Fail(ErrorCode::DeviceUnavailable)));
+    let e = s.position().unwrap_err();
+    assert_eq!(e.code(), ErrorCode::DeviceUnavailable);
+    assert_eq!(e.operation(), Some("stream_get_position"));
+    assert_eq!(e.to_string(), "stream_get_position: Device unavailable");
+}
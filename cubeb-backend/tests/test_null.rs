@@ -0,0 +1,163 @@
+// Copyright © 2017 Mozilla Foundation
+//
+// This program is made available under an ISC-style license.  See the
+// accompanying file LICENSE for details
+
+extern crate cubeb_backend;
+extern crate cubeb_core;
+
+mod common;
+
+use common::{collection_changed_cb, stream_init, stream_init_on, User};
+use cubeb_backend::null::{Config, NullContext, NullDevice, OPS};
+use cubeb_core::ffi;
+use std::ffi::CStr;
+use std::ptr;
+use std::thread;
+use std::time::{Duration, Instant};
+
+fn params(channels: u32) -> ffi::cubeb_stream_params {
+    common::params(ffi::CUBEB_SAMPLE_FLOAT32NE, 48000, channels, ffi::CUBEB_LAYOUT_UNDEFINED)
+}
+
+fn init() -> *mut ffi::cubeb {
+    let mut c: *mut ffi::cubeb = ptr::null_mut();
+    assert_eq!(unsafe { OPS.init.unwrap()(&mut c, ptr::null()) }, ffi::CUBEB_OK);
+    c
+}
+
+#[test]
+fn test_null_context() {
+    let c = init();
+    unsafe {
+        let id = CStr::from_ptr(OPS.get_backend_id.unwrap()(c));
+        assert_eq!(id.to_str().unwrap(), "null");
+
+        let mut value = 0u32;
+        assert_eq!(OPS.get_max_channel_count.unwrap()(c, &mut value), ffi::CUBEB_OK);
+        assert_eq!(value, 2);
+        assert_eq!(OPS.get_preferred_sample_rate.unwrap()(c, &mut value), ffi::CUBEB_OK);
+        assert_eq!(value, 48000);
+        assert_eq!(OPS.get_min_latency.unwrap()(c, params(2), &mut value), ffi::CUBEB_OK);
+        assert_eq!(value, 256);
+
+        // The fake devices never change.
+        let user = User::default();
+        assert_eq!(
+            OPS.register_device_collection_changed.unwrap()(
+                c,
+                ffi::CUBEB_DEVICE_TYPE_OUTPUT,
+                Some(collection_changed_cb),
+                &user as *const _ as *mut _
+            ),
+            ffi::CUBEB_ERROR_NOT_SUPPORTED
+        );
+
+        OPS.destroy.unwrap()(c);
+    }
+}
+
+#[test]
+fn test_null_enumerate_devices() {
+    let mut config = Config::default();
+    config.output_devices.push(NullDevice::new("Null Surround", 6));
+    let c = NullContext::init_with_config(config).unwrap();
+    let mut coll = ffi::cubeb_device_collection {
+        device: ptr::null(),
+        count: 0
+    };
+    unsafe {
+        let all = ffi::CUBEB_DEVICE_TYPE_INPUT | ffi::CUBEB_DEVICE_TYPE_OUTPUT;
+        assert_eq!(OPS.enumerate_devices.unwrap()(c, all, &mut coll), ffi::CUBEB_OK);
+        assert_eq!(coll.count, 3);
+        assert_eq!(OPS.device_collection_destroy.unwrap()(c, &mut coll), ffi::CUBEB_OK);
+
+        assert_eq!(
+            OPS.enumerate_devices.unwrap()(c, ffi::CUBEB_DEVICE_TYPE_OUTPUT, &mut coll),
+            ffi::CUBEB_OK
+        );
+        let infos = ::std::slice::from_raw_parts(coll.device, coll.count);
+        let names: Vec<_> = infos
+            .iter()
+            .map(|i| CStr::from_ptr(i.friendly_name).to_str().unwrap())
+            .collect();
+        assert_eq!(names, ["Null Output", "Null Surround"]);
+        assert_eq!(infos[1].max_channels, 6);
+        assert_eq!(infos[0].preferred, ffi::CUBEB_DEVICE_PREF_ALL);
+        assert_eq!(infos[1].preferred, ffi::CUBEB_DEVICE_PREF_NONE);
+
+        // Open a stream on the second device by id.
+        let user = User::default();
+        assert_eq!(
+            stream_init_on(&OPS, c, None, Some((infos[1].devid, &params(6))), 512, &user),
+            Err(ffi::CUBEB_ERROR_INVALID_FORMAT)
+        );
+        let output = Some((infos[1].devid, &params(2)));
+        let s = stream_init_on(&OPS, c, None, output, 512, &user).unwrap();
+        let mut device: *const ffi::cubeb_device = ptr::null();
+        assert_eq!(OPS.stream_get_current_device.unwrap()(s, &mut device), ffi::CUBEB_OK);
+        assert_eq!(CStr::from_ptr((*device).output_name).to_str().unwrap(), "Null Surround");
+        assert!((*device).input_name.is_null());
+        assert_eq!(OPS.stream_device_destroy.unwrap()(s, device), ffi::CUBEB_OK);
+        OPS.stream_destroy.unwrap()(s);
+
+        assert_eq!(
+            stream_init_on(&OPS, c, None, Some((0x1000 as _, &params(2))), 512, &user),
+            Err(ffi::CUBEB_ERROR_DEVICE_UNAVAILABLE)
+        );
+
+        OPS.device_collection_destroy.unwrap()(c, &mut coll);
+        OPS.destroy.unwrap()(c);
+    }
+}
+
+#[test]
+fn test_null_stream_real_time() {
+    let c = init();
+    let user = User::default();
+    let s = stream_init(&OPS, c, None, Some(&params(2)), 480, &user).unwrap();
+    unsafe {
+        let mut latency = 0u32;
+        assert_eq!(OPS.stream_get_latency.unwrap()(s, &mut latency), ffi::CUBEB_OK);
+        assert_eq!(latency, 480);
+
+        let started = Instant::now();
+        assert_eq!(OPS.stream_start.unwrap()(s), ffi::CUBEB_OK);
+        thread::sleep(Duration::from_millis(200));
+        assert_eq!(OPS.stream_stop.unwrap()(s), ffi::CUBEB_OK);
+        let elapsed = started.elapsed();
+
+        let mut position = 0u64;
+        assert_eq!(OPS.stream_get_position.unwrap()(s, &mut position), ffi::CUBEB_OK);
+        assert_eq!(position, *user.frames.lock().unwrap());
+        // Never ahead of the wall clock by more than one period.
+        let max = (elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1e9) * 48000.0;
+        assert!(position > 0 && position as f64 <= max + 480.0);
+
+        OPS.stream_destroy.unwrap()(s);
+        OPS.destroy.unwrap()(c);
+    }
+    assert_eq!(
+        *user.states.lock().unwrap(),
+        vec![ffi::CUBEB_STATE_STARTED, ffi::CUBEB_STATE_STOPPED]
+    );
+}
+
+#[test]
+fn test_null_stream_drain() {
+    let c = init();
+    let user = User {
+        limit: Some(1000),
+        ..Default::default()
+    };
+    let s = stream_init(&OPS, c, None, Some(&params(1)), 256, &user).unwrap();
+    unsafe {
+        assert_eq!(OPS.stream_start.unwrap()(s), ffi::CUBEB_OK);
+        user.wait_for_state(ffi::CUBEB_STATE_DRAINED);
+        let mut position = 0u64;
+        OPS.stream_get_position.unwrap()(s, &mut position);
+        assert_eq!(position, 1000);
+        OPS.stream_destroy.unwrap()(s);
+        OPS.destroy.unwrap()(c);
+    }
+}
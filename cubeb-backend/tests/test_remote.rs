@@ -0,0 +1,324 @@
+// Copyright © 2017 Mozilla Foundation
+//
+// This program is made available under an ISC-style license.  See the
+// accompanying file LICENSE for details
+
+#![cfg(unix)]
+
+extern crate cubeb_backend;
+extern crate cubeb_core;
+
+mod common;
+
+use common::{stream_init_on, temp_path, User};
+use cubeb_backend::{null, wav, Pacing};
+use cubeb_backend::raw::RawContext;
+use cubeb_backend::remote::{ClientContext, Server, OPS};
+use cubeb_backend::remote::protocol::{self, DeviceInfo, Request, Response, StreamDirection,
+                                      PROTOCOL_VERSION};
+use cubeb_core::{ChannelLayout, Result, SampleFormat};
+use cubeb_core::ffi;
+use std::ffi::CStr;
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::time::Duration;
+use std::{fs, ptr, slice, thread};
+
+// Start a server whose clients get contexts from `init`.
+fn spawn_server<F>(name: &str, init: F) -> PathBuf
+where
+    F: Fn(Option<&CStr>) -> Result<RawContext> + Send + Sync + 'static,
+{
+    let path = temp_path(name);
+    let _ = fs::remove_file(&path);
+    let server = Server::bind(&path, init).unwrap();
+    thread::spawn(move || server.run());
+    path
+}
+
+fn connect(path: &PathBuf) -> *mut ffi::cubeb {
+    ClientContext::connect(path, None).unwrap()
+}
+
+fn params(format: ffi::cubeb_sample_format, rate: u32, channels: u32) -> ffi::cubeb_stream_params {
+    common::params(format, rate, channels, ffi::CUBEB_LAYOUT_UNDEFINED)
+}
+
+fn stream_init(
+    c: *mut ffi::cubeb,
+    input: Option<&ffi::cubeb_stream_params>,
+    output: Option<&ffi::cubeb_stream_params>,
+    latency: u32,
+    user: &User,
+) -> ::std::result::Result<*mut ffi::cubeb_stream, i32> {
+    common::stream_init(&OPS, c, input, output, latency, user)
+}
+
+fn null_server(name: &str) -> PathBuf {
+    spawn_server(name, |name| RawContext::init(&null::OPS, name))
+}
+
+fn wav_server(name: &str, config: wav::Config) -> PathBuf {
+    spawn_server(name, move |_| {
+        let c = wav::WavContext::init_with_config(config.clone())?;
+        Ok(unsafe { RawContext::from_ptr(c) })
+    })
+}
+
+#[test]
+fn test_remote_context() {
+    let path = null_server("context");
+    let c = connect(&path);
+    let mut coll = ffi::cubeb_device_collection {
+        device: ptr::null(),
+        count: 0
+    };
+    unsafe {
+        let id = CStr::from_ptr(OPS.get_backend_id.unwrap()(c));
+        assert_eq!(id.to_str().unwrap(), "remote");
+
+        let mut value = 0u32;
+        assert_eq!(OPS.get_max_channel_count.unwrap()(c, &mut value), ffi::CUBEB_OK);
+        assert_eq!(value, 2);
+        assert_eq!(OPS.get_preferred_sample_rate.unwrap()(c, &mut value), ffi::CUBEB_OK);
+        assert_eq!(value, 48000);
+        let p = params(ffi::CUBEB_SAMPLE_FLOAT32NE, 48000, 2);
+        assert_eq!(OPS.get_min_latency.unwrap()(c, p, &mut value), ffi::CUBEB_OK);
+        assert_eq!(value, 256);
+        let mut layout = ffi::CUBEB_LAYOUT_UNDEFINED;
+        assert_eq!(OPS.get_preferred_channel_layout.unwrap()(c, &mut layout), ffi::CUBEB_OK);
+        assert_eq!(layout, ffi::CUBEB_LAYOUT_STEREO);
+
+        let all = ffi::CUBEB_DEVICE_TYPE_INPUT | ffi::CUBEB_DEVICE_TYPE_OUTPUT;
+        assert_eq!(OPS.enumerate_devices.unwrap()(c, all, &mut coll), ffi::CUBEB_OK);
+        let infos = slice::from_raw_parts(coll.device, coll.count);
+        let names: Vec<_> = infos
+            .iter()
+            .map(|i| CStr::from_ptr(i.friendly_name).to_str().unwrap())
+            .collect();
+        assert_eq!(names, ["Null Input", "Null Output"]);
+        assert!(CStr::from_ptr(infos[1].device_id).to_str().unwrap().starts_with("null:"));
+        assert!(infos[1].vendor_name.is_null());
+
+        // Server device ids are valid on the client.
+        let user = User::default();
+        let s = stream_init_on(&OPS, c, None, Some((infos[1].devid, &p)), 512, &user).unwrap();
+        let mut device: *const ffi::cubeb_device = ptr::null();
+        assert_eq!(OPS.stream_get_current_device.unwrap()(s, &mut device), ffi::CUBEB_OK);
+        assert_eq!(CStr::from_ptr((*device).output_name).to_str().unwrap(), "Null Output");
+        assert!((*device).input_name.is_null());
+        assert_eq!(OPS.stream_device_destroy.unwrap()(s, device), ffi::CUBEB_OK);
+        assert_eq!(OPS.stream_set_volume.unwrap()(s, 2.0), ffi::CUBEB_ERROR_INVALID_PARAMETER);
+        OPS.stream_destroy.unwrap()(s);
+
+        OPS.device_collection_destroy.unwrap()(c, &mut coll);
+        OPS.destroy.unwrap()(c);
+    }
+}
+
+#[test]
+fn test_remote_stream_start_stop() {
+    let path = null_server("start-stop");
+    let c = connect(&path);
+    let user = User::playing(8, vec![1; 48000 * 8]);
+    let p = params(ffi::CUBEB_SAMPLE_FLOAT32NE, 48000, 2);
+    let s = stream_init(c, None, Some(&p), 480, &user).unwrap();
+    unsafe {
+        let mut latency = 0u32;
+        assert_eq!(OPS.stream_get_latency.unwrap()(s, &mut latency), ffi::CUBEB_OK);
+        assert_eq!(latency, 480);
+
+        assert_eq!(OPS.stream_start.unwrap()(s), ffi::CUBEB_OK);
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(OPS.stream_stop.unwrap()(s), ffi::CUBEB_OK);
+
+        let mut position = 0u64;
+        assert_eq!(OPS.stream_get_position.unwrap()(s, &mut position), ffi::CUBEB_OK);
+        assert!(position > 0);
+        // The client stays at most three periods ahead of the server.
+        let played = *user.frames.lock().unwrap();
+        assert!(played >= position && played <= position + 3 * 480);
+
+        OPS.stream_destroy.unwrap()(s);
+        OPS.destroy.unwrap()(c);
+    }
+    assert_eq!(
+        *user.states.lock().unwrap(),
+        vec![ffi::CUBEB_STATE_STARTED, ffi::CUBEB_STATE_STOPPED]
+    );
+}
+
+#[test]
+fn test_remote_render_wav() {
+    let out = temp_path("render.wav");
+    let path = wav_server(
+        "render",
+        wav::Config {
+            output_files: vec![out.clone()],
+            pacing: Pacing::RealTime,
+            ..Default::default()
+        }
+    );
+    let c = connect(&path);
+    let samples: Vec<i16> = (0..4800).map(|i| (i % 1000 + 1) as i16).collect();
+    let data: Vec<u8> = samples.iter().flat_map(|x| x.to_le_bytes().to_vec()).collect();
+    let user = User::playing(2, data.clone());
+    let p = params(ffi::CUBEB_SAMPLE_S16LE, 48000, 1);
+    let s = stream_init(c, None, Some(&p), 256, &user).unwrap();
+    unsafe {
+        assert_eq!(OPS.stream_start.unwrap()(s), ffi::CUBEB_OK);
+        user.wait_for_state(ffi::CUBEB_STATE_DRAINED);
+        OPS.stream_destroy.unwrap()(s);
+        OPS.destroy.unwrap()(c);
+    }
+    assert_eq!(
+        *user.states.lock().unwrap(),
+        vec![ffi::CUBEB_STATE_STARTED, ffi::CUBEB_STATE_DRAINED]
+    );
+
+    // Underruns on a loaded machine insert silence, but never lose or
+    // reorder audio.
+    let reader = wav::WavReader::open(&out).unwrap();
+    assert_eq!(reader.spec().format, SampleFormat::S16LE);
+    let file = fs::read(&out).unwrap();
+    let rendered: Vec<u8> = file[44..]
+        .chunks(2)
+        .filter(|b| b != &[0, 0])
+        .flat_map(|b| b.to_vec())
+        .collect();
+    assert_eq!(rendered, data);
+    fs::remove_file(&out).unwrap();
+}
+
+#[test]
+fn test_remote_record_wav() {
+    let input = temp_path("record.wav");
+    let samples: Vec<f32> = (0..9600).map(|i| i as f32 / 9600.0).collect();
+    let spec = wav::WavSpec {
+        format: SampleFormat::Float32LE,
+        rate: 48000,
+        channels: 1,
+        layout: ChannelLayout::Mono
+    };
+    let mut writer = wav::WavWriter::create(&input, spec).unwrap();
+    let bytes: Vec<u8> = samples.iter().flat_map(|x| x.to_le_bytes().to_vec()).collect();
+    writer.write(&bytes, samples.len()).unwrap();
+    drop(writer);
+
+    let path = wav_server(
+        "record",
+        wav::Config {
+            input_files: vec![input.clone()],
+            pacing: Pacing::RealTime,
+            ..Default::default()
+        }
+    );
+    let c = connect(&path);
+    let user = User::recording(4);
+    let p = params(ffi::CUBEB_SAMPLE_FLOAT32LE, 48000, 1);
+    let s = stream_init(c, Some(&p), None, 480, &user).unwrap();
+    unsafe {
+        assert_eq!(OPS.stream_start.unwrap()(s), ffi::CUBEB_OK);
+        user.wait_for_state(ffi::CUBEB_STATE_DRAINED);
+        OPS.stream_destroy.unwrap()(s);
+        OPS.destroy.unwrap()(c);
+    }
+
+    // Input arrives in whole periods, so the tail of the file may be
+    // missing.
+    let recorded = user.input.lock().unwrap();
+    assert!(recorded.len() >= bytes.len() / 2);
+    assert_eq!(&recorded[..], &bytes[..recorded.len()]);
+    fs::remove_file(&input).unwrap();
+}
+
+#[test]
+fn test_remote_invalid_stream() {
+    let path = null_server("invalid");
+    let c = connect(&path);
+    let user = User::default();
+    // Rejected by the null backend.
+    let p = params(ffi::CUBEB_SAMPLE_FLOAT32NE, 48000, 6);
+    assert_eq!(
+        stream_init(c, None, Some(&p), 256, &user),
+        Err(ffi::CUBEB_ERROR_INVALID_FORMAT)
+    );
+    // Rejected by the server.
+    let p = params(ffi::CUBEB_SAMPLE_FLOAT32NE, 48000, 1);
+    assert_eq!(
+        stream_init(c, None, Some(&p), 0, &user),
+        Err(ffi::CUBEB_ERROR_INVALID_PARAMETER)
+    );
+    unsafe { OPS.destroy.unwrap()(c) };
+}
+
+#[test]
+fn test_remote_version_mismatch() {
+    let path = null_server("version");
+    let mut conn = UnixStream::connect(&path).unwrap();
+    let hello = Request::Hello {
+        version: PROTOCOL_VERSION + 98
+    };
+    protocol::write_message(&mut conn, &hello.encode()).unwrap();
+    let response = Response::decode(&protocol::read_message(&mut conn).unwrap()).unwrap();
+    assert_eq!(response, Response::Error(ffi::CUBEB_ERROR_NOT_SUPPORTED));
+    // The server hangs up.
+    assert!(protocol::read_message(&mut conn).is_err());
+}
+
+#[test]
+fn test_remote_protocol_round_trip() {
+    let request = Request::StreamInit {
+        name: Some("stream".to_string()),
+        input: None,
+        output: Some(StreamDirection {
+            devid: 3,
+            params: params(ffi::CUBEB_SAMPLE_S16LE, 44100, 2)
+        }),
+        latency_frames: 512
+    };
+    match Request::decode(&request.encode()).unwrap() {
+        Request::StreamInit {
+            name,
+            input,
+            output,
+            latency_frames
+        } => {
+            assert_eq!(name.as_ref().map(|s| &s[..]), Some("stream"));
+            assert!(input.is_none());
+            let output = output.unwrap();
+            assert_eq!(output.devid, 3);
+            assert_eq!(output.params.rate, 44100);
+            assert_eq!(output.params.channels, 2);
+            assert_eq!(latency_frames, 512);
+        },
+        r => panic!("decoded {:?}", r),
+    }
+
+    let response = Response::Devices(vec![DeviceInfo {
+        devid: 5,
+        device_id: Some("id".to_string()),
+        friendly_name: Some("Name".to_string()),
+        group_id: None,
+        vendor_name: None,
+        device_type: ffi::CUBEB_DEVICE_TYPE_OUTPUT,
+        state: ffi::CUBEB_DEVICE_STATE_ENABLED,
+        preferred: ffi::CUBEB_DEVICE_PREF_ALL,
+        format: ffi::CUBEB_DEVICE_FMT_ALL,
+        default_format: ffi::CUBEB_DEVICE_FMT_F32LE,
+        max_channels: 2,
+        default_rate: 48000,
+        max_rate: 48000,
+        min_rate: 8000,
+        latency_lo: 64,
+        latency_hi: 4096
+    }]);
+    assert_eq!(Response::decode(&response.encode()).unwrap(), response);
+
+    // Truncated and padded messages are rejected.
+    let encoded = response.encode();
+    assert!(Response::decode(&encoded[..encoded.len() - 1]).is_err());
+    let mut padded = encoded.clone();
+    padded.push(0);
+    assert!(Response::decode(&padded).is_err());
+}
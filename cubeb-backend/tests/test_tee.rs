@@ -0,0 +1,242 @@
+// Copyright © 2017 Mozilla Foundation
+//
+// This program is made available under an ISC-style license.  See the
+// accompanying file LICENSE for details
+
+extern crate cubeb_backend;
+extern crate cubeb_core;
+
+mod common;
+
+use common::{stream_init, temp_path, User};
+use cubeb_backend::Sink;
+use cubeb_backend::raw::RawContext;
+use cubeb_backend::ring;
+use cubeb_backend::tee::{FnSink, TeeContext, TeeStream, OPS};
+use cubeb_backend::wav::{self, WavContext, WavReader, WavSpec, WavWriter};
+use cubeb_core::{Result, SampleFormat, StreamParams};
+use cubeb_core::ffi;
+use std::fs;
+use std::path::Path;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+fn params(format: ffi::cubeb_sample_format, channels: u32) -> ffi::cubeb_stream_params {
+    common::params(format, 48000, channels, ffi::CUBEB_LAYOUT_UNDEFINED)
+}
+
+fn f32_ramp(samples: usize) -> Vec<u8> {
+    (0..samples)
+        .flat_map(|i| ((i % 1000) as f32 / 500.0 - 1.0).to_ne_bytes().to_vec())
+        .collect()
+}
+
+fn s16_ramp(samples: usize) -> Vec<u8> {
+    (0..samples)
+        .flat_map(|i| ((i * 37 % 65536) as u16 as i16).to_ne_bytes().to_vec())
+        .collect()
+}
+
+fn read_wav(path: &Path) -> (WavSpec, Vec<u8>) {
+    let mut reader = WavReader::open(path).unwrap();
+    let spec = reader.spec();
+    let sample_size = if spec.format == SampleFormat::S16LE { 2 } else { 4 };
+    let frame_size = spec.channels as usize * sample_size;
+    let mut data = Vec::new();
+    let mut buf = vec![0u8; 1024 * frame_size];
+    loop {
+        let n = reader.read(&mut buf, 1024).unwrap();
+        data.extend_from_slice(&buf[..n * frame_size]);
+        if n < 1024 {
+            return (spec, data);
+        }
+    }
+}
+
+// Plays `data` through the WAV backend, at `volume`, with a tee into
+// the sink `open_sink` returns. Returns what the WAV backend wrote and
+// the frames dropped from the copy.
+fn play<F>(
+    name: &str,
+    params: &ffi::cubeb_stream_params,
+    frame_size: usize,
+    data: Vec<u8>,
+    volume: f32,
+    open_sink: F,
+) -> (Vec<u8>, u64)
+where
+    F: Fn(&StreamParams) -> Result<Box<dyn Sink>> + Send + Sync + 'static,
+{
+    let path = temp_path(name);
+    let inner = WavContext::init_with_config(wav::Config {
+        output_files: vec![path.clone()],
+        ..Default::default()
+    }).unwrap();
+    let c = TeeContext::wrap(unsafe { RawContext::from_ptr(inner) }, open_sink);
+    let user = User::playing(frame_size, data);
+    let s = stream_init(&OPS, c, None, Some(params), 256, &user).unwrap();
+    let dropped = unsafe {
+        assert_eq!(OPS.stream_set_volume.unwrap()(s, volume), ffi::CUBEB_OK);
+        assert_eq!(OPS.stream_start.unwrap()(s), ffi::CUBEB_OK);
+        user.wait_for_state(ffi::CUBEB_STATE_DRAINED);
+        let dropped = (*(s as *const TeeStream)).dropped_frames();
+        // Waits for the copy to reach the sink.
+        OPS.stream_destroy.unwrap()(s);
+        OPS.destroy.unwrap()(c);
+        dropped
+    };
+    let (_, played) = read_wav(&path);
+    fs::remove_file(&path).unwrap();
+    (played, dropped)
+}
+
+#[test]
+fn test_tee_callback_sink() {
+    let copy = Arc::new(Mutex::new(Vec::new()));
+    let sink_copy = copy.clone();
+    let data = f32_ramp(2 * 10_000);
+    let (played, dropped) = play(
+        "callback",
+        &params(ffi::CUBEB_SAMPLE_FLOAT32LE, 2),
+        8,
+        data.clone(),
+        0.5,
+        move |_| {
+            let copy = sink_copy.clone();
+            Ok(Box::new(FnSink(move |buffer: &[u8], _| {
+                copy.lock().unwrap().extend_from_slice(buffer);
+                Ok(())
+            })) as Box<dyn Sink>)
+        }
+    );
+    assert_eq!(dropped, 0);
+    assert_eq!(played.len(), data.len());
+    assert!(played != data, "volume wasn't applied");
+    assert!(*copy.lock().unwrap() == played);
+}
+
+#[test]
+fn test_tee_wav_sink() {
+    let path = temp_path("copy");
+    let sink_path = path.clone();
+    let data = s16_ramp(3 * 7_777);
+    let (played, dropped) = play(
+        "wav-primary",
+        &params(ffi::CUBEB_SAMPLE_S16LE, 3),
+        6,
+        data,
+        0.3,
+        move |params| {
+            let writer = WavWriter::create(&sink_path, WavSpec::from_params(params)).unwrap();
+            Ok(Box::new(writer) as Box<dyn Sink>)
+        }
+    );
+    assert_eq!(dropped, 0);
+    let (spec, copy) = read_wav(&path);
+    assert_eq!(spec.channels, 3);
+    assert_eq!(spec.format, SampleFormat::S16LE);
+    assert!(copy == played);
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_tee_converting_sink() {
+    // A float sink behind a 16 bit stream gets the same samples, converted.
+    let path = temp_path("copy-f32");
+    let sink_path = path.clone();
+    let (played, dropped) = play(
+        "converting-primary",
+        &params(ffi::CUBEB_SAMPLE_S16LE, 1),
+        2,
+        s16_ramp(5_000),
+        1.0,
+        move |params| {
+            let spec = WavSpec {
+                format: SampleFormat::Float32LE,
+                ..WavSpec::from_params(params)
+            };
+            Ok(Box::new(WavWriter::create(&sink_path, spec).unwrap()) as Box<dyn Sink>)
+        }
+    );
+    assert_eq!(dropped, 0);
+    let (_, copy) = read_wav(&path);
+    let played: Vec<f32> = played
+        .chunks(2)
+        .map(|s| f32::from(i16::from_le_bytes([s[0], s[1]])) / 32768.0)
+        .collect();
+    let copy: Vec<f32> = copy
+        .chunks(4)
+        .map(|s| f32::from_le_bytes([s[0], s[1], s[2], s[3]]))
+        .collect();
+    assert!(copy == played);
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_tee_ring_sink() {
+    let (producer, mut consumer) = ring::ring(1 << 20);
+    let producer = Mutex::new(Some(producer));
+    let (played, dropped) = play(
+        "ring",
+        &params(ffi::CUBEB_SAMPLE_FLOAT32LE, 1),
+        4,
+        // Fits the tee's backlog, so nothing is dropped however fast the
+        // unpaced primary renders.
+        f32_ramp(12_000),
+        0.25,
+        move |_| Ok(Box::new(producer.lock().unwrap().take().unwrap()) as Box<dyn Sink>)
+    );
+    assert_eq!(dropped, 0);
+    let mut copy = vec![0u8; consumer.available()];
+    consumer.read(&mut copy);
+    assert!(copy == played);
+}
+
+#[test]
+fn test_tee_slow_sink() {
+    // A stuck sink neither stalls the stream nor corrupts the copy: whole
+    // buffers are dropped and counted.
+    let (release, released) = mpsc::channel::<()>();
+    let released = Mutex::new(Some(released));
+    let copy = Arc::new(Mutex::new(Vec::new()));
+    let sink_copy = copy.clone();
+    // Each sample holds its index, exact in an f32.
+    let frames = 48_000 * 4;
+    let data: Vec<u8> = (0..frames)
+        .flat_map(|i| (i as f32).to_ne_bytes().to_vec())
+        .collect();
+    thread::spawn(move || {
+        thread::sleep(Duration::from_millis(200));
+        let _ = release.send(());
+    });
+    let (played, dropped) = play(
+        "slow",
+        &params(ffi::CUBEB_SAMPLE_FLOAT32LE, 1),
+        4,
+        data,
+        1.0,
+        move |_| {
+            let released = released.lock().unwrap().take().unwrap();
+            let copy = sink_copy.clone();
+            Ok(Box::new(FnSink(move |buffer: &[u8], _| {
+                let _ = released.recv();
+                copy.lock().unwrap().extend_from_slice(buffer);
+                Ok(())
+            })) as Box<dyn Sink>)
+        }
+    );
+    assert_eq!(played.len(), frames * 4);
+    assert!(dropped > 0);
+    let copy = copy.lock().unwrap();
+    assert_eq!(copy.len() as u64 / 4 + dropped, frames as u64);
+    // What was copied is the stream in order, with gaps.
+    let indices: Vec<f32> = copy
+        .chunks(4)
+        .map(|s| f32::from_ne_bytes([s[0], s[1], s[2], s[3]]))
+        .collect();
+    assert!(indices.windows(2).all(|w| w[0] < w[1]));
+    for i in indices {
+        assert_eq!(played[i as usize * 4..i as usize * 4 + 4], i.to_ne_bytes());
+    }
+}
@@ -0,0 +1,334 @@
+// Copyright © 2017 Mozilla Foundation
+//
+// This program is made available under an ISC-style license.  See the
+// accompanying file LICENSE for details
+
+extern crate cubeb_backend;
+extern crate cubeb_core;
+
+mod common;
+
+use common::{stream_init, temp_path, User};
+use cubeb_backend::null::{self, NullContext};
+use cubeb_backend::raw::RawContext;
+use cubeb_backend::trace::{self, Config, Replay, TraceContext, TraceWriter, REPLAY_OPS};
+use cubeb_backend::trace::format::{self, Callback, DeviceInfo, Direction, Event, Op, Params, Record};
+use cubeb_backend::{Ops, Pacing};
+use cubeb_core::ffi;
+use std::{fs, io};
+use std::io::Write;
+use std::sync::{Arc, Condvar, Mutex};
+
+// A mono ramp over `frames` frames, which ends just short of 1.0.
+fn ramp(frames: u64) -> Vec<u8> {
+    (0..frames)
+        .flat_map(|i| (i as f32 / frames as f32).to_ne_bytes().to_vec())
+        .collect()
+}
+
+fn params(format: ffi::cubeb_sample_format) -> ffi::cubeb_stream_params {
+    common::params(format, 48000, 1, ffi::CUBEB_LAYOUT_MONO)
+}
+
+fn output_stream_init(
+    ops: &Ops,
+    c: *mut ffi::cubeb,
+    user: &User,
+) -> Result<*mut ffi::cubeb_stream, i32> {
+    stream_init(ops, c, None, Some(&params(ffi::CUBEB_SAMPLE_FLOAT32NE)), 256, user)
+}
+
+// Plays 3000 frames to the end, the same way against any backend.
+fn play(ops: &Ops, c: *mut ffi::cubeb) -> (User, u32, u64) {
+    let user = User::playing(4, ramp(3000));
+    unsafe {
+        let mut rate = 0u32;
+        assert_eq!(ops.get_preferred_sample_rate.unwrap()(c, &mut rate), ffi::CUBEB_OK);
+        let s = output_stream_init(ops, c, &user).unwrap();
+        assert_eq!(ops.stream_start.unwrap()(s), ffi::CUBEB_OK);
+        user.wait_for_state(ffi::CUBEB_STATE_DRAINED);
+        assert_eq!(ops.stream_stop.unwrap()(s), ffi::CUBEB_OK);
+        let mut position = 0u64;
+        assert_eq!(ops.stream_get_position.unwrap()(s, &mut position), ffi::CUBEB_OK);
+        ops.stream_destroy.unwrap()(s);
+        ops.destroy.unwrap()(c);
+        (user, rate, position)
+    }
+}
+
+#[test]
+fn test_trace_record_replay() {
+    let path = temp_path("record-replay");
+    let inner = NullContext::init_with_config(null::Config::default()).unwrap();
+    let writer = TraceWriter::create(&path, Config { buffers: true }).unwrap();
+    let c = TraceContext::wrap(unsafe { RawContext::from_ptr(inner) }, writer);
+    let (recorded, rate, position) = play(&trace::OPS, c);
+    assert_eq!(rate, 48000);
+    assert_eq!(position, 3000);
+
+    let replay = Replay::open(&path).unwrap();
+    let records = format::read_trace(&mut fs::File::open(&path).unwrap()).unwrap();
+    let data_callbacks = records
+        .iter()
+        .filter(|r| match r.event {
+            Event::Callback {
+                callback: Callback::Data { ref output, .. },
+                ..
+            } => output.is_some(),
+            _ => false,
+        })
+        .count();
+    assert_eq!(data_callbacks, recorded.callbacks.lock().unwrap().len());
+
+    let (replayed, rate, position) = play(&REPLAY_OPS, replay.context().unwrap());
+    assert_eq!(rate, 48000);
+    assert_eq!(position, 3000);
+    assert_eq!(*replayed.callbacks.lock().unwrap(), *recorded.callbacks.lock().unwrap());
+    assert_eq!(*replayed.states.lock().unwrap(), *recorded.states.lock().unwrap());
+    assert_eq!(replay.divergences(), Vec::<String>::new());
+    fs::remove_file(&path).unwrap();
+}
+
+// Keeps what is written, once `open` has been called.
+#[derive(Clone, Default)]
+struct GatedWriter {
+    gate: Arc<(Mutex<bool>, Condvar)>,
+    written: Arc<Mutex<Vec<u8>>>
+}
+
+impl GatedWriter {
+    fn open(&self) {
+        *self.gate.0.lock().unwrap() = true;
+        self.gate.1.notify_all();
+    }
+}
+
+impl Write for GatedWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut open = self.gate.0.lock().unwrap();
+        while !*open {
+            open = self.gate.1.wait(open).unwrap();
+        }
+        self.written.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn test_trace_drops_callbacks_when_writer_stalls() {
+    let config = null::Config {
+        pacing: Pacing::Unpaced,
+        ..Default::default()
+    };
+    let inner = NullContext::init_with_config(config).unwrap();
+    let gated = GatedWriter::default();
+    let writer = TraceWriter::new(Box::new(gated.clone()), Config { buffers: true }).unwrap();
+    let c = TraceContext::wrap(unsafe { RawContext::from_ptr(inner) }, writer);
+    let user = User::playing(4, ramp(1 << 20));
+    unsafe {
+        let s = output_stream_init(&trace::OPS, c, &user).unwrap();
+        // The stream renders to the end while the writer is stuck.
+        assert_eq!(trace::OPS.stream_start.unwrap()(s), ffi::CUBEB_OK);
+        user.wait_for_state(ffi::CUBEB_STATE_DRAINED);
+        gated.open();
+        assert_eq!(trace::OPS.stream_stop.unwrap()(s), ffi::CUBEB_OK);
+        trace::OPS.stream_destroy.unwrap()(s);
+        trace::OPS.destroy.unwrap()(c);
+    }
+
+    let written = gated.written.lock().unwrap();
+    let records = format::read_trace(&mut &written[..]).unwrap();
+    let data_callbacks = records
+        .iter()
+        .filter(|r| match r.event {
+            Event::Callback {
+                callback: Callback::Data { ref output, .. },
+                ..
+            } => output.is_some(),
+            _ => false,
+        })
+        .count();
+    let callbacks = user.callbacks.lock().unwrap().len();
+    assert!(data_callbacks > 0);
+    assert!(data_callbacks < callbacks, "{} of {}", data_callbacks, callbacks);
+    assert!(records.iter().any(|r| matches!(
+        r.event,
+        Event::Call {
+            op: Op::StreamDestroy,
+            ..
+        }
+    )));
+}
+
+fn input_trace() -> Vec<Record> {
+    let record = |time_us, event| Record { time_us, event };
+    let call = |stream, op| Event::Call {
+        stream: Some(stream),
+        result: ffi::CUBEB_OK,
+        op
+    };
+    let callback = |started, completed, callback| Event::Callback {
+        stream: Some(0),
+        started,
+        completed,
+        callback
+    };
+    let data = |nframes: u32, first: u8| Callback::Data {
+        nframes,
+        returned: i64::from(nframes),
+        duration_us: 10,
+        input: Some((0..nframes as u8 * 2).map(|i| first + i).collect()),
+        output: None
+    };
+    vec![
+        record(0, Event::Init {
+            backend_id: "test".to_string()
+        }),
+        record(1, Event::Call {
+            stream: None,
+            result: ffi::CUBEB_OK,
+            op: Op::StreamInit {
+                stream: 0,
+                name: None,
+                input: Some(Direction {
+                    devid: 0,
+                    params: Params::from(&params(ffi::CUBEB_SAMPLE_S16NE))
+                }),
+                output: None,
+                latency_frames: 256
+            }
+        }),
+        record(2, callback(1, 0, Callback::State(ffi::CUBEB_STATE_STARTED))),
+        record(3, call(0, Op::StreamStart)),
+        record(4, callback(1, 1, data(3, 0))),
+        record(5, callback(1, 1, data(2, 100))),
+        record(6, callback(2, 1, Callback::State(ffi::CUBEB_STATE_STOPPED))),
+        record(7, call(0, Op::StreamStop)),
+        record(8, call(0, Op::StreamDestroy)),
+    ]
+}
+
+fn input_stream_init(c: *mut ffi::cubeb, user: &User) -> Result<*mut ffi::cubeb_stream, i32> {
+    stream_init(&REPLAY_OPS, c, Some(&params(ffi::CUBEB_SAMPLE_S16NE)), None, 256, user)
+}
+
+#[test]
+fn test_trace_replay_input() {
+    let replay = Replay::new(input_trace());
+    let c = replay.context().unwrap();
+    let user = User::recording(2);
+    let s = input_stream_init(c, &user).unwrap();
+    unsafe {
+        assert_eq!(REPLAY_OPS.stream_start.unwrap()(s), ffi::CUBEB_OK);
+        // Every callback recorded before `stop` returned is delivered
+        // before it returns.
+        assert_eq!(REPLAY_OPS.stream_stop.unwrap()(s), ffi::CUBEB_OK);
+        REPLAY_OPS.stream_destroy.unwrap()(s);
+        REPLAY_OPS.destroy.unwrap()(c);
+    }
+    assert_eq!(*user.callbacks.lock().unwrap(), vec![3, 2]);
+    assert_eq!(
+        *user.input.lock().unwrap(),
+        vec![0, 1, 2, 3, 4, 5, 100, 101, 102, 103]
+    );
+    assert_eq!(
+        *user.states.lock().unwrap(),
+        vec![ffi::CUBEB_STATE_STARTED, ffi::CUBEB_STATE_STOPPED]
+    );
+    assert_eq!(replay.divergences(), Vec::<String>::new());
+}
+
+#[test]
+fn test_trace_replay_divergence() {
+    let replay = Replay::new(input_trace());
+    let c = replay.context().unwrap();
+    // Takes none of the input it's offered.
+    let user = User {
+        limit: Some(0),
+        ..User::recording(2)
+    };
+    let s = input_stream_init(c, &user).unwrap();
+    unsafe {
+        assert_eq!(REPLAY_OPS.stream_set_volume.unwrap()(s, 0.5), ffi::CUBEB_ERROR);
+        REPLAY_OPS.stream_destroy.unwrap()(s);
+        REPLAY_OPS.destroy.unwrap()(c);
+    }
+    // The replay carries on after a divergence, and reports every one.
+    let divergences = replay.divergences();
+    assert_eq!(divergences.len(), 4, "{:?}", divergences);
+    assert!(divergences[0].contains("StreamSetVolume(0.5) instead of StreamStart"));
+    assert!(divergences[1].contains("returned 0 instead of 3"));
+    assert!(divergences[3].contains("StreamDestroy instead of StreamStop"));
+    assert_eq!(*user.callbacks.lock().unwrap(), vec![3, 2]);
+}
+
+#[test]
+fn test_trace_format_round_trip() {
+    let mut records = input_trace();
+    records.push(Record {
+        time_us: 1 << 40,
+        event: Event::Call {
+            stream: None,
+            result: ffi::CUBEB_ERROR_DEVICE_UNAVAILABLE,
+            op: Op::EnumerateDevices {
+                devtype: ffi::CUBEB_DEVICE_TYPE_OUTPUT,
+                devices: vec![DeviceInfo {
+                    devid: u64::MAX,
+                    device_id: Some("id".to_string()),
+                    friendly_name: Some("Speakers ♪".to_string()),
+                    group_id: None,
+                    vendor_name: None,
+                    device_type: ffi::CUBEB_DEVICE_TYPE_OUTPUT,
+                    state: ffi::CUBEB_DEVICE_STATE_ENABLED,
+                    preferred: ffi::CUBEB_DEVICE_PREF_ALL,
+                    format: ffi::CUBEB_DEVICE_FMT_F32LE,
+                    default_format: ffi::CUBEB_DEVICE_FMT_F32LE,
+                    max_channels: 8,
+                    default_rate: 48000,
+                    max_rate: 192000,
+                    min_rate: 8000,
+                    latency_lo: 64,
+                    latency_hi: 8192
+                }]
+            }
+        }
+    });
+    records.push(Record {
+        time_us: 9,
+        event: Event::Call {
+            stream: Some(7),
+            result: ffi::CUBEB_OK,
+            op: Op::StreamSetVolume(-0.25)
+        }
+    });
+    records.push(Record {
+        time_us: 10,
+        event: Event::Callback {
+            stream: None,
+            started: 3,
+            completed: 3,
+            callback: Callback::Data {
+                nframes: 1,
+                returned: -1,
+                duration_us: 0,
+                input: None,
+                output: Some(vec![])
+            }
+        }
+    });
+
+    let mut buf = Vec::new();
+    format::write_header(&mut buf).unwrap();
+    for r in &records {
+        r.encode(&mut buf);
+    }
+    assert_eq!(format::read_trace(&mut &buf[..]).unwrap(), records);
+
+    // Truncation and foreign files are reported.
+    assert!(format::read_trace(&mut &buf[..buf.len() - 1]).is_err());
+    assert!(format::read_trace(&mut &b"RIFF\x01\x00\x00\x00"[..]).is_err());
+}
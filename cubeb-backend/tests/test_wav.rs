@@ -0,0 +1,239 @@
+// Copyright © 2017 Mozilla Foundation
+//
+// This program is made available under an ISC-style license.  See the
+// accompanying file LICENSE for details
+
+extern crate cubeb_backend;
+extern crate cubeb_core;
+
+mod common;
+
+use common::{params, stream_init_on, temp_path, User};
+use cubeb_backend::wav::{Config, WavContext, WavReader, WavSpec, WavWriter, OPS};
+use cubeb_core::{ChannelLayout, SampleFormat};
+use cubeb_core::ffi;
+use std::{fs, ptr, slice};
+
+fn stream_init(
+    c: *mut ffi::cubeb,
+    input: Option<&ffi::cubeb_stream_params>,
+    output: Option<&ffi::cubeb_stream_params>,
+    user: &User,
+) -> Result<*mut ffi::cubeb_stream, i32> {
+    common::stream_init(&OPS, c, input, output, 256, user)
+}
+
+// Render `data` through an output stream and return the file contents.
+fn render(name: &str, params: &ffi::cubeb_stream_params, frame_size: usize, data: Vec<u8>) -> Vec<u8> {
+    let path = temp_path(name);
+    let c = WavContext::init_with_config(Config {
+        output_files: vec![path.clone()],
+        ..Default::default()
+    }).unwrap();
+    let user = User::playing(frame_size, data);
+    let s = stream_init(c, None, Some(params), &user).unwrap();
+    unsafe {
+        assert_eq!(OPS.stream_start.unwrap()(s), ffi::CUBEB_OK);
+        user.wait_for_state(ffi::CUBEB_STATE_DRAINED);
+        OPS.stream_destroy.unwrap()(s);
+        OPS.destroy.unwrap()(c);
+    }
+    let bytes = fs::read(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+    bytes
+}
+
+fn u16_at(b: &[u8], i: usize) -> u16 {
+    u16::from_le_bytes([b[i], b[i + 1]])
+}
+
+fn u32_at(b: &[u8], i: usize) -> u32 {
+    u32::from_le_bytes([b[i], b[i + 1], b[i + 2], b[i + 3]])
+}
+
+#[test]
+fn test_wav_render_float_stereo() {
+    let samples: Vec<f32> = (0..2000).map(|i| i as f32 / 2000.0).collect();
+    let data: Vec<u8> = samples.iter().flat_map(|x| x.to_le_bytes().to_vec()).collect();
+    let p = params(ffi::CUBEB_SAMPLE_FLOAT32LE, 44100, 2, ffi::CUBEB_LAYOUT_STEREO);
+    let file = render("float-stereo", &p, 8, data.clone());
+
+    assert_eq!(&file[..4], b"RIFF");
+    assert_eq!(u32_at(&file, 4) as usize, file.len() - 8);
+    assert_eq!(&file[8..16], b"WAVEfmt ");
+    assert_eq!(u32_at(&file, 16), 18);
+    assert_eq!(u16_at(&file, 20), 3);
+    assert_eq!(u16_at(&file, 22), 2);
+    assert_eq!(u32_at(&file, 24), 44100);
+    assert_eq!(u32_at(&file, 28), 44100 * 8);
+    assert_eq!(u16_at(&file, 32), 8);
+    assert_eq!(u16_at(&file, 34), 32);
+    assert_eq!(&file[38..42], b"fact");
+    assert_eq!(u32_at(&file, 46), 1000);
+    assert_eq!(&file[50..54], b"data");
+    assert_eq!(u32_at(&file, 54) as usize, data.len());
+    assert_eq!(&file[58..], &data[..]);
+}
+
+#[test]
+fn test_wav_render_s16be_as_pcm() {
+    let samples: Vec<i16> = (0..1000).map(|i| (i * 30 - 15000) as i16).collect();
+    let be: Vec<u8> = samples.iter().flat_map(|x| x.to_be_bytes().to_vec()).collect();
+    let le: Vec<u8> = samples.iter().flat_map(|x| x.to_le_bytes().to_vec()).collect();
+    let p = params(ffi::CUBEB_SAMPLE_S16BE, 8000, 1, ffi::CUBEB_LAYOUT_MONO);
+    let file = render("s16be-mono", &p, 2, be);
+
+    assert_eq!(u32_at(&file, 16), 16);
+    assert_eq!(u16_at(&file, 20), 1);
+    assert_eq!(u16_at(&file, 34), 16);
+    assert_eq!(&file[36..40], b"data");
+    assert_eq!(&file[44..], &le[..]);
+}
+
+#[test]
+fn test_wav_extensible_layouts() {
+    let layouts = [
+        (ChannelLayout::Mono, 1, None),
+        (ChannelLayout::Stereo, 2, None),
+        (ChannelLayout::Undefined, 2, None),
+        (ChannelLayout::MonoLfe, 2, Some(0xC)),
+        (ChannelLayout::Layout3F, 3, Some(0x7)),
+        (ChannelLayout::Layout2F1Lfe, 4, Some(0x10B)),
+        (ChannelLayout::Layout2F2, 4, Some(0x603)),
+        (ChannelLayout::Layout3F2Lfe, 6, Some(0x60F)),
+        (ChannelLayout::Layout3F3RLfe, 7, Some(0x70F)),
+        (ChannelLayout::Layout3F4Lfe, 8, Some(0x63F)),
+        (ChannelLayout::Undefined, 5, Some(0)),
+    ];
+    for &(layout, channels, mask) in &layouts {
+        for &format in &[SampleFormat::S16LE, SampleFormat::Float32LE] {
+            let path = temp_path(&format!("layout-{:?}-{}-{:?}", layout, channels, format));
+            let spec = WavSpec {
+                format,
+                rate: 48000,
+                channels,
+                layout
+            };
+            let mut writer = WavWriter::create(&path, spec).unwrap();
+            let frame = vec![0; channels as usize * if format == SampleFormat::S16LE { 2 } else { 4 }];
+            writer.write(&frame, 1).unwrap();
+            drop(writer);
+
+            let file = fs::read(&path).unwrap();
+            let tag = if format == SampleFormat::S16LE { 1 } else { 3 };
+            match mask {
+                Some(mask) => {
+                    assert_eq!(u16_at(&file, 20), 0xFFFE);
+                    assert_eq!(u16_at(&file, 36), 22);
+                    assert_eq!(u32_at(&file, 40), mask);
+                    assert_eq!(u16_at(&file, 44), tag);
+                },
+                None => assert_eq!(u16_at(&file, 20), tag),
+            }
+
+            let reader = WavReader::open(&path).unwrap();
+            let expected = match layout {
+                ChannelLayout::Undefined if channels == 2 => ChannelLayout::Stereo,
+                l => l,
+            };
+            assert_eq!(reader.spec(), WavSpec { layout: expected, ..spec });
+            fs::remove_file(&path).unwrap();
+        }
+    }
+}
+
+#[test]
+fn test_wav_input() {
+    let path = temp_path("input");
+    let samples: Vec<i16> = (0..3000).map(|i| (i * 10 - 15000) as i16).collect();
+    let spec = WavSpec {
+        format: SampleFormat::S16LE,
+        rate: 16000,
+        channels: 1,
+        layout: ChannelLayout::Mono
+    };
+    let mut writer = WavWriter::create(&path, spec).unwrap();
+    let bytes: Vec<u8> = samples.iter().flat_map(|x| x.to_le_bytes().to_vec()).collect();
+    writer.write(&bytes, samples.len()).unwrap();
+    drop(writer);
+
+    let c = WavContext::init_with_config(Config {
+        input_files: vec![path.clone()],
+        ..Default::default()
+    }).unwrap();
+
+    // The stream has to match the file, apart from the sample format.
+    let user = User::recording(4);
+    let p = params(ffi::CUBEB_SAMPLE_FLOAT32NE, 48000, 1, ffi::CUBEB_LAYOUT_MONO);
+    assert_eq!(stream_init(c, Some(&p), None, &user), Err(ffi::CUBEB_ERROR_INVALID_FORMAT));
+
+    let p = params(ffi::CUBEB_SAMPLE_FLOAT32NE, 16000, 1, ffi::CUBEB_LAYOUT_MONO);
+    let s = stream_init(c, Some(&p), None, &user).unwrap();
+    unsafe {
+        assert_eq!(OPS.stream_start.unwrap()(s), ffi::CUBEB_OK);
+        user.wait_for_state(ffi::CUBEB_STATE_DRAINED);
+        OPS.stream_destroy.unwrap()(s);
+        OPS.destroy.unwrap()(c);
+    }
+
+    // The final period is padded with silence.
+    let input = user.input.lock().unwrap();
+    let recorded: Vec<f32> = input
+        .chunks(4)
+        .map(|b| f32::from_ne_bytes([b[0], b[1], b[2], b[3]]))
+        .collect();
+    assert!(recorded.len() >= samples.len());
+    for (x, s) in recorded.iter().zip(&samples) {
+        assert_eq!(*x, f32::from(*s) / 32768.0);
+    }
+    assert!(recorded[samples.len()..].iter().all(|x| *x == 0.0));
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_wav_enumerate_devices() {
+    let present = temp_path("present");
+    let missing = temp_path("missing");
+    let spec = WavSpec {
+        format: SampleFormat::Float32LE,
+        rate: 22050,
+        channels: 2,
+        layout: ChannelLayout::Stereo
+    };
+    drop(WavWriter::create(&present, spec).unwrap());
+
+    let c = WavContext::init_with_config(Config {
+        input_files: vec![present.clone(), missing.clone()],
+        output_files: vec![temp_path("out")],
+        ..Default::default()
+    }).unwrap();
+    let mut coll = ffi::cubeb_device_collection {
+        device: ptr::null(),
+        count: 0
+    };
+    unsafe {
+        assert_eq!(
+            OPS.enumerate_devices.unwrap()(c, ffi::CUBEB_DEVICE_TYPE_INPUT, &mut coll),
+            ffi::CUBEB_OK
+        );
+        let infos = slice::from_raw_parts(coll.device, coll.count);
+        assert_eq!(infos.len(), 2);
+        assert_eq!(infos[0].state, ffi::CUBEB_DEVICE_STATE_ENABLED);
+        assert_eq!(infos[0].default_rate, 22050);
+        assert_eq!(infos[0].max_channels, 2);
+        assert_eq!(infos[0].default_format, ffi::CUBEB_DEVICE_FMT_F32LE);
+        assert_eq!(infos[1].state, ffi::CUBEB_DEVICE_STATE_UNPLUGGED);
+
+        // Opening the missing file fails.
+        let user = User::default();
+        let p = params(ffi::CUBEB_SAMPLE_FLOAT32LE, 22050, 2, ffi::CUBEB_LAYOUT_STEREO);
+        assert_eq!(
+            stream_init_on(&OPS, c, Some((infos[1].devid, &p)), None, 256, &user),
+            Err(ffi::CUBEB_ERROR_DEVICE_UNAVAILABLE)
+        );
+
+        OPS.device_collection_destroy.unwrap()(c, &mut coll);
+        OPS.destroy.unwrap()(c);
+    }
+    fs::remove_file(&present).unwrap();
+}
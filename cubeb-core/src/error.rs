@@ -3,38 +3,103 @@ use ffi;
 use std::error;
 use std::ffi::NulError;
 use std::fmt;
+use std::io;
+use std::sync::Arc;
 
-#[derive(Clone, Copy, Debug, PartialEq)]
+/// An error from cubeb: its code, plus what's known of how it came about.
+///
+/// Errors compare equal when their code, operation, backend and detail
+/// do. Sources aren't compared.
+#[derive(Clone, Debug)]
 pub struct Error {
-    code: ErrorCode
+    code: ErrorCode,
+    operation: Option<&'static str>,
+    backend: Option<String>,
+    detail: Option<String>,
+    source: Option<Arc<dyn error::Error + Send + Sync>>
 }
 
 impl Error {
     pub fn new() -> Error {
-        Error {
-            code: ErrorCode::Error
-        }
+        Error::from(ErrorCode::Error)
     }
 
     pub unsafe fn from_raw(code: ffi::cubeb_error_code) -> Error {
         let code = match code {
+            ffi::CUBEB_ERROR => ErrorCode::Error,
             ffi::CUBEB_ERROR_INVALID_FORMAT => ErrorCode::InvalidFormat,
             ffi::CUBEB_ERROR_INVALID_PARAMETER => ErrorCode::InvalidParameter,
             ffi::CUBEB_ERROR_NOT_SUPPORTED => ErrorCode::NotSupported,
             ffi::CUBEB_ERROR_DEVICE_UNAVAILABLE => ErrorCode::DeviceUnavailable,
-            // Everything else is just the generic error
-            _ => ErrorCode::Error,
+            code => ErrorCode::Unknown(code),
         };
 
-        Error {
-            code: code
-        }
+        Error::from(code)
+    }
+
+    /// Records the call that failed, such as `cubeb_stream_init`.
+    pub fn with_operation(mut self, operation: &'static str) -> Error {
+        self.operation = Some(operation);
+        self
+    }
+
+    /// Records the id of the backend the call went to, such as `pulse`.
+    pub fn with_backend<S: Into<String>>(mut self, backend: S) -> Error {
+        self.backend = Some(backend.into());
+        self
+    }
+
+    /// Records a backend's own description of what went wrong.
+    pub fn with_detail<S: Into<String>>(mut self, detail: S) -> Error {
+        self.detail = Some(detail.into());
+        self
+    }
+
+    /// Records the error this one was caused by.
+    pub fn with_source<E>(mut self, source: E) -> Error
+    where
+        E: error::Error + Send + Sync + 'static,
+    {
+        self.source = Some(Arc::new(source));
+        self
     }
 
     pub fn code(&self) -> ErrorCode {
         self.code
     }
 
+    /// The call that failed, if known.
+    pub fn operation(&self) -> Option<&'static str> {
+        self.operation
+    }
+
+    /// The id of the backend the failed call went to, if known.
+    pub fn backend(&self) -> Option<&str> {
+        self.backend.as_deref()
+    }
+
+    /// The backend's description of what went wrong, if it gave one.
+    pub fn detail(&self) -> Option<&str> {
+        self.detail.as_deref()
+    }
+
+    /// Whether trying again shortly might succeed: the device was
+    /// unavailable, as it is while being hotplugged, or I/O underneath was
+    /// interrupted or timed out.
+    pub fn is_transient(&self) -> bool {
+        if self.code == ErrorCode::DeviceUnavailable {
+            return true;
+        }
+        let io = self.source
+            .as_ref()
+            .and_then(|source| source.downcast_ref::<io::Error>());
+        matches!(
+            io.map(|e| e.kind()),
+            Some(io::ErrorKind::Interrupted) |
+                Some(io::ErrorKind::WouldBlock) |
+                Some(io::ErrorKind::TimedOut)
+        )
+    }
 
     pub fn raw_code(&self) -> ffi::cubeb_error_code {
         match self.code {
@@ -43,6 +108,7 @@ impl Error {
             ErrorCode::InvalidParameter => ffi::CUBEB_ERROR_INVALID_PARAMETER,
             ErrorCode::NotSupported => ffi::CUBEB_ERROR_NOT_SUPPORTED,
             ErrorCode::DeviceUnavailable => ffi::CUBEB_ERROR_DEVICE_UNAVAILABLE,
+            ErrorCode::Unknown(code) => code,
         }
     }
 }
@@ -53,6 +119,13 @@ impl Default for Error {
     }
 }
 
+impl PartialEq for Error {
+    fn eq(&self, other: &Error) -> bool {
+        self.code == other.code && self.operation == other.operation &&
+            self.backend == other.backend && self.detail == other.detail
+    }
+}
+
 impl error::Error for Error {
     fn description(&self) -> &str {
         match self.code {
@@ -61,28 +134,64 @@ impl error::Error for Error {
             ErrorCode::InvalidParameter => "Invalid parameter",
             ErrorCode::NotSupported => "Not supported",
             ErrorCode::DeviceUnavailable => "Device unavailable",
+            ErrorCode::Unknown(_) => "Unknown error",
         }
     }
+
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        self.source.as_ref().map(|source| &**source as _)
+    }
 }
 
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         use std::error::Error;
-        write!(f, "{}", self.description())
+        match (self.operation, self.backend()) {
+            (Some(operation), Some(backend)) => write!(f, "{} on {}: ", operation, backend)?,
+            (Some(operation), None) => write!(f, "{}: ", operation)?,
+            (None, Some(backend)) => write!(f, "{}: ", backend)?,
+            (None, None) => {},
+        }
+        write!(f, "{}", self.description())?;
+        if let ErrorCode::Unknown(code) = self.code {
+            write!(f, " {}", code)?;
+        }
+        if let Some(ref detail) = self.detail {
+            write!(f, " ({})", detail)?;
+        }
+        Ok(())
     }
 }
 
 impl From<ErrorCode> for Error {
     fn from(code: ErrorCode) -> Error {
         Error {
-            code: code
+            code: code,
+            operation: None,
+            backend: None,
+            detail: None,
+            source: None
         }
     }
 }
 
+impl From<Error> for io::Error {
+    fn from(e: Error) -> io::Error {
+        let kind = match e.code {
+            ErrorCode::DeviceUnavailable => io::ErrorKind::NotFound,
+            ErrorCode::InvalidParameter | ErrorCode::InvalidFormat => {
+                io::ErrorKind::InvalidInput
+            },
+            ErrorCode::NotSupported => io::ErrorKind::Unsupported,
+            ErrorCode::Error | ErrorCode::Unknown(_) => io::ErrorKind::Other,
+        };
+        io::Error::new(kind, e)
+    }
+}
+
 impl From<NulError> for Error {
-    fn from(_: NulError) -> Error {
-        unsafe { Error::from_raw(ffi::CUBEB_ERROR) }
+    fn from(e: NulError) -> Error {
+        Error::new().with_source(e)
     }
 }
 
@@ -108,6 +217,10 @@ mod tests {
               CUBEB_ERROR_NOT_SUPPORTED => NotSupported,
               CUBEB_ERROR_DEVICE_UNAVAILABLE => DeviceUnavailable
         );
+
+        let e = unsafe { Error::from_raw(-42) };
+        assert_eq!(e.code(), ErrorCode::Unknown(-42));
+        assert_eq!(e.raw_code(), -42);
     }
 
     #[test]
@@ -129,4 +242,87 @@ mod tests {
         );
 
     }
+
+    #[test]
+    fn test_context() {
+        use std::error::Error as StdError;
+        let e = Error::from(ErrorCode::InvalidParameter);
+        assert_eq!(e.operation(), None);
+        assert_eq!(e.backend(), None);
+        assert_eq!(e.detail(), None);
+        assert!(e.source().is_none());
+        assert_eq!(e.to_string(), "Invalid parameter");
+
+        let e = e.with_operation("cubeb_stream_init").with_detail("no such device");
+        assert_eq!(e.code(), ErrorCode::InvalidParameter);
+        assert_eq!(e.operation(), Some("cubeb_stream_init"));
+        assert_eq!(e.detail(), Some("no such device"));
+        assert_eq!(e.raw_code(), ffi::CUBEB_ERROR_INVALID_PARAMETER);
+        assert_eq!(e.to_string(), "cubeb_stream_init: Invalid parameter (no such device)");
+        assert_eq!(unsafe { Error::from_raw(-42) }.to_string(), "Unknown error -42");
+
+        assert!(e != Error::from(ErrorCode::InvalidParameter));
+        assert_eq!(e, e.clone());
+
+        let e = e.with_backend("pulse");
+        assert_eq!(e.backend(), Some("pulse"));
+        assert_eq!(
+            e.to_string(),
+            "cubeb_stream_init on pulse: Invalid parameter (no such device)"
+        );
+        let e = Error::from(ErrorCode::NotSupported).with_backend("null");
+        assert_eq!(e.to_string(), "null: Not supported");
+        assert!(e != Error::from(ErrorCode::NotSupported));
+    }
+
+    #[test]
+    fn test_source() {
+        use std::error::Error as StdError;
+        use std::ffi::CString;
+        use std::io;
+        let e = Error::from(CString::new("a\0b").unwrap_err());
+        assert_eq!(e.code(), ErrorCode::Error);
+        let source = e.source().unwrap();
+        assert!(source.is::<NulError>());
+
+        let io = io::Error::other("broken pipe");
+        let e = Error::new().with_source(io);
+        assert_eq!(e.source().unwrap().to_string(), "broken pipe");
+        // Sources aren't compared.
+        assert_eq!(e, Error::new());
+    }
+
+    #[test]
+    fn test_transient() {
+        use std::io;
+        assert!(Error::from(ErrorCode::DeviceUnavailable).is_transient());
+        assert!(!Error::from(ErrorCode::InvalidParameter).is_transient());
+        assert!(!Error::new().is_transient());
+        let interrupted = io::Error::from(io::ErrorKind::Interrupted);
+        assert!(Error::new().with_source(interrupted).is_transient());
+        let denied = io::Error::from(io::ErrorKind::PermissionDenied);
+        assert!(!Error::new().with_source(denied).is_transient());
+    }
+
+    #[test]
+    fn test_into_io_error() {
+        use std::io;
+        let kinds = [
+            (ErrorCode::Error, io::ErrorKind::Other),
+            (ErrorCode::InvalidFormat, io::ErrorKind::InvalidInput),
+            (ErrorCode::InvalidParameter, io::ErrorKind::InvalidInput),
+            (ErrorCode::NotSupported, io::ErrorKind::Unsupported),
+            (ErrorCode::DeviceUnavailable, io::ErrorKind::NotFound),
+            (ErrorCode::Unknown(-42), io::ErrorKind::Other),
+        ];
+        for &(code, kind) in &kinds {
+            assert_eq!(io::Error::from(Error::from(code)).kind(), kind);
+        }
+        // The original error is kept.
+        let e = Error::from(ErrorCode::NotSupported).with_operation("cubeb_init");
+        let e = io::Error::from(e);
+        assert_eq!(e.to_string(), "cubeb_init: Not supported");
+        let inner = e.get_ref().unwrap().downcast_ref::<Error>().unwrap();
+        assert_eq!(inner.operation(), Some("cubeb_init"));
+    }
 }
@@ -0,0 +1,139 @@
+// Copyright © 2017 Mozilla Foundation
+//
+// This program is made available under an ISC-style license.  See the
+// accompanying file LICENSE for details.
+
+//! Frame counts and their durations at a sample rate.
+
+use std::time::Duration;
+
+/// `frames` long at `rate`, or zero for a rate of zero.
+pub fn frames_to_duration(frames: u64, rate: u32) -> Duration {
+    if rate == 0 {
+        return Duration::from_secs(0);
+    }
+    let rate = u64::from(rate);
+    let nanos = (frames % rate) * 1_000_000_000 / rate;
+    Duration::new(frames / rate, nanos as u32)
+}
+
+/// The nearest number of frames to `duration` at `rate`.
+pub fn duration_to_frames(duration: Duration, rate: u32) -> u64 {
+    let nanos = duration.as_nanos() * u128::from(rate) + 500_000_000;
+    let frames = nanos / 1_000_000_000;
+    if frames > u128::from(u64::MAX) {
+        u64::MAX
+    } else {
+        frames as u64
+    }
+}
+
+/// A number of frames at a sample rate.
+///
+/// Counts only combine at the same rate; convert one with `at_rate`
+/// first otherwise.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Frames {
+    count: u64,
+    rate: u32
+}
+
+impl Frames {
+    pub fn new(count: u64, rate: u32) -> Self {
+        Frames { count, rate }
+    }
+
+    /// The nearest number of frames to `duration` at `rate`.
+    pub fn from_duration(duration: Duration, rate: u32) -> Self {
+        Frames::new(duration_to_frames(duration, rate), rate)
+    }
+
+    pub fn count(self) -> u64 {
+        self.count
+    }
+
+    pub fn rate(self) -> u32 {
+        self.rate
+    }
+
+    pub fn to_duration(self) -> Duration {
+        frames_to_duration(self.count, self.rate)
+    }
+
+    /// The nearest count lasting as long at `rate`.
+    pub fn at_rate(self, rate: u32) -> Frames {
+        if rate == self.rate {
+            return self;
+        }
+        Frames::from_duration(self.to_duration(), rate)
+    }
+
+    /// `None` if the rates differ or the count overflows.
+    pub fn checked_add(self, other: Frames) -> Option<Frames> {
+        if self.rate != other.rate {
+            return None;
+        }
+        self.count
+            .checked_add(other.count)
+            .map(|count| Frames::new(count, self.rate))
+    }
+
+    /// `None` if the rates differ or `other` is longer.
+    pub fn checked_sub(self, other: Frames) -> Option<Frames> {
+        if self.rate != other.rate {
+            return None;
+        }
+        self.count
+            .checked_sub(other.count)
+            .map(|count| Frames::new(count, self.rate))
+    }
+}
+
+impl From<Frames> for Duration {
+    fn from(frames: Frames) -> Duration {
+        frames.to_duration()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Frames, duration_to_frames, frames_to_duration};
+    use std::time::Duration;
+
+    #[test]
+    fn frames_durations() {
+        assert_eq!(frames_to_duration(441, 44100), Duration::from_millis(10));
+        assert_eq!(frames_to_duration(48000 * 3 + 24000, 48000), Duration::from_millis(3500));
+        assert_eq!(frames_to_duration(100, 0), Duration::from_secs(0));
+        assert_eq!(frames_to_duration(u64::MAX, 1).as_secs(), u64::MAX);
+
+        assert_eq!(duration_to_frames(Duration::from_millis(10), 44100), 441);
+        // 1ms at 44.1kHz is 44.1 frames, 1.5ms is 66.15.
+        assert_eq!(duration_to_frames(Duration::from_millis(1), 44100), 44);
+        assert_eq!(duration_to_frames(Duration::from_micros(1500), 44100), 66);
+        assert_eq!(duration_to_frames(Duration::from_secs(u64::MAX), 48000), u64::MAX);
+    }
+
+    #[test]
+    fn frames_arithmetic() {
+        let frames = Frames::new(480, 48000);
+        let later = Frames::from_duration(Duration::from_millis(20), 48000);
+        let frames = frames.checked_add(later).unwrap();
+        assert_eq!(frames.count(), 1440);
+        assert_eq!(frames.checked_sub(Frames::new(440, 48000)), Some(Frames::new(1000, 48000)));
+        assert_eq!(frames.checked_sub(Frames::new(1441, 48000)), None);
+        assert_eq!(frames.checked_add(Frames::new(u64::MAX, 48000)), None);
+        assert_eq!(Duration::from(frames), Duration::from_millis(30));
+        assert_eq!(frames.rate(), 48000);
+    }
+
+    #[test]
+    fn frames_rates() {
+        let frames = Frames::new(480, 48000);
+        assert_eq!(frames.checked_add(Frames::new(441, 44100)), None);
+        assert_eq!(frames.checked_sub(Frames::new(441, 44100)), None);
+        let frames = frames.at_rate(44100);
+        assert_eq!(frames, Frames::new(441, 44100));
+        assert_eq!(frames.checked_add(Frames::new(441, 44100)), Some(Frames::new(882, 44100)));
+    }
+}
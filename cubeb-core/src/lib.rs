@@ -9,14 +9,22 @@ extern crate bitflags;
 pub mod ffi;
 pub mod binding;
 mod error;
+pub mod frames;
+mod parse;
+pub mod ring;
 mod util;
 
 use binding::Binding;
 pub use error::Error;
-use std::{marker, ptr, str};
+pub use frames::Frames;
+pub use parse::ParseError;
+use std::{fmt, marker, ptr, str};
+use std::hash::{Hash, Hasher};
+use std::ops::RangeInclusive;
+use std::time::Duration;
 use util::opt_bytes;
 
-#[derive(PartialEq, Eq, Clone, Debug, Copy)]
+#[derive(PartialEq, Eq, Clone, Debug, Copy, Hash)]
 pub enum SampleFormat {
     S16LE,
     S16BE,
@@ -118,7 +126,7 @@ pub enum LogLevel {
 /// RRS  | Rear Right Surround
 /// LFE  | Low Frequency Effects
 /// ---------------------------
-#[derive(PartialEq, Eq, Clone, Debug, Copy)]
+#[derive(PartialEq, Eq, Clone, Debug, Copy, Hash)]
 pub enum ChannelLayout {
     /// Indicate the speaker's layout is undefined.
     Undefined,
@@ -170,6 +178,93 @@ impl From<ffi::cubeb_channel_layout> for ChannelLayout {
     }
 }
 
+impl SampleFormat {
+    fn name(self) -> &'static str {
+        match self {
+            SampleFormat::S16LE => "s16le",
+            SampleFormat::S16BE => "s16be",
+            SampleFormat::S16NE => "s16ne",
+            SampleFormat::Float32LE => "f32le",
+            SampleFormat::Float32BE => "f32be",
+            SampleFormat::Float32NE => "f32ne",
+        }
+    }
+
+    fn raw(self) -> ffi::cubeb_sample_format {
+        match self {
+            SampleFormat::S16LE => ffi::CUBEB_SAMPLE_S16LE,
+            SampleFormat::S16BE => ffi::CUBEB_SAMPLE_S16BE,
+            SampleFormat::S16NE => ffi::CUBEB_SAMPLE_S16NE,
+            SampleFormat::Float32LE => ffi::CUBEB_SAMPLE_FLOAT32LE,
+            SampleFormat::Float32BE => ffi::CUBEB_SAMPLE_FLOAT32BE,
+            SampleFormat::Float32NE => ffi::CUBEB_SAMPLE_FLOAT32NE,
+        }
+    }
+}
+
+/// Formats as a short name such as `s16le` or `f32ne`.
+impl fmt::Display for SampleFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl ChannelLayout {
+    // Name as in the table above, in lower case.
+    fn name(self) -> &'static str {
+        match self {
+            ChannelLayout::Undefined => "undefined",
+            ChannelLayout::DualMono => "dual-mono",
+            ChannelLayout::DualMonoLfe => "dual-mono-lfe",
+            ChannelLayout::Mono => "mono",
+            ChannelLayout::MonoLfe => "mono-lfe",
+            ChannelLayout::Stereo => "stereo",
+            ChannelLayout::StereoLfe => "stereo-lfe",
+            ChannelLayout::Layout3F => "3f",
+            ChannelLayout::Layout3FLfe => "3f-lfe",
+            ChannelLayout::Layout2F1 => "2f1",
+            ChannelLayout::Layout2F1Lfe => "2f1-lfe",
+            ChannelLayout::Layout3F1 => "3f1",
+            ChannelLayout::Layout3F1Lfe => "3f1-lfe",
+            ChannelLayout::Layout2F2 => "2f2",
+            ChannelLayout::Layout2F2Lfe => "2f2-lfe",
+            ChannelLayout::Layout3F2 => "3f2",
+            ChannelLayout::Layout3F2Lfe => "3f2-lfe",
+            ChannelLayout::Layout3F3RLfe => "3f3r-lfe",
+            ChannelLayout::Layout3F4Lfe => "3f4-lfe",
+        }
+    }
+
+    /// Number of channels in the layout, or 0 if it's undefined.
+    pub fn channel_count(self) -> u32 {
+        match self {
+            ChannelLayout::Undefined => 0,
+            ChannelLayout::Mono => 1,
+            ChannelLayout::DualMono | ChannelLayout::MonoLfe | ChannelLayout::Stereo => 2,
+            ChannelLayout::DualMonoLfe |
+            ChannelLayout::StereoLfe |
+            ChannelLayout::Layout3F |
+            ChannelLayout::Layout2F1 => 3,
+            ChannelLayout::Layout3FLfe |
+            ChannelLayout::Layout2F1Lfe |
+            ChannelLayout::Layout3F1 |
+            ChannelLayout::Layout2F2 => 4,
+            ChannelLayout::Layout3F1Lfe | ChannelLayout::Layout2F2Lfe | ChannelLayout::Layout3F2 => 5,
+            ChannelLayout::Layout3F2Lfe => 6,
+            ChannelLayout::Layout3F3RLfe => 7,
+            ChannelLayout::Layout3F4Lfe => 8,
+        }
+    }
+}
+
+/// Formats as the lower case name from the table above, such as `stereo`
+/// or `3f2-lfe`.
+impl fmt::Display for ChannelLayout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
 /// Stream format initialization parameters.
 #[derive(Clone, Copy)]
 pub struct StreamParams {
@@ -177,13 +272,54 @@ pub struct StreamParams {
 }
 
 impl StreamParams {
+    fn new(
+        format: SampleFormat,
+        rate: u32,
+        channels: u32,
+        layout: ChannelLayout
+    ) -> StreamParams {
+        StreamParams {
+            raw: ffi::cubeb_stream_params {
+                format: format.raw(),
+                rate,
+                channels,
+                layout: layout as ffi::cubeb_channel_layout
+            }
+        }
+    }
+
+    /// Mono native endian 16 bit samples at `rate`.
+    pub fn mono_s16(rate: u32) -> StreamParams {
+        StreamParams::new(SampleFormat::S16NE, rate, 1, ChannelLayout::Mono)
+    }
+
+    /// Mono native endian float samples at `rate`.
+    pub fn mono_f32(rate: u32) -> StreamParams {
+        StreamParams::new(SampleFormat::Float32NE, rate, 1, ChannelLayout::Mono)
+    }
+
+    /// Stereo native endian 16 bit samples at `rate`.
+    pub fn stereo_s16(rate: u32) -> StreamParams {
+        StreamParams::new(SampleFormat::S16NE, rate, 2, ChannelLayout::Stereo)
+    }
+
+    /// Stereo native endian float samples at `rate`.
+    pub fn stereo_f32(rate: u32) -> StreamParams {
+        StreamParams::new(SampleFormat::Float32NE, rate, 2, ChannelLayout::Stereo)
+    }
+
     pub fn format(&self) -> SampleFormat {
+        self.known_format()
+            .unwrap_or_else(|| panic!("unknown sample format: {}", self.raw.format))
+    }
+
+    fn known_format(&self) -> Option<SampleFormat> {
         macro_rules! check( ($($raw:ident => $real:ident),*) => (
             $(if self.raw.format == ffi::$raw {
-                SampleFormat::$real
+                Some(SampleFormat::$real)
             }) else *
             else {
-                panic!("unknown sample format: {}", self.raw.format)
+                None
             }
         ) );
 
@@ -203,13 +339,28 @@ impl StreamParams {
         self.raw.channels as u32
     }
 
+    /// How long `frames` last at this rate. Zero if the rate is.
+    pub fn frames_to_duration(&self, frames: u64) -> Duration {
+        frames::frames_to_duration(frames, self.rate())
+    }
+
+    /// The nearest number of frames to `duration` at this rate.
+    pub fn duration_to_frames(&self, duration: Duration) -> u64 {
+        frames::duration_to_frames(duration, self.rate())
+    }
+
     pub fn layout(&self) -> ChannelLayout {
+        self.known_layout()
+            .unwrap_or_else(|| panic!("unknown channel layout: {}", self.raw.layout))
+    }
+
+    fn known_layout(&self) -> Option<ChannelLayout> {
         macro_rules! check( ($($raw:ident => $real:ident),*) => (
             $(if self.raw.layout == ffi::$raw {
-                ChannelLayout::$real
+                Some(ChannelLayout::$real)
             }) else *
             else {
-                panic!("unknown channel layout: {}", self.raw.layout)
+                None
             }
         ) );
 
@@ -235,6 +386,74 @@ impl StreamParams {
     }
 }
 
+impl PartialEq for StreamParams {
+    fn eq(&self, other: &StreamParams) -> bool {
+        self.raw.format == other.raw.format &&
+            self.raw.rate == other.raw.rate &&
+            self.raw.channels == other.raw.channels &&
+            self.raw.layout == other.raw.layout
+    }
+}
+
+impl Eq for StreamParams {}
+
+impl Hash for StreamParams {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.raw.format.hash(state);
+        self.raw.rate.hash(state);
+        self.raw.channels.hash(state);
+        self.raw.layout.hash(state);
+    }
+}
+
+// A value, or in hex the raw one it couldn't be read from.
+struct OrRaw<T, R>(Option<T>, R);
+
+impl<T: fmt::Debug, R: fmt::LowerHex> fmt::Debug for OrRaw<T, R> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            Some(ref value) => value.fmt(f),
+            None => write!(f, "{:#x}", self.1),
+        }
+    }
+}
+
+impl<T: fmt::Display, R: fmt::LowerHex> fmt::Display for OrRaw<T, R> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            Some(ref value) => value.fmt(f),
+            None => write!(f, "{:#x}", self.1),
+        }
+    }
+}
+
+impl fmt::Debug for StreamParams {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("StreamParams")
+            .field("format", &OrRaw(self.known_format(), self.raw.format))
+            .field("rate", &self.rate())
+            .field("channels", &self.channels())
+            .field("layout", &OrRaw(self.known_layout(), self.raw.layout))
+            .finish()
+    }
+}
+
+/// Formats as `rate:format:channels:layout`, for example
+/// `48000:f32le:2:stereo`, which `parse` reads back. A format or layout
+/// libcubeb added since is written as its raw value in hex.
+impl fmt::Display for StreamParams {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}:{}:{}:{}",
+            self.rate(),
+            OrRaw(self.known_format(), self.raw.format),
+            self.channels(),
+            OrRaw(self.known_layout(), self.raw.layout)
+        )
+    }
+}
+
 impl Binding for StreamParams {
     type Raw = *const ffi::cubeb_stream_params;
     unsafe fn from_raw(raw: *const ffi::cubeb_stream_params) -> Self {
@@ -317,7 +536,9 @@ pub enum ErrorCode {
     /// Requested operation is not supported
     NotSupported,
     /// Requested device is unavailable
-    DeviceUnavailable
+    DeviceUnavailable,
+    /// A raw error code libcubeb doesn't define
+    Unknown(i32)
 }
 
 /// Whether a particular device is an input device (e.g. a microphone), or an
@@ -490,6 +711,13 @@ impl DeviceInfo {
     pub fn latency_hi(&self) -> u32 {
         self.raw.latency_hi
     }
+
+    /// Lowest and highest possible latency at the default rate.
+    pub fn latency_range(&self) -> RangeInclusive<Duration> {
+        let rate = self.default_rate();
+        frames::frames_to_duration(u64::from(self.latency_lo()), rate)
+            ..=frames::frames_to_duration(u64::from(self.latency_hi()), rate)
+    }
 }
 
 pub type Result<T> = ::std::result::Result<T, Error>;
@@ -594,4 +822,100 @@ mod tests {
         assert_eq!(params.rate(), 44100);
     }
 
+    #[test]
+    fn channel_layout_channel_count() {
+        use super::ChannelLayout;
+        assert_eq!(ChannelLayout::Undefined.channel_count(), 0);
+        assert_eq!(ChannelLayout::Mono.channel_count(), 1);
+        assert_eq!(ChannelLayout::Stereo.channel_count(), 2);
+        assert_eq!(ChannelLayout::Layout2F1Lfe.channel_count(), 4);
+        assert_eq!(ChannelLayout::Layout3F2Lfe.channel_count(), 6);
+        assert_eq!(ChannelLayout::Layout3F4Lfe.channel_count(), 8);
+    }
+
+    #[test]
+    fn stream_params_durations() {
+        use std::time::Duration;
+        let mut raw: super::ffi::cubeb_stream_params = unsafe { mem::zeroed() };
+        raw.rate = 48000;
+        let params = unsafe { super::StreamParams::from_raw(&raw as *const _) };
+        assert_eq!(params.frames_to_duration(480), Duration::from_millis(10));
+        assert_eq!(params.duration_to_frames(Duration::from_millis(10)), 480);
+    }
+
+    #[test]
+    fn device_info_latency_range() {
+        use std::time::Duration;
+        let mut raw: super::ffi::cubeb_device_info = unsafe { mem::zeroed() };
+        raw.default_rate = 44100;
+        raw.latency_lo = 441;
+        raw.latency_hi = 4410;
+        let info = super::DeviceInfo { raw };
+        assert_eq!(info.latency_range(), Duration::from_millis(10)..=Duration::from_millis(100));
+    }
+
+    #[test]
+    fn stream_params_presets() {
+        use super::{ChannelLayout, SampleFormat, StreamParams};
+        let params = StreamParams::stereo_f32(48000);
+        assert_eq!(params.format(), SampleFormat::from(super::ffi::CUBEB_SAMPLE_FLOAT32NE));
+        assert_eq!(params.rate(), 48000);
+        assert_eq!(params.channels(), 2);
+        assert_eq!(params.layout(), ChannelLayout::Stereo);
+        let params = StreamParams::mono_s16(16000);
+        assert_eq!(params.format(), SampleFormat::from(super::ffi::CUBEB_SAMPLE_S16NE));
+        assert_eq!(params.channels(), 1);
+        assert_eq!(params.layout(), ChannelLayout::Mono);
+        assert_eq!(StreamParams::mono_f32(8000).channels(), 1);
+        assert_eq!(StreamParams::stereo_s16(8000).channels(), 2);
+    }
+
+    #[test]
+    fn stream_params_eq_hash_fmt() {
+        use super::StreamParams;
+        use std::collections::HashSet;
+        let params = StreamParams::stereo_f32(48000);
+        assert_eq!(params, StreamParams::stereo_f32(48000));
+        assert!(params != StreamParams::stereo_f32(44100));
+        assert!(params != StreamParams::stereo_s16(48000));
+        assert!(params != StreamParams::mono_f32(48000));
+        let set: HashSet<_> = [
+            params,
+            StreamParams::stereo_f32(48000),
+            StreamParams::mono_s16(8000),
+        ].iter()
+            .cloned()
+            .collect();
+        assert_eq!(set.len(), 2);
+
+        let endian = if cfg!(target_endian = "little") { "le" } else { "be" };
+        assert_eq!(params.to_string(), format!("48000:f32{}:2:stereo", endian));
+        assert_eq!(
+            format!("{:?}", StreamParams::mono_s16(8000)),
+            format!("StreamParams {{ format: S16{}, rate: 8000, channels: 1, layout: Mono }}",
+                    endian.to_uppercase())
+        );
+        let raw = super::ffi::cubeb_stream_params {
+            format: super::ffi::CUBEB_SAMPLE_S16BE,
+            rate: 44100,
+            channels: 6,
+            layout: super::ffi::CUBEB_LAYOUT_3F2_LFE
+        };
+        let params = unsafe { StreamParams::from_raw(&raw as *const _) };
+        assert_eq!(params.to_string(), "44100:s16be:6:3f2-lfe");
+
+        // Raw values this crate doesn't know are shown rather than panicking.
+        let raw = super::ffi::cubeb_stream_params {
+            format: 0x7,
+            layout: 0x1234,
+            ..raw
+        };
+        let params = unsafe { StreamParams::from_raw(&raw as *const _) };
+        assert_eq!(params.to_string(), "44100:0x7:6:0x1234");
+        assert_eq!(
+            format!("{:?}", params),
+            "StreamParams { format: 0x7, rate: 44100, channels: 6, layout: 0x1234 }"
+        );
+    }
+
 }
@@ -0,0 +1,418 @@
+// Copyright © 2017 Mozilla Foundation
+//
+// This program is made available under an ISC-style license.  See the
+// accompanying file LICENSE for details.
+
+//! Reading stream parameters from strings, for command lines, config
+//! files and test fixtures.
+
+use {ChannelLayout, SampleFormat, StreamParams};
+use std::{error, fmt};
+use std::str::FromStr;
+
+const LAYOUTS: [ChannelLayout; 19] = [
+    ChannelLayout::Undefined,
+    ChannelLayout::DualMono,
+    ChannelLayout::DualMonoLfe,
+    ChannelLayout::Mono,
+    ChannelLayout::MonoLfe,
+    ChannelLayout::Stereo,
+    ChannelLayout::StereoLfe,
+    ChannelLayout::Layout3F,
+    ChannelLayout::Layout3FLfe,
+    ChannelLayout::Layout2F1,
+    ChannelLayout::Layout2F1Lfe,
+    ChannelLayout::Layout3F1,
+    ChannelLayout::Layout3F1Lfe,
+    ChannelLayout::Layout2F2,
+    ChannelLayout::Layout2F2Lfe,
+    ChannelLayout::Layout3F2,
+    ChannelLayout::Layout3F2Lfe,
+    ChannelLayout::Layout3F3RLfe,
+    ChannelLayout::Layout3F4Lfe
+];
+
+/// A string that isn't a `StreamParams`, `SampleFormat` or
+/// `ChannelLayout`, and where it went wrong.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ParseError {
+    expected: String,
+    token: Option<String>,
+    position: usize
+}
+
+impl ParseError {
+    /// What was expected, such as "a sample format".
+    pub fn expected(&self) -> &str {
+        &self.expected
+    }
+
+    /// The token that was found instead, or `None` if the string ended
+    /// first.
+    pub fn token(&self) -> Option<&str> {
+        self.token.as_deref()
+    }
+
+    /// Byte offset of the token, or the string's length if it ended.
+    pub fn position(&self) -> usize {
+        self.position
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.token {
+            Some(ref token) => write!(
+                f,
+                "expected {}, found \"{}\" at {}",
+                self.expected,
+                token,
+                self.position
+            ),
+            None => write!(f, "expected {}, found end of input", self.expected),
+        }
+    }
+}
+
+impl error::Error for ParseError {}
+
+// Reads a token, or fails with `None`.
+type Parser<T> = fn(&str) -> Option<T>;
+
+fn format(token: &str) -> Option<SampleFormat> {
+    match &*token.to_ascii_lowercase() {
+        "s16" | "s16ne" => Some(SampleFormat::S16NE),
+        "s16le" => Some(SampleFormat::S16LE),
+        "s16be" => Some(SampleFormat::S16BE),
+        "f32" | "f32ne" | "float32" | "float32ne" => Some(SampleFormat::Float32NE),
+        "f32le" | "float32le" => Some(SampleFormat::Float32LE),
+        "f32be" | "float32be" => Some(SampleFormat::Float32BE),
+        _ => None,
+    }
+}
+
+fn layout(token: &str) -> Option<ChannelLayout> {
+    let name = token.to_ascii_lowercase().replace('_', "-");
+    match &*name {
+        "2.1" => Some(ChannelLayout::StereoLfe),
+        "5.0" => Some(ChannelLayout::Layout3F2),
+        "5.1" => Some(ChannelLayout::Layout3F2Lfe),
+        "6.1" => Some(ChannelLayout::Layout3F3RLfe),
+        "7.1" => Some(ChannelLayout::Layout3F4Lfe),
+        _ => LAYOUTS.iter().cloned().find(|layout| layout.name() == name),
+    }
+}
+
+fn digits(token: &str) -> Option<u32> {
+    if token.is_empty() || !token.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    token.parse().ok()
+}
+
+// A positive rate in Hz, such as `48000`, `48000hz`, `48k` or `44.1khz`.
+fn rate(token: &str) -> Option<u32> {
+    let token = token.to_ascii_lowercase();
+    let token = token.trim_end_matches("hz");
+    let rate = match token.strip_suffix('k') {
+        Some(khz) => {
+            let (whole, fraction) = khz.split_once('.').unwrap_or((khz, "0"));
+            if fraction.len() > 3 {
+                return None;
+            }
+            let milli = digits(fraction)? * 10u32.pow(3 - fraction.len() as u32);
+            digits(whole)?.checked_mul(1000)?.checked_add(milli)?
+        },
+        None => digits(token)?,
+    };
+    if rate == 0 {
+        None
+    } else {
+        Some(rate)
+    }
+}
+
+fn channels(token: &str) -> Option<u32> {
+    digits(token).filter(|&channels| channels > 0)
+}
+
+// The input split at colons and whitespace, with each token's offset.
+struct Tokens<'a> {
+    len: usize,
+    tokens: Vec<(usize, &'a str)>,
+    next: usize
+}
+
+impl<'a> Tokens<'a> {
+    fn new(s: &'a str) -> Tokens<'a> {
+        let tokens = s.split(|c: char| c == ':' || c.is_whitespace())
+            .filter(|token| !token.is_empty())
+            .map(|token| (token.as_ptr() as usize - s.as_ptr() as usize, token))
+            .collect();
+        Tokens {
+            len: s.len(),
+            tokens,
+            next: 0
+        }
+    }
+
+    fn at_end(&self) -> bool {
+        self.next == self.tokens.len()
+    }
+
+    // An error for the next token.
+    fn error<S: Into<String>>(&self, expected: S) -> ParseError {
+        self.error_at(self.next, expected)
+    }
+
+    // An error for the token just taken, which parsed but doesn't fit.
+    fn rejected<S: Into<String>>(&self, expected: S) -> ParseError {
+        self.error_at(self.next - 1, expected)
+    }
+
+    fn error_at<S: Into<String>>(&self, index: usize, expected: S) -> ParseError {
+        let (position, token) = match self.tokens.get(index) {
+            Some(&(position, token)) => (position, Some(token.to_owned())),
+            None => (self.len, None),
+        };
+        ParseError {
+            expected: expected.into(),
+            token,
+            position
+        }
+    }
+
+    // The next token, if it's a `T`.
+    fn optional<T>(&mut self, parse: Parser<T>) -> Option<T> {
+        let value = self.tokens.get(self.next).and_then(|&(_, token)| parse(token));
+        if value.is_some() {
+            self.next += 1;
+        }
+        value
+    }
+
+    fn expect<T>(&mut self, expected: &str, parse: Parser<T>) -> Result<T, ParseError>
+    {
+        self.optional(parse).ok_or_else(|| self.error(expected))
+    }
+
+    fn end(&self) -> Result<(), ParseError> {
+        if self.at_end() {
+            Ok(())
+        } else {
+            Err(self.error("end of input"))
+        }
+    }
+}
+
+fn single<T>(s: &str, expected: &str, parse: Parser<T>) -> Result<T, ParseError> {
+    let mut tokens = Tokens::new(s);
+    let value = tokens.expect(expected, parse)?;
+    tokens.end()?;
+    Ok(value)
+}
+
+/// Reads names as `Display` writes them, ignoring case. `s16` and `f32`
+/// are taken as native endian, and `float32` may be spelled out.
+impl FromStr for SampleFormat {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<SampleFormat, ParseError> {
+        single(s, "a sample format", format)
+    }
+}
+
+/// Reads names as `Display` writes them, ignoring case and with `_` for
+/// `-`, as well as `2.1`, `5.0`, `5.1`, `6.1` and `7.1`.
+impl FromStr for ChannelLayout {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<ChannelLayout, ParseError> {
+        single(s, "a channel layout", layout)
+    }
+}
+
+/// Reads `rate format [channels] [layout]`, separated by colons or
+/// whitespace, such as `48000:f32le:2:stereo` or `44.1k s16 5.1`.
+///
+/// The rate may be given in kHz. Without a channel count, the layout's is
+/// used. Without a layout, it's undefined.
+impl FromStr for StreamParams {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<StreamParams, ParseError> {
+        let mut tokens = Tokens::new(s);
+        let rate = tokens.expect("a sample rate", rate)?;
+        let format = tokens.expect("a sample format", format)?;
+        let (channels, layout) = match tokens.optional(channels) {
+            Some(channels) if tokens.at_end() => {
+                (channels, ChannelLayout::Undefined)
+            },
+            Some(channels) => {
+                let expected = format!("a layout with {} channels", channels);
+                let layout = tokens.expect(&expected, layout)?;
+                let undefined = layout == ChannelLayout::Undefined;
+                if !undefined && layout.channel_count() != channels {
+                    return Err(tokens.rejected(expected));
+                }
+                (channels, layout)
+            },
+            None => match tokens.expect("a channel count or layout", layout)? {
+                ChannelLayout::Undefined => {
+                    return Err(tokens.rejected("a channel count or layout"));
+                },
+                layout => (layout.channel_count(), layout),
+            },
+        };
+        tokens.end()?;
+        Ok(StreamParams::new(format, rate, channels, layout))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ParseError;
+    use {ChannelLayout, SampleFormat, StreamParams};
+
+    // Every layout under the name in `ChannelLayout`'s table.
+    const LAYOUT_NAMES: [(&str, ChannelLayout); 19] = [
+        ("UNDEFINED", ChannelLayout::Undefined),
+        ("DUAL-MONO", ChannelLayout::DualMono),
+        ("DUAL-MONO-LFE", ChannelLayout::DualMonoLfe),
+        ("MONO", ChannelLayout::Mono),
+        ("MONO-LFE", ChannelLayout::MonoLfe),
+        ("STEREO", ChannelLayout::Stereo),
+        ("STEREO-LFE", ChannelLayout::StereoLfe),
+        ("3F", ChannelLayout::Layout3F),
+        ("3F-LFE", ChannelLayout::Layout3FLfe),
+        ("2F1", ChannelLayout::Layout2F1),
+        ("2F1-LFE", ChannelLayout::Layout2F1Lfe),
+        ("3F1", ChannelLayout::Layout3F1),
+        ("3F1-LFE", ChannelLayout::Layout3F1Lfe),
+        ("2F2", ChannelLayout::Layout2F2),
+        ("2F2-LFE", ChannelLayout::Layout2F2Lfe),
+        ("3F2", ChannelLayout::Layout3F2),
+        ("3F2-LFE", ChannelLayout::Layout3F2Lfe),
+        ("3F3R-LFE", ChannelLayout::Layout3F3RLfe),
+        ("3F4-LFE", ChannelLayout::Layout3F4Lfe)
+    ];
+
+    fn error(expected: &str, token: Option<&str>, position: usize) -> ParseError {
+        ParseError {
+            expected: expected.to_owned(),
+            token: token.map(|t| t.to_owned()),
+            position
+        }
+    }
+
+    #[test]
+    fn parse_channel_layouts() {
+        for &(name, layout) in &LAYOUT_NAMES {
+            assert_eq!(name.parse(), Ok(layout));
+            assert_eq!(name.to_lowercase().parse(), Ok(layout));
+            assert_eq!(name.replace('-', "_").parse(), Ok(layout));
+            assert_eq!(layout.to_string(), name.to_lowercase());
+            assert_eq!(layout.to_string().parse(), Ok(layout));
+        }
+        let aliases = [
+            ("2.1", ChannelLayout::StereoLfe),
+            ("5.0", ChannelLayout::Layout3F2),
+            ("5.1", ChannelLayout::Layout3F2Lfe),
+            ("6.1", ChannelLayout::Layout3F3RLfe),
+            ("7.1", ChannelLayout::Layout3F4Lfe),
+        ];
+        for &(name, layout) in &aliases {
+            assert_eq!(name.parse(), Ok(layout));
+        }
+        let bad = [
+            ("", error("a channel layout", None, 0)),
+            ("quad", error("a channel layout", Some("quad"), 0)),
+            (" stereo mono", error("end of input", Some("mono"), 8)),
+        ];
+        for &(s, ref err) in &bad {
+            assert_eq!(s.parse::<ChannelLayout>().as_ref(), Err(err), "{}", s);
+        }
+    }
+
+    #[test]
+    fn parse_sample_formats() {
+        let formats = [
+            ("s16le", SampleFormat::S16LE, true),
+            ("s16be", SampleFormat::S16BE, true),
+            ("s16ne", SampleFormat::S16NE, true),
+            ("f32le", SampleFormat::Float32LE, true),
+            ("f32be", SampleFormat::Float32BE, true),
+            ("f32ne", SampleFormat::Float32NE, true),
+            ("S16", SampleFormat::S16NE, false),
+            ("f32", SampleFormat::Float32NE, false),
+            ("Float32", SampleFormat::Float32NE, false),
+            ("float32le", SampleFormat::Float32LE, false),
+            ("FLOAT32BE", SampleFormat::Float32BE, false),
+        ];
+        for &(name, format, canonical) in &formats {
+            assert_eq!(name.parse(), Ok(format));
+            if canonical {
+                assert_eq!(format.to_string(), name);
+            }
+        }
+        assert_eq!(
+            "u8".parse::<SampleFormat>(),
+            Err(error("a sample format", Some("u8"), 0))
+        );
+    }
+
+    #[test]
+    fn parse_stream_params() {
+        let le = |le, be| if cfg!(target_endian = "little") { le } else { be };
+        let params = [
+            ("48000:f32le:2:stereo", "48000:f32le:2:stereo"),
+            ("44.1k s16 5.1", le("44100:s16le:6:3f2-lfe", "44100:s16be:6:3f2-lfe")),
+            ("  16khz\tS16BE  mono ", "16000:s16be:1:mono"),
+            ("22.05k:f32be:4", "22050:f32be:4:undefined"),
+            ("8000Hz f32le 2 undefined", "8000:f32le:2:undefined"),
+            ("96k:s16le:8:7.1", "96000:s16le:8:3f4-lfe"),
+        ];
+        for &(s, shown) in &params {
+            let parsed: StreamParams = s.parse().unwrap();
+            assert_eq!(parsed.to_string(), shown, "{}", s);
+            assert_eq!(shown.parse(), Ok(parsed));
+        }
+        assert_eq!("44.1k s16 5.1".parse(), Ok(StreamParams::new(
+            SampleFormat::S16NE,
+            44100,
+            6,
+            ChannelLayout::Layout3F2Lfe
+        )));
+    }
+
+    #[test]
+    fn parse_stream_params_errors() {
+        let errors = [
+            ("", error("a sample rate", None, 0)),
+            ("0:s16:2", error("a sample rate", Some("0"), 0)),
+            ("44.1:s16:2", error("a sample rate", Some("44.1"), 0)),
+            ("44.1001k:s16:2", error("a sample rate", Some("44.1001k"), 0)),
+            ("99999999k:s16:2", error("a sample rate", Some("99999999k"), 0)),
+            ("48000", error("a sample format", None, 5)),
+            ("48000:f33le:2", error("a sample format", Some("f33le"), 6)),
+            ("48000:f32le", error("a channel count or layout", None, 11)),
+            ("48000:f32le:0", error("a channel count or layout", Some("0"), 12)),
+            ("48000 f32le undefined", error("a channel count or layout", Some("undefined"), 12)),
+            ("48000 f32le 2 3f", error("a layout with 2 channels", Some("3f"), 14)),
+            ("48000 f32le 2 sterio", error("a layout with 2 channels", Some("sterio"), 14)),
+            ("48000 f32le stereo 2", error("end of input", Some("2"), 19)),
+        ];
+        for &(s, ref err) in &errors {
+            assert_eq!(s.parse::<StreamParams>().as_ref(), Err(err), "{}", s);
+        }
+        assert_eq!(
+            errors[6].1.to_string(),
+            "expected a sample format, found \"f33le\" at 6"
+        );
+        assert_eq!(errors[1].1.token(), Some("0"));
+        assert_eq!(
+            errors[5].1.to_string(),
+            "expected a sample format, found end of input"
+        );
+    }
+}
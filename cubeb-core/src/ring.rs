@@ -0,0 +1,490 @@
+// Copyright © 2017 Mozilla Foundation
+//
+// This program is made available under an ISC-style license.  See the
+// accompanying file LICENSE for details.
+
+//! Wait-free single producer, single consumer ring buffer
+//!
+//! A ring moves samples, frames or other plain data between a control
+//! thread and a stream's `data_callback`. Its storage is allocated by
+//! `ring`, or lives in memory shared with another process with
+//! `from_raw`, and neither end allocates, locks or blocks after that, so
+//! both are safe to use on the audio thread.
+//!
+//! Besides copying slices in and out, each end can borrow the ring's
+//! storage directly as two slices, the second holding whatever wrapped
+//! around the end of the storage.
+//!
+//! # Example
+//! ```
+//! extern crate cubeb_core;
+//!
+//! fn main() {
+//!     let (mut producer, mut consumer) = cubeb_core::ring::ring::<f32>(256);
+//!     producer.write(&[0.5; 100]);
+//!
+//!     // In the data callback...
+//!     let mut output = [0.0; 64];
+//!     assert_eq!(consumer.read(&mut output), 64);
+//! }
+//! ```
+
+use std::cmp;
+use std::ptr;
+use std::slice;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+/// Read and write positions of a ring. The positions run freely and wrap
+/// at `u32::MAX`, which is why capacities are powers of two.
+#[repr(C)]
+#[derive(Debug, Default)]
+pub struct RingHeader {
+    read: AtomicU32,
+    write: AtomicU32
+}
+
+/// Largest supported capacity, in elements.
+pub const MAX_CAPACITY: usize = 1 << 31;
+
+struct Storage<T> {
+    header: *const RingHeader,
+    data: *mut T,
+    capacity: u32,
+    // Whether `header` and `data` were allocated by `ring`.
+    owned: bool
+}
+
+// The producer only touches elements between `write` and
+// `read + capacity`, the consumer only those between `read` and `write`.
+unsafe impl<T: Send> Send for Storage<T> {}
+unsafe impl<T: Send> Sync for Storage<T> {}
+
+impl<T> Drop for Storage<T> {
+    fn drop(&mut self) {
+        if self.owned {
+            unsafe {
+                let data = ptr::slice_from_raw_parts_mut(self.data, self.capacity());
+                drop(Box::from_raw(data));
+                drop(Box::from_raw(self.header as *mut RingHeader));
+            }
+        }
+    }
+}
+
+impl<T> Storage<T> {
+    fn header(&self) -> &RingHeader {
+        unsafe { &*self.header }
+    }
+
+    fn capacity(&self) -> usize {
+        self.capacity as usize
+    }
+
+    fn used(&self) -> usize {
+        let h = self.header();
+        let write = h.write.load(Ordering::Acquire);
+        let read = h.read.load(Ordering::Acquire);
+        // Never trust a shared header to be consistent.
+        cmp::min(write.wrapping_sub(read), self.capacity) as usize
+    }
+
+    // The two runs of storage, in ring order, starting at position `pos`
+    // and `len` elements long in total.
+    #[allow(clippy::mut_from_ref)]
+    unsafe fn runs(&self, pos: u32, len: usize) -> (&mut [T], &mut [T]) {
+        let start = (pos as usize) & (self.capacity() - 1);
+        let first = cmp::min(len, self.capacity() - start);
+        (
+            slice::from_raw_parts_mut(self.data.add(start), first),
+            slice::from_raw_parts_mut(self.data, len - first)
+        )
+    }
+
+    // `runs`, for views that are only read.
+    unsafe fn runs_shared(&self, pos: u32, len: usize) -> (&[T], &[T]) {
+        let start = (pos as usize) & (self.capacity() - 1);
+        let first = cmp::min(len, self.capacity() - start);
+        (
+            slice::from_raw_parts(self.data.add(start), first),
+            slice::from_raw_parts(self.data, len - first)
+        )
+    }
+}
+
+/// Create a heap allocated ring holding at least `capacity` elements. The
+/// capacity is rounded up to a power of two.
+pub fn ring<T>(capacity: usize) -> (Producer<T>, Consumer<T>)
+where
+    T: Copy + Default + Send,
+{
+    ring_at(capacity, 0)
+}
+
+// A ring whose positions start at `pos`.
+fn ring_at<T>(capacity: usize, pos: u32) -> (Producer<T>, Consumer<T>)
+where
+    T: Copy + Default + Send,
+{
+    let capacity = capacity.max(1).next_power_of_two();
+    assert!(capacity <= MAX_CAPACITY);
+    let data = vec![T::default(); capacity].into_boxed_slice();
+    let header = RingHeader {
+        read: AtomicU32::new(pos),
+        write: AtomicU32::new(pos)
+    };
+    split(Storage {
+        header: Box::into_raw(Box::new(header)),
+        data: Box::into_raw(data) as *mut T,
+        capacity: capacity as u32,
+        owned: true
+    })
+}
+
+/// Create the two ends of a ring over existing memory. The header must be
+/// zeroed, or in the state the other end left it in.
+///
+/// # Safety
+///
+/// `header` and the `capacity` elements at `data` must stay valid for as
+/// long as the returned handles, and `capacity` must be a power of two no
+/// larger than `MAX_CAPACITY`. At most one producer and one consumer may
+/// use the ring at a time, across all processes sharing it.
+pub unsafe fn from_raw<T>(header: *const RingHeader, data: *mut T, capacity: usize)
+    -> (Producer<T>, Consumer<T>)
+where
+    T: Copy + Send,
+{
+    assert!(capacity.is_power_of_two() && capacity <= MAX_CAPACITY);
+    split(Storage {
+        header,
+        data,
+        capacity: capacity as u32,
+        owned: false
+    })
+}
+
+fn split<T>(storage: Storage<T>) -> (Producer<T>, Consumer<T>) {
+    let storage = Arc::new(storage);
+    (
+        Producer {
+            storage: storage.clone()
+        },
+        Consumer {
+            storage
+        }
+    )
+}
+
+/// Writing end of a ring.
+pub struct Producer<T = u8> {
+    storage: Arc<Storage<T>>
+}
+
+impl<T: Copy> Producer<T> {
+    pub fn capacity(&self) -> usize {
+        self.storage.capacity()
+    }
+
+    /// Number of elements that can be written without overwriting unread
+    /// ones.
+    pub fn available(&self) -> usize {
+        self.capacity() - self.storage.used()
+    }
+
+    /// Append as much of `buf` as fits and return the number of elements
+    /// written.
+    pub fn write(&mut self, buf: &[T]) -> usize {
+        let len = {
+            let (a, b) = self.write_slices();
+            let len = cmp::min(buf.len(), a.len() + b.len());
+            let first = cmp::min(len, a.len());
+            a[..first].copy_from_slice(&buf[..first]);
+            b[..len - first].copy_from_slice(&buf[first..len]);
+            len
+        };
+        self.commit(len);
+        len
+    }
+
+    /// Append `bufs` back to back if they all fit, and return whether
+    /// they did. The consumer sees all of them or none.
+    pub fn write_all(&mut self, bufs: &[&[T]]) -> bool {
+        let len: usize = bufs.iter().map(|b| b.len()).sum();
+        if len > self.available() {
+            return false;
+        }
+        {
+            let (a, b) = self.write_slices();
+            let data = bufs.iter().flat_map(|b| b.iter());
+            for (x, y) in a.iter_mut().chain(b.iter_mut()).zip(data) {
+                *x = *y;
+            }
+        }
+        self.commit(len);
+        true
+    }
+
+    /// The free part of the ring, in the order it is written. Elements
+    /// written to it are appended by `commit`.
+    pub fn write_slices(&mut self) -> (&mut [T], &mut [T]) {
+        let write = self.storage.header().write.load(Ordering::Relaxed);
+        let available = self.available();
+        unsafe { self.storage.runs(write, available) }
+    }
+
+    /// Append the first `len` elements of `write_slices`.
+    ///
+    /// # Panics
+    ///
+    /// If `len` is more than `available`.
+    pub fn commit(&mut self, len: usize) {
+        assert!(len <= self.available());
+        let h = self.storage.header();
+        let write = h.write.load(Ordering::Relaxed);
+        h.write.store(write.wrapping_add(len as u32), Ordering::Release);
+    }
+}
+
+/// Reading end of a ring.
+pub struct Consumer<T = u8> {
+    storage: Arc<Storage<T>>
+}
+
+impl<T: Copy> Consumer<T> {
+    pub fn capacity(&self) -> usize {
+        self.storage.capacity()
+    }
+
+    /// Number of elements waiting to be read.
+    pub fn available(&self) -> usize {
+        self.storage.used()
+    }
+
+    /// Fill as much of `buf` as there are elements for and return the
+    /// number of elements read.
+    pub fn read(&mut self, buf: &mut [T]) -> usize {
+        let len = {
+            let (a, b) = self.read_slices();
+            let len = cmp::min(buf.len(), a.len() + b.len());
+            let first = cmp::min(len, a.len());
+            buf[..first].copy_from_slice(&a[..first]);
+            buf[first..len].copy_from_slice(&b[..len - first]);
+            len
+        };
+        self.release(len);
+        len
+    }
+
+    /// The elements waiting to be read, in order. They stay in the ring
+    /// until `release`d.
+    pub fn read_slices(&self) -> (&[T], &[T]) {
+        let read = self.storage.header().read.load(Ordering::Relaxed);
+        unsafe { self.storage.runs_shared(read, self.available()) }
+    }
+
+    /// Remove the first `len` elements of `read_slices` from the ring.
+    ///
+    /// # Panics
+    ///
+    /// If `len` is more than `available`.
+    pub fn release(&mut self, len: usize) {
+        assert!(len <= self.available());
+        let h = self.storage.header();
+        let read = h.read.load(Ordering::Relaxed);
+        h.read.store(read.wrapping_add(len as u32), Ordering::Release);
+    }
+
+    /// Remove everything waiting to be read.
+    pub fn clear(&mut self) {
+        let h = self.storage.header();
+        let write = h.write.load(Ordering::Acquire);
+        h.read.store(write, Ordering::Release);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{from_raw, ring, ring_at, RingHeader};
+    use std::{cmp, thread};
+
+    #[test]
+    fn ring_capacity() {
+        let (p, c) = ring::<f32>(1000);
+        assert_eq!(p.capacity(), 1024);
+        assert_eq!(c.capacity(), 1024);
+        assert_eq!(p.available(), 1024);
+        assert_eq!(c.available(), 0);
+    }
+
+    #[test]
+    fn ring_wraps() {
+        let (mut p, mut c) = ring(8);
+        let mut buf = [0i16; 8];
+        assert_eq!(p.write(&[1, 2, 3, 4, 5, 6]), 6);
+        assert_eq!(c.read(&mut buf[..4]), 4);
+        assert_eq!(p.write(&[7, 8, 9, 10, 11, 12, 13]), 6);
+        assert_eq!(p.available(), 0);
+        assert_eq!(c.read(&mut buf), 8);
+        assert_eq!(buf, [5, 6, 7, 8, 9, 10, 11, 12]);
+        assert_eq!(c.read(&mut buf), 0);
+    }
+
+    #[test]
+    fn ring_slices() {
+        let (mut p, mut c) = ring(4);
+        p.write(&[(1i16, -1i16); 3]);
+        c.release(2);
+        {
+            let (a, b) = p.write_slices();
+            assert_eq!((a.len(), b.len()), (1, 2));
+            a[0] = (2, -2);
+            b[0] = (3, -3);
+        }
+        p.commit(2);
+        {
+            let (a, b) = c.read_slices();
+            assert_eq!(a, [(1, -1), (2, -2)]);
+            assert_eq!(b, [(3, -3)]);
+        }
+        c.release(1);
+        assert_eq!(c.available(), 2);
+        c.clear();
+        assert_eq!(c.available(), 0);
+        assert_eq!(p.available(), 4);
+    }
+
+    #[test]
+    fn ring_write_all() {
+        let (mut p, mut c) = ring::<u8>(8);
+        assert!(p.write_all(&[&[1, 2], &[], &[3, 4, 5]]));
+        assert!(!p.write_all(&[&[6, 7], &[8, 9]]));
+        assert_eq!(c.available(), 5);
+        let mut buf = [0u8; 8];
+        assert_eq!(c.read(&mut buf), 5);
+        assert_eq!(&buf[..5], &[1, 2, 3, 4, 5]);
+        // Wraps around the end of the storage.
+        assert!(p.write_all(&[&[6, 7], &[8, 9, 10, 11]]));
+        assert_eq!(c.read(&mut buf), 6);
+        assert_eq!(&buf[..6], &[6, 7, 8, 9, 10, 11]);
+    }
+
+    #[test]
+    #[should_panic]
+    fn ring_commit_too_much() {
+        let (mut p, _) = ring::<f32>(4);
+        p.commit(5);
+    }
+
+    #[test]
+    #[should_panic]
+    fn ring_release_too_much() {
+        let (mut p, mut c) = ring::<f32>(4);
+        p.write(&[1.0]);
+        c.release(2);
+    }
+
+    #[test]
+    fn ring_positions_wrap() {
+        let (mut p, mut c) = ring_at(4, u32::MAX - 2);
+        let mut buf = [0u8; 4];
+        for i in 0..4u8 {
+            assert_eq!(p.write(&[i, i + 1, i + 2]), 3);
+            assert_eq!(c.available(), 3);
+            assert_eq!(c.read(&mut buf), 3);
+            assert_eq!(buf[..3], [i, i + 1, i + 2]);
+        }
+    }
+
+    #[test]
+    fn ring_from_raw() {
+        let header = RingHeader::default();
+        let mut data = [0u8; 4];
+        let mut buf = [0u8; 4];
+        {
+            let (mut p, mut c) = unsafe { from_raw(&header, data.as_mut_ptr(), 4) };
+            assert_eq!(p.write(&[1, 2, 3]), 3);
+            assert_eq!(c.read(&mut buf[..2]), 2);
+        }
+        // A later pair of handles picks up where the first left off.
+        let (mut p, mut c) = unsafe { from_raw(&header, data.as_mut_ptr(), 4) };
+        assert_eq!(p.write(&[4, 5, 6]), 3);
+        assert_eq!(c.read(&mut buf), 4);
+        assert_eq!(buf, [3, 4, 5, 6]);
+    }
+
+    // Moves `count` elements numbered in order through a small ring, in
+    // chunks whose sizes don't divide its capacity, using either `write`
+    // and `read` or the slice API, and checks they come out in order.
+    fn stress(count: u32, slices: bool) {
+        let (mut p, mut c) = ring_at::<u32>(64, u32::MAX - 1000);
+        let writer = thread::spawn(move || {
+            let values: Vec<_> = (0..count).collect();
+            let mut rest = &values[..];
+            let mut chunk = 1;
+            while !rest.is_empty() {
+                let len = cmp::min(rest.len(), chunk);
+                let n = if slices {
+                    let n = {
+                        let (a, b) = p.write_slices();
+                        let n = cmp::min(len, a.len() + b.len());
+                        for (x, v) in a.iter_mut().chain(b.iter_mut()).zip(&rest[..n]) {
+                            *x = *v;
+                        }
+                        n
+                    };
+                    p.commit(n);
+                    n
+                } else {
+                    p.write(&rest[..len])
+                };
+                if n == 0 {
+                    thread::yield_now();
+                }
+                rest = &rest[n..];
+                chunk = chunk % 23 + 1;
+            }
+        });
+        let mut next = 0;
+        let mut buf = [0u32; 17];
+        let mut chunk = 1;
+        while next < count {
+            let n = if slices {
+                let n = {
+                    let (a, b) = c.read_slices();
+                    let n = cmp::min(chunk, a.len() + b.len());
+                    for v in a.iter().chain(b).take(n) {
+                        assert_eq!(*v, next);
+                        next += 1;
+                    }
+                    n
+                };
+                c.release(n);
+                n
+            } else {
+                let n = c.read(&mut buf[..chunk]);
+                for v in &buf[..n] {
+                    assert_eq!(*v, next);
+                    next += 1;
+                }
+                n
+            };
+            if n == 0 {
+                thread::yield_now();
+            }
+            chunk = chunk % 17 + 1;
+        }
+        writer.join().unwrap();
+        assert_eq!(c.available(), 0);
+    }
+
+    #[test]
+    fn ring_stress() {
+        stress(200_000, false);
+    }
+
+    #[test]
+    fn ring_stress_slices() {
+        stress(200_000, true);
+    }
+}